@@ -0,0 +1,100 @@
+use hyper::body::Incoming;
+use hyper::{Request, Response};
+use hyper_mtls_server::MtlServer;
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+pub struct Pki {
+    dir: PathBuf,
+    client_config: Arc<ClientConfig>,
+}
+
+impl Pki {
+    pub fn generate(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "hyper-mtls-server-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Bench CA");
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_params =
+            CertificateParams::new(vec!["localhost".into()]).unwrap();
+        let server_key = KeyPair::generate().unwrap();
+        let server =
+            server_params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+        let mut client_params = CertificateParams::new(Vec::new()).unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "bench-client");
+        let client_key = KeyPair::generate().unwrap();
+        let client =
+            client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        std::fs::write(dir.join("ca.crt"), ca.pem()).unwrap();
+        std::fs::write(dir.join("server.crt"), server.pem()).unwrap();
+        std::fs::write(dir.join("server.key"), server_key.serialize_pem())
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let client_chain = vec![CertificateDer::from(client.der().to_vec())];
+        let client_key = PrivatePkcs8KeyDer::from(client_key.serialize_der());
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(client_chain, client_key.into())
+            .unwrap();
+
+        Self {
+            dir,
+            client_config: Arc::new(client_config),
+        }
+    }
+
+    pub fn server(&self) -> MtlServer {
+        let path = |name: &str| -> Box<str> {
+            self.dir.join(name).to_str().unwrap().into()
+        };
+        MtlServer::new(path("server.crt"), path("server.key"), path("ca.crt"))
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> TlsStream<TcpStream> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let connector = TlsConnector::from(self.client_config.clone());
+        let name = ServerName::try_from("localhost").unwrap();
+        connector.connect(name, stream).await.unwrap()
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub async fn spawn_server(server: MtlServer) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = tower::service_fn(|_: Request<Incoming>| async {
+        Ok::<_, Infallible>(Response::new(String::new()))
+    });
+    tokio::spawn(async move { server.serve_service(listener, service).await });
+    addr
+}
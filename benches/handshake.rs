@@ -0,0 +1,44 @@
+mod common;
+
+use common::{spawn_server, Pki};
+use criterion::{criterion_group, criterion_main, Criterion};
+use hyper_mtls_server::MtlServer;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::task::JoinSet;
+
+const CONCURRENT_HANDSHAKES: usize = 32;
+
+fn bench_handshakes(
+    c: &mut Criterion,
+    name: &str,
+    configure: fn(MtlServer) -> MtlServer,
+) {
+    let pki = Arc::new(Pki::generate(name));
+    let runtime = Runtime::new().unwrap();
+    let addr = runtime.block_on(spawn_server(configure(pki.server())));
+
+    c.bench_function(name, |b| {
+        b.to_async(&runtime).iter(|| async {
+            let mut handshakes = JoinSet::new();
+            for _ in 0..CONCURRENT_HANDSHAKES {
+                let pki = pki.clone();
+                handshakes.spawn(async move { pki.connect(addr).await });
+            }
+            while handshakes.join_next().await.is_some() {}
+        })
+    });
+}
+
+fn handshake_inline(c: &mut Criterion) {
+    bench_handshakes(c, "handshake_inline", |server| server);
+}
+
+fn handshake_offload(c: &mut Criterion) {
+    bench_handshakes(c, "handshake_offload", |server| {
+        server.with_handshake_offload(2, 64)
+    });
+}
+
+criterion_group!(benches, handshake_inline, handshake_offload);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+mod common;
+
+use common::{spawn_server, Pki};
+use criterion::{criterion_group, criterion_main, Criterion};
+use hyper::client::conn::http1;
+use hyper::Request;
+use hyper_mtls_server::MtlServer;
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use tokio::runtime::Runtime;
+
+const REQUESTS_PER_CONNECTION: usize = 100;
+
+async fn request(pki: &Pki, addr: SocketAddr, requests: usize) {
+    let stream = pki.connect(addr).await;
+    let (mut sender, conn) =
+        http1::handshake(TokioIo::new(stream)).await.unwrap();
+    tokio::spawn(conn);
+
+    for _ in 0..requests {
+        sender.ready().await.unwrap();
+        let req = Request::get("/").body(String::new()).unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        assert!(response.status().is_success());
+    }
+}
+
+fn config_setup(c: &mut Criterion) {
+    let pki = Pki::generate("config_setup");
+    let server = pki.server();
+
+    c.bench_function("config_setup", |b| {
+        b.iter(|| server.tls_acceptor().unwrap())
+    });
+}
+
+fn requests_keep_alive(c: &mut Criterion) {
+    let pki = Pki::generate("requests_keep_alive");
+    let runtime = Runtime::new().unwrap();
+    let addr = runtime.block_on(spawn_server(pki.server()));
+
+    c.bench_function("requests_keep_alive", |b| {
+        b.to_async(&runtime)
+            .iter(|| request(&pki, addr, REQUESTS_PER_CONNECTION))
+    });
+}
+
+fn bench_accept_loop(
+    c: &mut Criterion,
+    name: &str,
+    configure: fn(MtlServer) -> MtlServer,
+) {
+    let pki = Pki::generate(name);
+    let runtime = Runtime::new().unwrap();
+    let addr = runtime.block_on(spawn_server(configure(pki.server())));
+
+    c.bench_function(name, |b| {
+        b.to_async(&runtime).iter(|| request(&pki, addr, 1))
+    });
+}
+
+fn accept_loop(c: &mut Criterion) {
+    bench_accept_loop(c, "accept_loop", |server| server);
+}
+
+fn accept_loop_limited(c: &mut Criterion) {
+    bench_accept_loop(c, "accept_loop_limited", |server| {
+        server.with_max_connections(1024)
+    });
+}
+
+criterion_group!(
+    benches,
+    config_setup,
+    requests_keep_alive,
+    accept_loop,
+    accept_loop_limited
+);
+criterion_main!(benches);
@@ -4,7 +4,7 @@ use hyper::body::{Bytes, Incoming};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response};
-use hyper_mtls_server::MtlServer;
+use hyper_mtls_server::{MtlServer, MtlServerArgs};
 use hyper_util::rt::TokioIo;
 use std::convert::Infallible;
 use std::error::Error;
@@ -13,32 +13,16 @@ use tokio::net::TcpListener;
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Config {
-    #[clap(env)]
-    #[arg(short, long, value_name = "PORT", default_value = "3002")]
-    port: u32,
-
-    #[clap(env)]
-    #[arg(long, value_name = "FILE")]
-    server_certificate_path: String,
-
-    #[clap(env)]
-    #[arg(long, value_name = "FILE")]
-    server_private_key_path: String,
-
-    #[clap(env)]
-    #[arg(long, value_name = "FILE")]
-    client_ca_certificate_path: String,
+    #[command(flatten)]
+    server: MtlServerArgs,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let config = Config::parse();
 
-    let socket = TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
-    let client_ca_cert = Box::from(config.client_ca_certificate_path);
-    let server_crt = Box::from(config.server_certificate_path);
-    let server_key = Box::from(config.server_private_key_path);
-    let server = MtlServer::new(server_crt, server_key, client_ca_cert);
+    let socket = TcpListener::bind(config.server.bind_addr()).await?;
+    let server = MtlServer::from(config.server);
 
     let result = server
         .serve(socket, |stream, acceptor| {
@@ -0,0 +1,359 @@
+use crate::backend::{Backend, TlsBackend};
+use crate::ban::BanList;
+use crate::handle::ListenerRegistration;
+use crate::handshake::{HandshakeError, Handshaker};
+use crate::identity_cache::IdentityCache;
+use crate::metrics::Metrics;
+use crate::reload::{AcceptorReload, Reload};
+use crate::revocation::Revocations;
+use crate::{
+    ClientAuth, ConnInfo, ConnectionId, Error, MtlServer, ServerTlsStream,
+};
+use futures_util::stream::{FuturesUnordered, Stream, StreamExt};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tower_service::Service;
+
+/// The server's connection pipeline without the accept loop: the TLS
+/// handshake with the configured timeout and offload, followed by the
+/// revocation check. Use it to serve connections from your own listener or
+/// test driver. Failures are counted in the server metrics.
+///
+/// ```no_run
+/// # use hyper_mtls_server::MtlServer;
+/// # use tokio::net::TcpStream;
+/// # use tower_service::Service;
+/// # async fn example(server: MtlServer, tcp_stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+/// let mut acceptor = server.mtls_acceptor()?;
+/// let (stream, conn_info) = acceptor.call(tcp_stream).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MtlsAcceptor {
+    handshaker: Handshaker,
+    revocations: Revocations,
+    metrics: Arc<Metrics>,
+    identity_cache: Arc<IdentityCache>,
+    bans: Arc<BanList>,
+    rotation_window: Duration,
+    _reload: Arc<dyn Reload>,
+}
+
+impl MtlsAcceptor {
+    /// Performs the handshake on a connection from `remote_addr`. Besides
+    /// TCP streams, any byte stream works, e.g. a `tokio::io::duplex` pipe
+    /// in tests, see [`testing::connect_duplex`](crate::testing::connect_duplex).
+    pub async fn accept<IO>(
+        &self,
+        stream: IO,
+        remote_addr: SocketAddr,
+    ) -> Accepted<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.accept_with_id(stream, ConnectionId::new(), remote_addr)
+            .await
+    }
+
+    pub(crate) async fn accept_with_id<IO>(
+        &self,
+        stream: IO,
+        id: ConnectionId,
+        remote_addr: SocketAddr,
+    ) -> Accepted<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let pending = self.metrics.handshake_started();
+        let accepted = self.handshaker.accept(stream).await;
+        drop(pending);
+        let stream = accepted
+            .inspect_err(|err| self.handshake_failed(err, remote_addr))?;
+
+        let conn_info = ConnInfo::new(
+            id,
+            remote_addr,
+            Backend::session(&stream),
+            self.identity_cache.clone(),
+        );
+        if self.is_diagnostic(&stream) {
+            return Ok((stream, conn_info));
+        }
+        if !self.revocations.allows(&conn_info).await {
+            self.metrics.connection_revoked();
+            let err = HandshakeError::Revoked;
+            self.bans.failed(remote_addr.ip(), &err, &self.metrics);
+            return Err(err);
+        }
+        Ok((stream, conn_info))
+    }
+
+    fn handshake_failed(&self, err: &HandshakeError, remote_addr: SocketAddr) {
+        self.metrics.handshake_failed();
+        self.bans.failed(remote_addr.ip(), err, &self.metrics);
+        if self.metrics.within_rotation(self.rotation_window) {
+            self.metrics.handshake_failed_after_rotation();
+            info!(
+                after_rotation = true,
+                reason = err.label(),
+                "handshake failed shortly after the client CAs changed"
+            );
+        }
+    }
+
+    pub(crate) fn is_diagnostic<IO>(&self, stream: &ServerTlsStream<IO>) -> bool
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.handshaker.is_diagnostic(stream)
+    }
+}
+
+impl Service<TcpStream> for MtlsAcceptor {
+    type Response = (ServerTlsStream<TcpStream>, ConnInfo);
+    type Error = HandshakeError;
+    type Future = Pin<Box<dyn Future<Output = Accepted> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<(), HandshakeError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: TcpStream) -> Self::Future {
+        let acceptor = self.clone();
+        Box::pin(async move {
+            let remote_addr = stream.peer_addr()?;
+            acceptor.accept(stream, remote_addr).await
+        })
+    }
+}
+
+type Accepted<IO = TcpStream> =
+    Result<(ServerTlsStream<IO>, ConnInfo), HandshakeError>;
+type Handshake = Pin<Box<dyn Future<Output = Accepted> + Send>>;
+
+/// The connections accepted by [`MtlServer::incoming`]. Handshakes run
+/// concurrently, so connections are yielded in the order their handshakes
+/// complete. Failed handshakes and accept errors are yielded as errors;
+/// the stream ends once the server handle requests a shutdown.
+pub struct Incoming {
+    listener: TcpListener,
+    acceptor: MtlsAcceptor,
+    handshakes: FuturesUnordered<Handshake>,
+    shutdown: Pin<Box<dyn Future<Output = Option<Duration>> + Send>>,
+    _registration: ListenerRegistration,
+}
+
+impl Stream for Incoming {
+    type Item = Accepted;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if self.shutdown.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        loop {
+            match self.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, addr))) => {
+                    let acceptor = self.acceptor.clone();
+                    self.handshakes.push(Box::pin(async move {
+                        acceptor.accept(stream, addr).await
+                    }));
+                }
+                Poll::Ready(Err(err)) => {
+                    return Poll::Ready(Some(Err(HandshakeError::Io(err))))
+                }
+                Poll::Pending => break,
+            }
+        }
+        match self.handshakes.poll_next_unpin(cx) {
+            Poll::Ready(Some(accepted)) => Poll::Ready(Some(accepted)),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl MtlServer {
+    /// Accepts connections on `listener` as a stream, for callers who prefer
+    /// `while let Some(conn) = incoming.next().await` over a callback.
+    /// Connection limits, quotas and load shedding are not applied.
+    pub fn incoming(&self, listener: TcpListener) -> Result<Incoming, Error> {
+        let acceptor = self.mtls_acceptor()?;
+        let handle = self.handle.clone();
+        let registration = self.start_listening(&listener);
+
+        Ok(Incoming {
+            listener,
+            acceptor,
+            handshakes: FuturesUnordered::new(),
+            shutdown: Box::pin(
+                async move { handle.shutdown_requested().await },
+            ),
+            _registration: registration,
+        })
+    }
+
+    /// Loads the certificates and builds the pipeline `serve_service` runs
+    /// for every connection, for use with a custom accept loop.
+    pub fn mtls_acceptor(&self) -> Result<MtlsAcceptor, Error> {
+        self.create_mtls_acceptor(false)
+    }
+
+    /// `serving` is set by `serve_service` and friends, which can answer
+    /// connections without a verified certificate at the HTTP layer.
+    pub(crate) fn create_mtls_acceptor(
+        &self,
+        serving: bool,
+    ) -> Result<MtlsAcceptor, Error> {
+        // The handshake has to succeed for the error response to be sent.
+        let client_auth = if serving && self.missing_cert_response() {
+            ClientAuth::Optional
+        } else {
+            self.client_auth
+        };
+        let handshaker = self.create_handshaker(client_auth, serving)?;
+        let revocations = self.create_revocations()?;
+        let reload: Arc<dyn Reload> = Arc::new(AcceptorReload {
+            server: self.clone(),
+            client_auth,
+            diagnostics: serving,
+            handshaker: handshaker.clone(),
+            ocsp: revocations.ocsp.clone(),
+        });
+        self.handle.register_reload(&reload);
+
+        Ok(MtlsAcceptor {
+            handshaker,
+            revocations,
+            metrics: self.handle.metrics.clone(),
+            identity_cache: self.handle.identity_cache.clone(),
+            bans: self.handle.bans.clone(),
+            rotation_window: self.rotation_window,
+            _reload: reload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir, INTERMEDIATE_CERT};
+    use rustls::pki_types::ServerName;
+    use tokio::io::AsyncWriteExt;
+    use tokio_rustls::TlsConnector;
+
+    fn server(fixtures: &FixtureDir) -> MtlServer {
+        fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+    }
+
+    /// Connects `client` to `addr` over TLS in the background.
+    fn connect(fixtures: &FixtureDir, client: &str, addr: SocketAddr) {
+        let config = fixtures.client_config(client).unwrap();
+        tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await?;
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let mut stream = TlsConnector::from(config)
+                .connect(server_name, stream)
+                .await?;
+            // Keeps the connection open until the server closes it.
+            stream.write_all(b"hello").await?;
+            tokio::io::copy(&mut stream, &mut tokio::io::sink()).await
+        });
+    }
+
+    /// Sends bytes that are not a TLS handshake to `addr`.
+    fn connect_plain(addr: SocketAddr) {
+        tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            tokio::io::copy(&mut stream, &mut tokio::io::sink()).await
+        });
+    }
+
+    #[tokio::test]
+    async fn accepts_tcp_streams_as_a_service() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = server(&fixtures);
+        let mut acceptor = server.mtls_acceptor().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        connect(&fixtures, "alice", addr);
+        let (stream, client_addr) = listener.accept().await.unwrap();
+        let (_, conn_info) = acceptor.call(stream).await.unwrap();
+        assert_eq!(conn_info.remote_addr(), client_addr);
+        let identity = conn_info.client_identity().unwrap();
+        assert_eq!(identity.common_name(), Some("alice"));
+
+        connect_plain(addr);
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(acceptor.call(stream).await.is_err());
+        assert_eq!(server.handle().metrics().handshake_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn yields_accepted_connections_until_shutdown() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = server(&fixtures);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = server.incoming(listener).unwrap();
+        assert_eq!(server.handle().local_addr(), Some(addr));
+
+        connect(&fixtures, "alice", addr);
+        let (_, conn_info) = incoming.next().await.unwrap().unwrap();
+        let identity = conn_info.client_identity().unwrap();
+        assert_eq!(identity.common_name(), Some("alice"));
+
+        connect_plain(addr);
+        assert!(incoming.next().await.unwrap().is_err());
+
+        server.handle().shutdown();
+        assert!(incoming.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn counts_failures_shortly_after_a_client_ca_change() {
+        let fixtures = FixtureDir::new().unwrap();
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        let fail = |server: MtlServer| {
+            let anonymous = anonymous.clone();
+            async move {
+                let acceptor = server.mtls_acceptor().unwrap();
+                let conn = connect_duplex(&acceptor, anonymous, "localhost");
+                assert!(conn.await.is_err());
+                server.handle().metrics()
+            }
+        };
+
+        let metrics = fail(server(&fixtures)).await;
+        assert_eq!(metrics.handshake_failures, 1);
+        assert_eq!(metrics.handshake_failures_after_rotation, 0);
+
+        let server = server(&fixtures);
+        server.mtls_acceptor().unwrap();
+        let ca = fixtures.path().join("ca.crt");
+        std::fs::write(&ca, INTERMEDIATE_CERT).unwrap();
+        let metrics = fail(server.clone()).await;
+        assert_eq!(metrics.handshake_failures, 1);
+        assert_eq!(metrics.handshake_failures_after_rotation, 1);
+        let server = server.with_rotation_window(Duration::ZERO);
+        let metrics = fail(server).await;
+        assert_eq!(metrics.handshake_failures, 2);
+        assert_eq!(metrics.handshake_failures_after_rotation, 1);
+    }
+}
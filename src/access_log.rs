@@ -0,0 +1,177 @@
+use crate::ConnInfo;
+use hyper::{Method, Request, Response, Uri};
+use pin_project_lite::pin_project;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Logs every request with the `hyper_mtls_server::access` target at info
+/// level, annotated with the client certificate CN and SPIFFE ID. Relies on
+/// the [`ConnInfo`] extension added by `serve_service` and friends.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AccessLogLayer;
+
+impl AccessLogLayer {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLog<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLog { inner }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessLog<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for AccessLog<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Error: fmt::Display,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = AccessLogFuture<S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let entry = Entry {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            conn_info: req.extensions().get::<ConnInfo>().cloned(),
+            start: Instant::now(),
+        };
+        AccessLogFuture {
+            inner: self.inner.call(req),
+            entry: Some(entry),
+        }
+    }
+}
+
+struct Entry {
+    method: Method,
+    uri: Uri,
+    conn_info: Option<ConnInfo>,
+    start: Instant,
+}
+
+impl Entry {
+    fn log(self, status: &dyn fmt::Display) {
+        let conn_info = self.conn_info.as_ref();
+        let identity = conn_info.and_then(|x| x.client_identity());
+        info!(
+            target: "hyper_mtls_server::access",
+            method = %self.method,
+            path = self.uri.path(),
+            status = %status,
+            latency_ms = self.start.elapsed().as_secs_f64() * 1000.0,
+            conn_id = conn_info.map(|x| display(x.id())),
+            remote_addr = conn_info.map(|x| display(x.remote_addr())),
+            client_cn = identity.and_then(|x| x.common_name()),
+            spiffe_id = identity.and_then(|x| x.spiffe_id()),
+        );
+    }
+}
+
+pin_project! {
+    pub struct AccessLogFuture<F> {
+        #[pin]
+        inner: F,
+        entry: Option<Entry>,
+    }
+}
+
+impl<F, B, E> Future for AccessLogFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    E: fmt::Display,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let output = std::task::ready!(this.inner.poll(cx));
+        if let Some(entry) = this.entry.take() {
+            match &output {
+                Ok(response) => entry.log(&response.status().as_u16()),
+                Err(err) => entry.log(&format_args!("error: {}", err)),
+            }
+        }
+        Poll::Ready(output)
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::testing::Recorder;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn logs_requests_with_the_client_identity() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .mtls_acceptor()
+            .unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        let conn_info = conn.unwrap().conn_info;
+
+        let recorder = Recorder::default();
+        let _guard = recorder.install();
+        let service = AccessLogLayer::new().layer(service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let mut req = Request::post("/orders?id=1").body(()).unwrap();
+        req.extensions_mut().insert(conn_info.clone());
+        service.oneshot(req).await.unwrap();
+
+        let events = recorder.events("hyper_mtls_server::access");
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.level, tracing::Level::INFO);
+        assert_eq!(event.field("method"), Some("POST"));
+        assert_eq!(event.field("path"), Some("/orders"));
+        assert_eq!(event.field("status"), Some("200"));
+        let id = conn_info.id().to_string();
+        assert_eq!(event.field("conn_id"), Some(&*id));
+        assert_eq!(event.field("client_cn"), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn logs_failed_requests() {
+        let recorder = Recorder::default();
+        let _guard = recorder.install();
+        let service = AccessLogLayer::new().layer(service_fn(|_| async {
+            Err::<Response<()>, _>("backend down")
+        }));
+        let req = Request::get("/").body(()).unwrap();
+        assert!(service.oneshot(req).await.is_err());
+
+        let events = recorder.events("hyper_mtls_server::access");
+        assert_eq!(events[0].field("status"), Some("error: backend down"));
+        assert_eq!(events[0].field("client_cn"), None);
+    }
+}
@@ -0,0 +1,65 @@
+use rustls::server::ClientHello;
+use rustls::ServerConfig;
+
+/// What the server does when a client offers ALPN protocols of which none
+/// is enabled. Clients that offer no protocols at all are always served.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AlpnMismatch {
+    /// Fail the handshake with a `no_application_protocol` alert.
+    #[default]
+    Reject,
+    /// Complete the handshake without ALPN, as if the client offered no
+    /// protocols. The connection is then served as HTTP/1.1, or HTTP/2 if
+    /// the client starts with the HTTP/2 preface.
+    FallBack,
+}
+
+/// Whether the client offers protocols, but none that `config` enables.
+#[cfg_attr(feature = "openssl", allow(dead_code))]
+pub(crate) fn is_mismatch(
+    hello: &ClientHello<'_>,
+    config: &ServerConfig,
+) -> bool {
+    if config.alpn_protocols.is_empty() {
+        return false;
+    }
+    match hello.alpn() {
+        Some(mut offered) => {
+            !offered.any(|x| config.alpn_protocols.iter().any(|y| y == x))
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn falls_back_to_no_alpn_when_enabled() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let alice = fixtures.client_config("alice").unwrap();
+        let mut spdy = rustls::ClientConfig::clone(&alice);
+        spdy.alpn_protocols = vec![b"spdy/3".to_vec()];
+        let spdy = Arc::new(spdy);
+
+        let acceptor = server.mtls_acceptor().unwrap();
+        let conn = connect_duplex(&acceptor, spdy.clone(), "localhost").await;
+        assert!(conn.is_err());
+
+        let server = server.with_alpn_mismatch(AlpnMismatch::FallBack);
+        let acceptor = server.mtls_acceptor().unwrap();
+        let conn = connect_duplex(&acceptor, spdy, "localhost").await;
+        assert_eq!(conn.unwrap().conn_info.alpn_protocol(), None);
+        let conn = connect_duplex(&acceptor, alice, "localhost").await;
+        let conn_info = conn.unwrap().conn_info;
+        assert_eq!(conn_info.alpn_protocol(), Some(&b"http/1.1"[..]));
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{ClientIdentity, ConnInfo};
+use futures_util::future::{ready, Either, Ready};
+use hyper::{Request, Response, StatusCode};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// A condition the client certificate has to meet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Requirement {
+    /// Any verified client certificate.
+    ClientCert,
+    /// A subject common name equal to the value.
+    CommonName(Box<str>),
+    /// A subject `OU` attribute equal to the value.
+    OrganizationalUnit(Box<str>),
+    /// A SPIFFE ID in the given trust domain, e.g. `example.org`.
+    SpiffeTrustDomain(Box<str>),
+}
+
+impl Requirement {
+    pub(crate) fn allows(&self, identity: &ClientIdentity) -> bool {
+        match self {
+            Self::ClientCert => true,
+            Self::CommonName(name) => identity.common_name() == Some(name),
+            Self::OrganizationalUnit(unit) => {
+                identity.organizational_units().any(|x| x == &**unit)
+            }
+            Self::SpiffeTrustDomain(domain) => identity
+                .spiffe_id()
+                .and_then(|x| x.strip_prefix("spiffe://"))
+                .and_then(|x| x.split('/').next())
+                .is_some_and(|x| x.eq_ignore_ascii_case(domain)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    prefix: Box<str>,
+    requirement: Requirement,
+}
+
+impl Rule {
+    fn matches(&self, path: &str) -> bool {
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// Checks the client identity against per path rules before the inner
+/// service runs. Requests without a client certificate are answered with
+/// `401 Unauthorized`, requests whose certificate doesn't meet the rule with
+/// `403 Forbidden`. The most specific matching prefix wins; paths without a
+/// rule are let through. Relies on the [`ConnInfo`] extension added by
+/// `serve_service` and friends.
+///
+/// ```no_run
+/// # use hyper_mtls_server::{AuthorizationLayer, Requirement};
+/// let layer = AuthorizationLayer::new()
+///     .require("/admin", Requirement::OrganizationalUnit("ops".into()))
+///     .require("/api", Requirement::SpiffeTrustDomain("example.org".into()));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct AuthorizationLayer {
+    rules: Arc<Vec<Rule>>,
+}
+
+impl AuthorizationLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requires `requirement` for `prefix` and the paths below it.
+    pub fn require(mut self, prefix: &str, requirement: Requirement) -> Self {
+        let rules = Arc::make_mut(&mut self.rules);
+        rules.push(Rule {
+            prefix: prefix.into(),
+            requirement,
+        });
+        rules.sort_by_key(|x| std::cmp::Reverse(x.prefix.len()));
+        self
+    }
+
+    fn status(&self, path: &str, conn_info: Option<&ConnInfo>) -> StatusCode {
+        let Some(rule) = self.rules.iter().find(|x| x.matches(path)) else {
+            return StatusCode::OK;
+        };
+        match conn_info.and_then(|x| x.client_identity()) {
+            Some(identity) if rule.requirement.allows(identity) => {
+                StatusCode::OK
+            }
+            Some(identity) => {
+                debug!(
+                    "{} does not meet {:?} for {}",
+                    identity.subject(),
+                    rule.requirement,
+                    path
+                );
+                StatusCode::FORBIDDEN
+            }
+            None => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl<S> Layer<S> for AuthorizationLayer {
+    type Service = Authorization<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Authorization {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Authorization<S> {
+    inner: S,
+    layer: AuthorizationLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Authorization<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response<ResBody>, S::Error>>, S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let conn_info = req.extensions().get::<ConnInfo>();
+        let status = self.layer.status(req.uri().path(), conn_info);
+        if status != StatusCode::OK {
+            let mut response = Response::new(ResBody::default());
+            *response.status_mut() = status;
+            return Either::Left(ready(Ok(response)));
+        }
+        Either::Right(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use http_body_util::Empty;
+    use hyper::body::Bytes;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    async fn alice() -> ConnInfo {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        conn.unwrap().conn_info
+    }
+
+    async fn status(
+        layer: &AuthorizationLayer,
+        path: &str,
+        conn_info: Option<&ConnInfo>,
+    ) -> StatusCode {
+        let service = layer.layer(service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+        }));
+        let mut req = Request::get(path).body(()).unwrap();
+        if let Some(conn_info) = conn_info {
+            req.extensions_mut().insert(conn_info.clone());
+        }
+        service.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn checks_the_rule_of_the_longest_matching_prefix() {
+        let alice = alice().await;
+        let layer = AuthorizationLayer::new()
+            .require("/api", Requirement::CommonName("alice".into()))
+            .require("/api/admin", Requirement::CommonName("bob".into()))
+            .require("/ops/", Requirement::OrganizationalUnit("ops".into()))
+            .require(
+                "/mesh",
+                Requirement::SpiffeTrustDomain("EXAMPLE.org".into()),
+            )
+            .require("/other", Requirement::SpiffeTrustDomain("org".into()));
+
+        let alice = Some(&alice);
+        assert_eq!(status(&layer, "/api", alice).await, StatusCode::OK);
+        assert_eq!(status(&layer, "/api/users", alice).await, StatusCode::OK);
+        assert_eq!(
+            status(&layer, "/api/admin/x", alice).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(&layer, "/ops", alice).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&layer, "/mesh", alice).await, StatusCode::OK);
+        assert_eq!(
+            status(&layer, "/other", alice).await,
+            StatusCode::FORBIDDEN
+        );
+        // Prefixes match whole path segments only.
+        assert_eq!(status(&layer, "/apis", None).await, StatusCode::OK);
+        assert_eq!(status(&layer, "/", None).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn requires_a_client_certificate_for_guarded_paths() {
+        let layer =
+            AuthorizationLayer::new().require("/", Requirement::ClientCert);
+        let alice = alice().await;
+        assert_eq!(status(&layer, "/x", Some(&alice)).await, StatusCode::OK);
+        assert_eq!(status(&layer, "/x", None).await, StatusCode::UNAUTHORIZED);
+    }
+}
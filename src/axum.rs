@@ -0,0 +1,183 @@
+use crate::{ClientIdentity, ConnInfo, Error, MtlServer};
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::Router;
+use hyper::Request;
+use std::convert::Infallible;
+use std::task::{Context, Poll};
+use tokio::net::TcpListener;
+use tower_service::Service;
+
+/// Connection details available to handlers through axum's
+/// `ConnectInfo<MtlsConnectInfo>` extractor when served with
+/// [`MtlServer::serve_router`].
+pub type MtlsConnectInfo = ConnInfo;
+
+/// Extracts the identity of the client certificate, answering requests on
+/// connections without one with `401 Unauthorized`. Together with
+/// [`ClientAuth::Optional`](crate::ClientAuth::Optional) this lets public and
+/// mTLS protected routes share a listener:
+///
+/// ```no_run
+/// # use hyper_mtls_server::RequireClientCert;
+/// async fn admin(RequireClientCert(identity): RequireClientCert) -> String {
+///     format!("hello {}", identity.subject())
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RequireClientCert(pub ClientIdentity);
+
+impl<S: Send + Sync> FromRequestParts<S> for RequireClientCert {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(conn_info) = parts.extensions.get::<ConnInfo>() else {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "connection info missing, serve the router with MtlServer",
+            ));
+        };
+        match conn_info.client_identity() {
+            Some(identity) => Ok(Self(identity.clone())),
+            None if conn_info.peer_certificates().is_empty() => {
+                Err((StatusCode::UNAUTHORIZED, "client certificate required"))
+            }
+            None => Err((StatusCode::FORBIDDEN, "client certificate rejected")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct AddExtension<S, T> {
+    inner: S,
+    value: T,
+}
+
+impl<S, T> AddExtension<S, T> {
+    fn new(inner: S, value: T) -> Self {
+        Self { inner, value }
+    }
+}
+
+impl<S, T, B> Service<Request<B>> for AddExtension<S, T>
+where
+    S: Service<Request<B>>,
+    T: Clone + Send + Sync + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        req.extensions_mut().insert(self.value.clone());
+        self.inner.call(req)
+    }
+}
+
+impl MtlServer {
+    pub async fn serve_router(
+        &self,
+        listener: TcpListener,
+        router: Router,
+    ) -> Result<(), Error> {
+        self.serve_make_service(listener, move |conn_info| {
+            let service =
+                AddExtension::new(router.clone(), ConnectInfo(conn_info));
+            async move { Ok::<_, Infallible>(service) }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::testing::send;
+    use axum::routing::get;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    /// Serves `router` on an ephemeral port for the rest of the test.
+    async fn serve(server: MtlServer, router: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            async move { server.serve_router(listener, router).await },
+        );
+        addr
+    }
+
+    /// The body of `GET path` as Alice.
+    async fn get_as_alice(
+        fixtures: &FixtureDir,
+        addr: SocketAddr,
+        path: &str,
+    ) -> String {
+        let config = fixtures.client_config("alice").unwrap();
+        let req = Request::get(path).body(Empty::<Bytes>::new()).unwrap();
+        let response = send(config, addr, req).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        String::from_utf8(body.to_bytes().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn handlers_extract_the_connect_info() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let router = Router::new().route(
+            "/",
+            get(
+                |ConnectInfo(info): ConnectInfo<MtlsConnectInfo>| async move {
+                    let identity = info.client_identity().unwrap();
+                    identity.common_name().unwrap().to_owned()
+                },
+            ),
+        );
+        let addr = serve(server, router).await;
+        assert_eq!(get_as_alice(&fixtures, addr, "/").await, "alice");
+    }
+
+    #[tokio::test]
+    async fn requires_a_client_certificate_where_extracted() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_client_auth(crate::ClientAuth::Optional)
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let router = Router::new().route("/public", get(|| async { "public" }));
+        let router = router.route(
+            "/admin",
+            get(
+                |RequireClientCert(identity): RequireClientCert| async move {
+                    identity.common_name().unwrap().to_owned()
+                },
+            ),
+        );
+        let addr = serve(server, router).await;
+        assert_eq!(get_as_alice(&fixtures, addr, "/admin").await, "alice");
+
+        let config = fixtures.anonymous_client_config().unwrap();
+        let req = Request::get("/admin").body(Empty::new()).unwrap();
+        let response = send(config.clone(), addr, req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let req = Request::get("/public").body(Empty::new()).unwrap();
+        let response = send(config, addr, req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,380 @@
+use crate::conn::Session;
+use crate::handshake::Rustls;
+#[cfg(feature = "openssl")]
+use crate::openssl_tls::OpenSsl;
+use crate::{ClientAuth, Error, MtlServer};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The TLS implementation the server runs on. It turns the server settings
+/// into its configuration and performs handshakes with it; everything after
+/// the handshake, from the revocation check to serving HTTP, only sees the
+/// stream and the [`Session`] it negotiated. rustls is the default backend;
+/// with the `openssl` feature, `with_openssl_backend` selects OpenSSL
+/// instead. APIs handing out rustls types, like [`MtlServer::serve`] and
+/// [`MtlServer::tls_acceptor`], stay tied to rustls.
+pub(crate) trait TlsBackend {
+    /// The configuration handshakes are performed with, replaced as a
+    /// whole when the certificates are reloaded.
+    type Config: Clone + Send + Sync + 'static;
+
+    type Stream<IO>: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Builds the configuration from the certificates and settings of
+    /// `server`. `diagnostics` enables the diagnostics host, if configured.
+    fn config(
+        server: &MtlServer,
+        client_auth: ClientAuth,
+        diagnostics: bool,
+    ) -> Result<Self::Config, Error>;
+
+    /// Performs the handshake. Errors should wrap the error of the backend,
+    /// which [`HandshakeError`](crate::HandshakeError) classifies.
+    fn accept<IO>(
+        config: Self::Config,
+        stream: IO,
+    ) -> impl Future<Output = io::Result<Self::Stream<IO>>> + Send
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn session<IO>(stream: &Self::Stream<IO>) -> Session
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Whether `stream` was accepted for the diagnostics host, without
+    /// verifying the client certificate.
+    fn is_diagnostic<IO>(
+        config: &Self::Config,
+        stream: &Self::Stream<IO>,
+    ) -> bool
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Limits the plaintext the backend buffers for `stream`.
+    fn set_buffer_limit<IO>(stream: &mut Self::Stream<IO>, limit: usize)
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+}
+
+/// Dispatches to the backend the server was configured with: rustls, or
+/// OpenSSL once selected with `with_openssl_backend`.
+pub(crate) struct Backend;
+
+#[derive(Clone)]
+pub(crate) enum BackendConfig {
+    Rustls(crate::handshake::TlsConfigs),
+    #[cfg(feature = "openssl")]
+    OpenSsl(crate::openssl_tls::OpenSslConfig),
+}
+
+impl TlsBackend for Backend {
+    type Config = BackendConfig;
+    type Stream<IO>
+        = ServerTlsStream<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn config(
+        server: &MtlServer,
+        client_auth: ClientAuth,
+        diagnostics: bool,
+    ) -> Result<BackendConfig, Error> {
+        #[cfg(feature = "openssl")]
+        if server.openssl_backend {
+            return OpenSsl::config(server, client_auth, diagnostics)
+                .map(BackendConfig::OpenSsl);
+        }
+        Rustls::config(server, client_auth, diagnostics)
+            .map(BackendConfig::Rustls)
+    }
+
+    async fn accept<IO>(
+        config: BackendConfig,
+        stream: IO,
+    ) -> io::Result<ServerTlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let inner = match config {
+            BackendConfig::Rustls(config) => {
+                Stream::Rustls(Box::new(Rustls::accept(config, stream).await?))
+            }
+            #[cfg(feature = "openssl")]
+            BackendConfig::OpenSsl(config) => {
+                Stream::OpenSsl(OpenSsl::accept(config, stream).await?)
+            }
+        };
+        Ok(ServerTlsStream { inner })
+    }
+
+    fn session<IO>(stream: &ServerTlsStream<IO>) -> Session
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match &stream.inner {
+            Stream::Rustls(stream) => Rustls::session(stream),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => OpenSsl::session(stream),
+        }
+    }
+
+    fn is_diagnostic<IO>(
+        config: &BackendConfig,
+        stream: &ServerTlsStream<IO>,
+    ) -> bool
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match (config, &stream.inner) {
+            (BackendConfig::Rustls(config), Stream::Rustls(stream)) => {
+                Rustls::is_diagnostic(config, stream)
+            }
+            #[cfg(feature = "openssl")]
+            (BackendConfig::OpenSsl(config), Stream::OpenSsl(stream)) => {
+                OpenSsl::is_diagnostic(config, stream)
+            }
+            // Accepted before a reload switched the backend.
+            #[cfg(feature = "openssl")]
+            _ => false,
+        }
+    }
+
+    fn set_buffer_limit<IO>(stream: &mut ServerTlsStream<IO>, limit: usize)
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match &mut stream.inner {
+            Stream::Rustls(stream) => Rustls::set_buffer_limit(stream, limit),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => OpenSsl::set_buffer_limit(stream, limit),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Stream<IO> {
+    Rustls(Box<tokio_rustls::server::TlsStream<IO>>),
+    #[cfg(feature = "openssl")]
+    OpenSsl(tokio_openssl::SslStream<IO>),
+}
+
+/// The server side of a connection accepted by an
+/// [`MtlsAcceptor`](crate::MtlsAcceptor). A rustls stream unless the server
+/// was switched to OpenSSL with `with_openssl_backend`; the same type either
+/// way, so enabling the `openssl` feature doesn't change the API.
+#[derive(Debug)]
+pub struct ServerTlsStream<IO> {
+    inner: Stream<IO>,
+}
+
+impl<IO> ServerTlsStream<IO> {
+    /// The underlying connection.
+    pub fn get_ref(&self) -> &IO {
+        match &self.inner {
+            Stream::Rustls(stream) => stream.get_ref().0,
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => stream.get_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut IO {
+        match &mut self.inner {
+            Stream::Rustls(stream) => stream.get_mut().0,
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => stream.get_mut(),
+        }
+    }
+
+    /// The rustls stream, `None` on the OpenSSL backend.
+    pub fn rustls(&self) -> Option<&tokio_rustls::server::TlsStream<IO>> {
+        match &self.inner {
+            Stream::Rustls(stream) => Some(stream),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(_) => None,
+        }
+    }
+
+    pub fn rustls_mut(
+        &mut self,
+    ) -> Option<&mut tokio_rustls::server::TlsStream<IO>> {
+        match &mut self.inner {
+            Stream::Rustls(stream) => Some(stream),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(_) => None,
+        }
+    }
+
+    /// Unwraps the rustls stream, returning `self` on the OpenSSL backend.
+    pub fn into_rustls(
+        self,
+    ) -> Result<tokio_rustls::server::TlsStream<IO>, Self> {
+        match self.inner {
+            Stream::Rustls(stream) => Ok(*stream),
+            #[cfg(feature = "openssl")]
+            inner => Err(Self { inner }),
+        }
+    }
+
+    /// The OpenSSL stream, `None` on the rustls backend.
+    #[cfg(feature = "openssl")]
+    pub fn openssl(&self) -> Option<&tokio_openssl::SslStream<IO>> {
+        match &self.inner {
+            Stream::OpenSsl(stream) => Some(stream),
+            Stream::Rustls(_) => None,
+        }
+    }
+
+    #[cfg(feature = "openssl")]
+    pub fn openssl_mut(&mut self) -> Option<&mut tokio_openssl::SslStream<IO>> {
+        match &mut self.inner {
+            Stream::OpenSsl(stream) => Some(stream),
+            Stream::Rustls(_) => None,
+        }
+    }
+
+    /// Unwraps the OpenSSL stream, returning `self` on the rustls backend.
+    #[cfg(feature = "openssl")]
+    pub fn into_openssl(self) -> Result<tokio_openssl::SslStream<IO>, Self> {
+        match self.inner {
+            Stream::OpenSsl(stream) => Ok(stream),
+            inner => Err(Self { inner }),
+        }
+    }
+}
+
+impl<IO> AsyncRead for ServerTlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.inner {
+            Stream::Rustls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<IO> AsyncWrite for ServerTlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.inner {
+            Stream::Rustls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.inner {
+            Stream::Rustls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.inner {
+            Stream::Rustls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.inner {
+            Stream::Rustls(stream) => stream.is_write_vectored(),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.inner {
+            Stream::Rustls(stream) => {
+                Pin::new(stream).poll_write_vectored(cx, bufs)
+            }
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(stream) => {
+                Pin::new(stream).poll_write_vectored(cx, bufs)
+            }
+        }
+    }
+}
+
+/// A stream over which the handshake completed.
+pub(crate) type TlsStream<IO> = ServerTlsStream<IO>;
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::FixtureDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn rustls_by_default() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = fixtures.server().mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let mut conn = connect_duplex(&acceptor, config, "localhost")
+            .await
+            .unwrap();
+        assert!(conn.server.rustls().is_some());
+        #[cfg(feature = "openssl")]
+        assert!(conn.server.openssl().is_none());
+
+        conn.client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        conn.server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[cfg(feature = "openssl")]
+    #[tokio::test]
+    async fn openssl_when_selected() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = fixtures
+            .server()
+            .with_openssl_backend()
+            .mtls_acceptor()
+            .unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let mut conn = connect_duplex(&acceptor, config, "localhost")
+            .await
+            .unwrap();
+        assert!(conn.server.openssl().is_some());
+        assert!(conn.server.rustls().is_none());
+        assert!(!conn.conn_info.peer_certificates().is_empty());
+
+        conn.server.write_all(b"pong").await.unwrap();
+        conn.server.flush().await.unwrap();
+        let mut buf = [0; 4];
+        conn.client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+}
@@ -0,0 +1,196 @@
+use crate::metrics::Metrics;
+use crate::HandshakeError;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Addresses tracked at most, so a flood of failing handshakes from spoofed
+/// or rotating addresses can't grow the list without bound.
+const MAX_TRACKED: usize = 16 * 1024;
+
+/// Bans a client address for `ttl` once `max_failures` of its handshakes
+/// failed certificate verification within `window`, so brute-forcing or
+/// misconfigured clients stop consuming handshake CPU. Connections from
+/// banned addresses are closed right after they are accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BanPolicy {
+    max_failures: u32,
+    window: Duration,
+    ttl: Duration,
+}
+
+impl BanPolicy {
+    /// Bans for 10 minutes by default.
+    pub fn new(max_failures: u32, window: Duration) -> Self {
+        Self {
+            max_failures: max_failures.max(1),
+            window,
+            ttl: Duration::from_secs(10 * 60),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    config: Option<BanPolicy>,
+    failures: HashMap<IpAddr, Failures>,
+    banned: HashMap<IpAddr, Instant>,
+}
+
+/// The addresses with recently failed handshakes, shared by the listeners
+/// of a server.
+#[derive(Debug, Default)]
+pub(crate) struct BanList {
+    state: Mutex<State>,
+}
+
+impl BanList {
+    pub(crate) fn configure(&self, config: BanPolicy) {
+        self.state.lock().unwrap().config = Some(config);
+    }
+
+    /// Records a failed handshake from `addr`, banning it once there were
+    /// too many.
+    pub(crate) fn failed(
+        &self,
+        addr: IpAddr,
+        err: &HandshakeError,
+        metrics: &Metrics,
+    ) {
+        if !err.is_verification_failure() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let Some(config) = state.config else {
+            return;
+        };
+        let now = Instant::now();
+        if state.failures.len() >= MAX_TRACKED {
+            state
+                .failures
+                .retain(|_, x| now.duration_since(x.since) <= config.window);
+            state.banned.retain(|_, until| *until > now);
+            if state.failures.len() >= MAX_TRACKED {
+                return;
+            }
+        }
+        let failures = state.failures.entry(addr).or_insert(Failures {
+            count: 0,
+            since: now,
+        });
+        if now.duration_since(failures.since) > config.window {
+            failures.count = 0;
+            failures.since = now;
+        }
+        failures.count += 1;
+        if failures.count < config.max_failures {
+            return;
+        }
+        state.failures.remove(&addr);
+        state.banned.insert(addr, now + config.ttl);
+        metrics.client_banned();
+        warn!(
+            "{} handshakes from {} failed within {:?}, banning it for {:?}",
+            config.max_failures, addr, config.window, config.ttl
+        );
+    }
+
+    pub(crate) fn is_banned(&self, addr: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.banned.get(&addr) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                state.banned.remove(&addr);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn banned(&self) -> Vec<IpAddr> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        let banned = state.banned.iter().filter(|(_, until)| **until > now);
+        banned.map(|(addr, _)| *addr).collect()
+    }
+
+    pub(crate) fn unban(&self, addr: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failures.remove(&addr);
+        state.banned.remove(&addr).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread::sleep;
+
+    const ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 8));
+
+    fn bans(policy: BanPolicy) -> (BanList, Metrics) {
+        let bans = BanList::default();
+        bans.configure(policy);
+        (bans, Metrics::default())
+    }
+
+    #[test]
+    fn bans_addresses_failing_verification() {
+        let policy = BanPolicy::new(2, Duration::from_secs(60));
+        let (bans, metrics) = bans(policy);
+        // Network trouble doesn't count.
+        bans.failed(ADDR, &HandshakeError::Timeout, &metrics);
+        bans.failed(ADDR, &HandshakeError::UnknownCa, &metrics);
+        bans.failed(OTHER, &HandshakeError::UnknownCa, &metrics);
+        assert!(!bans.is_banned(ADDR));
+        bans.failed(ADDR, &HandshakeError::Expired, &metrics);
+        assert!(bans.is_banned(ADDR));
+        assert!(!bans.is_banned(OTHER));
+        assert_eq!(bans.banned(), [ADDR]);
+        assert_eq!(metrics.snapshot().clients_banned, 1);
+
+        assert!(bans.unban(ADDR));
+        assert!(!bans.unban(ADDR));
+        assert!(!bans.is_banned(ADDR));
+    }
+
+    #[test]
+    fn bans_expire_after_the_ttl() {
+        let ttl = Duration::from_millis(20);
+        let policy = BanPolicy::new(1, Duration::from_secs(60)).with_ttl(ttl);
+        let (bans, metrics) = bans(policy);
+        bans.failed(ADDR, &HandshakeError::UnknownCa, &metrics);
+        assert!(bans.is_banned(ADDR));
+
+        sleep(ttl * 2);
+        assert!(bans.banned().is_empty());
+        assert!(!bans.is_banned(ADDR));
+        assert!(bans.state.lock().unwrap().banned.is_empty());
+    }
+
+    #[test]
+    fn forgets_failures_outside_the_window() {
+        let window = Duration::from_millis(20);
+        let (bans, metrics) = bans(BanPolicy::new(2, window));
+        bans.failed(ADDR, &HandshakeError::UnknownCa, &metrics);
+        sleep(window * 2);
+        bans.failed(ADDR, &HandshakeError::UnknownCa, &metrics);
+        assert!(!bans.is_banned(ADDR));
+        bans.failed(ADDR, &HandshakeError::UnknownCa, &metrics);
+        assert!(bans.is_banned(ADDR));
+    }
+}
@@ -0,0 +1,284 @@
+//! Development PKI tooling around hyper-mtls-server: issues a CA and
+//! certificates, checks a server configuration and probes a running server.
+//! The certificates are long-lived and meant for development only.
+
+use clap::{Parser, Subcommand};
+use hyper_mtls_server::{testing, MtlServer, MtlServerArgs};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+};
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(Parser)]
+#[command(name = "mtls-dev", version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Creates a CA, written to ca.crt and ca.key.
+    Ca {
+        #[arg(long, default_value = "Development CA")]
+        name: String,
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        out: PathBuf,
+    },
+    /// Issues a server certificate for DNS names and IP addresses, written
+    /// to server.crt and server.key.
+    Server {
+        #[arg(required = true)]
+        names: Vec<String>,
+        /// Directory containing ca.crt and ca.key.
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        ca: PathBuf,
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        out: PathBuf,
+    },
+    /// Issues a client certificate, written to <COMMON_NAME>.crt and
+    /// <COMMON_NAME>.key.
+    Client {
+        common_name: String,
+        /// URI SANs, e.g. a SPIFFE ID.
+        #[arg(long)]
+        uri: Vec<String>,
+        /// Directory containing ca.crt and ca.key.
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        ca: PathBuf,
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        out: PathBuf,
+    },
+    /// Loads and validates a server configuration without serving.
+    Check {
+        #[command(flatten)]
+        args: MtlServerArgs,
+    },
+    /// Performs a handshake with a server, sends `GET /` and prints what
+    /// was negotiated.
+    Probe {
+        /// host:port of the server, the host is verified.
+        addr: String,
+        #[arg(long, value_name = "FILE")]
+        cert: String,
+        #[arg(long, value_name = "FILE")]
+        key: String,
+        /// CA certificate used to verify the server.
+        #[arg(long, value_name = "FILE")]
+        ca: String,
+    },
+}
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Ca { name, out } => create_ca(&name, &out),
+        Command::Server { names, ca, out } => server(names, &ca, &out),
+        Command::Client {
+            common_name,
+            uri,
+            ca,
+            out,
+        } => client(&common_name, uri, &ca, &out),
+        Command::Check { args } => check(args),
+        Command::Probe {
+            addr,
+            cert,
+            key,
+            ca,
+        } => probe(&addr, &cert, &key, &ca).await,
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            print_error(&*err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn print_error(err: &dyn Error) {
+    eprintln!("error: {}", err);
+    let mut source = err.source();
+    while let Some(err) = source {
+        eprintln!("  caused by: {}", err);
+        source = err.source();
+    }
+}
+
+fn create_ca(name: &str, out: &Path) -> Result<()> {
+    let mut params = CertificateParams::new(Vec::new())?;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.key_usages =
+        vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    write(out, "ca", &cert, &key)
+}
+
+fn server(names: Vec<String>, ca: &Path, out: &Path) -> Result<()> {
+    let mut params = CertificateParams::new(names.clone())?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, &names[0]);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    issue(params, ca, out, "server")
+}
+
+fn client(
+    common_name: &str,
+    uris: Vec<String>,
+    ca: &Path,
+    out: &Path,
+) -> Result<()> {
+    let mut params = CertificateParams::new(Vec::new())?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, common_name);
+    for uri in uris {
+        params
+            .subject_alt_names
+            .push(rcgen::SanType::URI(uri.try_into()?));
+    }
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    issue(params, ca, out, common_name)
+}
+
+fn issue(
+    params: CertificateParams,
+    ca: &Path,
+    out: &Path,
+    name: &str,
+) -> Result<()> {
+    let ca_cert = read(&ca.join("ca.crt"))?;
+    let ca_key = KeyPair::from_pem(&read(&ca.join("ca.key"))?)?;
+    let ca_cert =
+        CertificateParams::from_ca_cert_pem(&ca_cert)?.self_signed(&ca_key)?;
+
+    let key = KeyPair::generate()?;
+    let cert = params.signed_by(&key, &ca_cert, &ca_key)?;
+    write(out, name, &cert, &key)
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .map_err(|x| format!("failed reading {}: {}", path.display(), x).into())
+}
+
+fn write(
+    dir: &Path,
+    name: &str,
+    cert: &Certificate,
+    key: &KeyPair,
+) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let cert_path = dir.join(format!("{}.crt", name));
+    std::fs::write(&cert_path, cert.pem())?;
+
+    let key_path = dir.join(format!("{}.key", name));
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&key_path)?
+        .write_all(key.serialize_pem().as_bytes())?;
+
+    println!("wrote {} and {}", cert_path.display(), key_path.display());
+    Ok(())
+}
+
+fn check(args: MtlServerArgs) -> Result<()> {
+    match MtlServer::from(args).check() {
+        Ok(info) => {
+            println!("{:#?}", info);
+            Ok(())
+        }
+        Err(errors) => {
+            errors.iter().for_each(|x| print_error(x));
+            Err(format!("{} problem(s) found", errors.len()).into())
+        }
+    }
+}
+
+async fn probe(addr: &str, cert: &str, key: &str, ca: &str) -> Result<()> {
+    let report = testing::probe(addr, cert, key, ca).await?;
+    println!("tls version: {:?}", report.tls_version);
+    println!("cipher suite: {:?}", report.cipher_suite);
+    println!(
+        "alpn: {}",
+        report.alpn_protocol.as_deref().unwrap_or("none")
+    );
+    for cert in &report.server_chain {
+        println!(
+            "server certificate: {} (issuer {}, fingerprint {})",
+            cert.subject, cert.issuer, cert.fingerprint
+        );
+    }
+    println!("handshake time: {:?}", report.handshake_time);
+    println!("status: {}", report.status);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper_mtls_server::testing::connect_duplex;
+    use hyper_mtls_server::testing::fixtures::FixtureDir;
+
+    #[tokio::test]
+    async fn issues_a_working_pki() {
+        let fixtures = FixtureDir::new().unwrap();
+        let dir = fixtures.path().join("dev");
+        let file = |name| dir.join(name).to_string_lossy().into_owned();
+        create_ca("Test CA", &dir).unwrap();
+        let names = vec!["localhost".into(), "127.0.0.1".into()];
+        server(names, &dir, &dir).unwrap();
+        let uris = vec!["spiffe://example.org/carol".into()];
+        client("carol", uris, &dir, &dir).unwrap();
+
+        let server = MtlServer::new(
+            file("server.crt").into(),
+            file("server.key").into(),
+            file("ca.crt").into(),
+        );
+        let info = server.check().unwrap();
+        assert!(info.server_cert.unwrap().subject.ends_with("CN=localhost"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key = std::fs::metadata(file("carol.key")).unwrap();
+            assert_eq!(key.permissions().mode() & 0o777, 0o600);
+        }
+
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = testing::client_config(
+            &file("carol.crt"),
+            &file("carol.key"),
+            &file("ca.crt"),
+        )
+        .unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        let identity = conn.unwrap().conn_info.client_identity().cloned();
+        let identity = identity.unwrap();
+        assert_eq!(identity.common_name(), Some("carol"));
+        assert_eq!(identity.spiffe_id(), Some("spiffe://example.org/carol"));
+    }
+
+    #[test]
+    fn needs_the_ca_to_issue() {
+        let fixtures = FixtureDir::new().unwrap();
+        let dir = fixtures.path().join("empty");
+        let err = client("carol", Vec::new(), &dir, &dir).unwrap_err();
+        assert!(err.to_string().starts_with("failed reading"), "{}", err);
+    }
+}
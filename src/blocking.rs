@@ -0,0 +1,269 @@
+use crate::conn::Session;
+use crate::handshake::HandshakeError;
+use crate::Error::BlockingListenerError;
+use crate::{CloseReason, ConnInfo, ConnectionId, Error, LogEvent, MtlServer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the accept loop checks for a shutdown while no connections
+/// arrive.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+/// A connection served by [`MtlServer::serve_blocking`].
+pub type BlockingTlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+impl MtlServer {
+    /// Serves `listener` without an async runtime, for CLIs and embedded
+    /// tools that need an mTLS endpoint but no tokio. Every connection gets
+    /// a thread of its own, which completes the handshake within the
+    /// handshake timeout and hands the stream to `handler`; handler errors
+    /// are logged. Returns once the handle requests a shutdown, leaving the
+    /// connection threads to finish on their own.
+    ///
+    /// Client certificates are verified as by [`MtlServer::tls_acceptor`],
+    /// and metrics and bans are kept; connection limits, load shedding,
+    /// OCSP and reloads need the async server.
+    ///
+    /// ```no_run
+    /// # use hyper_mtls_server::MtlServer;
+    /// # use std::io::Write;
+    /// # fn example(server: MtlServer) -> Result<(), Box<dyn std::error::Error>> {
+    /// let listener = std::net::TcpListener::bind("0.0.0.0:8443")?;
+    /// server.serve_blocking(listener, |mut stream, conn_info| {
+    ///     stream.write_all(b"hello")
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn serve_blocking<F>(
+        &self,
+        listener: TcpListener,
+        handler: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(BlockingTlsStream, ConnInfo) -> io::Result<()>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let config = Arc::new(self.create_tls_config(self.client_auth)?);
+        let handler = Arc::new(handler);
+        // Nonblocking, so a shutdown is noticed without a new connection.
+        listener
+            .set_nonblocking(true)
+            .map_err(BlockingListenerError)?;
+        self.handle.set_local_addr(listener.local_addr().ok());
+        info!("serving blocking mTLS on {:?}", listener.local_addr().ok());
+
+        while !self.handle.is_shutting_down() {
+            let (stream, addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(SHUTDOWN_POLL);
+                    continue;
+                }
+                Err(err) => {
+                    error!("server listener accept error: {:?}", err);
+                    continue;
+                }
+            };
+            if self.handle.bans.is_banned(addr.ip()) {
+                self.handle.metrics.connection_banned();
+                log_event!(
+                    self.handle.logs,
+                    LogEvent::Rejections,
+                    "closing connection from banned {}",
+                    addr
+                );
+                continue;
+            }
+            if self.handle.serving_expired_cert() {
+                self.handle.metrics.connection_refused_expired();
+                log_event!(
+                    self.handle.logs,
+                    LogEvent::Rejections,
+                    "closing connection from {}, the server certificate expired",
+                    addr
+                );
+                continue;
+            }
+
+            let server = self.clone();
+            let config = config.clone();
+            let handler = handler.clone();
+            thread::spawn(move || {
+                server
+                    .serve_blocking_connection(stream, addr, config, &*handler)
+            });
+        }
+        Ok(())
+    }
+
+    fn serve_blocking_connection<F>(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        config: Arc<ServerConfig>,
+        handler: &F,
+    ) where
+        F: Fn(BlockingTlsStream, ConnInfo) -> io::Result<()>,
+    {
+        let metrics = &self.handle.metrics;
+        let _active = metrics.connection_accepted();
+        let pending = metrics.handshake_started();
+        let accepted = self.blocking_handshake(stream, config);
+        drop(pending);
+        let stream = match accepted {
+            Ok(stream) => stream,
+            Err(err) => {
+                metrics.handshake_failed();
+                metrics.connection_closed(CloseReason::HandshakeFailed);
+                self.handle.bans.failed(addr.ip(), &err, metrics);
+                let logs = &self.handle.logs;
+                if logs.handshake_failed(err.label(), addr.ip()) {
+                    log_event!(
+                        logs,
+                        LogEvent::Handshakes,
+                        reason = err.label(),
+                        alert = err.alert_sent(),
+                        "error accepting mTLS: {}",
+                        err
+                    );
+                }
+                return;
+            }
+        };
+
+        let conn_info = ConnInfo::new(
+            ConnectionId::new(),
+            addr,
+            Session::from_rustls(&stream.conn),
+            self.handle.identity_cache.clone(),
+        );
+        log_event!(
+            self.handle.logs,
+            LogEvent::Accepts,
+            client = conn_info.client_identity().map(|x| x.subject()),
+            "accepted mTLS connection from {}",
+            addr
+        );
+        let served = panic::catch_unwind(AssertUnwindSafe(|| {
+            handler(stream, conn_info)
+        }));
+        let reason = match served {
+            Ok(Ok(())) => CloseReason::ClientClosed,
+            Ok(Err(err)) => {
+                error!("error serving connection: {:?}", err);
+                CloseReason::Error
+            }
+            Err(_) => {
+                metrics.connection_panicked();
+                CloseReason::Error
+            }
+        };
+        metrics.connection_closed(reason);
+    }
+
+    fn blocking_handshake(
+        &self,
+        mut stream: TcpStream,
+        config: Arc<ServerConfig>,
+    ) -> Result<BlockingTlsStream, HandshakeError> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(self.handshake_timeout)?;
+        stream.set_write_timeout(self.handshake_timeout)?;
+        let mut conn =
+            ServerConnection::new(config).map_err(HandshakeError::from)?;
+        while conn.is_handshaking() {
+            match conn.complete_io(&mut stream) {
+                Ok(_) => {}
+                Err(err) if is_timeout(&err) => {
+                    return Err(HandshakeError::Timeout)
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(StreamOwned::new(conn, stream))
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection};
+    use std::io::{Read, Write};
+    use std::time::Instant;
+
+    /// Connects to `addr` as the client of `config` and reads `len` bytes.
+    fn read(
+        config: Arc<ClientConfig>,
+        addr: SocketAddr,
+        len: usize,
+    ) -> io::Result<Vec<u8>> {
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let conn = ClientConnection::new(config, server_name).unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(addr)?);
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn serves_each_connection_on_a_thread() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let handle = server.handle();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            server.serve_blocking(listener, |mut stream, conn_info| {
+                let identity = conn_info.client_identity().unwrap();
+                match identity.common_name() {
+                    Some("alice") => stream.write_all(b"hello alice"),
+                    _ => panic!("handler panicked"),
+                }
+            })
+        });
+
+        let alice = fixtures.client_config("alice").unwrap();
+        assert_eq!(read(alice, addr, 11).unwrap(), b"hello alice");
+        let bob = fixtures.client_config("bob").unwrap();
+        assert!(read(bob, addr, 1).is_err());
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        assert!(read(anonymous, addr, 1).is_err());
+
+        // Connection threads record how their connection ended on their own.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let metrics = loop {
+            let metrics = handle.metrics();
+            if metrics.connection_panics == 1 && metrics.handshake_failures == 1
+            {
+                break metrics;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(metrics.connections_accepted, 3);
+
+        handle.shutdown();
+        serving.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use crate::metrics::Metrics;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Pauses accepting connections for `cooldown` once `threshold` connections
+/// failed within `window`, so a broken service or a crashing handler
+/// doesn't burn CPU on handshakes for connections that are doomed anyway.
+/// Failures are connections closed with
+/// [`CloseReason::Error`](crate::CloseReason::Error), which includes panics
+/// in connection tasks, and panics in HTTP/2 request handlers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// Pauses for `window` by default.
+    pub fn new(threshold: u32, window: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window,
+            cooldown: window,
+        }
+    }
+
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    config: Option<CircuitBreaker>,
+    failures: VecDeque<Instant>,
+    open_until: Option<Instant>,
+}
+
+/// The failures of recent connections, shared by the listeners of a server.
+#[derive(Debug, Default)]
+pub(crate) struct Breaker {
+    state: Mutex<State>,
+}
+
+impl Breaker {
+    pub(crate) fn configure(&self, config: CircuitBreaker) {
+        self.state.lock().unwrap().config = Some(config);
+    }
+
+    /// Records a failed connection, opening the circuit once there were too
+    /// many.
+    pub(crate) fn failed(&self, metrics: &Metrics) {
+        let mut state = self.state.lock().unwrap();
+        let Some(config) = state.config else {
+            return;
+        };
+        let now = Instant::now();
+        while state
+            .failures
+            .front()
+            .is_some_and(|x| now.duration_since(*x) > config.window)
+        {
+            state.failures.pop_front();
+        }
+        state.failures.push_back(now);
+        if state.failures.len() < config.threshold as usize {
+            return;
+        }
+        state.failures.clear();
+        state.open_until = Some(now + config.cooldown);
+        metrics.circuit_opened();
+        warn!(
+            "{} connections failed within {:?}, pausing accepts for {:?}",
+            config.threshold, config.window, config.cooldown
+        );
+    }
+
+    /// When accepting resumes, if it is paused.
+    pub(crate) fn open_until(&self) -> Option<Instant> {
+        let open_until = self.state.lock().unwrap().open_until?;
+        (open_until > Instant::now()).then_some(open_until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn opens_once_too_many_connections_failed() {
+        let (breaker, metrics) = (Breaker::default(), Metrics::default());
+        // Without a configuration, failures are not tracked.
+        breaker.failed(&metrics);
+        breaker.failed(&metrics);
+        assert_eq!(breaker.open_until(), None);
+
+        breaker.configure(CircuitBreaker::new(2, Duration::from_secs(60)));
+        breaker.failed(&metrics);
+        assert_eq!(breaker.open_until(), None);
+        breaker.failed(&metrics);
+        let open_until = breaker.open_until().unwrap();
+        assert!(open_until > Instant::now() + Duration::from_secs(50));
+        assert_eq!(metrics.snapshot().circuit_breaker_trips, 1);
+    }
+
+    #[test]
+    fn closes_after_the_cooldown() {
+        let (breaker, metrics) = (Breaker::default(), Metrics::default());
+        let cooldown = Duration::from_millis(20);
+        breaker.configure(
+            CircuitBreaker::new(2, Duration::from_secs(60))
+                .with_cooldown(cooldown),
+        );
+        breaker.failed(&metrics);
+        breaker.failed(&metrics);
+        assert!(breaker.open_until().is_some());
+
+        sleep(cooldown * 2);
+        assert_eq!(breaker.open_until(), None);
+        // The failures that opened the circuit don't count again: a single
+        // failure keeps it closed, another one opens it again.
+        breaker.failed(&metrics);
+        assert_eq!(breaker.open_until(), None);
+        breaker.failed(&metrics);
+        assert!(breaker.open_until().is_some());
+        assert_eq!(metrics.snapshot().circuit_breaker_trips, 2);
+    }
+
+    #[test]
+    fn forgets_failures_outside_the_window() {
+        let (breaker, metrics) = (Breaker::default(), Metrics::default());
+        let window = Duration::from_millis(20);
+        breaker.configure(CircuitBreaker::new(2, window));
+        breaker.failed(&metrics);
+        sleep(window * 2);
+        breaker.failed(&metrics);
+        assert_eq!(breaker.open_until(), None);
+        breaker.failed(&metrics);
+        assert!(breaker.open_until().is_some());
+    }
+}
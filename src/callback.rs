@@ -0,0 +1,24 @@
+use std::error::Error as StdError;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// What the callback of [`MtlServer::serve`](crate::MtlServer::serve)
+/// returns: `()`, or a `Result` whose errors are logged, counted in
+/// [`MetricsSnapshot::callback_errors`](crate::MetricsSnapshot) and can stop
+/// the server, see
+/// [`MtlServer::with_max_callback_failures`](crate::MtlServer::with_max_callback_failures).
+pub trait CallbackResult {
+    fn into_result(self) -> Result<(), BoxError>;
+}
+
+impl CallbackResult for () {
+    fn into_result(self) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+impl<E: Into<BoxError>> CallbackResult for Result<(), E> {
+    fn into_result(self) -> Result<(), BoxError> {
+        self.map_err(Into::into)
+    }
+}
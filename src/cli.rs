@@ -0,0 +1,113 @@
+use crate::env::{
+    ALPN_VAR, CLIENT_AUTH_VAR, CLIENT_CA_VAR, HANDSHAKE_TIMEOUT_MS_VAR,
+    MAX_CONNECTIONS_VAR, PORT_VAR, SERVER_CERT_VAR, SERVER_KEY_VAR,
+};
+use crate::{ClientAuth, MtlServer, Protocol};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+/// Command line arguments for the server, to be flattened into an
+/// application's own parser with `#[command(flatten)]`. Every argument can
+/// also be given through the environment variable
+/// [`MtlServer::from_env`] reads, and the port through `MTLS_PORT`.
+#[derive(Clone, Debug, clap::Args)]
+pub struct MtlServerArgs {
+    #[arg(env = PORT_VAR)]
+    #[arg(short, long, value_name = "PORT", default_value = "3002")]
+    pub port: u16,
+
+    #[arg(env = SERVER_CERT_VAR)]
+    #[arg(long, value_name = "FILE")]
+    pub server_certificate_path: String,
+
+    #[arg(env = SERVER_KEY_VAR)]
+    #[arg(long, value_name = "FILE")]
+    pub server_private_key_path: String,
+
+    #[arg(env = CLIENT_CA_VAR)]
+    #[arg(long, value_name = "FILE")]
+    pub client_ca_certificate_path: Option<String>,
+
+    #[arg(env = CLIENT_AUTH_VAR)]
+    #[arg(long, value_name = "MODE", default_value = "required")]
+    #[arg(value_parser = parse_client_auth)]
+    pub client_auth: ClientAuth,
+
+    #[arg(env = ALPN_VAR)]
+    #[arg(long, value_name = "PROTOCOLS", value_delimiter = ',')]
+    #[arg(value_parser = parse_protocol)]
+    pub alpn: Vec<Protocol>,
+
+    #[arg(env = HANDSHAKE_TIMEOUT_MS_VAR)]
+    #[arg(long, value_name = "MS")]
+    pub handshake_timeout_ms: Option<u64>,
+
+    #[arg(env = MAX_CONNECTIONS_VAR)]
+    #[arg(long, value_name = "COUNT")]
+    pub max_connections: Option<usize>,
+}
+
+fn parse_client_auth(value: &str) -> Result<ClientAuth, String> {
+    ClientAuth::from_name(value)
+        .ok_or_else(|| "expected required, optional or disabled".into())
+}
+
+fn parse_protocol(value: &str) -> Result<Protocol, String> {
+    value.parse().map_err(|x: crate::Error| x.to_string())
+}
+
+impl MtlServerArgs {
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.port))
+    }
+}
+
+impl From<MtlServerArgs> for MtlServer {
+    fn from(args: MtlServerArgs) -> Self {
+        let protocols = if args.alpn.is_empty() {
+            Protocol::defaults()
+        } else {
+            args.alpn.into_boxed_slice()
+        };
+        let mut server = MtlServer::from_parts(
+            args.server_certificate_path.into(),
+            args.server_private_key_path.into(),
+            args.client_ca_certificate_path.map(Box::from),
+            args.client_auth,
+            Some(protocols),
+        );
+
+        if let Some(timeout) = args.handshake_timeout_ms {
+            server =
+                server.with_handshake_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(max_connections) = args.max_connections {
+            server = server.with_max_connections(max_connections);
+        }
+
+        server
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Args, Command};
+
+    #[test]
+    fn reads_the_environment_variables_of_from_env() {
+        let command = MtlServerArgs::augment_args(Command::new("server"));
+        let env = |id: &str| {
+            let arg = command.get_arguments().find(|x| x.get_id() == id);
+            arg.unwrap().get_env().unwrap().to_str().unwrap().to_owned()
+        };
+        assert_eq!(env("port"), "MTLS_PORT");
+        assert_eq!(env("server_certificate_path"), "MTLS_SERVER_CERT");
+        assert_eq!(env("server_private_key_path"), "MTLS_SERVER_KEY");
+        assert_eq!(env("client_ca_certificate_path"), "MTLS_CLIENT_CA");
+        assert_eq!(env("client_auth"), "MTLS_CLIENT_AUTH");
+        assert_eq!(env("alpn"), "MTLS_ALPN");
+        assert_eq!(env("handshake_timeout_ms"), "MTLS_HANDSHAKE_TIMEOUT_MS");
+        assert_eq!(env("max_connections"), "MTLS_MAX_CONNECTIONS");
+    }
+}
@@ -0,0 +1,394 @@
+#[cfg(feature = "reqwest")]
+use crate::CertErrorDetail;
+#[cfg(feature = "reqwest")]
+use crate::Error::{
+    CertFileReadError, PrivateKeyFileReadError, ReqwestConversionError,
+};
+use crate::Error::{ClientConfigError, TrustStoreError};
+use crate::{crypto_provider, Error, MtlServer, Protocol};
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::TokioIo;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tower_service::Service;
+
+#[derive(Debug)]
+struct Paths {
+    client_cert: Box<str>,
+    client_key: Box<str>,
+    server_ca_cert: Box<str>,
+}
+
+/// Connects to `https://` URIs, authenticating with a client certificate.
+/// Use it with `hyper_util::client::legacy::Client`:
+///
+/// ```no_run
+/// # use http_body_util::Empty;
+/// # use hyper::body::Bytes;
+/// # use hyper_mtls_server::MtlsConnector;
+/// # use hyper_util::client::legacy::Client;
+/// # use hyper_util::rt::TokioExecutor;
+/// # fn example(cert: Box<str>, key: Box<str>, server_ca: Box<str>) -> Result<(), Box<dyn std::error::Error>> {
+/// let connector = MtlsConnector::new(cert, key, server_ca)?;
+/// let client = Client::builder(TokioExecutor::new()).build(connector);
+/// # let _: Client<_, Empty<Bytes>> = client;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Clones share their configuration, so a [`MtlsConnector::reload`] is seen
+/// by the client the connector was handed to.
+#[derive(Clone)]
+pub struct MtlsConnector {
+    paths: Arc<Paths>,
+    config: Arc<RwLock<Arc<ClientConfig>>>,
+}
+
+impl fmt::Debug for MtlsConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MtlsConnector")
+            .field("paths", &self.paths)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MtlsConnector {
+    /// Loads the client certificate and key, and the CA certificate used
+    /// to verify servers.
+    pub fn new(
+        client_cert_path: Box<str>,
+        client_key_path: Box<str>,
+        server_ca_cert_path: Box<str>,
+    ) -> Result<Self, Error> {
+        let paths = Paths {
+            client_cert: client_cert_path,
+            client_key: client_key_path,
+            server_ca_cert: server_ca_cert_path,
+        };
+        let config = Self::load_config(&paths)?;
+
+        Ok(Self {
+            paths: Arc::new(paths),
+            config: Arc::new(RwLock::new(config)),
+        })
+    }
+
+    /// Reads the certificate files again, e.g. after the client
+    /// certificate was renewed. Pooled connections stay open; connections
+    /// established afterwards use the new configuration. On error the
+    /// previous configuration is kept.
+    pub fn reload(&self) -> Result<(), Error> {
+        let config = Self::load_config(&self.paths)?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// The configuration used for new connections, e.g. to build a
+    /// `tokio_rustls::TlsConnector` or to pass to reqwest's
+    /// `ClientBuilder::use_preconfigured_tls`. Call it again after a
+    /// reload.
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// The client certificate and key as a reqwest identity, read from the
+    /// files again on every call.
+    #[cfg(feature = "reqwest")]
+    pub fn reqwest_identity(&self) -> Result<reqwest::Identity, Error> {
+        let mut pem = std::fs::read(&*self.paths.client_cert).map_err(|x| {
+            let msg = format!(
+                "failed to read certificate form path: {}",
+                self.paths.client_cert
+            );
+            CertFileReadError(CertErrorDetail::new(msg, x))
+        })?;
+        pem.push(b'\n');
+        let key = std::fs::read(&*self.paths.client_key)
+            .map_err(PrivateKeyFileReadError)?;
+        pem.extend_from_slice(&key);
+        reqwest::Identity::from_pem(&pem).map_err(ReqwestConversionError)
+    }
+
+    /// The CA certificates used to verify servers, for reqwest's
+    /// `ClientBuilder::add_root_certificate`.
+    #[cfg(feature = "reqwest")]
+    pub fn reqwest_root_certificates(
+        &self,
+    ) -> Result<Vec<reqwest::Certificate>, Error> {
+        MtlServer::load_cert(&self.paths.server_ca_cert)?
+            .iter()
+            .map(|x| reqwest::Certificate::from_der(x))
+            .collect::<Result<_, _>>()
+            .map_err(ReqwestConversionError)
+    }
+
+    fn load_config(paths: &Paths) -> Result<Arc<ClientConfig>, Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in MtlServer::load_cert(&paths.server_ca_cert)? {
+            roots.add(cert).map_err(TrustStoreError)?;
+        }
+        let cert_chain = MtlServer::load_cert(&paths.client_cert)?;
+        let key = MtlServer::load_key(&paths.client_key)?;
+
+        let mut config = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(ClientConfigError)?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(cert_chain, key)
+            .map_err(ClientConfigError)?;
+        config.alpn_protocols = [Protocol::HTTP_2, Protocol::HTTP_1]
+            .iter()
+            .map(|x| x.as_bytes().to_vec())
+            .collect();
+
+        Ok(Arc::new(config))
+    }
+
+    async fn connect(
+        config: Arc<ClientConfig>,
+        uri: Uri,
+    ) -> io::Result<MtlsStream> {
+        if uri.scheme_str() != Some("https") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only https:// URIs are supported",
+            ));
+        }
+        let host = uri.host().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "URI without host")
+        })?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|x| io::Error::new(io::ErrorKind::InvalidInput, x))?;
+
+        let stream =
+            TcpStream::connect((host, uri.port_u16().unwrap_or(443))).await?;
+        let stream = TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await?;
+        Ok(MtlsStream {
+            inner: TokioIo::new(stream),
+        })
+    }
+}
+
+impl Service<Uri> for MtlsConnector {
+    type Response = MtlsStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<MtlsStream>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let config = self.config.read().unwrap().clone();
+        Box::pin(Self::connect(config, uri))
+    }
+}
+
+/// An established mutual TLS connection.
+pub struct MtlsStream {
+    inner: TokioIo<TlsStream<TcpStream>>,
+}
+
+impl MtlsStream {
+    /// The certificate chain presented by the server.
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.inner.inner().get_ref().1.peer_certificates()
+    }
+}
+
+impl Connection for MtlsStream {
+    fn connected(&self) -> Connected {
+        let (_, conn) = self.inner.inner().get_ref();
+        if conn.alpn_protocol() == Some(Protocol::HTTP_2.as_bytes()) {
+            Connected::new().negotiated_h2()
+        } else {
+            Connected::new()
+        }
+    }
+}
+
+impl Read for MtlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl Write for MtlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::ConnInfo;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::{Bytes, Incoming};
+    use hyper::{Request, Response};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    /// Serves the common name of each client on an ephemeral port for the
+    /// rest of the test.
+    async fn serve(fixtures: &FixtureDir) -> Uri {
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let service = service_fn(|req: Request<Incoming>| async move {
+            let conn_info = req.extensions().get::<ConnInfo>().unwrap();
+            let identity = conn_info.client_identity().unwrap();
+            let name = identity.common_name().unwrap().to_owned();
+            Ok::<_, Infallible>(Response::new(Full::<Bytes>::from(name)))
+        });
+        tokio::spawn(async move {
+            let _: Result<(), Error> =
+                server.serve_service(listener, service).await;
+        });
+        format!("https://localhost:{}/", port).parse().unwrap()
+    }
+
+    /// The common name the server saw, over a new connection.
+    async fn get(connector: &MtlsConnector, uri: Uri) -> String {
+        let client = Client::builder(TokioExecutor::new())
+            .build::<_, Empty<Bytes>>(connector.clone());
+        let response = client.get(uri).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        String::from_utf8(body.to_bytes().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connects_with_the_reloaded_certificate() {
+        let fixtures = FixtureDir::new().unwrap();
+        let uri = serve(&fixtures).await;
+        let cert = fixtures.file("client.crt");
+        let key = fixtures.file("client.key");
+        std::fs::copy(&*fixtures.file("alice.crt"), &*cert).unwrap();
+        std::fs::copy(&*fixtures.file("alice.key"), &*key).unwrap();
+        let connector = MtlsConnector::new(
+            cert.clone(),
+            key.clone(),
+            fixtures.file("ca.crt"),
+        )
+        .unwrap();
+        assert_eq!(get(&connector, uri.clone()).await, "alice");
+
+        std::fs::copy(&*fixtures.file("bob.crt"), &*cert).unwrap();
+        std::fs::copy(&*fixtures.file("bob.key"), &*key).unwrap();
+        connector.reload().unwrap();
+        assert_eq!(get(&connector, uri.clone()).await, "bob");
+
+        // A failed reload keeps the previous configuration.
+        std::fs::remove_file(&*key).unwrap();
+        assert!(connector.reload().is_err());
+        assert_eq!(get(&connector, uri).await, "bob");
+    }
+
+    #[tokio::test]
+    async fn refuses_plain_http_uris() {
+        let fixtures = FixtureDir::new().unwrap();
+        let mut connector = MtlsConnector::new(
+            fixtures.file("alice.crt"),
+            fixtures.file("alice.key"),
+            fixtures.file("ca.crt"),
+        )
+        .unwrap();
+        let uri = Uri::from_static("http://localhost/");
+        let err = connector.call(uri).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn exports_the_client_config() {
+        let fixtures = FixtureDir::new().unwrap();
+        let connector = MtlsConnector::new(
+            fixtures.file("alice.crt"),
+            fixtures.file("alice.key"),
+            fixtures.file("ca.crt"),
+        )
+        .unwrap();
+        let config = connector.client_config();
+        assert!(config.client_auth_cert_resolver.has_certs());
+        assert_eq!(config.alpn_protocols, [&b"h2"[..], &b"http/1.1"[..]]);
+        connector.reload().unwrap();
+        assert!(!Arc::ptr_eq(&connector.client_config(), &config));
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[tokio::test]
+    async fn configures_reqwest() {
+        let fixtures = FixtureDir::new().unwrap();
+        let uri = serve(&fixtures).await;
+        let connector = MtlsConnector::new(
+            fixtures.file("alice.crt"),
+            fixtures.file("alice.key"),
+            fixtures.file("ca.crt"),
+        )
+        .unwrap();
+
+        let mut builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .tls_built_in_root_certs(false)
+            .identity(connector.reqwest_identity().unwrap());
+        for cert in connector.reqwest_root_certificates().unwrap() {
+            builder = builder.add_root_certificate(cert);
+        }
+        let client = builder.build().unwrap();
+        let response = client.get(uri.to_string()).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "alice");
+    }
+}
@@ -0,0 +1,272 @@
+#[cfg(feature = "tokio")]
+use crate::graceful::{serve_until_expired, Closed};
+#[cfg(feature = "tokio")]
+use crate::hooks::CloseHook;
+#[cfg(feature = "tokio")]
+use crate::metrics::Metrics;
+#[cfg(feature = "tokio")]
+use crate::serve::catch_panic;
+#[cfg(feature = "tokio")]
+use crate::trace::{Instrument, Span};
+#[cfg(feature = "tokio")]
+use crate::usage::UsageReport;
+#[cfg(feature = "tokio")]
+use crate::{ConnInfo, ServerHandle};
+#[cfg(feature = "tokio")]
+use hyper_util::server::graceful::GracefulConnection;
+use std::error::Error as StdError;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::io;
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+#[cfg(feature = "tokio")]
+use std::time::Duration;
+
+/// Why a connection served by `serve_service` and friends ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// The client closed the connection or went away.
+    ClientClosed,
+    /// The server shut down, gracefully or after the shutdown deadline, or
+    /// closed the connection through the handle.
+    ServerShutdown,
+    /// No request arrived in time, the connection was idle for too long, or
+    /// the client stopped answering HTTP/2 keep-alive pings.
+    IdleTimeout,
+    /// The connection reached its maximum age.
+    MaxAge,
+    /// The TLS handshake or the revocation check failed.
+    HandshakeFailed,
+    /// The connection quota or the identity mapper refused the client.
+    PolicyRejected,
+    /// A protocol or I/O error, or a panic while serving.
+    Error,
+}
+
+impl CloseReason {
+    /// A short, stable name of the reason, e.g. for metrics labels.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::ClientClosed => "client_closed",
+            Self::ServerShutdown => "server_shutdown",
+            Self::IdleTimeout => "idle_timeout",
+            Self::MaxAge => "max_age",
+            Self::HandshakeFailed => "handshake_failed",
+            Self::PolicyRejected => "policy_rejected",
+            Self::Error => "error",
+        }
+    }
+
+    /// Classifies the error a connection ended with.
+    pub(crate) fn of_error(err: &(dyn StdError + 'static)) -> Self {
+        let mut source = Some(err);
+        while let Some(err) = source {
+            if let Some(err) = err.downcast_ref::<hyper::Error>() {
+                if err.is_timeout() {
+                    return Self::IdleTimeout;
+                }
+                if err.is_incomplete_message() {
+                    return Self::ClientClosed;
+                }
+            }
+            if let Some(err) = err.downcast_ref::<io::Error>() {
+                match err.kind() {
+                    io::ErrorKind::TimedOut => return Self::IdleTimeout,
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof => {
+                        return Self::ClientClosed
+                    }
+                    _ => {}
+                }
+            }
+            source = err.source();
+        }
+        Self::Error
+    }
+}
+
+/// Connections closed for each [`CloseReason`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CloseCounts {
+    pub client_closed: u64,
+    pub server_shutdown: u64,
+    pub idle_timeout: u64,
+    pub max_age: u64,
+    pub handshake_failed: u64,
+    pub policy_rejected: u64,
+    pub error: u64,
+}
+
+impl CloseCounts {
+    pub(crate) fn add(&mut self, reason: CloseReason, count: u64) {
+        let counter = match reason {
+            CloseReason::ClientClosed => &mut self.client_closed,
+            CloseReason::ServerShutdown => &mut self.server_shutdown,
+            CloseReason::IdleTimeout => &mut self.idle_timeout,
+            CloseReason::MaxAge => &mut self.max_age,
+            CloseReason::HandshakeFailed => &mut self.handshake_failed,
+            CloseReason::PolicyRejected => &mut self.policy_rejected,
+            CloseReason::Error => &mut self.error,
+        };
+        *counter += count;
+    }
+}
+
+/// Records why the connection ended when dropped. Without a reason set, the
+/// connection task was cancelled by the shutdown deadline or panicked.
+#[cfg(feature = "tokio")]
+pub(crate) struct Closing {
+    reason: Option<CloseReason>,
+    handle: ServerHandle,
+    metrics: Arc<Metrics>,
+    pub(crate) usage: Option<UsageReport>,
+    pub(crate) on_close: Option<(Arc<CloseHook>, ConnInfo)>,
+}
+
+#[cfg(feature = "tokio")]
+impl Closing {
+    pub(crate) fn new(handle: &ServerHandle) -> Self {
+        Self {
+            reason: None,
+            handle: handle.clone(),
+            metrics: handle.metrics.clone(),
+            usage: None,
+            on_close: None,
+        }
+    }
+
+    pub(crate) fn set(&mut self, reason: CloseReason) {
+        self.reason = Some(reason);
+    }
+
+    /// Serves `conn` until it ends, closing it gracefully once `close` or
+    /// `expire` completes, and sets the reason from how it ended.
+    pub(crate) async fn serve<C, F, E>(&mut self, conn: C, close: F, expire: E)
+    where
+        C: GracefulConnection,
+        C::Error: Into<Box<dyn StdError + Send + Sync>>,
+        F: Future<Output = ()>,
+        E: Future<Output = (CloseReason, Duration)>,
+    {
+        // Boxed like the connection itself, the future is large enough to
+        // overflow the stack of debug builds otherwise.
+        let serving = Box::pin(serve_until_expired(conn, close, expire));
+        let (result, closed) = serving.await;
+        if let Closed::Expired(reason) = closed {
+            self.set(reason);
+            return;
+        }
+        let closed =
+            closed == Closed::Requested || self.handle.is_shutting_down();
+        let reason = match result {
+            Ok(()) if closed => CloseReason::ServerShutdown,
+            Ok(()) => CloseReason::ClientClosed,
+            Err(err) => {
+                let err = err.into();
+                match CloseReason::of_error(&*err) {
+                    // Graceful shutdown interrupts connections still waiting
+                    // for their first bytes.
+                    CloseReason::Error if closed => CloseReason::ServerShutdown,
+                    CloseReason::Error => {
+                        debug!("error serving connection: {:?}", err);
+                        CloseReason::Error
+                    }
+                    reason => reason,
+                }
+            }
+        };
+        self.set(reason);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Drop for Closing {
+    fn drop(&mut self) {
+        let reason = self.reason.unwrap_or_else(|| {
+            if self.handle.is_shutting_down() {
+                CloseReason::ServerShutdown
+            } else {
+                CloseReason::Error
+            }
+        });
+        self.metrics.connection_closed(reason);
+        if reason == CloseReason::Error {
+            self.handle.breaker.failed(&self.metrics);
+        }
+        debug!(reason = reason.label(), "connection closed");
+        if let Some(usage) = self.usage.take() {
+            usage.report(reason);
+        }
+        if let Some((hook, conn_info)) = self.on_close.take() {
+            let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+                return;
+            };
+            let metrics = self.metrics.clone();
+            let task = async move {
+                catch_panic(hook(conn_info, reason), &metrics).await;
+            };
+            runtime.spawn(task.instrument(Span::current()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Wrapped(io::Error);
+
+    impl std::fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("wrapped")
+        }
+    }
+
+    impl StdError for Wrapped {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn reason(kind: io::ErrorKind) -> CloseReason {
+        CloseReason::of_error(&io::Error::from(kind))
+    }
+
+    #[test]
+    fn classifies_the_errors_connections_end_with() {
+        assert_eq!(reason(io::ErrorKind::TimedOut), CloseReason::IdleTimeout);
+        for kind in [
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::BrokenPipe,
+            io::ErrorKind::UnexpectedEof,
+        ] {
+            assert_eq!(reason(kind), CloseReason::ClientClosed);
+        }
+        assert_eq!(reason(io::ErrorKind::InvalidData), CloseReason::Error);
+        // The cause is found behind the errors wrapping it.
+        let wrapped = Wrapped(io::ErrorKind::BrokenPipe.into());
+        assert_eq!(CloseReason::of_error(&wrapped), CloseReason::ClientClosed);
+    }
+
+    #[test]
+    fn counts_each_reason() {
+        let mut counts = CloseCounts::default();
+        counts.add(CloseReason::MaxAge, 2);
+        counts.add(CloseReason::MaxAge, 1);
+        counts.add(CloseReason::PolicyRejected, 1);
+        assert_eq!(
+            counts,
+            CloseCounts {
+                max_age: 3,
+                policy_rejected: 1,
+                ..CloseCounts::default()
+            }
+        );
+        assert_eq!(CloseReason::MaxAge.label(), "max_age");
+    }
+}
@@ -0,0 +1,156 @@
+#[cfg(feature = "config")]
+use crate::Error;
+#[cfg(feature = "config")]
+use crate::Error::{ConfigFileReadError, ConfigParseError};
+use crate::{ClientAuth, MtlServer, Protocol, TlsVersion};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Server settings, e.g. as read from a TOML config file:
+///
+/// ```toml
+/// server_cert_path = "/etc/mtls/server.crt"
+/// server_key_path = "/etc/mtls/server.key"
+/// client_ca_cert_path = "/etc/mtls/client-ca.crt"
+/// client_auth = "required"
+/// protocols = ["h2", "http/1.1"]
+/// min_tls_version = "1.2"
+/// max_tls_version = "1.3"
+/// handshake_timeout_ms = 10000
+/// max_connections = 1024
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MtlServerConfig {
+    pub server_cert_path: Box<str>,
+    pub server_key_path: Box<str>,
+    #[serde(default)]
+    pub client_ca_cert_path: Option<Box<str>>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    #[serde(default)]
+    pub protocols: Option<Box<[Protocol]>>,
+    #[serde(default)]
+    pub min_tls_version: Option<TlsVersion>,
+    #[serde(default)]
+    pub max_tls_version: Option<TlsVersion>,
+    #[serde(default)]
+    pub handshake_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+}
+
+impl MtlServer {
+    pub fn from_config(config: MtlServerConfig) -> Self {
+        let protocols = config.protocols.unwrap_or_else(Protocol::defaults);
+        let mut server = Self::from_parts(
+            config.server_cert_path,
+            config.server_key_path,
+            config.client_ca_cert_path,
+            config.client_auth,
+            Some(protocols),
+        );
+
+        if config.min_tls_version.is_some() || config.max_tls_version.is_some()
+        {
+            server = server.with_tls_versions(
+                config.min_tls_version.unwrap_or(TlsVersion::Tls12),
+                config.max_tls_version.unwrap_or(TlsVersion::Tls13),
+            );
+        }
+        if let Some(timeout) = config.handshake_timeout_ms {
+            server =
+                server.with_handshake_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(max_connections) = config.max_connections {
+            server = server.with_max_connections(max_connections);
+        }
+
+        server
+    }
+
+    #[cfg(feature = "config")]
+    pub fn from_config_file(path: &str) -> Result<Self, Error> {
+        let content =
+            std::fs::read_to_string(path).map_err(ConfigFileReadError)?;
+        let config: MtlServerConfig =
+            toml::from_str(&content).map_err(ConfigParseError)?;
+
+        Ok(Self::from_config(config))
+    }
+}
+
+#[cfg(all(test, feature = "config"))]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use std::sync::Arc;
+
+    /// Writes `settings` below the fixture paths to `server.toml`.
+    fn write_config(fixtures: &FixtureDir, settings: &str) -> Box<str> {
+        let config = format!(
+            "server_cert_path = {:?}\n\
+             server_key_path = {:?}\n\
+             client_ca_cert_path = {:?}\n\
+             {}",
+            fixtures.file("server.crt"),
+            fixtures.file("server.key"),
+            fixtures.file("ca.crt"),
+            settings,
+        );
+        let path = fixtures.file("server.toml");
+        std::fs::write(&*path, config).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn serves_with_the_settings_of_a_config_file() {
+        let fixtures = FixtureDir::new().unwrap();
+        let path = write_config(&fixtures, "protocols = [\"h2\"]\n");
+        let acceptor = MtlServer::from_config_file(&path)
+            .unwrap()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .mtls_acceptor()
+            .unwrap();
+
+        let alice = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+        assert_eq!(conn.conn_info.alpn_protocol(), Some(&b"h2"[..]));
+    }
+
+    #[test]
+    fn rejects_unreadable_and_invalid_config_files() {
+        let fixtures = FixtureDir::new().unwrap();
+        assert!(matches!(
+            MtlServer::from_config_file(&fixtures.file("missing.toml")),
+            Err(ConfigFileReadError(_))
+        ));
+        let path = write_config(&fixtures, "max_conections = 10\n");
+        assert!(matches!(
+            MtlServer::from_config_file(&path),
+            Err(ConfigParseError(_))
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_toml() {
+        let config: MtlServerConfig = toml::from_str(
+            "server_cert_path = \"server.crt\"\n\
+             server_key_path = \"server.key\"\n\
+             client_auth = \"optional\"\n\
+             protocols = [\"h2\"]\n\
+             min_tls_version = \"1.3\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.client_auth, ClientAuth::Optional);
+        assert_eq!(config.min_tls_version, Some(TlsVersion::Tls13));
+
+        let written = toml::to_string(&config).unwrap();
+        assert!(written.contains("protocols = [\"h2\"]"));
+        let read: MtlServerConfig = toml::from_str(&written).unwrap();
+        assert_eq!(read.client_auth, ClientAuth::Optional);
+        assert_eq!(read.min_tls_version, Some(TlsVersion::Tls13));
+        assert_eq!(read.protocols, config.protocols);
+    }
+}
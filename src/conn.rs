@@ -0,0 +1,190 @@
+use crate::identity_cache::IdentityCache;
+use crate::principal::{IdentityMapper, MappedPrincipal, Rejection};
+use crate::ClientIdentity;
+use rustls::{CipherSuite, ProtocolVersion};
+use rustls_pki_types::CertificateDer;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use uuid::Uuid;
+
+/// What a handshake negotiated, independent of the backend.
+#[derive(Debug)]
+pub(crate) struct Session {
+    /// The verified client certificate chain, leaf first.
+    pub(crate) peer_certificates: Vec<CertificateDer<'static>>,
+    pub(crate) alpn_protocol: Option<Vec<u8>>,
+    pub(crate) server_name: Option<Box<str>>,
+    pub(crate) protocol_version: Option<ProtocolVersion>,
+    pub(crate) cipher_suite: Option<CipherSuite>,
+}
+
+/// Unique, time ordered id of an accepted connection. The crate's log
+/// output for a connection is recorded in a span carrying this id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(Uuid);
+
+impl ConnectionId {
+    pub(crate) fn new() -> Self {
+        Self(Uuid::now_v7())
+    }
+
+    pub fn as_uuid(&self) -> Uuid {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    id: ConnectionId,
+    remote_addr: SocketAddr,
+    peer_certificates: Vec<CertificateDer<'static>>,
+    alpn_protocol: Option<Vec<u8>>,
+    server_name: Option<Box<str>>,
+    protocol_version: Option<ProtocolVersion>,
+    cipher_suite: Option<CipherSuite>,
+    client_identity: OnceLock<Option<Arc<ClientIdentity>>>,
+    identity_cache: Arc<IdentityCache>,
+}
+
+/// Cheap to clone, it is shared by all requests on the connection.
+#[derive(Clone, Debug)]
+pub struct ConnInfo {
+    inner: Arc<Inner>,
+}
+
+impl ConnInfo {
+    pub(crate) fn new(
+        id: ConnectionId,
+        remote_addr: SocketAddr,
+        session: Session,
+        identity_cache: Arc<IdentityCache>,
+    ) -> Self {
+        let inner = Inner {
+            id,
+            remote_addr,
+            peer_certificates: session.peer_certificates,
+            alpn_protocol: session.alpn_protocol,
+            server_name: session.server_name,
+            protocol_version: session.protocol_version,
+            cipher_suite: session.cipher_suite,
+            client_identity: OnceLock::new(),
+            identity_cache,
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.inner.id
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.inner.remote_addr
+    }
+
+    /// The verified client certificate chain, leaf first. Empty when the
+    /// client did not present a certificate.
+    pub fn peer_certificates(&self) -> &[CertificateDer<'static>] {
+        &self.inner.peer_certificates
+    }
+
+    /// The identity parsed from the client certificate, on first use, or
+    /// taken from the identity cache.
+    pub fn client_identity(&self) -> Option<&ClientIdentity> {
+        self.inner
+            .client_identity
+            .get_or_init(|| {
+                let leaf = self.inner.peer_certificates.first()?;
+                self.inner.identity_cache.get(leaf)
+            })
+            .as_deref()
+    }
+
+    /// The principal `mapper` maps the client identity to, taken from the
+    /// identity cache if the certificate was mapped before.
+    pub(crate) fn principal(
+        &self,
+        mapper: &Arc<IdentityMapper>,
+    ) -> Option<Result<MappedPrincipal, Rejection>> {
+        let identity = self.client_identity()?;
+        let leaf = self.inner.peer_certificates.first()?;
+        Some(self.inner.identity_cache.principal(leaf, identity, mapper))
+    }
+
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.inner.alpn_protocol.as_deref()
+    }
+
+    pub fn server_name(&self) -> Option<&str> {
+        self.inner.server_name.as_deref()
+    }
+
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.inner.protocol_version
+    }
+
+    pub fn cipher_suite(&self) -> Option<CipherSuite> {
+        self.inner.cipher_suite
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::Protocol;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn describes_the_session() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        let conn_info = conn.unwrap().conn_info;
+
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        assert_eq!(conn_info.remote_addr(), addr);
+        // The leaf and its intermediate.
+        assert_eq!(conn_info.peer_certificates().len(), 2);
+        let identity = conn_info.client_identity().unwrap();
+        assert_eq!(identity.common_name(), Some("alice"));
+        assert_eq!(conn_info.server_name(), Some("localhost"));
+        // The first of the server's protocols the client offers.
+        let alpn = Some(Protocol::HTTP_1.as_bytes());
+        assert_eq!(conn_info.alpn_protocol(), alpn);
+        assert!(conn_info.protocol_version().is_some());
+        assert!(conn_info.cipher_suite().is_some());
+        // Clones share the connection.
+        assert_eq!(conn_info.clone().id(), conn_info.id());
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_session() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        let conn_info = conn.unwrap().conn_info;
+
+        let clone = conn_info.clone();
+        assert!(Arc::ptr_eq(&clone.inner, &conn_info.inner));
+        let certs = conn_info.peer_certificates().as_ptr();
+        assert_eq!(clone.peer_certificates().as_ptr(), certs);
+    }
+}
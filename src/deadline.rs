@@ -0,0 +1,453 @@
+#[cfg(feature = "tokio")]
+use crate::CloseReason;
+#[cfg(feature = "tokio")]
+use std::future::{pending, Future};
+#[cfg(feature = "tokio")]
+use std::io;
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+#[cfg(feature = "tokio")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+#[cfg(feature = "tokio")]
+use std::task::{Context, Poll};
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "tokio")]
+use tokio::time::{sleep_until, Instant, Sleep};
+
+/// Fails reads with `TimedOut` when no application data arrived before the
+/// deadline. Once the first byte was read, the deadline is gone. Also
+/// records when data was last read or written, for the idle timeout.
+#[cfg(feature = "tokio")]
+pub(crate) struct FirstByteDeadline<S> {
+    inner: S,
+    deadline: Option<Pin<Box<Sleep>>>,
+    activity: Option<Arc<LastActivity>>,
+}
+
+#[cfg(feature = "tokio")]
+impl<S> FirstByteDeadline<S> {
+    pub(crate) fn new(
+        inner: S,
+        deadline: Option<Instant>,
+        activity: Option<Arc<LastActivity>>,
+    ) -> Self {
+        Self {
+            inner,
+            deadline: deadline.map(|x| Box::pin(sleep_until(x))),
+            activity,
+        }
+    }
+
+    fn touch(&self) {
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
+    }
+}
+
+/// When a connection last read or wrote data.
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub(crate) struct LastActivity {
+    start: Instant,
+    elapsed_ms: AtomicU64,
+}
+
+#[cfg(feature = "tokio")]
+impl LastActivity {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            elapsed_ms: AtomicU64::new(0),
+        })
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.elapsed_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn at(&self) -> Instant {
+        let elapsed = self.elapsed_ms.load(Ordering::Relaxed);
+        self.start + Duration::from_millis(elapsed)
+    }
+}
+
+/// The timeouts after which a connection is closed gracefully, see
+/// [`MtlServer::with_idle_timeout`](crate::MtlServer::with_idle_timeout).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ConnTimeouts {
+    pub(crate) idle: Option<Duration>,
+    pub(crate) max_age: Option<Duration>,
+    pub(crate) grace: Duration,
+    pub(crate) read: Option<Duration>,
+    pub(crate) write: Option<Duration>,
+}
+
+impl Default for ConnTimeouts {
+    fn default() -> Self {
+        Self {
+            idle: None,
+            max_age: None,
+            grace: Duration::from_secs(10),
+            read: None,
+            write: None,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl ConnTimeouts {
+    /// Tracks activity if there is an idle timeout.
+    pub(crate) fn activity(&self) -> Option<Arc<LastActivity>> {
+        self.idle.map(|_| LastActivity::new())
+    }
+
+    /// Completes once a timeout fired, with the reason to close the
+    /// connection for and the grace the response in flight gets.
+    pub(crate) async fn expired(
+        self,
+        accepted: Instant,
+        activity: Option<Arc<LastActivity>>,
+    ) -> (CloseReason, Duration) {
+        let age = async {
+            match self.max_age {
+                Some(max_age) => sleep_until(accepted + max_age).await,
+                None => pending().await,
+            }
+        };
+        let idle = async {
+            let (Some(timeout), Some(activity)) = (self.idle, activity) else {
+                return pending().await;
+            };
+            loop {
+                let deadline = activity.at() + timeout;
+                if deadline <= Instant::now() {
+                    return;
+                }
+                sleep_until(deadline).await;
+            }
+        };
+        tokio::select! {
+            () = age => (CloseReason::MaxAge, self.grace),
+            () = idle => (CloseReason::IdleTimeout, self.grace),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncRead + Unpin> AsyncRead for FirstByteDeadline<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.touch();
+        }
+        let Some(deadline) = &mut self.deadline else {
+            return poll;
+        };
+        match poll {
+            Poll::Ready(Ok(())) if buf.filled().len() > filled => {
+                self.deadline = None;
+            }
+            Poll::Pending if deadline.as_mut().poll(cx).is_ready() => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no request received before the deadline",
+                )));
+            }
+            _ => {}
+        }
+        poll
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncWrite + Unpin> AsyncWrite for FirstByteDeadline<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(1..))) {
+            self.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if matches!(poll, Poll::Ready(Ok(1..))) {
+            self.touch();
+        }
+        poll
+    }
+}
+
+/// Fails reads or writes with `TimedOut` once they made no progress for
+/// the read or write timeout, e.g. clients that stop reading a streamed
+/// response.
+#[cfg(feature = "tokio")]
+pub(crate) struct StallTimeouts<S> {
+    inner: S,
+    read: Option<Stall>,
+    write: Option<Stall>,
+}
+
+#[cfg(feature = "tokio")]
+impl<S> StallTimeouts<S> {
+    pub(crate) fn new(inner: S, timeouts: &ConnTimeouts) -> Self {
+        Self {
+            inner,
+            read: timeouts.read.map(Stall::new),
+            write: timeouts.write.map(Stall::new),
+        }
+    }
+}
+
+/// The timer of reads or writes, running while they are pending.
+#[cfg(feature = "tokio")]
+struct Stall {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    armed: bool,
+}
+
+#[cfg(feature = "tokio")]
+impl Stall {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sleep: Box::pin(sleep_until(Instant::now() + timeout)),
+            armed: false,
+        }
+    }
+
+    /// Updates the timer from how an operation was polled, failing it once
+    /// it was pending for too long.
+    fn check<T>(
+        stall: &mut Option<Self>,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+        message: &'static str,
+    ) -> Poll<io::Result<T>> {
+        let Some(stall) = stall else {
+            return poll;
+        };
+        if poll.is_ready() {
+            stall.armed = false;
+            return poll;
+        }
+        if !stall.armed {
+            stall.armed = true;
+            let deadline = Instant::now() + stall.timeout;
+            stall.sleep.as_mut().reset(deadline);
+        }
+        match stall.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                message,
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+const READ_STALLED: &str = "no data received within the read timeout";
+#[cfg(feature = "tokio")]
+const WRITE_STALLED: &str = "client didn't read within the write timeout";
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncRead + Unpin> AsyncRead for StallTimeouts<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        Stall::check(&mut self.read, cx, poll, READ_STALLED)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncWrite + Unpin> AsyncWrite for StallTimeouts<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        Stall::check(&mut self.write, cx, poll, WRITE_STALLED)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        Stall::check(&mut self.write, cx, poll, WRITE_STALLED)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_shutdown(cx);
+        Stall::check(&mut self.write, cx, poll, WRITE_STALLED)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        Stall::check(&mut self.write, cx, poll, WRITE_STALLED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const DEADLINE: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn times_out_without_application_data() {
+        let (_client, server) = tokio::io::duplex(64);
+        let deadline = Some(Instant::now() + DEADLINE);
+        let mut stream = FirstByteDeadline::new(server, deadline, None);
+
+        let err = stream.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn lifts_the_deadline_after_the_first_byte() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let deadline = Some(Instant::now() + DEADLINE);
+        let mut stream = FirstByteDeadline::new(server, deadline, None);
+
+        client.write_all(b"GET").await.unwrap();
+        assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 3);
+        tokio::time::sleep(DEADLINE * 2).await;
+        client.write_all(b" /").await.unwrap();
+        assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_after_their_max_age() {
+        let timeouts = ConnTimeouts {
+            max_age: Some(DEADLINE),
+            grace: Duration::from_secs(1),
+            ..ConnTimeouts::default()
+        };
+        assert!(timeouts.activity().is_none());
+        let accepted = Instant::now();
+        let expired = timeouts.expired(accepted, None).await;
+        assert_eq!(expired, (CloseReason::MaxAge, Duration::from_secs(1)));
+        assert!(accepted.elapsed() >= DEADLINE);
+    }
+
+    #[tokio::test]
+    async fn activity_postpones_the_idle_timeout() {
+        let timeouts = ConnTimeouts {
+            idle: Some(DEADLINE),
+            ..ConnTimeouts::default()
+        };
+        let activity = timeouts.activity();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut stream = FirstByteDeadline::new(server, None, activity.clone());
+        let accepted = Instant::now();
+        let expired = tokio::spawn(timeouts.expired(accepted, activity));
+
+        tokio::time::sleep(DEADLINE / 2).await;
+        client.write_all(b"GET").await.unwrap();
+        stream.read_exact(&mut [0; 3]).await.unwrap();
+        let (reason, _) = expired.await.unwrap();
+        assert_eq!(reason, CloseReason::IdleTimeout);
+        assert!(accepted.elapsed() >= DEADLINE + DEADLINE / 4);
+    }
+
+    fn stall_timeouts(read: bool, write: bool) -> ConnTimeouts {
+        ConnTimeouts {
+            read: read.then_some(DEADLINE),
+            write: write.then_some(DEADLINE),
+            ..ConnTimeouts::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_reads_without_progress() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let timeouts = stall_timeouts(true, false);
+        let mut stream = StallTimeouts::new(server, &timeouts);
+
+        // Progress within the timeout restarts it.
+        for _ in 0..2 {
+            let write = async {
+                tokio::time::sleep(DEADLINE / 2).await;
+                client.write_all(b"x").await.unwrap();
+            };
+            let mut buf = [0; 8];
+            let (read, ()) = tokio::join!(stream.read(&mut buf), write);
+            assert_eq!(read.unwrap(), 1);
+        }
+        let err = stream.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn fails_writes_the_client_does_not_read() {
+        let (_client, server) = tokio::io::duplex(8);
+        let mut stream =
+            StallTimeouts::new(server, &stall_timeouts(false, true));
+        let err = stream.write_all(&[0; 64]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Reads are not limited by the write timeout.
+        let (_client, server) = tokio::io::duplex(8);
+        let mut stream =
+            StallTimeouts::new(server, &stall_timeouts(false, true));
+        let mut buf = [0; 8];
+        let read = stream.read(&mut buf);
+        assert!(tokio::time::timeout(DEADLINE * 2, read).await.is_err());
+    }
+}
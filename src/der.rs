@@ -0,0 +1,230 @@
+pub(crate) const INTEGER: u8 = 0x02;
+pub(crate) const BIT_STRING: u8 = 0x03;
+pub(crate) const OCTET_STRING: u8 = 0x04;
+pub(crate) const NULL: u8 = 0x05;
+pub(crate) const OID: u8 = 0x06;
+pub(crate) const ENUMERATED: u8 = 0x0a;
+pub(crate) const SEQUENCE: u8 = 0x30;
+
+pub(crate) const fn context(n: u8) -> u8 {
+    0xa0 | n
+}
+
+pub(crate) const fn context_primitive(n: u8) -> u8 {
+    0x80 | n
+}
+
+/// A cursor over consecutive DER elements.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Reader<'a> {
+    input: &'a [u8],
+}
+
+/// A single element: its tag, contents, and the full encoding.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Element<'a> {
+    pub(crate) tag: u8,
+    pub(crate) contents: &'a [u8],
+    pub(crate) raw: &'a [u8],
+}
+
+impl<'a> Element<'a> {
+    pub(crate) fn reader(&self) -> Reader<'a> {
+        Reader::new(self.contents)
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(input: &'a [u8]) -> Self {
+        Self { input }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    pub(crate) fn peek_tag(&self) -> Option<u8> {
+        self.input.first().copied()
+    }
+
+    pub(crate) fn read(&mut self) -> Option<Element<'a>> {
+        let (&tag, rest) = self.input.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let len = match first {
+            0..=0x7f => first as usize,
+            0x81..=0x84 => {
+                let n = (first & 0x7f) as usize;
+                if rest.len() < n {
+                    return None;
+                }
+                let (bytes, tail) = rest.split_at(n);
+                rest = tail;
+                bytes.iter().fold(0, |len, x| (len << 8) | *x as usize)
+            }
+            _ => return None,
+        };
+        if rest.len() < len {
+            return None;
+        }
+
+        let header = self.input.len() - rest.len();
+        let raw = &self.input[..header + len];
+        self.input = &rest[len..];
+        Some(Element {
+            tag,
+            contents: &rest[..len],
+            raw,
+        })
+    }
+
+    /// Reads the next element, failing unless it has the expected tag.
+    pub(crate) fn expect(&mut self, tag: u8) -> Option<Element<'a>> {
+        self.read().filter(|x| x.tag == tag)
+    }
+
+    /// Reads the next element only if it has the given tag.
+    pub(crate) fn optional(&mut self, tag: u8) -> Option<Element<'a>> {
+        match self.peek_tag() {
+            Some(x) if x == tag => self.read(),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the bits of a BIT STRING without the unused bits octet.
+pub(crate) fn bit_string<'a>(element: &Element<'a>) -> Option<&'a [u8]> {
+    match element.contents.split_first() {
+        Some((0, bits)) if element.tag == BIT_STRING => Some(bits),
+        _ => None,
+    }
+}
+
+pub(crate) fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(contents.len() + 6);
+    out.push(tag);
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|x| **x == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+pub(crate) fn sequence(elements: &[&[u8]]) -> Vec<u8> {
+    encode(SEQUENCE, &elements.concat())
+}
+
+/// Encodes big-endian `bytes` as a non-negative INTEGER.
+#[cfg(any(test, all(windows, feature = "windows-store")))]
+fn unsigned_integer(bytes: &[u8]) -> Vec<u8> {
+    let skip = bytes.iter().take_while(|x| **x == 0).count();
+    let bytes = &bytes[skip..];
+    match bytes.first() {
+        Some(x) if x & 0x80 == 0 => encode(INTEGER, bytes),
+        _ => encode(INTEGER, &[&[0][..], bytes].concat()),
+    }
+}
+
+/// Converts an ECDSA signature from the fixed-size `r || s` form, which CNG
+/// produces, to the DER form TLS uses.
+#[cfg(any(test, all(windows, feature = "windows-store")))]
+pub(crate) fn ecdsa_signature(fixed: &[u8]) -> Option<Vec<u8>> {
+    if fixed.is_empty() || !fixed.len().is_multiple_of(2) {
+        return None;
+    }
+    let (r, s) = fixed.split_at(fixed.len() / 2);
+    Some(sequence(&[&unsigned_integer(r), &unsigned_integer(s)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_short_and_long_lengths() {
+        for len in [0, 5, 0x7f, 0x80, 200, 300, 70_000] {
+            let contents = vec![7; len];
+            let encoded = encode(OCTET_STRING, &contents);
+            let mut reader = Reader::new(&encoded);
+            let element = reader.read().unwrap();
+            assert_eq!(element.tag, OCTET_STRING);
+            assert_eq!(element.contents, contents);
+            assert_eq!(element.raw, encoded);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_indefinite_lengths() {
+        let encoded = encode(OCTET_STRING, &[1; 300]);
+        for end in [0, 1, 2, 3, encoded.len() - 1] {
+            assert!(Reader::new(&encoded[..end]).read().is_none());
+        }
+        // Indefinite length, which DER doesn't allow.
+        assert!(Reader::new(&[SEQUENCE, 0x80, 0, 0]).read().is_none());
+        // Lengths of more than 4 bytes.
+        let huge = [OCTET_STRING, 0x85, 1, 0, 0, 0, 0];
+        assert!(Reader::new(&huge).read().is_none());
+    }
+
+    #[test]
+    fn reads_consecutive_and_nested_elements() {
+        let encoded = sequence(&[
+            &encode(INTEGER, &[1]),
+            &encode(context(0), &encode(NULL, &[])),
+        ]);
+        let mut outer = Reader::new(&encoded);
+        let mut inner = outer.expect(SEQUENCE).unwrap().reader();
+        assert!(outer.is_empty());
+
+        assert!(inner.optional(context(0)).is_none());
+        assert_eq!(inner.expect(INTEGER).unwrap().contents, [1]);
+        assert_eq!(inner.peek_tag(), Some(context(0)));
+        let tagged = inner.optional(context(0)).unwrap();
+        assert_eq!(tagged.reader().expect(NULL).unwrap().contents, []);
+        assert!(inner.is_empty());
+        assert!(inner.read().is_none());
+    }
+
+    #[test]
+    fn expect_fails_on_other_tags() {
+        let encoded = encode(INTEGER, &[1]);
+        assert!(Reader::new(&encoded).expect(OCTET_STRING).is_none());
+    }
+
+    #[test]
+    fn bit_string_strips_the_unused_bits_octet() {
+        let encoded = encode(BIT_STRING, &[0, 0xab, 0xcd]);
+        let element = Reader::new(&encoded).read().unwrap();
+        assert_eq!(bit_string(&element), Some(&[0xab, 0xcd][..]));
+
+        let partial = encode(BIT_STRING, &[4, 0xa0]);
+        let element = Reader::new(&partial).read().unwrap();
+        assert!(bit_string(&element).is_none());
+
+        let octets = encode(OCTET_STRING, &[0, 1]);
+        let element = Reader::new(&octets).read().unwrap();
+        assert!(bit_string(&element).is_none());
+    }
+
+    #[test]
+    fn converts_fixed_size_ecdsa_signatures() {
+        let fixed = [0, 0, 0x12, 0x34, 0x80, 0, 0, 1];
+        let der = ecdsa_signature(&fixed).unwrap();
+        let mut outer = Reader::new(&der);
+        let mut inner = outer.expect(SEQUENCE).unwrap().reader();
+        assert_eq!(inner.expect(INTEGER).unwrap().contents, [0x12, 0x34]);
+        assert_eq!(inner.expect(INTEGER).unwrap().contents, [0, 0x80, 0, 0, 1]);
+        assert!(inner.is_empty());
+
+        let zero = ecdsa_signature(&[0; 4]).unwrap();
+        assert_eq!(zero, sequence(&[&[INTEGER, 1, 0], &[INTEGER, 1, 0]]));
+        assert!(ecdsa_signature(&[1, 2, 3]).is_none());
+        assert!(ecdsa_signature(&[]).is_none());
+    }
+}
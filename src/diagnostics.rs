@@ -0,0 +1,242 @@
+use crate::{ConnInfo, Error, HandshakeError, MtlServer};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::Response;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use std::fmt::Write;
+use std::sync::Arc;
+use x509_parser::prelude::{FromDer, X509Certificate, X509Name};
+
+/// Accepts any client certificate during the handshake, so the diagnostics
+/// host can explain at the HTTP layer what is wrong with it. Signatures are
+/// still checked, proving the client holds the key.
+#[cfg_attr(feature = "openssl", allow(dead_code))]
+#[derive(Debug)]
+struct AcceptAnyClientCert(Arc<dyn ClientCertVerifier>);
+
+impl ClientCertVerifier for AcceptAnyClientCert {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.0.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// Answers requests to the diagnostics host.
+pub(crate) struct Diagnostics {
+    verifier: Arc<dyn ClientCertVerifier>,
+    accepted_issuers: Vec<String>,
+}
+
+impl Diagnostics {
+    pub(crate) fn respond(
+        &self,
+        conn_info: &ConnInfo,
+    ) -> Response<Full<Bytes>> {
+        let mut body = String::from("{");
+        let chain = conn_info.peer_certificates();
+        let _ = write!(
+            body,
+            "\"client_certificate_presented\":{}",
+            !chain.is_empty()
+        );
+
+        if let Some((leaf, intermediates)) = chain.split_first() {
+            let verified = self.verifier.verify_client_cert(
+                leaf,
+                intermediates,
+                UnixTime::now(),
+            );
+            match verified {
+                Ok(_) => body.push_str(",\"verification\":\"ok\""),
+                Err(err) => {
+                    let err = HandshakeError::from(err);
+                    let _ = write!(
+                        body,
+                        ",\"verification\":{},\"error\":{}",
+                        json_string(err.label()),
+                        json_string(&err.to_string())
+                    );
+                }
+            }
+            if let Ok((_, cert)) = X509Certificate::from_der(leaf) {
+                let validity = cert.validity();
+                let _ = write!(
+                    body,
+                    ",\"subject\":{},\"issuer\":{},\"not_before\":{},\
+                     \"not_after\":{}",
+                    json_string(&cert.subject().to_string()),
+                    json_string(&cert.issuer().to_string()),
+                    json_string(&validity.not_before.to_string()),
+                    json_string(&validity.not_after.to_string())
+                );
+            }
+            let _ = write!(body, ",\"chain_length\":{}", chain.len());
+        }
+
+        let issuers: Vec<String> = self
+            .accepted_issuers
+            .iter()
+            .map(|x| json_string(x))
+            .collect();
+        let _ = write!(body, ",\"accepted_issuers\":[{}]}}", issuers.join(","));
+
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response
+    }
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl MtlServer {
+    pub(crate) fn create_diagnostics(
+        &self,
+    ) -> Result<Option<Diagnostics>, Error> {
+        if self.diagnostics_host.is_none()
+            || self.client_auth == crate::ClientAuth::Disabled
+        {
+            return Ok(None);
+        }
+        let verifier = self.create_client_verifier(self.client_auth)?;
+        let accepted_issuers = verifier
+            .root_hint_subjects()
+            .iter()
+            .filter_map(|x| X509Name::from_der(x.as_ref()).ok())
+            .map(|(_, name)| name.to_string())
+            .collect();
+
+        Ok(Some(Diagnostics {
+            verifier,
+            accepted_issuers,
+        }))
+    }
+
+    #[cfg_attr(feature = "openssl", allow(dead_code))]
+    pub(crate) fn create_diagnostics_config(
+        &self,
+    ) -> Result<rustls::ServerConfig, Error> {
+        let verifier = self.create_client_verifier(self.client_auth)?;
+        self.create_tls_config_with(Some(Arc::new(AcceptAnyClientCert(
+            verifier,
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::testing::send;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::Request;
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn reports_on_the_client_certificate() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_diagnostics_host("localhost".into());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Full::<Bytes>::from("app")))
+            });
+            server.serve_service(listener, service).await
+        });
+        let report = |config| async move {
+            let req = Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+            let response = send(config, addr, req).await.unwrap();
+            assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+            let body = response.into_body().collect().await.unwrap();
+            String::from_utf8(body.to_bytes().to_vec()).unwrap()
+        };
+
+        let alice = report(fixtures.client_config("alice").unwrap()).await;
+        assert!(alice.starts_with(
+            "{\"client_certificate_presented\":true,\"verification\":\"ok\""
+        ));
+        assert!(alice.contains("CN=alice"), "{}", alice);
+        assert!(alice.contains("\"chain_length\":2"), "{}", alice);
+        assert!(alice.contains("\"accepted_issuers\":[\""), "{}", alice);
+
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        let anonymous = report(anonymous).await;
+        assert!(
+            anonymous.starts_with("{\"client_certificate_presented\":false,")
+        );
+        assert!(!anonymous.contains("verification"));
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("CN=a\"b\\c\n"), "\"CN=a\\\"b\\\\c\\u000a\"");
+    }
+}
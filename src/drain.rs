@@ -0,0 +1,272 @@
+use crate::{ConnInfo, ConnectionId, ServerHandle};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncWriteExt;
+#[cfg(feature = "tokio")]
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+
+/// The connections whose handshake completed and that are still open, each
+/// with the signal asking it to close.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    open: Mutex<HashMap<ConnectionId, (ConnInfo, Arc<Notify>)>>,
+}
+
+impl Connections {
+    /// Tracks the connection until the returned guard is dropped.
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        conn_info: ConnInfo,
+    ) -> Registration {
+        let id = conn_info.id();
+        let close = Arc::new(Notify::new());
+        let mut open = self.open.lock().unwrap();
+        open.insert(id, (conn_info, close.clone()));
+        Registration {
+            connections: self.clone(),
+            id,
+            close,
+        }
+    }
+
+    /// Asks the matching connections to close, returning how many.
+    fn close_where(
+        &self,
+        mut predicate: impl FnMut(&ConnInfo) -> bool,
+    ) -> usize {
+        let open = self.open.lock().unwrap();
+        let mut closed = 0;
+        for (conn_info, close) in open.values() {
+            if predicate(conn_info) {
+                // Stores a permit if the connection isn't waiting yet.
+                close.notify_one();
+                closed += 1;
+            }
+        }
+        closed
+    }
+}
+
+pub(crate) struct Registration {
+    connections: Arc<Connections>,
+    id: ConnectionId,
+    close: Arc<Notify>,
+}
+
+impl Registration {
+    /// Completes once this connection or all of them should close.
+    pub(crate) fn close_requested(
+        &self,
+        close_all: impl Future<Output = ()>,
+    ) -> impl Future<Output = ()> {
+        let close = self.close.clone();
+        async move {
+            tokio::select! {
+                () = close_all => {}
+                () = close.notified() => {}
+            }
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ServerHandle {
+    /// Marks the server as draining, or no longer draining, so load
+    /// balancers stop sending it new clients while it keeps serving, e.g.
+    /// before maintenance. [`Health::is_ready`](crate::Health::is_ready)
+    /// turns false and the agent check answers `drain`. Connections already
+    /// open are left alone, so clients keeping one open, like HTTP/2 and
+    /// gRPC clients, stay; [`ServerHandle::drain`] moves them too.
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    /// Marks the server as draining and asks the connections open now to
+    /// close gracefully, see [`ServerHandle::close_connections`]: HTTP/2
+    /// clients get a GOAWAY, finish their streams in flight and reconnect,
+    /// by then to another instance. `set_draining(false)` ends the drain.
+    pub fn drain(&self) {
+        self.set_draining(true);
+        self.close_connections();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Asks the connections open now to finish the requests in flight and
+    /// close: HTTP/2 connections get a GOAWAY, HTTP/1 connections close after
+    /// the current response. Clients reconnect and pick up, e.g., rolled out
+    /// certificates or another instance. Applies to `serve_service` and
+    /// friends.
+    pub fn close_connections(&self) {
+        self.closes.send_replace(());
+    }
+
+    /// Asks the connection with `id` to close like
+    /// [`ServerHandle::close_connections`] does, e.g. to evict a client.
+    /// Returns whether it was open.
+    pub fn close_connection(&self, id: ConnectionId) -> bool {
+        self.close_connections_where(|x| x.id() == id) > 0
+    }
+
+    /// Asks the connections matching `predicate` to close like
+    /// [`ServerHandle::close_connections`] does and returns how many matched,
+    /// e.g. those of a client identity that was revoked. Connections still in
+    /// their handshake are not considered.
+    pub fn close_connections_where<F>(&self, predicate: F) -> usize
+    where
+        F: FnMut(&ConnInfo) -> bool,
+    {
+        self.connections.close_where(predicate)
+    }
+
+    /// The connections served by `serve_service` and friends whose handshake
+    /// completed and that are still open.
+    pub fn connections(&self) -> Vec<ConnInfo> {
+        let open = self.connections.open.lock().unwrap();
+        open.values()
+            .map(|(conn_info, _)| conn_info.clone())
+            .collect()
+    }
+
+    /// Answers HAProxy agent checks on `listener` with the state of the
+    /// server: `drain` while draining or shutting down, `up ready` when
+    /// [`Health::is_ready`](crate::Health::is_ready), `down` otherwise. Runs
+    /// until dropped.
+    #[cfg(feature = "tokio")]
+    pub async fn serve_agent_check(&self, listener: TcpListener) {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    debug!("agent check accept error: {:?}", err);
+                    continue;
+                }
+            };
+            let state: &[u8] = if self.is_draining() || self.is_shutting_down()
+            {
+                b"drain\n"
+            } else if self.health().is_ready() {
+                b"up ready\n"
+            } else {
+                b"down\n"
+            };
+            tokio::spawn(async move {
+                if let Err(err) = stream.write_all(state).await {
+                    debug!("error answering agent check: {:?}", err);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::ConnInfo;
+    use futures_util::FutureExt;
+    use std::future::pending;
+    use std::sync::Arc;
+
+    async fn connect(fixtures: &FixtureDir) -> ConnInfo {
+        let acceptor = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .mtls_acceptor()
+            .unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        conn.unwrap().conn_info
+    }
+
+    #[tokio::test]
+    async fn drain_closes_the_open_connections() {
+        let fixtures = FixtureDir::new().unwrap();
+        let handle = fixtures.server().handle();
+        let close = handle.close_requested();
+        tokio::pin!(close);
+
+        handle.set_draining(true);
+        assert!(handle.is_draining());
+        assert!(close.as_mut().now_or_never().is_none());
+
+        handle.set_draining(false);
+        handle.drain();
+        assert!(handle.is_draining());
+        close.await;
+    }
+
+    #[tokio::test]
+    async fn closes_the_selected_connections() {
+        let fixtures = FixtureDir::new().unwrap();
+        let handle = fixtures.server().handle();
+        let (first, second) =
+            (connect(&fixtures).await, connect(&fixtures).await);
+        let id = first.id();
+        let first = handle.connections.register(first);
+        let second = handle.connections.register(second);
+        assert_eq!(handle.connections().len(), 2);
+
+        assert!(handle.close_connection(id));
+        assert!(first.close_requested(pending()).now_or_never().is_some());
+        assert!(second.close_requested(pending()).now_or_never().is_none());
+        assert_eq!(handle.close_connections_where(|x| x.id() != id), 1);
+        assert!(second.close_requested(pending()).now_or_never().is_some());
+
+        // Closed connections are no longer tracked.
+        drop(first);
+        assert!(!handle.close_connection(id));
+        assert_eq!(handle.connections().len(), 1);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn answers_agent_checks() {
+        use http_body_util::Empty;
+        use hyper::body::Bytes;
+        use hyper::Response;
+        use std::convert::Infallible;
+        use tokio::io::AsyncReadExt;
+        use tokio::net::{TcpListener, TcpStream};
+
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let handle = server.handle();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let agent = handle.clone();
+        let checks = tokio::spawn(async move {
+            agent.serve_agent_check(listener).await;
+        });
+        let check = || async {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut state = String::new();
+            stream.read_to_string(&mut state).await.unwrap();
+            state
+        };
+
+        assert_eq!(check().await, "down\n");
+        let service = tower::service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+        });
+        server.serve_ephemeral(service).await.unwrap();
+        assert_eq!(check().await, "up ready\n");
+        handle.set_draining(true);
+        assert_eq!(check().await, "drain\n");
+        handle.set_draining(false);
+        handle.shutdown();
+        assert_eq!(check().await, "drain\n");
+        checks.abort();
+    }
+}
@@ -0,0 +1,187 @@
+use crate::Error::{EnvVarInvalidError, EnvVarMissingError};
+use crate::{ClientAuth, Error, MtlServer, Protocol};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub(crate) const SERVER_CERT_VAR: &str = "MTLS_SERVER_CERT";
+pub(crate) const SERVER_KEY_VAR: &str = "MTLS_SERVER_KEY";
+pub(crate) const CLIENT_CA_VAR: &str = "MTLS_CLIENT_CA";
+pub(crate) const CLIENT_AUTH_VAR: &str = "MTLS_CLIENT_AUTH";
+pub(crate) const ALPN_VAR: &str = "MTLS_ALPN";
+pub(crate) const HANDSHAKE_TIMEOUT_MS_VAR: &str = "MTLS_HANDSHAKE_TIMEOUT_MS";
+pub(crate) const MAX_CONNECTIONS_VAR: &str = "MTLS_MAX_CONNECTIONS";
+/// Only read by [`MtlServerArgs`](crate::MtlServerArgs), the server itself
+/// doesn't bind.
+#[cfg(feature = "clap")]
+pub(crate) const PORT_VAR: &str = "MTLS_PORT";
+
+fn var(name: &'static str) -> Option<String> {
+    std::env::var(name).ok().filter(|x| !x.is_empty())
+}
+
+fn required_var(name: &'static str) -> Result<String, Error> {
+    var(name).ok_or(EnvVarMissingError(name))
+}
+
+fn parse_var<T: FromStr>(name: &'static str) -> Result<Option<T>, Error> {
+    var(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| EnvVarInvalidError { name, value })
+        })
+        .transpose()
+}
+
+impl MtlServer {
+    /// Creates the server from environment variables:
+    ///
+    /// - `MTLS_SERVER_CERT`: server certificate chain path (required)
+    /// - `MTLS_SERVER_KEY`: server private key path (required)
+    /// - `MTLS_CLIENT_CA`: client CA certificate path (required unless
+    ///   client authentication is disabled)
+    /// - `MTLS_CLIENT_AUTH`: `required` (default), `optional` or `disabled`
+    /// - `MTLS_ALPN`: comma separated protocols, e.g. `h2,http/1.1`
+    /// - `MTLS_HANDSHAKE_TIMEOUT_MS`: handshake timeout in milliseconds
+    /// - `MTLS_MAX_CONNECTIONS`: maximum number of concurrent connections
+    pub fn from_env() -> Result<Self, Error> {
+        let server_cert_path = required_var(SERVER_CERT_VAR)?;
+        let server_key_path = required_var(SERVER_KEY_VAR)?;
+
+        let client_auth = match var(CLIENT_AUTH_VAR) {
+            Some(value) => {
+                ClientAuth::from_name(&value).ok_or(EnvVarInvalidError {
+                    name: CLIENT_AUTH_VAR,
+                    value,
+                })?
+            }
+            None => ClientAuth::Required,
+        };
+        let client_ca_cert_path = match client_auth {
+            ClientAuth::Disabled => var(CLIENT_CA_VAR),
+            _ => Some(required_var(CLIENT_CA_VAR)?),
+        };
+
+        let protocols = match var(ALPN_VAR) {
+            Some(value) => value
+                .split(',')
+                .map(|x| x.trim().parse())
+                .collect::<Result<Box<[Protocol]>, _>>()
+                .map_err(|_| EnvVarInvalidError {
+                    name: ALPN_VAR,
+                    value,
+                })?,
+            None => Protocol::defaults(),
+        };
+
+        let mut server = Self::from_parts(
+            server_cert_path.into(),
+            server_key_path.into(),
+            client_ca_cert_path.map(Box::from),
+            client_auth,
+            Some(protocols),
+        );
+
+        if let Some(timeout) = parse_var(HANDSHAKE_TIMEOUT_MS_VAR)? {
+            server =
+                server.with_handshake_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(max_connections) = parse_var(MAX_CONNECTIONS_VAR)? {
+            server = server.with_max_connections(max_connections);
+        }
+
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use std::sync::Arc;
+
+    const VARS: [&str; 7] = [
+        SERVER_CERT_VAR,
+        SERVER_KEY_VAR,
+        CLIENT_CA_VAR,
+        CLIENT_AUTH_VAR,
+        ALPN_VAR,
+        HANDSHAKE_TIMEOUT_MS_VAR,
+        MAX_CONNECTIONS_VAR,
+    ];
+
+    fn set_vars(vars: &[(&str, &str)]) {
+        for name in VARS {
+            std::env::remove_var(name);
+        }
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+    }
+
+    // The environment is shared by the whole process, so every case runs in
+    // this one test.
+    #[tokio::test]
+    async fn creates_the_server_from_environment_variables() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server_cert = fixtures.file("server.crt");
+        let server_key = fixtures.file("server.key");
+        let client_ca = fixtures.file("ca.crt");
+
+        set_vars(&[(SERVER_KEY_VAR, &server_key)]);
+        assert!(matches!(
+            MtlServer::from_env(),
+            Err(EnvVarMissingError(SERVER_CERT_VAR))
+        ));
+
+        set_vars(&[
+            (SERVER_CERT_VAR, &server_cert),
+            (SERVER_KEY_VAR, &server_key),
+        ]);
+        assert!(matches!(
+            MtlServer::from_env(),
+            Err(EnvVarMissingError(CLIENT_CA_VAR))
+        ));
+
+        set_vars(&[
+            (SERVER_CERT_VAR, &server_cert),
+            (SERVER_KEY_VAR, &server_key),
+            (CLIENT_AUTH_VAR, "disabled"),
+        ]);
+        assert!(MtlServer::from_env().is_ok());
+
+        set_vars(&[
+            (SERVER_CERT_VAR, &server_cert),
+            (SERVER_KEY_VAR, &server_key),
+            (CLIENT_CA_VAR, &client_ca),
+            (MAX_CONNECTIONS_VAR, "many"),
+        ]);
+        assert!(matches!(
+            MtlServer::from_env(),
+            Err(EnvVarInvalidError {
+                name: MAX_CONNECTIONS_VAR,
+                ..
+            })
+        ));
+
+        set_vars(&[
+            (SERVER_CERT_VAR, &server_cert),
+            (SERVER_KEY_VAR, &server_key),
+            (CLIENT_CA_VAR, &client_ca),
+            (ALPN_VAR, "h2"),
+            (HANDSHAKE_TIMEOUT_MS_VAR, "5000"),
+            (MAX_CONNECTIONS_VAR, "16"),
+        ]);
+        let server = MtlServer::from_env();
+        set_vars(&[]);
+        let acceptor = server
+            .unwrap()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .mtls_acceptor()
+            .unwrap();
+        let alice = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+        assert_eq!(conn.conn_info.alpn_protocol(), Some(&b"h2"[..]));
+    }
+}
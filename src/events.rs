@@ -0,0 +1,236 @@
+use crate::{CertChange, CertificateInfo, PkiInfo, ServerHandle};
+use std::sync::Mutex;
+#[cfg(feature = "tokio")]
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Events queued for a slow receiver before it starts missing them.
+const CAPACITY: usize = 64;
+
+/// How often the server certificate's expiry is checked.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A change of the certificates in effect or presented by clients, or a
+/// warning about them, for applications that forward them to their own alerting. Subscribe with
+/// [`ServerHandle::events`]. Fingerprints are lowercase hex SHA-256.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum CertEvent {
+    ReloadStarted,
+    ReloadSucceeded {
+        /// The new server chain, leaf first.
+        server_chain: Vec<Box<str>>,
+        /// The new client CAs.
+        trust_anchors: Vec<Box<str>>,
+    },
+    /// The previous certificates stay in effect.
+    ReloadFailed {
+        error: Box<str>,
+    },
+    /// The server certificate expires within the window set with
+    /// [`MtlServer::with_expiry_warning`](crate::MtlServer::with_expiry_warning).
+    /// Sent once per certificate, checked hourly and after reloads.
+    CertExpiringSoon {
+        subject: Box<str>,
+        fingerprint: Box<str>,
+        /// Unix timestamp in seconds.
+        not_after: i64,
+    },
+    /// A known client identity presented another certificate than last
+    /// time, see
+    /// [`MtlServer::with_identity_tracking`](crate::MtlServer::with_identity_tracking).
+    ClientCertChanged {
+        /// The SPIFFE ID, or else the subject.
+        identity: Box<str>,
+        previous: Box<str>,
+        fingerprint: Box<str>,
+        change: CertChange,
+    },
+}
+
+impl CertEvent {
+    pub(crate) fn reloaded(pki: &PkiInfo) -> Self {
+        let fingerprints = |x: &[CertificateInfo]| {
+            x.iter().map(|x| x.fingerprint.clone()).collect()
+        };
+        Self::ReloadSucceeded {
+            server_chain: fingerprints(&pki.server_chain),
+            trust_anchors: fingerprints(&pki.trust_anchors),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Expiry {
+    warning: Duration,
+    /// The fingerprint of the certificate last warned about.
+    warned: Option<Box<str>>,
+    ticking: bool,
+}
+
+/// The event channel of a server and the expiry check feeding it.
+#[derive(Debug)]
+pub(crate) struct Events {
+    sender: broadcast::Sender<CertEvent>,
+    expiry: Mutex<Expiry>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            expiry: Mutex::new(Expiry {
+                warning: Duration::from_secs(30 * 24 * 60 * 60),
+                warned: None,
+                ticking: false,
+            }),
+        }
+    }
+}
+
+impl Events {
+    pub(crate) fn configure(&self, warning: Duration) {
+        self.expiry.lock().unwrap().warning = warning;
+    }
+
+    pub(crate) fn send(&self, event: CertEvent) {
+        // Without receivers nobody is interested.
+        let _ = self.sender.send(event);
+    }
+
+    /// Warns about the leaf of `pki` if it expires soon and wasn't warned
+    /// about yet.
+    fn check_expiry(&self, pki: &PkiInfo) {
+        let Some(leaf) = pki.server_chain.first() else {
+            return;
+        };
+        let mut expiry = self.expiry.lock().unwrap();
+        let horizon = SystemTime::now()
+            .checked_add(expiry.warning)
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map_or(i64::MAX, |x| x.as_secs() as i64);
+        if leaf.not_after > horizon
+            || expiry.warned.as_ref() == Some(&leaf.fingerprint)
+        {
+            return;
+        }
+        expiry.warned = Some(leaf.fingerprint.clone());
+        drop(expiry);
+        warn!(
+            subject = &*leaf.subject,
+            not_after = leaf.not_after,
+            "server certificate expires soon"
+        );
+        self.send(CertEvent::CertExpiringSoon {
+            subject: leaf.subject.clone(),
+            fingerprint: leaf.fingerprint.clone(),
+            not_after: leaf.not_after,
+        });
+    }
+}
+
+impl ServerHandle {
+    /// Subscribes to [`CertEvent`]s. Receivers lagging more than 64 events
+    /// behind miss the oldest ones.
+    pub fn events(&self) -> broadcast::Receiver<CertEvent> {
+        self.events.sender.subscribe()
+    }
+
+    pub(crate) fn check_expiry(&self) {
+        self.events.check_expiry(&self.pki.lock().unwrap());
+    }
+
+    /// Checks the expiry of the server certificate now and then hourly,
+    /// if a runtime is present.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn start_expiry_checks(&self) {
+        self.check_expiry();
+        let mut expiry = self.events.expiry.lock().unwrap();
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if expiry.ticking {
+            return;
+        }
+        expiry.ticking = true;
+        let events = Arc::downgrade(&self.events);
+        let pki = Arc::downgrade(&self.pki);
+        runtime.spawn(async move {
+            loop {
+                tokio::time::sleep(EXPIRY_CHECK_INTERVAL).await;
+                let (Some(events), Some(pki)) =
+                    (Weak::upgrade(&events), Weak::upgrade(&pki))
+                else {
+                    break;
+                };
+                events.check_expiry(&pki.lock().unwrap());
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::FixtureDir;
+    use broadcast::error::TryRecvError;
+
+    #[test]
+    fn broadcasts_the_outcome_of_reloads() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let _acceptor = server.reloadable_acceptor().unwrap();
+        let handle = server.handle();
+        let mut events = handle.events();
+
+        handle.reload().unwrap();
+        assert_eq!(events.try_recv().unwrap(), CertEvent::ReloadStarted);
+        let pki = handle.pki();
+        assert_eq!(events.try_recv().unwrap(), CertEvent::reloaded(&pki));
+        let CertEvent::ReloadSucceeded { server_chain, .. } =
+            CertEvent::reloaded(&pki)
+        else {
+            unreachable!()
+        };
+        assert_eq!(server_chain.len(), 2);
+
+        std::fs::write(fixtures.file("server.key").as_ref(), "").unwrap();
+        assert!(handle.reload().is_err());
+        assert_eq!(events.try_recv().unwrap(), CertEvent::ReloadStarted);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            CertEvent::ReloadFailed { .. }
+        ));
+        assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn warns_once_about_an_expiring_certificate() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let _acceptor = server.reloadable_acceptor().unwrap();
+        let handle = server.handle();
+        let mut events = handle.events();
+
+        handle.check_expiry();
+        assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+
+        let server = server.with_expiry_warning(Duration::from_secs(1 << 40));
+        let handle = server.handle();
+        handle.check_expiry();
+        let leaf = handle.pki().server_chain[0].clone();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            CertEvent::CertExpiringSoon {
+                subject: leaf.subject,
+                fingerprint: leaf.fingerprint,
+                not_after: leaf.not_after,
+            }
+        );
+        handle.check_expiry();
+        assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+    }
+}
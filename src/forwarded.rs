@@ -0,0 +1,444 @@
+use crate::Error::CidrParseError;
+use crate::{ClientIdentity, ConnInfo, Error};
+use hyper::header::HeaderName;
+use hyper::Request;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+const X_FORWARDED_CLIENT_CERT: HeaderName =
+    HeaderName::from_static("x-forwarded-client-cert");
+
+/// A block of IP addresses, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address
+/// stands for itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || CidrParseError(s.into());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => {
+                (addr, Some(prefix_len.parse().map_err(|_| invalid())?))
+            }
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max);
+        if prefix_len > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// The identity of the client a request came from: the client certificate
+/// of the connection, or the certificate a trusted proxy reported in the
+/// `x-forwarded-client-cert` (XFCC) header. Added to requests by
+/// [`PeerIdentityLayer`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    fingerprint: Option<Box<str>>,
+    subject: Option<Box<str>>,
+    uris: Box<[Box<str>]>,
+    dns_names: Box<[Box<str>]>,
+    forwarded_by: Option<SocketAddr>,
+}
+
+impl PeerIdentity {
+    fn direct(identity: &ClientIdentity) -> Self {
+        Self {
+            fingerprint: Some(identity.fingerprint().into()),
+            subject: Some(identity.subject().into()),
+            uris: identity.uris().map(Box::from).collect(),
+            dns_names: identity.dns_names().map(Box::from).collect(),
+            forwarded_by: None,
+        }
+    }
+
+    /// Parses the element of an XFCC header the nearest proxy added, the
+    /// last one.
+    fn forwarded(header: &str, proxy: SocketAddr) -> Option<Self> {
+        let element = split_unquoted(header, ',').last()?;
+        let mut identity = Self {
+            fingerprint: None,
+            subject: None,
+            uris: Box::default(),
+            dns_names: Box::default(),
+            forwarded_by: Some(proxy),
+        };
+        let mut uris = Vec::new();
+        let mut dns_names = Vec::new();
+        for pair in split_unquoted(element, ';') {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            let value = unquote(value.trim());
+            match key.trim().to_ascii_lowercase().as_str() {
+                "hash" => identity.fingerprint = Some(value),
+                "subject" => identity.subject = Some(value),
+                "uri" => uris.push(value),
+                "dns" => dns_names.push(value),
+                _ => {}
+            }
+        }
+        identity.uris = uris.into();
+        identity.dns_names = dns_names.into();
+        let empty = identity.fingerprint.is_none()
+            && identity.subject.is_none()
+            && identity.uris.is_empty()
+            && identity.dns_names.is_empty();
+        (!empty).then_some(identity)
+    }
+
+    /// Lowercase hex SHA-256 of the certificate. Proxies may leave it out.
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+
+    /// The subject distinguished name. Proxies may leave it out.
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    pub fn uris(&self) -> impl Iterator<Item = &str> {
+        self.uris.iter().map(|x| x.as_ref())
+    }
+
+    pub fn dns_names(&self) -> impl Iterator<Item = &str> {
+        self.dns_names.iter().map(|x| x.as_ref())
+    }
+
+    /// The first `spiffe://` URI SAN, if any.
+    pub fn spiffe_id(&self) -> Option<&str> {
+        self.uris().find(|x| x.starts_with("spiffe://"))
+    }
+
+    /// The address of the proxy that reported the identity, `None` when it
+    /// is the client certificate of the connection.
+    pub fn forwarded_by(&self) -> Option<SocketAddr> {
+        self.forwarded_by
+    }
+}
+
+/// Splits `s` at `separator`s outside of double quotes.
+fn split_unquoted(s: &str, separator: char) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    let mut escaped = false;
+    s.split(move |c: char| {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            _ => {}
+        }
+        c == separator && !quoted
+    })
+}
+
+fn unquote(value: &str) -> Box<str> {
+    let Some(value) = value.strip_prefix('"').and_then(|x| x.strip_suffix('"'))
+    else {
+        return value.into();
+    };
+    let mut unquoted = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted.into()
+}
+
+/// Adds the [`PeerIdentity`] of the client to requests, so handlers get the
+/// identity the same way whether clients connect directly or through an L7
+/// proxy that terminated mutual TLS and forwards the client certificate in
+/// the `x-forwarded-client-cert` header, as Envoy does. The header is only
+/// believed on connections from
+/// [`with_trusted_proxies`](Self::with_trusted_proxies); everywhere else it
+/// is removed so handlers can't be fooled by it. Requests from trusted
+/// proxies without the header get no identity rather than the proxy's. Relies
+/// on the [`ConnInfo`] extension added by `serve_service` and friends.
+///
+/// ```no_run
+/// # use hyper_mtls_server::PeerIdentityLayer;
+/// # fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let layer = PeerIdentityLayer::new()
+///     .with_trusted_proxies(["10.1.0.0/16".parse()?]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PeerIdentityLayer {
+    trusted: Arc<[IpCidr]>,
+}
+
+impl PeerIdentityLayer {
+    /// Takes identities from client certificates only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_trusted_proxies<I>(mut self, proxies: I) -> Self
+    where
+        I: IntoIterator<Item = IpCidr>,
+    {
+        let trusted = self.trusted.iter().copied().chain(proxies);
+        self.trusted = trusted.collect();
+        self
+    }
+
+    fn identity<B>(&self, req: &mut Request<B>) -> Option<PeerIdentity> {
+        let conn_info = req.extensions().get::<ConnInfo>()?.clone();
+        let proxy = conn_info.remote_addr();
+        if !self.trusted.iter().any(|x| x.contains(proxy.ip())) {
+            if req.headers_mut().remove(X_FORWARDED_CLIENT_CERT).is_some() {
+                debug!("removed XFCC header from untrusted peer {}", proxy);
+            }
+            return conn_info.client_identity().map(PeerIdentity::direct);
+        }
+        // Proxies may append their element as a header line of its own, so
+        // only the last line is the trusted proxy's; the others, like the
+        // leading elements of it, may come from the client.
+        let headers = req.headers().get_all(X_FORWARDED_CLIENT_CERT);
+        let header = headers.iter().next_back()?;
+        PeerIdentity::forwarded(header.to_str().ok()?, proxy)
+    }
+}
+
+impl<S> Layer<S> for PeerIdentityLayer {
+    type Service = PeerIdentityService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PeerIdentityService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerIdentityService<S> {
+    inner: S,
+    layer: PeerIdentityLayer,
+}
+
+impl<S, B> Service<Request<B>> for PeerIdentityService<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        if let Some(identity) = self.layer.identity(&mut req) {
+            req.extensions_mut().insert(identity);
+        }
+        self.inner.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn proxy() -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::new(10, 1, 2, 3), 443))
+    }
+
+    fn cidr(s: &str) -> IpCidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(
+            cidr("10.0.0.0/8"),
+            IpCidr {
+                addr: Ipv4Addr::new(10, 0, 0, 0).into(),
+                prefix_len: 8,
+            }
+        );
+        assert_eq!(cidr("10.1.2.3"), cidr("10.1.2.3/32"));
+        assert_eq!(cidr("fd00::1"), cidr("fd00::1/128"));
+        for s in [
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "example.com/8",
+            "",
+        ] {
+            assert!(
+                matches!(s.parse::<IpCidr>(), Err(CidrParseError(x)) if *x == *s),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn contains_the_addresses_of_the_prefix() {
+        let v4 = |a, b, c, d| IpAddr::from(Ipv4Addr::new(a, b, c, d));
+        assert!(cidr("0.0.0.0/0").contains(v4(203, 0, 113, 7)));
+        assert!(!cidr("0.0.0.0/0").contains(Ipv6Addr::LOCALHOST.into()));
+        assert!(cidr("10.0.0.0/8").contains(v4(10, 255, 0, 1)));
+        assert!(!cidr("10.0.0.0/8").contains(v4(11, 0, 0, 1)));
+        assert!(cidr("10.1.2.3/32").contains(v4(10, 1, 2, 3)));
+        assert!(!cidr("10.1.2.3/32").contains(v4(10, 1, 2, 4)));
+
+        let v6 = |s: &str| IpAddr::from(s.parse::<Ipv6Addr>().unwrap());
+        assert!(cidr("::/0").contains(v6("2001:db8::1")));
+        assert!(!cidr("::/0").contains(v4(10, 0, 0, 1)));
+        assert!(cidr("fd00::/8").contains(v6("fdff::1")));
+        assert!(!cidr("fd00::/8").contains(v6("fe80::1")));
+        assert!(cidr("fd00::1/128").contains(v6("fd00::1")));
+        assert!(!cidr("fd00::1/128").contains(v6("fd00::2")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_peers_as_ipv4() {
+        let peer = IpAddr::from(Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped());
+        assert!(cidr("10.0.0.0/8").contains(peer));
+        assert!(cidr("10.1.2.3").contains(peer));
+        assert!(!cidr("::ffff:0:0/96").contains(peer));
+    }
+
+    #[test]
+    fn parses_forwarded_identities() {
+        let header = "Hash=0af3;Subject=\"CN=alice,O=Example\";\
+            URI=spiffe://example.org/alice;URI=https://alice.example;\
+            DNS=alice.example;By=spiffe://example.org/proxy";
+        let identity = PeerIdentity::forwarded(header, proxy()).unwrap();
+        assert_eq!(identity.fingerprint(), Some("0af3"));
+        assert_eq!(identity.subject(), Some("CN=alice,O=Example"));
+        assert_eq!(
+            identity.uris().collect::<Vec<_>>(),
+            ["spiffe://example.org/alice", "https://alice.example"]
+        );
+        assert_eq!(identity.spiffe_id(), Some("spiffe://example.org/alice"));
+        assert_eq!(identity.dns_names().collect::<Vec<_>>(), ["alice.example"]);
+        assert_eq!(identity.forwarded_by(), Some(proxy()));
+    }
+
+    #[test]
+    fn unescapes_quoted_values() {
+        let header = r#"Subject="CN=\"a;b,c\",O=Ex\\ample";Hash=1"#;
+        let identity = PeerIdentity::forwarded(header, proxy()).unwrap();
+        assert_eq!(identity.subject(), Some(r#"CN="a;b,c",O=Ex\ample"#));
+        assert_eq!(identity.fingerprint(), Some("1"));
+    }
+
+    #[test]
+    fn takes_the_element_of_the_nearest_proxy() {
+        let header = "Hash=forged;URI=spiffe://example.org/admin,\
+            Hash=real;URI=spiffe://example.org/alice";
+        let identity = PeerIdentity::forwarded(header, proxy()).unwrap();
+        assert_eq!(identity.fingerprint(), Some("real"));
+        assert_eq!(identity.spiffe_id(), Some("spiffe://example.org/alice"));
+
+        let header = "Subject=\"CN=a,Hash=forged\";Hash=real";
+        let identity = PeerIdentity::forwarded(header, proxy()).unwrap();
+        assert_eq!(identity.fingerprint(), Some("real"));
+    }
+
+    #[test]
+    fn ignores_elements_without_an_identity() {
+        assert_eq!(PeerIdentity::forwarded("", proxy()), None);
+        assert_eq!(PeerIdentity::forwarded("Hash=a,By=b;x", proxy()), None);
+    }
+
+    async fn alice() -> ConnInfo {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        conn.unwrap().conn_info
+    }
+
+    fn request(conn_info: &ConnInfo, xfcc: &[&str]) -> Request<()> {
+        let mut req = Request::new(());
+        req.extensions_mut().insert(conn_info.clone());
+        for value in xfcc {
+            let value = value.parse().unwrap();
+            req.headers_mut().append(X_FORWARDED_CLIENT_CERT, value);
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn believes_the_last_header_line_of_trusted_proxies() {
+        let conn_info = alice().await;
+        let layer = PeerIdentityLayer::new()
+            .with_trusted_proxies([cidr("127.0.0.0/8")]);
+
+        let mut req = request(&conn_info, &["Hash=forged", "Hash=a,Hash=real"]);
+        let identity = layer.identity(&mut req).unwrap();
+        assert_eq!(identity.fingerprint(), Some("real"));
+        assert_eq!(identity.forwarded_by(), Some(conn_info.remote_addr()));
+
+        let mut req = request(&conn_info, &[]);
+        assert_eq!(layer.identity(&mut req), None);
+    }
+
+    #[tokio::test]
+    async fn strips_the_header_from_untrusted_peers() {
+        let conn_info = alice().await;
+        let layer =
+            PeerIdentityLayer::new().with_trusted_proxies([cidr("10.0.0.0/8")]);
+        let mut req = request(&conn_info, &["Hash=forged", "Hash=forged"]);
+        let identity = layer.identity(&mut req).unwrap();
+
+        let direct = conn_info.client_identity().unwrap();
+        assert_eq!(identity, PeerIdentity::direct(direct));
+        assert_eq!(identity.forwarded_by(), None);
+        assert!(!req.headers().contains_key(X_FORWARDED_CLIENT_CERT));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::ban::BanList;
+use crate::conn::Session;
+use crate::handshake::HandshakeError;
+use crate::identity_cache::IdentityCache;
+use crate::metrics::Metrics;
+#[cfg(feature = "tokio")]
+use crate::ocsp::OcspChecker;
+use crate::reload::{Commit, Material, Reload};
+#[cfg(feature = "tokio")]
+use crate::revocation::Revocations;
+#[cfg(feature = "tokio")]
+use crate::Error::RevocationRuntimeError;
+use crate::{ConnInfo, ConnectionId, Error, MtlServer};
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+/// Performs the mTLS handshake on `futures-io` streams, for applications on
+/// async-std, smol or other runtimes than tokio. Client certificates are
+/// verified as by [`MtlServer::tls_acceptor`], the configuration follows
+/// [`ServerHandle::reload`](crate::ServerHandle::reload), and handshake
+/// failures count in the server metrics and toward bans. The handshake
+/// timeout is not applied; wrap `accept` in the timeout of your runtime.
+///
+/// With the `tokio` feature, OCSP and the
+/// [`RevocationCheck`](crate::RevocationCheck) run after the handshake as
+/// for [`MtlsAcceptor`](crate::MtlsAcceptor). They run on the tokio runtime
+/// the acceptor was created in, which has to keep running alongside yours.
+///
+/// ```no_run
+/// # use futures_io::{AsyncRead, AsyncWrite};
+/// # use hyper_mtls_server::MtlServer;
+/// # use std::{io, net::SocketAddr};
+/// # struct Listener<S>(S);
+/// # impl<S> Listener<S> {
+/// #     async fn accept(&self) -> io::Result<(S, SocketAddr)> { todo!() }
+/// # }
+/// # async fn example<S>(server: MtlServer, listener: Listener<S>) -> Result<(), Box<dyn std::error::Error>>
+/// # where
+/// #     S: AsyncRead + AsyncWrite + Unpin,
+/// # {
+/// let acceptor = server.futures_acceptor()?;
+/// let (stream, addr) = listener.accept().await?;
+/// let (stream, conn_info) = acceptor.accept(stream, addr).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct FuturesAcceptor {
+    current: Arc<RwLock<TlsAcceptor>>,
+    metrics: Arc<Metrics>,
+    identity_cache: Arc<IdentityCache>,
+    bans: Arc<BanList>,
+    /// The post-handshake checks with the runtime they run on, if any are
+    /// configured.
+    #[cfg(feature = "tokio")]
+    revocations: Option<(Revocations, tokio::runtime::Handle)>,
+    _reload: Arc<dyn Reload>,
+}
+
+impl FuturesAcceptor {
+    pub async fn accept<IO>(
+        &self,
+        stream: IO,
+        remote_addr: SocketAddr,
+    ) -> Result<(TlsStream<IO>, ConnInfo), HandshakeError>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let acceptor = self.current.read().unwrap().clone();
+        let pending = self.metrics.handshake_started();
+        let accepted = acceptor.accept(stream).await;
+        drop(pending);
+        let stream =
+            accepted.map_err(HandshakeError::from).inspect_err(|err| {
+                self.metrics.handshake_failed();
+                self.bans.failed(remote_addr.ip(), err, &self.metrics);
+            })?;
+
+        let conn_info = ConnInfo::new(
+            ConnectionId::new(),
+            remote_addr,
+            Session::from_rustls(stream.get_ref().1),
+            self.identity_cache.clone(),
+        );
+        #[cfg(feature = "tokio")]
+        if !self.allows(&conn_info).await {
+            self.metrics.connection_revoked();
+            let err = HandshakeError::Revoked;
+            self.bans.failed(remote_addr.ip(), &err, &self.metrics);
+            return Err(err);
+        }
+        Ok((stream, conn_info))
+    }
+
+    /// Runs the revocation checks on their runtime, whose timers and
+    /// connections they need.
+    #[cfg(feature = "tokio")]
+    async fn allows(&self, conn_info: &ConnInfo) -> bool {
+        let Some((revocations, runtime)) = &self.revocations else {
+            return true;
+        };
+        let revocations = revocations.clone();
+        let conn_info = conn_info.clone();
+        let check = async move { revocations.allows(&conn_info).await };
+        // A panicking check rejects the client.
+        runtime.spawn(check).await.unwrap_or(false)
+    }
+}
+
+struct FuturesAcceptorReload {
+    server: MtlServer,
+    current: Arc<RwLock<TlsAcceptor>>,
+    #[cfg(feature = "tokio")]
+    ocsp: Option<Arc<OcspChecker>>,
+}
+
+impl Reload for FuturesAcceptorReload {
+    fn server(&self) -> &MtlServer {
+        &self.server
+    }
+
+    fn stage(&self, material: &Arc<Material>) -> Result<Commit, Error> {
+        let staged = self.server.staged(material);
+        let acceptor = staged.futures_tls_acceptor()?;
+        #[cfg(feature = "tokio")]
+        let issuers = staged.ocsp_issuers()?;
+
+        let current = self.current.clone();
+        #[cfg(feature = "tokio")]
+        let ocsp = self.ocsp.clone();
+        let handle = self.server.handle.clone();
+        Ok(Box::new(move || {
+            *current.write().unwrap() = acceptor;
+            #[cfg(feature = "tokio")]
+            if let Some(ocsp) = ocsp {
+                ocsp.set_issuers(issuers);
+            }
+            handle.replace_pki(staged.handle.pki());
+        }))
+    }
+}
+
+impl MtlServer {
+    /// Loads the certificates and builds a [`FuturesAcceptor`]. With OCSP
+    /// or a revocation check configured, it has to be called within a tokio
+    /// runtime, which runs the checks.
+    pub fn futures_acceptor(&self) -> Result<FuturesAcceptor, Error> {
+        let current = Arc::new(RwLock::new(self.futures_tls_acceptor()?));
+        #[cfg(feature = "tokio")]
+        let revocations = match self.create_revocations()? {
+            x if x.is_empty() => None,
+            x => {
+                let runtime = tokio::runtime::Handle::try_current()
+                    .map_err(|_| RevocationRuntimeError)?;
+                Some((x, runtime))
+            }
+        };
+        let reload: Arc<dyn Reload> = Arc::new(FuturesAcceptorReload {
+            server: self.clone(),
+            current: current.clone(),
+            #[cfg(feature = "tokio")]
+            ocsp: revocations.as_ref().and_then(|(x, _)| x.ocsp.clone()),
+        });
+        self.handle.register_reload(&reload);
+
+        Ok(FuturesAcceptor {
+            current,
+            metrics: self.handle.metrics.clone(),
+            identity_cache: self.handle.identity_cache.clone(),
+            bans: self.handle.bans.clone(),
+            #[cfg(feature = "tokio")]
+            revocations,
+            _reload: reload,
+        })
+    }
+
+    fn futures_tls_acceptor(&self) -> Result<TlsAcceptor, Error> {
+        let config = self.create_tls_config(self.client_auth)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::{RevocationCheck, RevocationQuery, RevocationStatus};
+    use rustls::pki_types::ServerName;
+    use std::convert::Infallible;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+    use tokio::io::{DuplexStream, ReadBuf};
+    use tokio_rustls::TlsConnector;
+
+    /// A tokio pipe end as a `futures-io` stream.
+    struct Compat(DuplexStream);
+
+    impl AsyncRead for Compat {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut buf = ReadBuf::new(buf);
+            let pipe = Pin::new(&mut self.0);
+            ready!(tokio::io::AsyncRead::poll_read(pipe, cx, &mut buf))?;
+            Poll::Ready(Ok(buf.filled().len()))
+        }
+    }
+
+    impl AsyncWrite for Compat {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            tokio::io::AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+        }
+    }
+
+    async fn connect(
+        acceptor: &FuturesAcceptor,
+        fixtures: &FixtureDir,
+        client: &str,
+    ) -> Result<ConnInfo, HandshakeError> {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let config = fixtures.client_config(client).unwrap();
+        let connector = TlsConnector::from(config);
+        let name = ServerName::try_from("localhost").unwrap();
+        let client = tokio::spawn(connector.connect(name, client_io));
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let accepted = acceptor.accept(Compat(server_io), addr).await;
+        let _ = client.await;
+        accepted.map(|(_, conn_info)| conn_info)
+    }
+
+    fn revoke_bob() -> RevocationCheck {
+        RevocationCheck::new(|query: RevocationQuery| async move {
+            let status = match query.identity.common_name() {
+                Some("bob") => RevocationStatus::Revoked,
+                _ => RevocationStatus::Good,
+            };
+            Ok::<_, Infallible>(status)
+        })
+    }
+
+    fn server(fixtures: &FixtureDir) -> MtlServer {
+        fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_revocation_check(revoke_bob())
+    }
+
+    #[tokio::test]
+    async fn runs_the_revocation_check() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = server(&fixtures);
+        let acceptor = server.futures_acceptor().unwrap();
+
+        let err = connect(&acceptor, &fixtures, "bob").await.err().unwrap();
+        assert!(matches!(err, HandshakeError::Revoked), "{:?}", err);
+        assert_eq!(server.handle().metrics().connections_revoked, 1);
+
+        let conn_info = connect(&acceptor, &fixtures, "alice").await.unwrap();
+        let identity = conn_info.client_identity().unwrap();
+        assert_eq!(identity.common_name(), Some("alice"));
+    }
+
+    #[test]
+    fn revocation_checks_need_a_runtime() {
+        let fixtures = FixtureDir::new().unwrap();
+        let err = server(&fixtures).futures_acceptor().err().unwrap();
+        assert!(matches!(err, Error::RevocationRuntimeError), "{:?}", err);
+
+        let server = fixtures.server();
+        assert!(server.futures_acceptor().is_ok());
+    }
+}
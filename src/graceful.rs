@@ -0,0 +1,74 @@
+use crate::CloseReason;
+use hyper_util::server::graceful::GracefulConnection;
+use std::future::{pending, Future};
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+/// Why the server closed a connection, if it did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Closed {
+    No,
+    Requested,
+    Expired(CloseReason),
+}
+
+/// Serves `conn` until it ends, closing it gracefully once `close`
+/// completes: HTTP/2 connections get a GOAWAY, HTTP/1 connections close
+/// after the response in flight. Also returns whether it was asked to close.
+pub(crate) async fn serve_until<C, F>(
+    conn: C,
+    close: F,
+) -> (Result<(), C::Error>, bool)
+where
+    C: GracefulConnection,
+    F: Future<Output = ()>,
+{
+    let (result, closed) = serve_until_expired(conn, close, pending()).await;
+    (result, closed != Closed::No)
+}
+
+/// Like [`serve_until`], but also closes `conn` gracefully once `expire`
+/// completes with the reason and the grace the responses in flight get.
+/// Connections still open after the grace are dropped.
+pub(crate) async fn serve_until_expired<C, F, E>(
+    conn: C,
+    close: F,
+    expire: E,
+) -> (Result<(), C::Error>, Closed)
+where
+    C: GracefulConnection,
+    F: Future<Output = ()>,
+    E: Future<Output = (CloseReason, Duration)>,
+{
+    // Boxed, connections are large enough to overflow the stack of debug
+    // builds when moved around inline.
+    let mut conn = Box::pin(conn);
+    tokio::pin!(close);
+    tokio::pin!(expire);
+    let mut closed = Closed::No;
+    let mut cut = None;
+    loop {
+        tokio::select! {
+            result = &mut conn => return (result, closed),
+            () = &mut close, if closed == Closed::No => {
+                closed = Closed::Requested;
+                conn.as_mut().graceful_shutdown();
+            }
+            (reason, grace) = &mut expire, if closed == Closed::No => {
+                debug!(
+                    reason = reason.label(),
+                    "closing connection, allowing {:?} to finish", grace
+                );
+                closed = Closed::Expired(reason);
+                cut = Some(Instant::now() + grace);
+                conn.as_mut().graceful_shutdown();
+            }
+            () = sleep_until(cut.unwrap_or_else(Instant::now)),
+                if cut.is_some() =>
+            {
+                debug!("responses in flight didn't finish within the grace");
+                return (Ok(()), closed);
+            }
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use crate::{ConnInfo, Requirement};
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http_body_util::{BodyExt, Either, Limited};
+use hyper::body::{Body, Bytes, Frame};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use hyper::{Request, Response};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::watch;
+use tower_layer::Layer;
+use tower_service::Service;
+
+type Statuses = HashMap<Box<str>, HealthStatus>;
+
+const CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+const WATCH_PATH: &str = "/grpc.health.v1.Health/Watch";
+pub(crate) const MAX_REQUEST_SIZE: usize = 4096;
+
+pub(crate) const OK: u8 = 0;
+pub(crate) const INVALID_ARGUMENT: u8 = 3;
+pub(crate) const NOT_FOUND: u8 = 5;
+pub(crate) const PERMISSION_DENIED: u8 = 7;
+pub(crate) const UNIMPLEMENTED: u8 = 12;
+pub(crate) const UNAUTHENTICATED: u8 = 16;
+
+const SERVING: u8 = 1;
+const NOT_SERVING: u8 = 2;
+const SERVICE_UNKNOWN: u8 = 3;
+
+/// The status the gRPC health service reports for a service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HealthStatus {
+    Serving,
+    NotServing,
+}
+
+/// Answers `grpc.health.v1.Health/Check` and `Watch` in front of a gRPC
+/// service, e.g. a tonic router. Only verified clients may probe it unless
+/// [`allow_without_client_cert`](Self::allow_without_client_cert) is set,
+/// for meshes that probe without a certificate on a server with
+/// [`ClientAuth::Optional`](crate::ClientAuth), or
+/// [`with_requirement`](Self::with_requirement) narrows it down further.
+/// Clones share the statuses, so keep one to update them; `Watch` streams
+/// see every change.
+///
+/// ```no_run
+/// # use hyper_mtls_server::{GrpcHealthLayer, HealthStatus};
+/// # use tower::ServiceBuilder;
+/// # fn example<S>(router: S) {
+/// let health = GrpcHealthLayer::new();
+/// health.set_status("example.Orders", HealthStatus::Serving);
+/// let service = ServiceBuilder::new().layer(health.clone()).service(router);
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct GrpcHealthLayer {
+    statuses: Arc<watch::Sender<Statuses>>,
+    requirement: Option<Requirement>,
+}
+
+impl Default for GrpcHealthLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GrpcHealthLayer {
+    /// Reports the server as a whole, the empty service name, as serving.
+    pub fn new() -> Self {
+        let statuses = HashMap::from([("".into(), HealthStatus::Serving)]);
+        Self {
+            statuses: Arc::new(watch::Sender::new(statuses)),
+            requirement: Some(Requirement::ClientCert),
+        }
+    }
+
+    /// Lets clients without a verified certificate probe the health service.
+    pub fn allow_without_client_cert(mut self) -> Self {
+        self.requirement = None;
+        self
+    }
+
+    /// Only lets clients whose certificate meets `requirement` probe the
+    /// health service, e.g. the identity of the load balancer. Others get
+    /// `PERMISSION_DENIED`, clients without a certificate `UNAUTHENTICATED`.
+    pub fn with_requirement(mut self, requirement: Requirement) -> Self {
+        self.requirement = Some(requirement);
+        self
+    }
+
+    /// Sets the status of `service`, `""` for the server as a whole.
+    pub fn set_status(&self, service: &str, status: HealthStatus) {
+        self.statuses.send_if_modified(|statuses| {
+            statuses.insert(service.into(), status) != Some(status)
+        });
+    }
+
+    fn status(&self, service: &str) -> Option<HealthStatus> {
+        self.statuses.borrow().get(service).copied()
+    }
+}
+
+/// The gRPC status refusing `req`, if its client doesn't meet
+/// `requirement`.
+pub(crate) fn refusal<B>(
+    requirement: Option<&Requirement>,
+    req: &Request<B>,
+) -> Option<u8> {
+    let requirement = requirement?;
+    let identity = req
+        .extensions()
+        .get::<ConnInfo>()
+        .and_then(|x| x.client_identity());
+    match identity {
+        None => Some(UNAUTHENTICATED),
+        Some(x) if !requirement.allows(x) => Some(PERMISSION_DENIED),
+        Some(_) => None,
+    }
+}
+
+impl<S> Layer<S> for GrpcHealthLayer {
+    type Service = GrpcHealth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcHealth {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GrpcHealth<S> {
+    inner: S,
+    layer: GrpcHealthLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for GrpcHealth<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ReqBody: Body + Send + 'static,
+    ReqBody::Data: Send,
+    ReqBody::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    ResBody: Body<Data = Bytes> + Send + 'static,
+{
+    type Response = Response<Either<ResBody, GrpcBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, S::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let path = req.uri().path();
+        if path != CHECK_PATH && path != WATCH_PATH {
+            return self
+                .inner
+                .call(req)
+                .map(|x| x.map(|x| x.map(Either::Left)))
+                .boxed();
+        }
+
+        if let Some(code) = refusal(self.layer.requirement.as_ref(), &req) {
+            return ready(GrpcBody::status(code));
+        }
+
+        let watch = path == WATCH_PATH;
+        let layer = self.layer.clone();
+        async move {
+            let body = Limited::new(req.into_body(), MAX_REQUEST_SIZE)
+                .collect()
+                .await;
+            let service = body.ok().and_then(|x| decode_service(&x.to_bytes()));
+            let body = match service {
+                None => GrpcBody::status(INVALID_ARGUMENT),
+                Some(service) if watch => GrpcBody::watch(&layer, service),
+                Some(service) => match layer.status(&service) {
+                    Some(status) => {
+                        GrpcBody::message(encode_status(code(status)))
+                    }
+                    None => GrpcBody::status(NOT_FOUND),
+                },
+            };
+            Ok(grpc_response(body))
+        }
+        .boxed()
+    }
+}
+
+pub(crate) fn ready<R, E: Send + 'static>(
+    body: GrpcBody,
+) -> BoxFuture<'static, Result<Response<Either<R, GrpcBody>>, E>>
+where
+    R: Send + 'static,
+{
+    futures_util::future::ready(Ok(grpc_response(body))).boxed()
+}
+
+pub(crate) fn grpc_response<R>(
+    body: GrpcBody,
+) -> Response<Either<R, GrpcBody>> {
+    let mut response = Response::new(Either::Right(body));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    response
+}
+
+/// Reads the `service` field of a framed, uncompressed
+/// `HealthCheckRequest`.
+fn decode_service(frame: &[u8]) -> Option<String> {
+    let (&[0, a, b, c, d], mut message) = frame.split_first_chunk::<5>()?
+    else {
+        return None;
+    };
+    if u32::from_be_bytes([a, b, c, d]) as usize != message.len() {
+        return None;
+    }
+    let mut service = String::new();
+    while let Some((&tag, rest)) = message.split_first() {
+        let (value, rest) = match tag & 7 {
+            0 => (&[][..], read_varint(rest)?.1),
+            2 => {
+                let (len, rest) = read_varint(rest)?;
+                let len = usize::try_from(len).ok()?;
+                (rest.get(..len)?, rest.get(len..)?)
+            }
+            _ => return None,
+        };
+        if tag == 0x0a {
+            service = String::from_utf8(value.to_vec()).ok()?;
+        }
+        message = rest;
+    }
+    Some(service)
+}
+
+pub(crate) fn read_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+/// The `ServingStatus` of `status` on the wire.
+fn code(status: HealthStatus) -> u8 {
+    match status {
+        HealthStatus::Serving => SERVING,
+        HealthStatus::NotServing => NOT_SERVING,
+    }
+}
+
+/// A framed `HealthCheckResponse`.
+fn encode_status(status: u8) -> Bytes {
+    Bytes::from(vec![0, 0, 0, 0, 2, 0x08, status])
+}
+
+/// The body of a response from the health or reflection service: the
+/// messages, one for a `Check`, one per change for a `Watch` and one per
+/// request for reflection, followed by the `grpc-status` trailer.
+pub struct GrpcBody {
+    /// The framed messages, or the status ending the stream early.
+    messages: Option<BoxStream<'static, Result<Bytes, u8>>>,
+    trailers: Option<HeaderMap>,
+}
+
+impl fmt::Debug for GrpcBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcBody")
+            .field("trailers", &self.trailers)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GrpcBody {
+    fn message(message: Bytes) -> Self {
+        Self::stream(stream::once(async { Ok(message) }).boxed())
+    }
+
+    /// The framed `messages`, then `OK` unless one of them is an error
+    /// status, which ends the body instead.
+    pub(crate) fn stream(
+        messages: BoxStream<'static, Result<Bytes, u8>>,
+    ) -> Self {
+        Self {
+            messages: Some(messages),
+            ..Self::status(OK)
+        }
+    }
+
+    pub(crate) fn status(code: u8) -> Self {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from(u16::from(code)));
+        Self {
+            messages: None,
+            trailers: Some(trailers),
+        }
+    }
+
+    /// Sends the status of `service` now and again whenever it changes,
+    /// `SERVICE_UNKNOWN` while it isn't set. Ends once every clone of the
+    /// layer is dropped.
+    fn watch(layer: &GrpcHealthLayer, service: String) -> Self {
+        let statuses = layer.statuses.subscribe();
+        let messages = stream::unfold(
+            (statuses, service, None),
+            |(mut statuses, service, sent)| async move {
+                loop {
+                    let status = statuses
+                        .borrow_and_update()
+                        .get(&*service)
+                        .map_or(SERVICE_UNKNOWN, |x| code(*x));
+                    if sent != Some(status) {
+                        let message = Ok(encode_status(status));
+                        return Some((
+                            message,
+                            (statuses, service, Some(status)),
+                        ));
+                    }
+                    statuses.changed().await.ok()?;
+                }
+            },
+        );
+        Self::stream(messages.boxed())
+    }
+}
+
+impl Body for GrpcBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if let Some(messages) = &mut self.messages {
+            match std::task::ready!(messages.poll_next_unpin(cx)) {
+                Some(Ok(message)) => {
+                    return Poll::Ready(Some(Ok(Frame::data(message))))
+                }
+                Some(Err(code)) => *self = Self::status(code),
+                None => self.messages = None,
+            }
+        }
+        Poll::Ready(self.trailers.take().map(|x| Ok(Frame::trailers(x))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.messages.is_none() && self.trailers.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use http_body_util::{Empty, Full};
+    use tower::{service_fn, ServiceExt};
+
+    async fn alice() -> ConnInfo {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        conn.unwrap().conn_info
+    }
+
+    /// A framed `HealthCheckRequest` for `service` to `path`.
+    fn request(
+        path: &str,
+        service: &str,
+        conn_info: Option<&ConnInfo>,
+    ) -> Request<Full<Bytes>> {
+        let mut frame = vec![0, 0, 0, 0, service.len() as u8 + 2, 0x0a];
+        frame.push(service.len() as u8);
+        frame.extend_from_slice(service.as_bytes());
+        let mut req = Request::post(path).body(Full::from(frame)).unwrap();
+        if let Some(conn_info) = conn_info {
+            req.extensions_mut().insert(conn_info.clone());
+        }
+        req
+    }
+
+    async fn call(
+        layer: &GrpcHealthLayer,
+        req: Request<Full<Bytes>>,
+    ) -> GrpcBody {
+        let inner = service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+        });
+        let response = layer.layer(inner).oneshot(req).await.unwrap();
+        match response.into_body() {
+            Either::Right(body) => body,
+            Either::Left(_) => panic!("passed through to the inner service"),
+        }
+    }
+
+    /// The `ServingStatus` of the next message, or the `grpc-status` once
+    /// the messages ended.
+    async fn next(body: &mut GrpcBody) -> Result<u8, u16> {
+        let frame = body.frame().await.unwrap().unwrap();
+        match frame.into_data() {
+            Ok(message) => Ok(*message.last().unwrap()),
+            Err(frame) => {
+                let trailers = frame.into_trailers().unwrap();
+                let status = trailers["grpc-status"].to_str().unwrap();
+                Err(status.parse().unwrap())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_streams_every_change() {
+        let conn_info = alice().await;
+        let layer = GrpcHealthLayer::new();
+        let req = request(WATCH_PATH, "example.Orders", Some(&conn_info));
+        let mut body = call(&layer, req).await;
+        assert_eq!(next(&mut body).await, Ok(SERVICE_UNKNOWN));
+
+        layer.set_status("example.Orders", HealthStatus::Serving);
+        assert_eq!(next(&mut body).await, Ok(SERVING));
+        layer.set_status("example.Other", HealthStatus::NotServing);
+        layer.set_status("example.Orders", HealthStatus::Serving);
+        layer.set_status("example.Orders", HealthStatus::NotServing);
+        assert_eq!(next(&mut body).await, Ok(NOT_SERVING));
+
+        drop(layer);
+        assert_eq!(next(&mut body).await, Err(OK.into()));
+        assert!(body.is_end_stream());
+    }
+
+    #[tokio::test]
+    async fn check_answers_the_status() {
+        let conn_info = alice().await;
+        let layer = GrpcHealthLayer::new();
+        let mut body =
+            call(&layer, request(CHECK_PATH, "", Some(&conn_info))).await;
+        assert_eq!(next(&mut body).await, Ok(SERVING));
+        assert_eq!(next(&mut body).await, Err(OK.into()));
+
+        let req = request(CHECK_PATH, "example.Orders", Some(&conn_info));
+        let mut body = call(&layer, req).await;
+        assert_eq!(next(&mut body).await, Err(NOT_FOUND.into()));
+    }
+
+    #[tokio::test]
+    async fn probes_have_to_meet_the_requirement() {
+        let conn_info = alice().await;
+        let layer = GrpcHealthLayer::new()
+            .with_requirement(Requirement::CommonName("bob".into()));
+        for path in [CHECK_PATH, WATCH_PATH] {
+            let mut body =
+                call(&layer, request(path, "", Some(&conn_info))).await;
+            assert_eq!(next(&mut body).await, Err(PERMISSION_DENIED.into()));
+            let mut body = call(&layer, request(path, "", None)).await;
+            assert_eq!(next(&mut body).await, Err(UNAUTHENTICATED.into()));
+        }
+
+        let layer = GrpcHealthLayer::new()
+            .with_requirement(Requirement::CommonName("alice".into()));
+        let mut body =
+            call(&layer, request(CHECK_PATH, "", Some(&conn_info))).await;
+        assert_eq!(next(&mut body).await, Ok(SERVING));
+
+        let layer = GrpcHealthLayer::new().allow_without_client_cert();
+        let mut body = call(&layer, request(CHECK_PATH, "", None)).await;
+        assert_eq!(next(&mut body).await, Ok(SERVING));
+    }
+}
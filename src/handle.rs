@@ -0,0 +1,328 @@
+use crate::ban::BanList;
+use crate::breaker::Breaker;
+use crate::drain::Connections;
+use crate::events::Events;
+use crate::identity_cache::IdentityCache;
+#[cfg(feature = "tokio")]
+use crate::listener::Accept;
+use crate::log_policy::Logs;
+use crate::metrics::{IdentityMetrics, Metrics, MetricsSnapshot};
+use crate::reload::Reloads;
+use crate::renewal::Renewal;
+use crate::{PkiInfo, StartupInfo};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Running,
+    Shutdown(Option<Duration>),
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerHandle {
+    state: Arc<watch::Sender<State>>,
+    local_addr: Arc<watch::Sender<Option<SocketAddr>>>,
+    listeners: Arc<Mutex<BTreeMap<u64, Listener>>>,
+    pub(crate) draining: Arc<AtomicBool>,
+    pub(crate) closes: Arc<watch::Sender<()>>,
+    pub(crate) connections: Arc<Connections>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) pki: Arc<Mutex<PkiInfo>>,
+    pub(crate) startup: Arc<Mutex<Option<StartupInfo>>>,
+    pub(crate) reloads: Arc<Reloads>,
+    pub(crate) breaker: Arc<Breaker>,
+    pub(crate) identity_cache: Arc<IdentityCache>,
+    pub(crate) bans: Arc<BanList>,
+    pub(crate) logs: Arc<Logs>,
+    pub(crate) events: Arc<Events>,
+    pub(crate) renewal: Arc<Renewal>,
+}
+
+impl Default for ServerHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerHandle {
+    pub fn new() -> Self {
+        let (state, _) = watch::channel(State::Running);
+        let (closes, _) = watch::channel(());
+        let (local_addr, _) = watch::channel(None);
+        let metrics = Arc::new(Metrics::default());
+        Self {
+            state: Arc::new(state),
+            local_addr: Arc::new(local_addr),
+            listeners: Arc::default(),
+            draining: Arc::default(),
+            closes: Arc::new(closes),
+            connections: Arc::default(),
+            metrics: metrics.clone(),
+            pki: Arc::default(),
+            startup: Arc::default(),
+            reloads: Arc::default(),
+            breaker: Arc::default(),
+            identity_cache: Arc::new(IdentityCache::new(metrics)),
+            bans: Arc::default(),
+            logs: Arc::default(),
+            events: Arc::default(),
+            renewal: Arc::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Per-client counters, empty unless enabled with
+    /// [`MtlServer::with_identity_metrics`](crate::MtlServer::with_identity_metrics).
+    pub fn identity_metrics(&self) -> Vec<IdentityMetrics> {
+        self.metrics.identity_snapshot()
+    }
+
+    /// The address the TLS listener is bound to, once serving started.
+    /// Useful with listeners bound to port 0. With several listeners sharing
+    /// the handle, see [`MtlServer::for_listener`](crate::MtlServer::for_listener),
+    /// the one serving longest.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.borrow()
+    }
+
+    /// The addresses of all listeners serving with this handle, in the order
+    /// they started.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let listeners = self.listeners.lock().unwrap();
+        listeners.values().filter_map(|x| x.addr).collect()
+    }
+
+    /// The report logged when serving started, see [`StartupInfo`]. With
+    /// several listeners, the one of the listener serving longest.
+    pub fn startup_info(&self) -> Option<StartupInfo> {
+        self.startup.lock().unwrap().clone()
+    }
+
+    /// Waits until the server started serving and returns its address.
+    pub async fn listening(&self) -> SocketAddr {
+        let mut local_addr = self.local_addr.subscribe();
+        loop {
+            if let Some(addr) = *local_addr.borrow_and_update() {
+                return addr;
+            }
+            // The sender is owned by `self`, so it can't be dropped.
+            let _ = local_addr.changed().await;
+        }
+    }
+
+    /// Publishes the address of `listener` and its startup report and, on
+    /// Unix, keeps a duplicate of its socket for [`ServerHandle::hand_over`]
+    /// until the returned guard is dropped.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn register_listener(
+        &self,
+        listener: &impl Accept,
+        startup: StartupInfo,
+    ) -> ListenerRegistration {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        #[cfg(unix)]
+        let fd = match listener.as_fd().try_clone_to_owned() {
+            Ok(fd) => Some(fd),
+            Err(err) => {
+                warn!("failed duplicating the listener: {:?}", err);
+                None
+            }
+        };
+        let entry = Listener {
+            addr: listener.local_addr().ok(),
+            startup,
+            #[cfg(unix)]
+            fd,
+        };
+        self.listeners.lock().unwrap().insert(id, entry);
+        self.publish_first_listener();
+        ListenerRegistration {
+            handle: self.clone(),
+            id,
+        }
+    }
+
+    /// Publishes the address and startup report of the listener serving
+    /// longest. The last ones stay once no listener is left.
+    fn publish_first_listener(&self) {
+        let listeners = self.listeners.lock().unwrap();
+        if let Some(first) = listeners.values().next() {
+            self.set_local_addr(first.addr);
+            *self.startup.lock().unwrap() = Some(first.startup.clone());
+        }
+    }
+
+    pub(crate) fn is_listening(&self) -> bool {
+        !self.listeners.lock().unwrap().is_empty()
+    }
+
+    /// A duplicate of the socket of the listener serving longest.
+    #[cfg(unix)]
+    pub(crate) fn listener_fd(&self) -> Option<std::io::Result<OwnedFd>> {
+        let listeners = self.listeners.lock().unwrap();
+        let fd = listeners.values().find_map(|x| x.fd.as_ref())?;
+        Some(fd.try_clone())
+    }
+
+    pub(crate) fn set_local_addr(&self, addr: Option<SocketAddr>) {
+        self.local_addr.send_replace(addr);
+    }
+
+    /// Whether accepting is paused because too many connections failed, see
+    /// [`MtlServer::with_circuit_breaker`](crate::MtlServer::with_circuit_breaker).
+    pub fn is_circuit_open(&self) -> bool {
+        self.breaker.open_until().is_some()
+    }
+
+    /// The client addresses banned for failing handshakes, see
+    /// [`MtlServer::with_ban_policy`](crate::MtlServer::with_ban_policy).
+    pub fn banned_addrs(&self) -> Vec<IpAddr> {
+        self.bans.banned()
+    }
+
+    /// Lifts the ban of `addr`, e.g. once a misconfigured client was fixed.
+    /// Returns whether it was banned.
+    pub fn unban(&self, addr: IpAddr) -> bool {
+        self.bans.unban(addr)
+    }
+
+    /// Stops accepting new connections and waits for in-flight requests to
+    /// complete without a deadline.
+    pub fn shutdown(&self) {
+        self.graceful_shutdown(None);
+    }
+
+    /// Stops accepting new connections and waits up to `timeout` for
+    /// in-flight requests to complete before the serve call returns.
+    pub fn graceful_shutdown(&self, timeout: Option<Duration>) {
+        self.state.send_replace(State::Shutdown(timeout));
+    }
+
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        matches!(*self.state.borrow(), State::Shutdown(_))
+    }
+
+    /// Completes once a connection starting now should close: on shutdown
+    /// or when asked to through [`ServerHandle::close_connections`].
+    pub(crate) fn close_requested(
+        &self,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let handle = self.clone();
+        let mut closes = self.closes.subscribe();
+        async move {
+            tokio::select! {
+                _ = handle.shutdown_requested() => {}
+                _ = closes.changed() => {}
+            }
+        }
+    }
+
+    pub(crate) async fn shutdown_requested(&self) -> Option<Duration> {
+        let mut state = self.state.subscribe();
+        loop {
+            if let State::Shutdown(timeout) = *state.borrow_and_update() {
+                return timeout;
+            }
+            if state.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Listener {
+    addr: Option<SocketAddr>,
+    startup: StartupInfo,
+    #[cfg(unix)]
+    fd: Option<OwnedFd>,
+}
+
+pub(crate) struct ListenerRegistration {
+    handle: ServerHandle,
+    id: u64,
+}
+
+impl Drop for ListenerRegistration {
+    fn drop(&mut self) {
+        self.handle.listeners.lock().unwrap().remove(&self.id);
+        self.handle.publish_first_listener();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::fixtures::FixtureDir;
+    use crate::ListenerConfig;
+    use http_body_util::Empty;
+    use hyper::body::Bytes;
+    use hyper::Response;
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn reports_the_address_once_serving() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let handle = server.handle();
+        assert_eq!(handle.local_addr(), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+            });
+            server.serve_service(listener, service).await
+        });
+        assert_eq!(handle.listening().await, addr);
+        assert_eq!(handle.local_addr(), Some(addr));
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn listeners_register_independently() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let plain = server.for_listener(ListenerConfig::new());
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let first_addr = first.local_addr().unwrap();
+        let second_addr = second.local_addr().unwrap();
+
+        let handle = server.handle();
+        let first = server.start_listening(&first);
+        let second = plain.start_listening(&second);
+        assert_eq!(handle.local_addr(), Some(first_addr));
+        assert_eq!(handle.local_addrs(), [first_addr, second_addr]);
+
+        drop(first);
+        assert_eq!(handle.local_addr(), Some(second_addr));
+        assert_eq!(handle.local_addrs(), [second_addr]);
+        assert!(handle.is_listening());
+        #[cfg(unix)]
+        assert!(handle.listener_fd().is_some());
+
+        drop(second);
+        assert!(!handle.is_listening());
+        assert_eq!(handle.local_addr(), Some(second_addr));
+        #[cfg(unix)]
+        assert!(handle.listener_fd().is_none());
+    }
+}
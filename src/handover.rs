@@ -0,0 +1,128 @@
+use crate::Error::{EnvVarInvalidError, HandoverError, NotListeningError};
+use crate::{Error, ServerHandle};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Names the inherited listening socket in the environment of the new
+/// process.
+pub const LISTEN_FD_VAR: &str = "HYPER_MTLS_SERVER_LISTEN_FD";
+
+static INHERITED: AtomicBool = AtomicBool::new(false);
+
+/// Takes over the listening socket handed over by the previous process with
+/// [`ServerHandle::hand_over`]. Returns `None` when the process was started
+/// without one, or when the socket was taken already.
+pub fn inherited_listener() -> Result<Option<TcpListener>, Error> {
+    let Ok(value) = std::env::var(LISTEN_FD_VAR) else {
+        return Ok(None);
+    };
+    let fd: RawFd = value.parse().map_err(|_| EnvVarInvalidError {
+        name: LISTEN_FD_VAR,
+        value: value.clone(),
+    })?;
+    if INHERITED.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+
+    // SAFETY: the previous process passed this descriptor to us for this
+    // purpose, and the flag above makes sure it's only owned once.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true).map_err(HandoverError)?;
+    TcpListener::from_std(listener)
+        .map(Some)
+        .map_err(HandoverError)
+}
+
+impl ServerHandle {
+    /// Starts `command`, typically the upgraded binary, with the listening
+    /// socket of this server, the one serving longest if several share the
+    /// handle, then shuts this server down gracefully with
+    /// `timeout`. The new process picks the socket up with
+    /// [`inherited_listener`]; connections arriving in between wait in the
+    /// listen backlog, so none are refused during the upgrade.
+    pub fn hand_over(
+        &self,
+        mut command: Command,
+        timeout: Option<Duration>,
+    ) -> Result<Child, Error> {
+        let fd = match self.listener_fd() {
+            Some(fd) => fd.map_err(HandoverError)?,
+            None => return Err(NotListeningError),
+        };
+        let socket = socket2::Socket::from(fd);
+        socket.set_cloexec(false).map_err(HandoverError)?;
+
+        command.env(LISTEN_FD_VAR, socket.as_raw_fd().to_string());
+        let child = command.spawn().map_err(HandoverError)?;
+        drop(socket);
+
+        info!("handed the listener over to process {}", child.id());
+        self.graceful_shutdown(timeout);
+        Ok(child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::FixtureDir;
+    use http_body_util::Empty;
+    use hyper::body::Bytes;
+    use hyper::Response;
+    use std::convert::Infallible;
+    use std::os::fd::IntoRawFd;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn hands_the_listener_to_the_new_process() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let handle = server.handle();
+        let command = || Command::new("true");
+        assert!(matches!(
+            handle.hand_over(command(), None),
+            Err(NotListeningError)
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+            });
+            server.serve_service(listener, service).await
+        });
+        handle.listening().await;
+
+        // The shell fails if the descriptor wasn't inherited.
+        let mut command = Command::new("sh");
+        command.args(["-c", &format!("true <&\"${}\"", LISTEN_FD_VAR)]);
+        let mut child = handle.hand_over(command, None).unwrap();
+        assert!(child.wait().unwrap().success());
+        serving.await.unwrap().unwrap();
+    }
+
+    // The environment is shared by the whole process, so every case runs in
+    // this one test.
+    #[tokio::test]
+    async fn takes_over_the_inherited_listener_once() {
+        std::env::remove_var(LISTEN_FD_VAR);
+        assert!(inherited_listener().unwrap().is_none());
+
+        std::env::set_var(LISTEN_FD_VAR, "stdin");
+        assert!(matches!(
+            inherited_listener(),
+            Err(EnvVarInvalidError { .. })
+        ));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::env::set_var(LISTEN_FD_VAR, listener.into_raw_fd().to_string());
+        let inherited = inherited_listener().unwrap().unwrap();
+        assert_eq!(inherited.local_addr().unwrap(), addr);
+        assert!(inherited_listener().unwrap().is_none());
+        std::env::remove_var(LISTEN_FD_VAR);
+    }
+}
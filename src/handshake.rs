@@ -0,0 +1,725 @@
+#[cfg(feature = "tokio")]
+use crate::alpn::{self, AlpnMismatch};
+#[cfg(feature = "tokio")]
+use crate::backend::{Backend, TlsBackend, TlsStream};
+use crate::conn::Session;
+use crate::sni::ServerNameRejected;
+#[cfg(feature = "tokio")]
+use crate::sni::{MissingSni, SniAllowlist};
+#[cfg(feature = "tokio")]
+use crate::Error::HandshakeRuntimeError;
+#[cfg(feature = "tokio")]
+use crate::{ClientAuth, Error, MtlServer};
+#[cfg(feature = "tokio")]
+use rustls::server::Acceptor;
+#[cfg(feature = "tokio")]
+use rustls::ServerConfig;
+use rustls::{AlertDescription, CertificateError, ServerConnection};
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::io;
+#[cfg(feature = "tokio")]
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "tokio")]
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tokio")]
+use tokio::runtime::Runtime;
+#[cfg(feature = "tokio")]
+use tokio::sync::Semaphore;
+#[cfg(feature = "tokio")]
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
+
+/// Why a handshake performed by the server failed.
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum HandshakeError {
+    #[error("client presented no certificate")]
+    NoClientCert,
+
+    #[error("client certificate issued by an unknown CA")]
+    UnknownCa,
+
+    #[error("client certificate expired")]
+    Expired,
+
+    #[error("client certificate not valid yet")]
+    NotValidYet,
+
+    #[error("client certificate has a bad signature")]
+    BadSignature,
+
+    #[error("client certificate revoked")]
+    Revoked,
+
+    #[error("no TLS version, cipher suite or protocol in common with client")]
+    ProtocolMismatch(#[source] rustls::Error),
+
+    #[error("client violated the TLS protocol: {0}")]
+    ProtocolViolation(rustls::Error),
+
+    #[error("client aborted the handshake with a {} alert", alert_name(*.0))]
+    AlertReceived(AlertDescription),
+
+    #[error("TLS handshake timed out")]
+    Timeout,
+
+    #[error("server name not allowed")]
+    ServerNameRejected,
+
+    #[error("TLS handshake failed: {0}")]
+    Tls(rustls::Error),
+
+    #[error("I/O error during TLS handshake")]
+    Io(#[source] io::Error),
+
+    #[cfg(feature = "openssl")]
+    #[error("TLS handshake failed: {0}")]
+    OpenSsl(openssl::ssl::Error),
+}
+
+impl HandshakeError {
+    /// A short, stable name of the failure category, e.g. for metrics
+    /// labels.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::NoClientCert => "no_client_cert",
+            Self::UnknownCa => "unknown_ca",
+            Self::Expired => "expired",
+            Self::NotValidYet => "not_valid_yet",
+            Self::BadSignature => "bad_signature",
+            Self::Revoked => "revoked",
+            Self::ProtocolMismatch(_) => "protocol_mismatch",
+            Self::ProtocolViolation(_) => "protocol_violation",
+            Self::AlertReceived(_) => "alert_received",
+            Self::Timeout => "timeout",
+            Self::ServerNameRejected => "server_name_rejected",
+            Self::Tls(_) => "tls",
+            Self::Io(_) => "io",
+            #[cfg(feature = "openssl")]
+            Self::OpenSsl(_) => "tls",
+        }
+    }
+
+    /// Whether the client certificate failed verification, as opposed to
+    /// network or protocol trouble.
+    pub(crate) fn is_verification_failure(&self) -> bool {
+        matches!(
+            self,
+            Self::NoClientCert
+                | Self::UnknownCa
+                | Self::Expired
+                | Self::NotValidYet
+                | Self::BadSignature
+                | Self::Revoked
+        )
+    }
+
+    /// The alert the server sent the client, e.g. `unknown_ca`, for
+    /// correlating with client side errors.
+    pub fn alert_sent(&self) -> Option<&'static str> {
+        let alert = match self {
+            Self::NoClientCert => AlertDescription::CertificateRequired,
+            Self::UnknownCa => AlertDescription::UnknownCA,
+            Self::Expired | Self::NotValidYet => {
+                AlertDescription::CertificateExpired
+            }
+            Self::BadSignature => AlertDescription::DecryptError,
+            Self::Revoked => AlertDescription::CertificateRevoked,
+            Self::ProtocolMismatch(rustls::Error::NoApplicationProtocol) => {
+                AlertDescription::NoApplicationProtocol
+            }
+            Self::ProtocolMismatch(_) => AlertDescription::HandshakeFailure,
+            Self::ProtocolViolation(rustls::Error::InvalidMessage(_)) => {
+                AlertDescription::DecodeError
+            }
+            Self::ProtocolViolation(_) => AlertDescription::UnexpectedMessage,
+            Self::Tls(rustls::Error::InvalidCertificate(err)) => {
+                AlertDescription::from(err.clone())
+            }
+            _ => return None,
+        };
+        Some(alert_name(alert))
+    }
+}
+
+/// The name of `alert` as in the TLS specification.
+fn alert_name(alert: AlertDescription) -> &'static str {
+    match alert {
+        AlertDescription::CloseNotify => "close_notify",
+        AlertDescription::UnexpectedMessage => "unexpected_message",
+        AlertDescription::BadRecordMac => "bad_record_mac",
+        AlertDescription::RecordOverflow => "record_overflow",
+        AlertDescription::HandshakeFailure => "handshake_failure",
+        AlertDescription::BadCertificate => "bad_certificate",
+        AlertDescription::UnsupportedCertificate => "unsupported_certificate",
+        AlertDescription::CertificateRevoked => "certificate_revoked",
+        AlertDescription::CertificateExpired => "certificate_expired",
+        AlertDescription::CertificateUnknown => "certificate_unknown",
+        AlertDescription::IllegalParameter => "illegal_parameter",
+        AlertDescription::UnknownCA => "unknown_ca",
+        AlertDescription::AccessDenied => "access_denied",
+        AlertDescription::DecodeError => "decode_error",
+        AlertDescription::DecryptError => "decrypt_error",
+        AlertDescription::ProtocolVersion => "protocol_version",
+        AlertDescription::InsufficientSecurity => "insufficient_security",
+        AlertDescription::InternalError => "internal_error",
+        AlertDescription::InappropriateFallback => "inappropriate_fallback",
+        AlertDescription::UserCanceled => "user_canceled",
+        AlertDescription::MissingExtension => "missing_extension",
+        AlertDescription::UnsupportedExtension => "unsupported_extension",
+        AlertDescription::UnrecognisedName => "unrecognized_name",
+        AlertDescription::CertificateRequired => "certificate_required",
+        AlertDescription::NoApplicationProtocol => "no_application_protocol",
+        _ => "unknown",
+    }
+}
+
+impl From<rustls::Error> for HandshakeError {
+    fn from(err: rustls::Error) -> Self {
+        match err {
+            rustls::Error::NoCertificatesPresented => Self::NoClientCert,
+            rustls::Error::InvalidCertificate(ref cert) => match cert {
+                CertificateError::UnknownIssuer => Self::UnknownCa,
+                CertificateError::Expired => Self::Expired,
+                CertificateError::NotValidYet => Self::NotValidYet,
+                CertificateError::BadSignature => Self::BadSignature,
+                CertificateError::Revoked => Self::Revoked,
+                _ => Self::Tls(err),
+            },
+            rustls::Error::PeerIncompatible(_)
+            | rustls::Error::NoApplicationProtocol => {
+                Self::ProtocolMismatch(err)
+            }
+            rustls::Error::PeerMisbehaved(_)
+            | rustls::Error::InvalidMessage(_)
+            | rustls::Error::InappropriateMessage { .. }
+            | rustls::Error::InappropriateHandshakeMessage { .. } => {
+                Self::ProtocolViolation(err)
+            }
+            rustls::Error::AlertReceived(alert) => Self::AlertReceived(alert),
+            err => Self::Tls(err),
+        }
+    }
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::TimedOut {
+            return Self::Timeout;
+        }
+        let Some(inner) = err.get_ref() else {
+            return Self::Io(err);
+        };
+        if inner.is::<ServerNameRejected>() {
+            return Self::ServerNameRejected;
+        }
+        // Backends other than rustls classify their errors themselves.
+        if inner.is::<HandshakeError>() {
+            let inner = err.into_inner().expect("checked above");
+            return *inner.downcast().expect("checked above");
+        }
+        match inner.downcast_ref::<rustls::Error>() {
+            Some(tls) => Self::from(tls.clone()),
+            None => Self::Io(err),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OffloadConfig {
+    pub(crate) threads: usize,
+    pub(crate) max_pending: usize,
+}
+
+#[cfg(feature = "tokio")]
+struct HandshakeOffload {
+    runtime: Option<Runtime>,
+    pending: Semaphore,
+}
+
+#[cfg(feature = "tokio")]
+impl HandshakeOffload {
+    fn new(config: OffloadConfig) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(config.threads.max(1))
+            .thread_name("mtls-handshake")
+            .enable_all()
+            .build()
+            .map_err(HandshakeRuntimeError)?;
+
+        Ok(Self {
+            runtime: Some(runtime),
+            pending: Semaphore::new(config.max_pending.max(1)),
+        })
+    }
+
+    async fn accept<IO>(
+        &self,
+        config: <Backend as TlsBackend>::Config,
+        stream: IO,
+    ) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let _permit = self.pending.acquire().await.map_err(io::Error::other)?;
+        let runtime = self.runtime.as_ref().expect("runtime is set until drop");
+        runtime
+            .spawn(Backend::accept(config, stream))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Drop for HandshakeOffload {
+    fn drop(&mut self) {
+        // The last reference is usually dropped from within the serving
+        // runtime, where blocking on the runtime shutdown is not allowed.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// The server configuration, and optionally a second one for connections to
+/// the diagnostics host, chosen by the server name in the ClientHello, which
+/// is also checked against the SNI allowlist. With
+/// [`AlpnMismatch::FallBack`], the chosen one is copied without ALPN for
+/// clients offering no enabled protocol.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub(crate) struct TlsConfigs {
+    config: Arc<ServerConfig>,
+    diagnostics: Option<(Box<str>, Arc<ServerConfig>)>,
+    alpn_mismatch: AlpnMismatch,
+    allowed_sni: Option<Arc<SniAllowlist>>,
+    missing_sni: MissingSni,
+}
+
+#[cfg(feature = "tokio")]
+impl TlsConfigs {
+    async fn accept<IO>(
+        self,
+        stream: IO,
+    ) -> io::Result<tokio_rustls::server::TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        if self.diagnostics.is_none()
+            && self.alpn_mismatch == AlpnMismatch::Reject
+            && self.allowed_sni.is_none()
+            && self.missing_sni.completes_handshake()
+        {
+            return TlsAcceptor::from(self.config).accept(stream).await;
+        }
+        let start =
+            LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        let hello = start.client_hello();
+        let config = match (&self.diagnostics, hello.server_name()) {
+            (Some((host, diagnostics)), Some(x))
+                if x.eq_ignore_ascii_case(host) =>
+            {
+                diagnostics.clone()
+            }
+            (_, server_name) => {
+                let allowed = match (server_name, &self.allowed_sni) {
+                    (Some(name), Some(allowed)) => allowed.allows(name),
+                    (Some(_), None) => true,
+                    (None, _) => self.missing_sni.completes_handshake(),
+                };
+                if !allowed {
+                    debug!(
+                        "rejected handshake for server name {:?}",
+                        server_name
+                    );
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        ServerNameRejected,
+                    ));
+                }
+                self.config
+            }
+        };
+        let config = match self.alpn_mismatch {
+            AlpnMismatch::FallBack if alpn::is_mismatch(&hello, &config) => {
+                debug!("no offered ALPN protocol is enabled");
+                let mut config = ServerConfig::clone(&config);
+                config.alpn_protocols.clear();
+                Arc::new(config)
+            }
+            _ => config,
+        };
+        start.into_stream(config).await
+    }
+}
+
+impl Session {
+    pub(crate) fn from_rustls(conn: &ServerConnection) -> Self {
+        let peer_certificates = conn
+            .peer_certificates()
+            .map(|certs| certs.iter().map(|x| x.clone().into_owned()).collect())
+            .unwrap_or_default();
+        Self {
+            peer_certificates,
+            alpn_protocol: conn.alpn_protocol().map(Vec::from),
+            server_name: conn.server_name().map(Box::from),
+            protocol_version: conn.protocol_version(),
+            cipher_suite: conn.negotiated_cipher_suite().map(|x| x.suite()),
+        }
+    }
+}
+
+/// The rustls backend.
+#[cfg(feature = "tokio")]
+pub(crate) struct Rustls;
+
+#[cfg(feature = "tokio")]
+impl TlsBackend for Rustls {
+    type Config = TlsConfigs;
+    type Stream<IO>
+        = tokio_rustls::server::TlsStream<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn config(
+        server: &MtlServer,
+        client_auth: ClientAuth,
+        diagnostics: bool,
+    ) -> Result<TlsConfigs, Error> {
+        server.create_tls_configs(client_auth, diagnostics)
+    }
+
+    fn accept<IO>(
+        config: TlsConfigs,
+        stream: IO,
+    ) -> impl Future<Output = io::Result<Self::Stream<IO>>> + Send
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        config.accept(stream)
+    }
+
+    fn session<IO>(stream: &Self::Stream<IO>) -> Session
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Session::from_rustls(stream.get_ref().1)
+    }
+
+    fn is_diagnostic<IO>(config: &TlsConfigs, stream: &Self::Stream<IO>) -> bool
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match &config.diagnostics {
+            None => false,
+            Some((host, _)) => stream
+                .get_ref()
+                .1
+                .server_name()
+                .is_some_and(|x| x.eq_ignore_ascii_case(host)),
+        }
+    }
+
+    fn set_buffer_limit<IO>(stream: &mut Self::Stream<IO>, limit: usize)
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        stream.get_mut().1.set_buffer_limit(Some(limit));
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub(crate) struct Handshaker {
+    configs: Arc<RwLock<<Backend as TlsBackend>::Config>>,
+    timeout: Option<Duration>,
+    offload: Option<Arc<HandshakeOffload>>,
+    buffer_limit: Option<usize>,
+}
+
+#[cfg(feature = "tokio")]
+impl Handshaker {
+    pub(crate) async fn accept<IO>(
+        &self,
+        stream: IO,
+    ) -> Result<TlsStream<IO>, HandshakeError>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let config = self.configs.read().unwrap().clone();
+        let accept = async {
+            match &self.offload {
+                Some(offload) => offload.accept(config, stream).await,
+                None => Backend::accept(config, stream).await,
+            }
+        };
+
+        let mut stream = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, accept)
+                .await
+                .map_err(|_| HandshakeError::Timeout)??,
+            None => accept.await?,
+        };
+        if let Some(limit) = self.buffer_limit {
+            Backend::set_buffer_limit(&mut stream, limit);
+        }
+        Ok(stream)
+    }
+
+    /// Whether `stream` was accepted for the diagnostics host, without
+    /// verifying the client certificate.
+    pub(crate) fn is_diagnostic<IO>(&self, stream: &TlsStream<IO>) -> bool
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Backend::is_diagnostic(&self.configs.read().unwrap(), stream)
+    }
+
+    /// Switches new handshakes to `config`; running ones finish with the
+    /// config they started with.
+    pub(crate) fn set_config(&self, config: <Backend as TlsBackend>::Config) {
+        *self.configs.write().unwrap() = config;
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl MtlServer {
+    /// `diagnostics` enables the diagnostics host, if configured. Only
+    /// `serve_service` and friends may enable it, as they answer its
+    /// connections with diagnostics instead of the application.
+    pub(crate) fn create_handshaker(
+        &self,
+        client_auth: ClientAuth,
+        diagnostics: bool,
+    ) -> Result<Handshaker, Error> {
+        let configs = Backend::config(self, client_auth, diagnostics)?;
+        let offload = match self.handshake_offload {
+            Some(config) => Some(Arc::new(HandshakeOffload::new(config)?)),
+            None => None,
+        };
+
+        Ok(Handshaker {
+            configs: Arc::new(RwLock::new(configs)),
+            timeout: self.handshake_timeout,
+            offload,
+            buffer_limit: self.tls_buffer_limit,
+        })
+    }
+
+    pub(crate) fn create_tls_configs(
+        &self,
+        client_auth: ClientAuth,
+        diagnostics: bool,
+    ) -> Result<TlsConfigs, Error> {
+        let config = Arc::new(self.create_tls_config(client_auth)?);
+        let diagnostics = match (&self.diagnostics_host, diagnostics) {
+            (Some(host), true) if client_auth != ClientAuth::Disabled => Some(
+                (host.clone(), Arc::new(self.create_diagnostics_config()?)),
+            ),
+            _ => None,
+        };
+
+        Ok(TlsConfigs {
+            config,
+            diagnostics,
+            alpn_mismatch: self.alpn_mismatch,
+            allowed_sni: self.allowed_sni.clone(),
+            missing_sni: self.missing_sni(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir, SERVER_CERT};
+    use rustls::pki_types::UnixTime;
+    use rustls::time_provider::TimeProvider;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A fixture clock remembering the threads certificates were verified
+    /// on.
+    #[derive(Debug, Default)]
+    struct ThreadClock {
+        threads: Mutex<Vec<Option<String>>>,
+    }
+
+    impl TimeProvider for ThreadClock {
+        fn current_time(&self) -> Option<UnixTime> {
+            let thread = std::thread::current().name().map(String::from);
+            self.threads.lock().unwrap().push(thread);
+            FixedClock::fixture().current_time()
+        }
+    }
+
+    #[tokio::test]
+    async fn offloads_handshakes_to_the_handshake_threads() {
+        let fixtures = FixtureDir::new().unwrap();
+        let clock = Arc::new(ThreadClock::default());
+        let acceptor = fixtures
+            .server()
+            .with_time_provider(clock.clone())
+            .with_handshake_offload(1, 1)
+            .mtls_acceptor()
+            .unwrap();
+
+        let alice = fixtures.client_config("alice").unwrap();
+        let bob = fixtures.client_config("bob").unwrap();
+        let (alice, bob) = tokio::join!(
+            connect_duplex(&acceptor, alice, "localhost"),
+            connect_duplex(&acceptor, bob, "localhost"),
+        );
+        alice.unwrap();
+        bob.unwrap();
+
+        let threads = clock.threads.lock().unwrap();
+        assert!(!threads.is_empty());
+        assert!(threads
+            .iter()
+            .all(|x| x.as_deref() == Some("mtls-handshake")));
+    }
+
+    #[tokio::test]
+    async fn writes_beyond_the_buffer_limit() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_tls_buffer_limit(1024)
+            .mtls_acceptor()
+            .unwrap();
+        let alice = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+
+        let (mut server, mut client) = (conn.server, conn.client);
+        let response = vec![7; 256 * 1024];
+        let write = async {
+            server.write_all(&response).await.unwrap();
+            server.shutdown().await.unwrap();
+        };
+        let mut received = Vec::new();
+        let read = client.read_to_end(&mut received);
+        let ((), read) = tokio::join!(write, read);
+        read.unwrap();
+        assert_eq!(received, response);
+    }
+
+    async fn handshake_error(
+        server: MtlServer,
+        config: Arc<rustls::ClientConfig>,
+    ) -> HandshakeError {
+        let acceptor = server.mtls_acceptor().unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        conn.err().unwrap()
+    }
+
+    #[tokio::test]
+    async fn classifies_rejected_client_certificates() {
+        let fixtures = FixtureDir::new().unwrap();
+        let alice = fixtures.client_config("alice").unwrap();
+        let at = |secs| {
+            let time = UnixTime::since_unix_epoch(Duration::from_secs(secs));
+            fixtures
+                .server()
+                .with_time_provider(Arc::new(FixedClock::at(time)))
+        };
+
+        let err = handshake_error(at(0), alice.clone()).await;
+        assert!(matches!(err, HandshakeError::NotValidYet), "{:?}", err);
+        let err = handshake_error(at(u32::MAX as u64 * 2), alice).await;
+        assert!(matches!(err, HandshakeError::Expired), "{:?}", err);
+        assert_eq!(err.label(), "expired");
+
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let err = handshake_error(server, anonymous).await;
+        assert!(matches!(err, HandshakeError::NoClientCert), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn times_out_silent_clients() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_handshake_timeout(Duration::from_millis(50))
+            .mtls_acceptor()
+            .unwrap();
+        let (_client, server) = tokio::io::duplex(1024);
+        let remote_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let err = acceptor.accept(server, remote_addr).await.err().unwrap();
+        assert!(matches!(err, HandshakeError::Timeout), "{:?}", err);
+        assert_eq!(err.label(), "timeout");
+    }
+
+    #[test]
+    fn classifies_tls_errors() {
+        let err = HandshakeError::from(rustls::Error::InvalidCertificate(
+            CertificateError::UnknownIssuer,
+        ));
+        assert!(matches!(err, HandshakeError::UnknownCa));
+        let err = HandshakeError::from(rustls::Error::NoApplicationProtocol);
+        assert_eq!(err.label(), "protocol_mismatch");
+        let err = HandshakeError::from(rustls::Error::DecryptError);
+        assert_eq!(err.label(), "tls");
+
+        // rustls errors wrapped by tokio-rustls keep their category.
+        let io = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::NoCertificatesPresented,
+        );
+        assert!(matches!(
+            HandshakeError::from(io),
+            HandshakeError::NoClientCert
+        ));
+        let io = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(HandshakeError::from(io).label(), "io");
+    }
+
+    #[tokio::test]
+    async fn names_the_alerts_sent_and_received() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        let err = handshake_error(server.clone(), anonymous).await;
+        assert_eq!(err.alert_sent(), Some("certificate_required"));
+
+        // A client not trusting the server aborts with an alert.
+        let leaf = fixtures.path().join("leaf.crt");
+        let end = "-----END CERTIFICATE-----\n";
+        let pem = SERVER_CERT.split_inclusive(end).next().unwrap();
+        std::fs::write(&leaf, pem).unwrap();
+        let distrustful = crate::testing::client_config(
+            &fixtures.file("alice.crt"),
+            &fixtures.file("alice.key"),
+            leaf.to_str().unwrap(),
+        )
+        .unwrap();
+        let err = handshake_error(server, distrustful).await;
+        assert!(matches!(
+            err,
+            HandshakeError::AlertReceived(AlertDescription::UnknownCA)
+        ));
+        assert_eq!(
+            err.to_string(),
+            "client aborted the handshake with a unknown_ca alert"
+        );
+        assert_eq!(err.alert_sent(), None);
+
+        let err = HandshakeError::from(rustls::Error::InappropriateMessage {
+            expect_types: Vec::new(),
+            got_type: rustls::ContentType::Alert,
+        });
+        assert_eq!(err.label(), "protocol_violation");
+        assert_eq!(err.alert_sent(), Some("unexpected_message"));
+    }
+}
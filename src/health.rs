@@ -0,0 +1,98 @@
+use crate::ServerHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The state of a server for readiness and liveness probes, see
+/// [`ServerHandle::health`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct Health {
+    /// A listener was bound.
+    pub bound: bool,
+    /// A listener is accepting connections and no shutdown was requested.
+    pub serving: bool,
+    /// The most recent certificate reload succeeded, or there was none.
+    pub last_reload_ok: bool,
+    /// The server certificate in effect is within its validity period.
+    pub cert_expiry_ok: bool,
+    /// Accepting isn't paused by the circuit breaker.
+    pub accept_loop_healthy: bool,
+    /// The server was marked as draining, see
+    /// [`ServerHandle::set_draining`].
+    pub draining: bool,
+}
+
+impl Health {
+    /// Whether the server should receive traffic: all checks pass and it
+    /// isn't draining.
+    pub fn is_ready(&self) -> bool {
+        self.bound
+            && self.serving
+            && self.last_reload_ok
+            && self.cert_expiry_ok
+            && self.accept_loop_healthy
+            && !self.draining
+    }
+}
+
+impl ServerHandle {
+    /// The state of the server, for the application's own health endpoint
+    /// or Kubernetes probes.
+    pub fn health(&self) -> Health {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs() as i64);
+        let pki = self.pki.lock().unwrap();
+        let cert_expiry_ok = pki
+            .server_chain
+            .first()
+            .is_some_and(|x| x.not_before <= now && now <= x.not_after);
+        drop(pki);
+        Health {
+            bound: self.local_addr().is_some(),
+            serving: self.is_listening() && !self.is_shutting_down(),
+            last_reload_ok: self
+                .last_reload()
+                .is_none_or(|x| x.error.is_none()),
+            cert_expiry_ok,
+            accept_loop_healthy: !self.is_circuit_open(),
+            draining: self.is_draining(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use crate::testing::fixtures::FixtureDir;
+    use http_body_util::Empty;
+    use hyper::body::Bytes;
+    use hyper::Response;
+    use std::convert::Infallible;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn reports_the_state_of_the_server() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let health = server.handle().health();
+        assert!(!health.bound && !health.serving && !health.is_ready());
+
+        let service = service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+        });
+        let (_, handle) = server.serve_ephemeral(service).await.unwrap();
+        let health = handle.health();
+        assert!(health.bound && health.serving && health.cert_expiry_ok);
+        assert!(health.last_reload_ok && health.accept_loop_healthy);
+        assert!(health.is_ready());
+
+        handle.set_draining(true);
+        let health = handle.health();
+        assert!(health.draining && health.serving && !health.is_ready());
+
+        handle.set_draining(false);
+        handle.shutdown();
+        let health = handle.health();
+        assert!(!health.serving && !health.is_ready());
+    }
+}
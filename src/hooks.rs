@@ -0,0 +1,10 @@
+use crate::{CloseReason, ConnInfo};
+use futures_util::future::BoxFuture;
+
+/// Awaited once the handshake of a connection completed.
+pub(crate) type OpenHook =
+    dyn Fn(ConnInfo) -> BoxFuture<'static, ()> + Send + Sync;
+
+/// Spawned once a connection whose handshake completed ended.
+pub(crate) type CloseHook =
+    dyn Fn(ConnInfo, CloseReason) -> BoxFuture<'static, ()> + Send + Sync;
@@ -0,0 +1,238 @@
+use crate::sni::SniAllowlist;
+use crate::ConnInfo;
+use futures_util::future::{ready, Either, Ready};
+use hyper::header::HOST;
+use hyper::http::uri::Authority;
+use hyper::{Request, Response, StatusCode};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Checks the `Host` header, or the `:authority` of HTTP/2 requests,
+/// against the server name the client sent with SNI, so a connection
+/// established for one host can't be used to reach another one behind the
+/// same IP address. Hosts matching
+/// [`with_allowed_hosts`](Self::with_allowed_hosts) are accepted on any
+/// connection, e.g. for clients connecting by IP address without SNI;
+/// [`without_sni_match`](Self::without_sni_match) accepts only those, which
+/// also protects against DNS rebinding. Mismatches are answered with
+/// `421 Misdirected Request`, requests without a valid host with
+/// `400 Bad Request`. Relies on the [`ConnInfo`] extension added by
+/// `serve_service` and friends.
+///
+/// ```no_run
+/// # use hyper_mtls_server::HostValidationLayer;
+/// let layer = HostValidationLayer::new().with_allowed_hosts(["10.0.0.7"]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct HostValidationLayer {
+    allowed: Arc<SniAllowlist>,
+    match_sni: bool,
+}
+
+impl Default for HostValidationLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostValidationLayer {
+    /// Accepts requests whose host is the server name sent with SNI.
+    pub fn new() -> Self {
+        Self {
+            allowed: Arc::default(),
+            match_sni: true,
+        }
+    }
+
+    /// Also accepts the listed hosts; `*` stands for a single label, as in
+    /// [`MtlServer::with_allowed_sni`](crate::MtlServer::with_allowed_sni).
+    pub fn with_allowed_hosts<I>(mut self, hosts: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Box<str>>,
+    {
+        let allowed = Arc::make_mut(&mut self.allowed);
+        for host in hosts {
+            allowed.insert(host.into());
+        }
+        self
+    }
+
+    /// Accepts only the hosts listed with
+    /// [`with_allowed_hosts`](Self::with_allowed_hosts), whatever the SNI.
+    pub fn without_sni_match(mut self) -> Self {
+        self.match_sni = false;
+        self
+    }
+
+    fn status<B>(&self, req: &Request<B>) -> StatusCode {
+        let Some(host) = host(req) else {
+            return StatusCode::BAD_REQUEST;
+        };
+        let host = host.trim_end_matches('.');
+        let server_name = req
+            .extensions()
+            .get::<ConnInfo>()
+            .and_then(|x| x.server_name())
+            .filter(|_| self.match_sni);
+        let sni_matches = server_name.is_some_and(|x| {
+            x.trim_end_matches('.').eq_ignore_ascii_case(host)
+        });
+        if sni_matches || self.allowed.allows(host) {
+            return StatusCode::OK;
+        }
+        debug!("host {} does not match server name {:?}", host, server_name);
+        StatusCode::MISDIRECTED_REQUEST
+    }
+}
+
+/// The host of the request URI, which HTTP/2 requests and HTTP/1 requests in
+/// absolute form carry, or of the `Host` header, without the port.
+fn host<B>(req: &Request<B>) -> Option<String> {
+    if let Some(authority) = req.uri().authority() {
+        return Some(authority.host().to_owned());
+    }
+    let header = req.headers().get(HOST)?.to_str().ok()?;
+    let authority = header.parse::<Authority>().ok()?;
+    Some(authority.host().to_owned())
+}
+
+impl<S> Layer<S> for HostValidationLayer {
+    type Service = HostValidation<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HostValidation {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HostValidation<S> {
+    inner: S,
+    layer: HostValidationLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for HostValidation<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response<ResBody>, S::Error>>, S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let status = self.layer.status(&req);
+        if status != StatusCode::OK {
+            let mut response = Response::new(ResBody::default());
+            *response.status_mut() = status;
+            return Either::Left(ready(Ok(response)));
+        }
+        Either::Right(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use http_body_util::Empty;
+    use hyper::body::Bytes;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    /// A connection established for `localhost`.
+    async fn localhost() -> ConnInfo {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        conn.unwrap().conn_info
+    }
+
+    async fn status(
+        layer: &HostValidationLayer,
+        uri: &str,
+        host: Option<&str>,
+        conn_info: &ConnInfo,
+    ) -> StatusCode {
+        let service = layer.layer(service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+        }));
+        let mut req = Request::get(uri);
+        if let Some(host) = host {
+            req = req.header(HOST, host);
+        }
+        let mut req = req.body(()).unwrap();
+        req.extensions_mut().insert(conn_info.clone());
+        service.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn checks_the_host_against_the_server_name() {
+        let conn_info = localhost().await;
+        let layer = HostValidationLayer::new();
+        for host in ["localhost", "LocalHost:8443", "localhost."] {
+            let status = status(&layer, "/", Some(host), &conn_info).await;
+            assert_eq!(status, StatusCode::OK, "{}", host);
+        }
+        assert_eq!(
+            status(&layer, "/", Some("other.example"), &conn_info).await,
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        // The authority of the request URI takes precedence.
+        let uri = "https://other.example/";
+        assert_eq!(
+            status(&layer, uri, Some("localhost"), &conn_info).await,
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        assert_eq!(
+            status(&layer, "https://localhost:8443/", None, &conn_info).await,
+            StatusCode::OK
+        );
+        for host in [None, Some("bad host")] {
+            let status = status(&layer, "/", host, &conn_info).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_the_allowed_hosts() {
+        let conn_info = localhost().await;
+        let layer = HostValidationLayer::new()
+            .with_allowed_hosts(["10.0.0.7", "*.internal"]);
+        for host in ["localhost", "10.0.0.7:8443", "api.internal"] {
+            let status = status(&layer, "/", Some(host), &conn_info).await;
+            assert_eq!(status, StatusCode::OK, "{}", host);
+        }
+        assert_eq!(
+            status(&layer, "/", Some("a.api.internal"), &conn_info).await,
+            StatusCode::MISDIRECTED_REQUEST
+        );
+
+        let layer = layer.without_sni_match();
+        assert_eq!(
+            status(&layer, "/", Some("localhost"), &conn_info).await,
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        assert_eq!(
+            status(&layer, "/", Some("api.internal"), &conn_info).await,
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use crate::Error;
+use crate::Error::{HeaderSizeLimitError, Http2SettingError};
+use hyper::{Request, StatusCode};
+#[cfg(feature = "tokio")]
+use hyper_util::rt::TokioTimer;
+use hyper_util::server::conn::auto;
+use std::time::Duration;
+
+const MIN_FRAME_SIZE: u32 = 16_384;
+const MAX_FRAME_SIZE: u32 = 16_777_215;
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+pub(crate) const MIN_HEADER_SIZE: usize = 8192;
+
+/// HTTP/2 settings for connections served by `serve_service` and friends.
+/// Unset values keep hyper's defaults.
+///
+/// ```no_run
+/// # use hyper_mtls_server::{Http2Config, MtlServer};
+/// # use std::time::Duration;
+/// # fn example(server: MtlServer) {
+/// let http2 = Http2Config::new()
+///     .with_max_concurrent_streams(1000)
+///     .with_initial_stream_window_size(1 << 20)
+///     .with_keep_alive(Duration::from_secs(20), Duration::from_secs(10));
+/// let server = server.with_http2_config(http2);
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Http2Config {
+    max_concurrent_streams: Option<u32>,
+    initial_stream_window_size: Option<u32>,
+    initial_connection_window_size: Option<u32>,
+    adaptive_window: bool,
+    max_frame_size: Option<u32>,
+    keep_alive: Option<(Duration, Duration)>,
+}
+
+impl Http2Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the streams a client may open at the same time, 200 by
+    /// default.
+    pub fn with_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Sets the flow control window of every stream, up to `2^31 - 1`.
+    pub fn with_initial_stream_window_size(mut self, size: u32) -> Self {
+        self.initial_stream_window_size = Some(size);
+        self
+    }
+
+    /// Sets the flow control window of the connection, up to `2^31 - 1`.
+    pub fn with_initial_connection_window_size(mut self, size: u32) -> Self {
+        self.initial_connection_window_size = Some(size);
+        self
+    }
+
+    /// Sizes the flow control windows from the measured bandwidth-delay
+    /// product, overriding the initial window sizes.
+    pub fn with_adaptive_window(mut self) -> Self {
+        self.adaptive_window = true;
+        self
+    }
+
+    /// Sets the largest frame the server accepts, from 16384 to 16777215.
+    pub fn with_max_frame_size(mut self, size: u32) -> Self {
+        self.max_frame_size = Some(size);
+        self
+    }
+
+    /// Sends a PING every `interval` and closes the connection if it isn't
+    /// acknowledged within `timeout`.
+    pub fn with_keep_alive(
+        mut self,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        self.keep_alive = Some((interval, timeout));
+        self
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if let Some(size) = self.max_frame_size {
+            if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&size) {
+                return Err(Http2SettingError("max frame size", size));
+            }
+        }
+        let windows = [
+            (
+                "initial stream window size",
+                self.initial_stream_window_size,
+            ),
+            (
+                "initial connection window size",
+                self.initial_connection_window_size,
+            ),
+        ];
+        for (name, size) in windows {
+            if let Some(size) = size.filter(|x| *x > MAX_WINDOW_SIZE) {
+                return Err(Http2SettingError(name, size));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn apply<E>(&self, builder: &mut auto::Builder<E>) {
+        let mut http2 = builder.http2();
+        // `None` would lift hyper's default limit.
+        if let Some(max) = self.max_concurrent_streams {
+            http2.max_concurrent_streams(max);
+        }
+        http2
+            .initial_stream_window_size(self.initial_stream_window_size)
+            .initial_connection_window_size(self.initial_connection_window_size)
+            .max_frame_size(self.max_frame_size);
+        if self.adaptive_window {
+            http2.adaptive_window(true);
+        }
+        if let Some((interval, timeout)) = self.keep_alive {
+            http2
+                .timer(TokioTimer::new())
+                .keep_alive_interval(interval)
+                .keep_alive_timeout(timeout);
+        }
+    }
+}
+
+/// Limits on the request head, applied to connections served by
+/// `serve_service` and friends. By default, requests are limited to 100
+/// headers and 64 KiB of header data, for HTTP/1 and HTTP/2 alike.
+///
+/// Framing is always strict: hyper answers folded headers, conflicting
+/// `Content-Length` values and a `Transfer-Encoding` not ending in `chunked`
+/// with `400 Bad Request`, and drops `Content-Length` from requests that
+/// also carry `Transfer-Encoding`, closing the connection after them.
+#[derive(Clone, Copy, Debug)]
+pub struct HttpLimits {
+    max_headers: usize,
+    max_header_size: usize,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        Self {
+            max_headers: 100,
+            max_header_size: 64 * 1024,
+        }
+    }
+}
+
+impl HttpLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers requests with more than `max` headers with `431 Request
+    /// Header Fields Too Large`.
+    pub fn with_max_headers(mut self, max: usize) -> Self {
+        self.max_headers = max;
+        self
+    }
+
+    /// Limits the size of the request head to `size` bytes, at least 8192.
+    /// For HTTP/1, this is also the largest read buffer of a connection.
+    pub fn with_max_header_size(mut self, size: usize) -> Self {
+        self.max_header_size = size;
+        self
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.max_header_size < MIN_HEADER_SIZE {
+            return Err(HeaderSizeLimitError(self.max_header_size));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply<E>(&self, builder: &mut auto::Builder<E>) {
+        builder
+            .http1()
+            .max_headers(self.max_headers)
+            .max_buf_size(self.max_header_size);
+        let list_size = u32::try_from(self.max_header_size).unwrap_or(u32::MAX);
+        builder.http2().max_header_list_size(list_size);
+    }
+
+    /// The status to answer `req` with instead of serving it, if any.
+    /// hyper only counts the headers of HTTP/1 requests.
+    pub(crate) fn reject<B>(&self, req: &Request<B>) -> Option<StatusCode> {
+        (req.headers().len() > self.max_headers)
+            .then_some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::testing::send;
+    use crate::{MtlServer, ServerHandle};
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::Bytes;
+    use hyper::Response;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rustls::pki_types::ServerName;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+    use tokio_rustls::TlsConnector;
+    use tower::service_fn;
+
+    fn server(fixtures: &FixtureDir) -> MtlServer {
+        fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+    }
+
+    /// Serves `ok` on an ephemeral port until shut down.
+    async fn serve(
+        server: MtlServer,
+    ) -> (SocketAddr, ServerHandle, JoinHandle<Result<(), Error>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Full::<Bytes>::from("ok")))
+            });
+            server.serve_service(listener, service).await
+        });
+        (addr, handle, serving)
+    }
+
+    #[test]
+    fn checks_the_http2_settings() {
+        assert!(Http2Config::new().check().is_ok());
+        let config = Http2Config::new().with_max_frame_size(MIN_FRAME_SIZE - 1);
+        assert!(matches!(
+            config.check(),
+            Err(Http2SettingError("max frame size", 16_383))
+        ));
+        let config = Http2Config::new()
+            .with_initial_connection_window_size(MAX_WINDOW_SIZE + 1);
+        assert!(matches!(
+            config.check(),
+            Err(Http2SettingError("initial connection window size", _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn serves_http2_with_the_settings() {
+        let fixtures = FixtureDir::new().unwrap();
+        let invalid = Http2Config::new().with_max_frame_size(MAX_FRAME_SIZE);
+        let invalid = invalid.with_initial_stream_window_size(u32::MAX);
+        let (_, _, serving) =
+            serve(server(&fixtures).with_http2_config(invalid)).await;
+        assert!(matches!(serving.await.unwrap(), Err(Http2SettingError(..))));
+
+        let http2 = Http2Config::new()
+            .with_max_concurrent_streams(10)
+            .with_initial_stream_window_size(1 << 20)
+            .with_adaptive_window()
+            .with_max_frame_size(MAX_FRAME_SIZE)
+            .with_keep_alive(Duration::from_secs(20), Duration::from_secs(10));
+        let server = server(&fixtures).with_http2_config(http2);
+        let (addr, handle, serving) = serve(server).await;
+
+        let alice = fixtures.client_config("alice").unwrap();
+        let mut config = rustls::ClientConfig::clone(&alice);
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let tls = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+            .unwrap();
+        let (mut sender, conn) = hyper::client::conn::http2::handshake(
+            TokioExecutor::new(),
+            TokioIo::new(tls),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(conn);
+        let req = Request::get("https://localhost/")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        let body = response.into_body().collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "ok");
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_the_request_headers() {
+        let fixtures = FixtureDir::new().unwrap();
+        let limits =
+            HttpLimits::new().with_max_header_size(MIN_HEADER_SIZE - 1);
+        let (_, _, serving) =
+            serve(server(&fixtures).with_http_limits(limits)).await;
+        assert!(matches!(
+            serving.await.unwrap(),
+            Err(HeaderSizeLimitError(8191))
+        ));
+
+        let limits = HttpLimits::new()
+            .with_max_headers(3)
+            .with_max_header_size(MIN_HEADER_SIZE);
+        let server = server(&fixtures).with_http_limits(limits);
+        let (addr, handle, serving) = serve(server).await;
+        let alice = fixtures.client_config("alice").unwrap();
+        let get = |headers: usize, size: usize| {
+            let mut req = Request::get("/");
+            for i in 0..headers {
+                req = req.header(format!("x-{}", i), "x".repeat(size));
+            }
+            req.body(Empty::<Bytes>::new()).unwrap()
+        };
+
+        let response = send(alice.clone(), addr, get(2, 1)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(alice.clone(), addr, get(4, 1)).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        // hyper closes the connection of a head beyond its buffer.
+        let response = send(alice, addr, get(1, 2 * MIN_HEADER_SIZE)).await;
+        assert!(response.map_or(true, |x| !x.status().is_success()));
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use rustls_pki_types::CertificateDer;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// The parts of a client certificate commonly used to identify the client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientIdentity {
+    fingerprint: Box<str>,
+    subject: Box<str>,
+    common_name: Option<Box<str>>,
+    organizational_units: Box<[Box<str>]>,
+    dns_names: Box<[Box<str>]>,
+    uris: Box<[Box<str>]>,
+}
+
+impl ClientIdentity {
+    /// Parses the leaf of a verified chain. Returns `None` when the
+    /// certificate cannot be parsed, which the verifier would have rejected.
+    pub(crate) fn from_cert(cert: &CertificateDer<'_>) -> Option<Self> {
+        let (_, parsed) = X509Certificate::from_der(cert).ok()?;
+
+        let mut dns_names = Vec::new();
+        let mut uris = Vec::new();
+        if let Ok(Some(san)) = parsed.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(x) => dns_names.push(Box::from(*x)),
+                    GeneralName::URI(x) => uris.push(Box::from(*x)),
+                    _ => {}
+                }
+            }
+        }
+
+        let common_name = parsed
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|x| x.as_str().ok())
+            .map(Box::from);
+        let organizational_units = parsed
+            .subject()
+            .iter_organizational_unit()
+            .filter_map(|x| x.as_str().ok())
+            .map(Box::from)
+            .collect();
+
+        Some(Self {
+            fingerprint: sha256_hex(cert).into(),
+            subject: parsed.subject().to_string().into(),
+            common_name,
+            organizational_units,
+            dns_names: dns_names.into(),
+            uris: uris.into(),
+        })
+    }
+
+    /// Lowercase hex SHA-256 of the DER encoded certificate.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// The subject distinguished name, e.g. `CN=client, O=Example`.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// The `OU` attributes of the subject.
+    pub fn organizational_units(&self) -> impl Iterator<Item = &str> {
+        self.organizational_units.iter().map(|x| x.as_ref())
+    }
+
+    pub fn dns_names(&self) -> impl Iterator<Item = &str> {
+        self.dns_names.iter().map(|x| x.as_ref())
+    }
+
+    pub fn uris(&self) -> impl Iterator<Item = &str> {
+        self.uris.iter().map(|x| x.as_ref())
+    }
+
+    /// The first `spiffe://` URI SAN, if any.
+    pub fn spiffe_id(&self) -> Option<&str> {
+        self.uris().find(|x| x.starts_with("spiffe://"))
+    }
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().fold(
+        String::with_capacity(64),
+        |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::ALICE_CERT;
+
+    #[test]
+    fn identifies_the_client_of_a_certificate() {
+        let mut pem = ALICE_CERT.as_bytes();
+        let cert = rustls_pemfile::certs(&mut pem).next().unwrap().unwrap();
+        let identity = ClientIdentity::from_cert(&cert).unwrap();
+
+        assert_eq!(
+            identity.fingerprint(),
+            "9568743d9cd69b835e76bdc68fabcbd1c2c239dd915ce2a7dbf5261a854c032a"
+        );
+        assert_eq!(
+            identity.subject(),
+            "O=hyper-mtls-server fixtures, CN=alice"
+        );
+        assert_eq!(identity.common_name(), Some("alice"));
+        assert_eq!(identity.spiffe_id(), Some("spiffe://example.org/alice"));
+        assert_eq!(identity.dns_names().count(), 0);
+    }
+
+    #[test]
+    fn rejects_unparsable_certificates() {
+        let cert = CertificateDer::from(&b"not a certificate"[..]);
+        assert!(ClientIdentity::from_cert(&cert).is_none());
+    }
+}
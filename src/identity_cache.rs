@@ -0,0 +1,252 @@
+use crate::metrics::Metrics;
+use crate::principal::{IdentityMapper, MappedPrincipal, Rejection};
+use crate::ClientIdentity;
+use rustls_pki_types::CertificateDer;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Entries kept unless configured with
+/// [`MtlServer::with_identity_cache`](crate::MtlServer::with_identity_cache).
+const DEFAULT_CAPACITY: usize = 256;
+
+type Principal = Result<MappedPrincipal, Rejection>;
+
+struct Entry {
+    identity: Option<Arc<ClientIdentity>>,
+    /// What the mapper returned for the identity, with the mapper, as
+    /// servers sharing the cache may have different ones.
+    principal: Option<(Arc<IdentityMapper>, Principal)>,
+    last_used: u64,
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("identity", &self.identity)
+            .field("principal", &self.principal.as_ref().map(|x| &x.1))
+            .field("last_used", &self.last_used)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    capacity: usize,
+    entries: HashMap<Box<[u8]>, Entry>,
+    /// The certificates of `entries` by `last_used`, least recent first.
+    recency: BTreeMap<u64, Box<[u8]>>,
+    uses: u64,
+}
+
+impl State {
+    fn touch(&mut self, cert: &[u8]) -> Option<&mut Entry> {
+        self.uses += 1;
+        let entry = self.entries.get_mut(cert)?;
+        let key = self.recency.remove(&entry.last_used)?;
+        entry.last_used = self.uses;
+        self.recency.insert(self.uses, key);
+        Some(entry)
+    }
+
+    fn insert(&mut self, cert: &[u8], identity: Option<Arc<ClientIdentity>>) {
+        if self.entries.contains_key(cert) {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some((_, lru)) = self.recency.pop_first() {
+                self.entries.remove(&lru);
+            }
+        }
+        self.uses += 1;
+        let entry = Entry {
+            identity,
+            principal: None,
+            last_used: self.uses,
+        };
+        self.entries.insert(cert.into(), entry);
+        self.recency.insert(self.uses, cert.into());
+    }
+}
+
+/// The identities parsed from recently seen client certificates, and the
+/// principals they were mapped to, so clients reconnecting frequently don't
+/// have their certificate parsed, hashed and mapped every time. Evicts the
+/// least recently used certificate when full.
+#[derive(Debug)]
+pub(crate) struct IdentityCache {
+    state: Mutex<State>,
+    metrics: Arc<Metrics>,
+}
+
+impl IdentityCache {
+    pub(crate) fn new(metrics: Arc<Metrics>) -> Self {
+        let state = State {
+            capacity: DEFAULT_CAPACITY,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            uses: 0,
+        };
+        Self {
+            state: Mutex::new(state),
+            metrics,
+        }
+    }
+
+    pub(crate) fn configure(&self, capacity: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.capacity = capacity;
+        state.entries.clear();
+        state.recency.clear();
+    }
+
+    /// The identity of `cert`, parsed unless it is cached.
+    pub(crate) fn get(
+        &self,
+        cert: &CertificateDer<'_>,
+    ) -> Option<Arc<ClientIdentity>> {
+        let mut state = self.state.lock().unwrap();
+        if state.capacity == 0 {
+            drop(state);
+            return ClientIdentity::from_cert(cert).map(Arc::new);
+        }
+        if let Some(entry) = state.touch(cert) {
+            self.metrics.identity_cache_hit();
+            return entry.identity.clone();
+        }
+        drop(state);
+
+        self.metrics.identity_cache_miss();
+        let identity = ClientIdentity::from_cert(cert).map(Arc::new);
+        let mut state = self.state.lock().unwrap();
+        if state.capacity > 0 {
+            state.insert(cert, identity.clone());
+        }
+        identity
+    }
+
+    /// The principal `mapper` maps `identity`, the identity of `cert`, to,
+    /// mapped unless it is cached.
+    pub(crate) fn principal(
+        &self,
+        cert: &CertificateDer<'_>,
+        identity: &ClientIdentity,
+        mapper: &Arc<IdentityMapper>,
+    ) -> Principal {
+        let cached = |state: &State| {
+            let (by, principal) =
+                state.entries.get(cert.as_ref())?.principal.as_ref()?;
+            Arc::ptr_eq(by, mapper).then(|| principal.clone())
+        };
+        if let Some(principal) = cached(&self.state.lock().unwrap()) {
+            return principal;
+        }
+
+        let principal = mapper(identity);
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.get_mut(cert.as_ref()) {
+            entry.principal = Some((mapper.clone(), principal.clone()));
+        }
+        principal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{ALICE_CERT, BOB_CERT, SERVER_CERT};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cert(pem: &str) -> CertificateDer<'static> {
+        let mut pem = pem.as_bytes();
+        let cert = rustls_pemfile::certs(&mut pem).next();
+        cert.unwrap().unwrap()
+    }
+
+    fn cache(capacity: usize) -> (IdentityCache, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let cache = IdentityCache::new(metrics.clone());
+        cache.configure(capacity);
+        (cache, metrics)
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_certificate() {
+        let (cache, metrics) = cache(2);
+        let [alice, bob, server] =
+            [ALICE_CERT, BOB_CERT, SERVER_CERT].map(cert);
+        cache.get(&alice).unwrap();
+        cache.get(&bob).unwrap();
+        cache.get(&alice).unwrap();
+        cache.get(&server).unwrap();
+        assert_eq!(metrics.snapshot().identity_cache_hits, 1);
+
+        // Bob was evicted, Alice wasn't.
+        cache.get(&alice).unwrap();
+        assert_eq!(metrics.snapshot().identity_cache_hits, 2);
+        cache.get(&bob).unwrap();
+        assert_eq!(metrics.snapshot().identity_cache_misses, 4);
+        let state = cache.state.lock().unwrap();
+        assert_eq!(state.entries.len(), 2);
+        assert_eq!(state.recency.len(), 2);
+    }
+
+    #[test]
+    fn maps_each_certificate_once() {
+        let (cache, _) = cache(1);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mapper: Arc<IdentityMapper> = Arc::new({
+            let calls = calls.clone();
+            move |identity: &ClientIdentity| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                match identity.common_name() {
+                    Some("alice") => Ok(MappedPrincipal::new(1)),
+                    _ => Err(Rejection::new("not alice")),
+                }
+            }
+        });
+        let [alice, bob] = [ALICE_CERT, BOB_CERT].map(cert);
+        for _ in 0..3 {
+            let identity = cache.get(&alice).unwrap();
+            assert!(cache.principal(&alice, &identity, &mapper).is_ok());
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Another mapper doesn't get the cached principal.
+        let other: Arc<IdentityMapper> =
+            Arc::new(|_: &ClientIdentity| Err(Rejection::new("nobody")));
+        let identity = cache.get(&alice).unwrap();
+        assert!(cache.principal(&alice, &identity, &other).is_err());
+
+        // Rejections are cached too, and go with the evicted certificate.
+        for _ in 0..2 {
+            let identity = cache.get(&bob).unwrap();
+            let rejection = cache.principal(&bob, &identity, &mapper);
+            assert_eq!(rejection.err().unwrap().reason(), "not alice");
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        let identity = cache.get(&alice).unwrap();
+        assert!(cache.principal(&alice, &identity, &mapper).is_ok());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn maps_every_time_without_a_cache() {
+        let (cache, _) = cache(0);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mapper: Arc<IdentityMapper> = Arc::new({
+            let calls = calls.clone();
+            move |_: &ClientIdentity| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Ok(MappedPrincipal::new(()))
+            }
+        });
+        let alice = cert(ALICE_CERT);
+        for _ in 0..2 {
+            let identity = cache.get(&alice).unwrap();
+            cache.principal(&alice, &identity, &mapper).unwrap();
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(cache.state.lock().unwrap().entries.is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+use crate::{CertEvent, CertificateInfo, ClientIdentity, ServerHandle};
+use rustls_pki_types::CertificateDer;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The certificate a client identity presented last.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct SeenCert {
+    pub cert: CertificateInfo,
+    /// Unix timestamp in seconds.
+    pub last_seen: i64,
+}
+
+/// How the certificate of a known client identity changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CertChange {
+    /// Issued by the same CA, no earlier than the previous certificate.
+    Renewed,
+    /// Issued by another CA, or before the previous certificate, e.g. an
+    /// old certificate that leaked or a rogue issuer.
+    Suspicious,
+}
+
+impl CertChange {
+    fn of(previous: &CertificateInfo, current: &CertificateInfo) -> Self {
+        if current.issuer == previous.issuer
+            && current.not_before >= previous.not_before
+        {
+            Self::Renewed
+        } else {
+            Self::Suspicious
+        }
+    }
+}
+
+/// Keeps the certificate each client identity presented last for
+/// [`MtlServer::with_identity_tracking`](crate::MtlServer::with_identity_tracking),
+/// e.g. in a shared database so all replicas notice changes. Called from
+/// the connection task once per connection, so keep it quick.
+pub trait IdentityStore: Send + Sync + 'static {
+    fn get(&self, identity: &str) -> Option<SeenCert>;
+
+    fn put(&self, identity: &str, seen: SeenCert);
+}
+
+#[derive(Debug)]
+struct Seen {
+    certs: HashMap<Box<str>, SeenCert>,
+    capacity: usize,
+}
+
+/// An [`IdentityStore`] in memory, forgetting the identity seen least
+/// recently once it holds `capacity` of them.
+#[derive(Debug)]
+pub struct MemoryIdentityStore {
+    seen: Mutex<Seen>,
+}
+
+impl MemoryIdentityStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: Mutex::new(Seen {
+                certs: HashMap::new(),
+                capacity: capacity.max(1),
+            }),
+        }
+    }
+}
+
+impl IdentityStore for MemoryIdentityStore {
+    fn get(&self, identity: &str) -> Option<SeenCert> {
+        self.seen.lock().unwrap().certs.get(identity).cloned()
+    }
+
+    fn put(&self, identity: &str, seen: SeenCert) {
+        let mut state = self.seen.lock().unwrap();
+        if !state.certs.contains_key(identity)
+            && state.certs.len() >= state.capacity
+        {
+            let oldest = state
+                .certs
+                .iter()
+                .min_by_key(|(_, x)| x.last_seen)
+                .map(|(identity, _)| identity.clone());
+            if let Some(oldest) = oldest {
+                state.certs.remove(&oldest);
+            }
+        }
+        state.certs.insert(identity.into(), seen);
+    }
+}
+
+/// The name certificates are tracked under: the SPIFFE ID, or else the
+/// subject.
+fn tracked_name(identity: &ClientIdentity) -> &str {
+    identity.spiffe_id().unwrap_or(identity.subject())
+}
+
+impl ServerHandle {
+    /// Records the certificate `identity` presented, sending
+    /// [`CertEvent::ClientCertChanged`] if it isn't the one it presented
+    /// last.
+    pub(crate) fn track_identity(
+        &self,
+        store: &dyn IdentityStore,
+        identity: &ClientIdentity,
+        leaf: &CertificateDer<'_>,
+    ) {
+        let name = tracked_name(identity);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs() as i64);
+        let previous = store.get(name);
+        if let Some(previous) = previous
+            .as_ref()
+            .filter(|x| &*x.cert.fingerprint == identity.fingerprint())
+        {
+            let seen = SeenCert {
+                cert: previous.cert.clone(),
+                last_seen: now,
+            };
+            store.put(name, seen);
+            return;
+        }
+        let Some(cert) = CertificateInfo::from_cert(leaf) else {
+            return;
+        };
+        if let Some(previous) = previous {
+            let change = CertChange::of(&previous.cert, &cert);
+            match change {
+                CertChange::Renewed => info!(
+                    identity = name,
+                    previous = &*previous.cert.fingerprint,
+                    fingerprint = &*cert.fingerprint,
+                    "client presented a renewed certificate"
+                ),
+                CertChange::Suspicious => {
+                    self.metrics.suspicious_cert_change();
+                    warn!(
+                        identity = name,
+                        previous = &*previous.cert.fingerprint,
+                        previous_issuer = &*previous.cert.issuer,
+                        fingerprint = &*cert.fingerprint,
+                        issuer = &*cert.issuer,
+                        "client presented a suspicious certificate change"
+                    );
+                }
+            }
+            self.events.send(CertEvent::ClientCertChanged {
+                identity: name.into(),
+                previous: previous.cert.fingerprint,
+                fingerprint: cert.fingerprint.clone(),
+                change,
+            });
+        }
+        store.put(
+            name,
+            SeenCert {
+                cert,
+                last_seen: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::FixtureDir;
+    use crate::CertEvent;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair,
+    };
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    struct Ca {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new(name: &str) -> Self {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        /// Issues a certificate for alice valid from the start of `year`.
+        fn issue(&self, year: i32) -> CertificateDer<'static> {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.distinguished_name.push(DnType::CommonName, "alice");
+            params.not_before = rcgen::date_time_ymd(year, 1, 1);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            cert.der().clone()
+        }
+    }
+
+    /// Tracks `cert`, returning its fingerprint.
+    fn track(
+        handle: &ServerHandle,
+        store: &MemoryIdentityStore,
+        cert: &CertificateDer<'_>,
+    ) -> Box<str> {
+        let identity = ClientIdentity::from_cert(cert).unwrap();
+        handle.track_identity(store, &identity, cert);
+        identity.fingerprint().into()
+    }
+
+    #[test]
+    fn reports_identities_presenting_another_certificate() {
+        let fixtures = FixtureDir::new().unwrap();
+        let handle = fixtures.server().handle();
+        let mut events = handle.events();
+        let store = MemoryIdentityStore::new(8);
+        let changed =
+            |previous, fingerprint, change| CertEvent::ClientCertChanged {
+                identity: "CN=alice".into(),
+                previous,
+                fingerprint,
+                change,
+            };
+
+        let ca = Ca::new("ca");
+        let first = track(&handle, &store, &ca.issue(2024));
+        let cert = ca.issue(2025);
+        track(&handle, &store, &cert);
+        let renewed = track(&handle, &store, &cert);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            changed(first, renewed.clone(), CertChange::Renewed)
+        );
+
+        let older = track(&handle, &store, &ca.issue(2023));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            changed(renewed, older.clone(), CertChange::Suspicious)
+        );
+        let rogue = track(&handle, &store, &Ca::new("rogue").issue(2026));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            changed(older, rogue, CertChange::Suspicious)
+        );
+        assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(handle.metrics().suspicious_cert_changes, 2);
+    }
+
+    #[test]
+    fn forgets_the_identity_seen_least_recently() {
+        let store = MemoryIdentityStore::new(2);
+        let seen = |last_seen| SeenCert {
+            cert: CertificateInfo::from_cert(&Ca::new("ca").issue(2024))
+                .unwrap(),
+            last_seen,
+        };
+        store.put("a", seen(1));
+        store.put("b", seen(3));
+        store.put("a", seen(2));
+        store.put("c", seen(4));
+        assert!(store.get("a").is_none());
+        assert_eq!(store.get("b").unwrap().last_seen, 3);
+        assert_eq!(store.get("c").unwrap().last_seen, 4);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::store::{
+    is_self_signed, KeyKind, StoreIdentity, StoreKey, StoreSigner,
+};
+use crate::Error::KeychainError;
+use crate::{Error, MtlServer};
+use rustls::sign::SigningKey;
+use rustls::SignatureScheme;
+use rustls_pki_types::CertificateDer;
+use security_framework::identity::SecIdentity;
+use security_framework::item::{
+    ItemClass, ItemSearchOptions, Limit, Reference, SearchResult,
+};
+use security_framework::key::{Algorithm, SecKey};
+use std::sync::Arc;
+use std::{fmt, io};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// `errSecItemNotFound`, what searches without a match fail with.
+const ITEM_NOT_FOUND: i32 = -25300;
+
+impl MtlServer {
+    /// Serves the identity labeled `label` in the Keychain search list,
+    /// with the intermediates the Keychain holds, instead of the
+    /// certificate and key files. The key stays in the Keychain, which
+    /// signs the handshakes, so non-extractable and Secure Enclave keys
+    /// work. Reloads look the identity up again. Only RSA, P-256 and P-384
+    /// keys are supported.
+    pub fn with_keychain_identity(
+        mut self,
+        label: impl Into<Box<str>>,
+    ) -> Self {
+        let label = label.into();
+        self.store_identity = Some(Arc::new(KeychainIdentity { label }));
+        self
+    }
+}
+
+#[derive(Debug)]
+struct KeychainIdentity {
+    label: Box<str>,
+}
+
+impl KeychainIdentity {
+    fn find(&self) -> Result<SecIdentity, Error> {
+        let results = ItemSearchOptions::new()
+            .class(ItemClass::identity())
+            .label(&self.label)
+            .load_refs(true)
+            .search()
+            .map_err(|x| self.error("finding", x))?;
+        let identity = results.into_iter().find_map(|x| match x {
+            SearchResult::Ref(Reference::Identity(x)) => Some(x),
+            _ => None,
+        });
+        identity.ok_or_else(|| KeychainError {
+            what: format!("no Keychain identity labeled {:?}", self.label)
+                .into(),
+            source: io::ErrorKind::NotFound.into(),
+        })
+    }
+
+    fn error(&self, what: &str, err: security_framework::base::Error) -> Error {
+        let source = match err.code() {
+            ITEM_NOT_FOUND => io::Error::new(io::ErrorKind::NotFound, err),
+            _ => io::Error::other(err),
+        };
+        KeychainError {
+            what: format!("failed {} Keychain identity {:?}", what, self.label)
+                .into(),
+            source,
+        }
+    }
+}
+
+impl StoreIdentity for KeychainIdentity {
+    fn chain(&self) -> Result<Vec<CertificateDer<'static>>, Error> {
+        let leaf = self
+            .find()?
+            .certificate()
+            .map_err(|x| self.error("reading the certificate of", x))?;
+        let mut chain = vec![CertificateDer::from(leaf.to_der())];
+        let certs = match ItemSearchOptions::new()
+            .class(ItemClass::certificate())
+            .load_refs(true)
+            .limit(Limit::All)
+            .search()
+        {
+            Ok(results) => results,
+            Err(err) if err.code() == ITEM_NOT_FOUND => Vec::new(),
+            Err(err) => {
+                return Err(self.error("finding the intermediates of", err));
+            }
+        };
+        let certs: Vec<CertificateDer<'static>> = certs
+            .into_iter()
+            .filter_map(|x| match x {
+                SearchResult::Ref(Reference::Certificate(x)) => {
+                    Some(x.to_der().into())
+                }
+                _ => None,
+            })
+            .collect();
+
+        // Follow the issuers up to the root, which clients already have.
+        while let Some(issuer) = issuer_of(&chain[chain.len() - 1], &certs) {
+            if is_self_signed(issuer) || chain.contains(issuer) {
+                break;
+            }
+            chain.push(issuer.clone());
+        }
+        Ok(chain)
+    }
+
+    fn key(&self) -> Result<Arc<dyn SigningKey>, Error> {
+        let identity = self.find()?;
+        let leaf = identity
+            .certificate()
+            .map_err(|x| self.error("reading the certificate of", x))?;
+        let leaf = CertificateDer::from(leaf.to_der());
+        let kind = KeyKind::of_cert(&leaf).ok_or_else(|| KeychainError {
+            what: format!(
+                "the key of Keychain identity {:?} is not RSA, P-256 or P-384",
+                self.label
+            )
+            .into(),
+            source: io::ErrorKind::Unsupported.into(),
+        })?;
+        let key = identity
+            .private_key()
+            .map_err(|x| self.error("reading the private key of", x))?;
+        let signer = KeychainKey(key);
+        Ok(Arc::new(StoreKey::new(kind, Arc::new(signer))))
+    }
+}
+
+/// The certificate in `certs` that issued `cert`.
+fn issuer_of<'a>(
+    cert: &CertificateDer<'_>,
+    certs: &'a [CertificateDer<'static>],
+) -> Option<&'a CertificateDer<'static>> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    certs.iter().find(|x| {
+        X509Certificate::from_der(x)
+            .is_ok_and(|(_, x)| x.subject().as_raw() == cert.issuer().as_raw())
+    })
+}
+
+/// A Keychain key, signing with `SecKeyCreateSignature`.
+struct KeychainKey(SecKey);
+
+impl fmt::Debug for KeychainKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeychainKey")
+    }
+}
+
+impl StoreSigner for KeychainKey {
+    fn sign(
+        &self,
+        scheme: SignatureScheme,
+        message: &[u8],
+    ) -> Result<Vec<u8>, rustls::Error> {
+        let algorithm = match scheme {
+            SignatureScheme::RSA_PSS_SHA256 => {
+                Algorithm::RSASignatureMessagePSSSHA256
+            }
+            SignatureScheme::RSA_PSS_SHA384 => {
+                Algorithm::RSASignatureMessagePSSSHA384
+            }
+            SignatureScheme::RSA_PSS_SHA512 => {
+                Algorithm::RSASignatureMessagePSSSHA512
+            }
+            SignatureScheme::RSA_PKCS1_SHA256 => {
+                Algorithm::RSASignatureMessagePKCS1v15SHA256
+            }
+            SignatureScheme::RSA_PKCS1_SHA384 => {
+                Algorithm::RSASignatureMessagePKCS1v15SHA384
+            }
+            SignatureScheme::RSA_PKCS1_SHA512 => {
+                Algorithm::RSASignatureMessagePKCS1v15SHA512
+            }
+            SignatureScheme::ECDSA_NISTP256_SHA256 => {
+                Algorithm::ECDSASignatureMessageX962SHA256
+            }
+            SignatureScheme::ECDSA_NISTP384_SHA384 => {
+                Algorithm::ECDSASignatureMessageX962SHA384
+            }
+            _ => {
+                return Err(rustls::Error::General(format!(
+                    "signature scheme {:?} is not supported",
+                    scheme
+                )));
+            }
+        };
+        // X9.62 ECDSA signatures are DER encoded already.
+        self.0.create_signature(algorithm, message).map_err(|err| {
+            rustls::Error::General(format!(
+                "signing with the Keychain key failed: {}",
+                err
+            ))
+        })
+    }
+}
+
+// SAFETY: Security framework keys are immutable and may be used from any
+// thread.
+unsafe impl Send for KeychainKey {}
+unsafe impl Sync for KeychainKey {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_a_missing_identity() {
+        let identity = KeychainIdentity {
+            label: "hyper-mtls-server missing identity".into(),
+        };
+        let Err(KeychainError { source, .. }) = identity.chain() else {
+            panic!("found a Keychain identity with the test label");
+        };
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -1,24 +1,341 @@
+// Without tokio, the accept loop and what only it uses aren't built.
+#![cfg_attr(not(feature = "tokio"), allow(dead_code, unused_macros))]
+
+#[cfg(feature = "native-roots")]
+use crate::Error::NativeRootsEmptyError;
 use crate::Error::{
-    CertExtractError, CertFileReadError, ClientVerifierBuildError,
+    CertExtractError, CertFileReadError, ClientCaCertMissingError,
+    ClientVerifierBuildError, CryptoProviderError, MaxFragmentSizeError,
     PrivateKeyExtractError, PrivateKeyFileReadError, PrivateKeyItemEmptyError,
-    ServerConfigError, TrustStoreError,
+    ProtocolParseError, ServerConfigError, TlsVersionBoundsError,
+    TrustStoreError,
+};
+
+#[macro_use]
+mod trace;
+
+#[cfg(feature = "tokio")]
+mod acceptor;
+mod access_log;
+mod alpn;
+mod authz;
+#[cfg(feature = "axum")]
+mod axum;
+#[cfg(feature = "tokio")]
+mod backend;
+mod ban;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod breaker;
+mod callback;
+#[cfg(feature = "clap")]
+mod cli;
+#[cfg(feature = "client")]
+mod client;
+mod close;
+#[cfg(feature = "serde")]
+mod config;
+mod conn;
+mod deadline;
+mod der;
+mod diagnostics;
+mod drain;
+mod env;
+mod events;
+mod forwarded;
+#[cfg(feature = "futures-io")]
+mod futures_acceptor;
+#[cfg(feature = "tokio")]
+mod graceful;
+mod grpc;
+mod handle;
+#[cfg(all(unix, feature = "tokio"))]
+mod handover;
+mod handshake;
+mod health;
+mod hooks;
+mod host;
+mod http;
+mod identity;
+mod identity_cache;
+mod identity_tracking;
+#[cfg(all(target_os = "macos", feature = "keychain"))]
+mod keychain;
+mod lint;
+mod listener;
+mod log_policy;
+mod metrics;
+mod missing_cert;
+#[cfg(feature = "tokio")]
+mod ocsp;
+#[cfg(feature = "openssl")]
+mod openssl_tls;
+#[cfg(feature = "tokio")]
+mod passthrough;
+mod pki;
+mod policy;
+mod principal;
+#[cfg(feature = "proxy")]
+mod proxy;
+mod quota;
+mod ratelimit;
+#[cfg(feature = "tokio")]
+mod redirect;
+mod reflection;
+mod reload;
+mod renewal;
+#[cfg(feature = "tokio")]
+mod revocation;
+#[cfg(feature = "tokio")]
+mod serve;
+mod shed;
+mod sni;
+mod startup;
+mod store;
+#[cfg(feature = "tokio")]
+pub mod testing;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
+mod usage;
+#[cfg(all(windows, feature = "windows-store"))]
+mod windows_store;
+mod workers;
+
+#[cfg(feature = "axum")]
+pub use crate::axum::{MtlsConnectInfo, RequireClientCert};
+#[cfg(feature = "tokio")]
+pub use acceptor::{Incoming, MtlsAcceptor};
+pub use access_log::{AccessLog, AccessLogFuture, AccessLogLayer};
+pub use alpn::AlpnMismatch;
+pub use authz::{Authorization, AuthorizationLayer, Requirement};
+#[cfg(feature = "tokio")]
+pub use backend::ServerTlsStream;
+pub use ban::BanPolicy;
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingTlsStream;
+pub use breaker::CircuitBreaker;
+pub use callback::CallbackResult;
+#[cfg(feature = "clap")]
+pub use cli::MtlServerArgs;
+#[cfg(feature = "client")]
+pub use client::{MtlsConnector, MtlsStream};
+pub use close::{CloseCounts, CloseReason};
+#[cfg(feature = "serde")]
+pub use config::MtlServerConfig;
+pub use conn::{ConnInfo, ConnectionId};
+pub use events::CertEvent;
+pub use forwarded::{
+    IpCidr, PeerIdentity, PeerIdentityLayer, PeerIdentityService,
+};
+#[cfg(feature = "futures-io")]
+pub use futures_acceptor::FuturesAcceptor;
+pub use grpc::{GrpcBody, GrpcHealth, GrpcHealthLayer, HealthStatus};
+pub use handle::ServerHandle;
+#[cfg(all(unix, feature = "tokio"))]
+pub use handover::{inherited_listener, LISTEN_FD_VAR};
+pub use handshake::HandshakeError;
+pub use health::Health;
+pub use host::{HostValidation, HostValidationLayer};
+pub use http::{Http2Config, HttpLimits};
+pub use identity::ClientIdentity;
+pub use identity_tracking::{
+    CertChange, IdentityStore, MemoryIdentityStore, SeenCert,
 };
+pub use lint::LintWarning;
+pub use listener::ListenerConfig;
+pub use log_policy::{FailureSummary, LogEvent, LogLevel, LogPolicy};
+pub use metrics::{
+    IdentityLabel, IdentityMetrics, MetricsSnapshot, OVERFLOW_IDENTITY_LABEL,
+};
+pub use missing_cert::MissingClientCert;
+#[cfg(feature = "tokio")]
+pub use ocsp::OcspConfig;
+pub use pki::{CertificateInfo, PkiInfo};
+pub use policy::TlsPolicy;
+pub use principal::Rejection;
+#[cfg(feature = "proxy")]
+pub use proxy::{ProxyBody, ReverseProxy};
+pub use quota::QuotaKey;
+pub use ratelimit::{RateLimit, RateLimitLayer, RateLimited};
+#[cfg(feature = "tokio")]
+pub use redirect::{HttpsRedirect, RedirectStatus};
+pub use reflection::{GrpcReflection, GrpcReflectionLayer};
+pub use reload::ReloadStatus;
+#[cfg(feature = "tokio")]
+pub use reload::ReloadableAcceptor;
+#[cfg(feature = "tokio")]
+pub use revocation::{
+    RevocationCheck, RevocationChecker, RevocationPolicy, RevocationQuery,
+    RevocationStatus,
+};
+pub use shed::{Load, LoadShedPolicy, LoadShedThresholds};
+pub use sni::MissingSni;
+pub use startup::StartupInfo;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub use uring::UringListener;
+pub use usage::{Usage, UsageSink};
+#[cfg(all(windows, feature = "windows-store"))]
+pub use windows_store::{CertSelector, StoreLocation, WindowsStore};
+
+use deadline::ConnTimeouts;
+use futures_util::FutureExt;
+use handshake::OffloadConfig;
+use hooks::{CloseHook, OpenHook};
+use hyper::header::HeaderName;
+#[cfg(feature = "tokio")]
+use listener::Accept;
+#[cfg(feature = "tokio")]
+use passthrough::Passthrough;
+use principal::{IdentityMapper, MappedPrincipal};
+use quota::ClientQuota;
+use reload::Material;
+use rustls::crypto::CryptoProvider;
+use rustls::server::danger::ClientCertVerifier;
 use rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
-use rustls::{RootCertStore, ServerConfig};
+use rustls::sign::CertifiedKey;
+use rustls::time_provider::TimeProvider;
+use rustls::{RootCertStore, ServerConfig, SupportedProtocolVersion};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use sni::SniAllowlist;
+#[cfg(feature = "tokio")]
+use sni::SniFallback;
+use std::borrow::Cow;
+use std::fmt;
 use std::fs::File;
+use std::future::Future;
 use std::io::BufReader;
-use std::ops::Deref;
+#[cfg(feature = "tokio")]
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+use store::{ServerKey, SingleCert, StoreCas, StoreIdentity};
+#[cfg(feature = "tokio")]
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+#[cfg(feature = "tokio")]
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+#[cfg(feature = "tokio")]
 use tokio_rustls::TlsAcceptor;
+use workers::WorkerConfig;
 
-#[derive(Clone, Debug)]
-pub struct Protocol(&'static str);
+/// An ALPN protocol ID the server offers, one of the constants or any other
+/// name of 1 to 255 bytes, e.g. parsed from a config file.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Protocol(Cow<'static, str>);
 
 impl Protocol {
-    pub const HTTP_1: Protocol = Protocol("http/1.1");
-    pub const HTTP_2: Protocol = Protocol("h2");
+    pub const HTTP_1: Protocol = Protocol(Cow::Borrowed("http/1.1"));
+    pub const HTTP_2: Protocol = Protocol(Cow::Borrowed("h2"));
+    pub const HTTP_3: Protocol = Protocol(Cow::Borrowed("h3"));
+    pub const GRPC_EXP: Protocol = Protocol(Cow::Borrowed("grpc-exp"));
+
+    /// Fails with [`Error::ProtocolParseError`] unless `name` is 1 to 255
+    /// bytes long, as ALPN requires.
+    pub fn new(name: impl Into<Cow<'static, str>>) -> Result<Self, Error> {
+        let name = name.into();
+        if !(1..=255).contains(&name.len()) {
+            return Err(ProtocolParseError(name.into()));
+        }
+        Ok(Self(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    fn defaults() -> Box<[Protocol]> {
+        vec![Protocol::HTTP_1, Protocol::HTTP_2].into_boxed_slice()
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Self::new(s.to_owned())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Protocol {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Protocol {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::{Error as _, Unexpected};
+
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(|_| {
+            D::Error::invalid_value(
+                Unexpected::Str(&name),
+                &"an ALPN protocol of 1 to 255 bytes",
+            )
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ClientAuth {
+    /// Clients must present a certificate issued by the client CA.
+    #[default]
+    Required,
+    /// Clients may connect without a certificate, but a presented
+    /// certificate must be issued by the client CA.
+    Optional,
+    /// Plain TLS, client certificates are not requested.
+    Disabled,
+}
+
+impl ClientAuth {
+    fn from_name(name: &str) -> Option<ClientAuth> {
+        match name {
+            "required" => Some(ClientAuth::Required),
+            "optional" => Some(ClientAuth::Optional),
+            "disabled" => Some(ClientAuth::Disabled),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TlsVersion {
+    #[cfg_attr(feature = "serde", serde(rename = "1.2"))]
+    Tls12,
+    #[cfg_attr(feature = "serde", serde(rename = "1.3"))]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn rustls_version(self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -58,15 +375,234 @@ pub enum Error {
     #[error("failed building server tsl config")]
     ServerConfigError(#[source] rustls::Error),
 
+    #[error("failed building client tls config")]
+    ClientConfigError(#[source] rustls::Error),
+
     #[error("failed to build client verifier")]
     ClientVerifierBuildError(#[source] VerifierBuilderError),
+
+    #[error("client CA certificate is required for client authentication")]
+    ClientCaCertMissingError,
+
+    #[error("client CA certificate {0} has expired")]
+    ClientCaExpiredError(Box<str>),
+
+    #[error("client CA certificate #{0} in the bundle can't be parsed")]
+    ClientCaParseError(usize),
+
+    #[cfg(feature = "native-roots")]
+    #[error("no usable certificates found in the native trust store")]
+    NativeRootsEmptyError,
+
+    #[error("crypto provider doesn't support the enabled TLS versions")]
+    CryptoProviderError(#[source] rustls::Error),
+
+    #[error("failed to start the handshake runtime")]
+    HandshakeRuntimeError(#[source] std::io::Error),
+
+    #[error("minimum TLS version {0:?} is above maximum TLS version {1:?}")]
+    TlsVersionBoundsError(TlsVersion, TlsVersion),
+
+    #[error(
+        "max fragment size {0} is outside the allowed range \
+         {MIN_FRAGMENT_SIZE}..={MAX_FRAGMENT_SIZE}"
+    )]
+    MaxFragmentSizeError(usize),
+
+    #[error(
+        "short-lived certificate reload point {0} is outside the lifetime \
+         range (0, 1]"
+    )]
+    ReloadPointError(f64),
+
+    #[error("HTTP/2 setting {0} of {1} is outside the allowed range")]
+    Http2SettingError(&'static str, u32),
+
+    #[error(
+        "max header size {0} is below the minimum of {} bytes",
+        http::MIN_HEADER_SIZE
+    )]
+    HeaderSizeLimitError(usize),
+
+    #[error("server certificate {0} has expired")]
+    ServerCertExpiredError(Box<str>),
+
+    #[error("server certificate {0} is not valid yet")]
+    ServerCertNotYetValidError(Box<str>),
+
+    #[error("server private key does not belong to certificate {0}")]
+    ServerKeyMismatchError(Box<str>),
+
+    #[error(
+        "server private key {key_file} ({key}) doesn't match the {cert} \
+         key of certificate {cert_file}"
+    )]
+    ServerKeyTypeMismatchError {
+        key: Box<str>,
+        key_file: Box<str>,
+        cert: Box<str>,
+        cert_file: Box<str>,
+    },
+
+    #[error("failed connecting to the server")]
+    ProbeConnectError(#[source] std::io::Error),
+
+    #[error("request to the server failed")]
+    ProbeRequestError(#[source] hyper::Error),
+
+    #[error("environment variable {0} is not set")]
+    EnvVarMissingError(&'static str),
+
+    #[error("environment variable {name} has invalid value {value:?}")]
+    EnvVarInvalidError { name: &'static str, value: String },
+
+    #[error("the server is not listening")]
+    NotListeningError,
+
+    #[error(
+        "MissingSni::Fallback needs the service of with_missing_sni_fallback"
+    )]
+    MissingSniFallbackError,
+
+    #[error("failed handing over the listener")]
+    HandoverError(#[source] std::io::Error),
+
+    #[error("the serve callback failed {failures} times in a row")]
+    CallbackError {
+        failures: u32,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("failed binding an ephemeral port")]
+    EphemeralBindError(#[source] std::io::Error),
+
+    #[error("invalid IP address block {0:?}")]
+    CidrParseError(Box<str>),
+
+    #[error("invalid ALPN protocol {0:?}, expected 1 to 255 bytes")]
+    ProtocolParseError(Box<str>),
+
+    #[error("invalid file descriptor set")]
+    DescriptorSetParseError,
+
+    #[cfg(all(feature = "futures-io", feature = "tokio"))]
+    #[error("revocation checks need a tokio runtime to run on")]
+    RevocationRuntimeError,
+
+    #[cfg(all(windows, feature = "windows-store"))]
+    #[error("{what}")]
+    WindowsStoreError {
+        what: Box<str>,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[cfg(all(target_os = "macos", feature = "keychain"))]
+    #[error("{what}")]
+    KeychainError {
+        what: Box<str>,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[cfg(feature = "blocking")]
+    #[error("failed polling the blocking listener")]
+    BlockingListenerError(#[source] std::io::Error),
+
+    #[cfg(feature = "openssl")]
+    #[error("failed building OpenSSL server config")]
+    OpenSslConfigError(#[source] openssl::error::ErrorStack),
+
+    #[cfg(feature = "openssl")]
+    #[error("{0} is not supported by the OpenSSL backend")]
+    BackendUnsupportedError(&'static str),
+
+    #[cfg(feature = "reqwest")]
+    #[error("failed converting certificates for reqwest")]
+    ReqwestConversionError(#[source] reqwest::Error),
+
+    #[cfg(feature = "proxy")]
+    #[error("upstream must be an absolute https:// URI")]
+    ProxyUpstreamError,
+
+    #[cfg(feature = "config")]
+    #[error("failed reading config file")]
+    ConfigFileReadError(#[source] std::io::Error),
+
+    #[cfg(feature = "config")]
+    #[error("failed parsing config file")]
+    ConfigParseError(#[source] toml::de::Error),
 }
 
+/// The process default provider if one was installed, aws-lc-rs otherwise.
+/// Unlike the rustls builders, this keeps working when another dependency
+/// enables a second provider.
+fn crypto_provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default().cloned().unwrap_or_else(|| {
+        Arc::new(rustls::crypto::aws_lc_rs::default_provider())
+    })
+}
+
+const MIN_FRAGMENT_SIZE: usize = 32;
+const MAX_FRAGMENT_SIZE: usize = 16384 + 5;
+
+#[derive(Clone)]
 pub struct MtlServer {
     server_cert_path: Box<str>,
     server_key_path: Box<str>,
-    client_ca_cert_path: Box<str>,
+    client_ca_cert_path: Option<Box<str>>,
+    cert_chain: Option<Arc<[CertificateDer<'static>]>>,
+    key: Option<Arc<PrivateKeyDer<'static>>>,
+    store_identity: Option<Arc<dyn StoreIdentity>>,
+    store_client_cas: Option<Arc<dyn StoreCas>>,
+    client_auth: ClientAuth,
+    strict_client_cas: bool,
+    #[cfg(feature = "native-roots")]
+    native_client_roots: bool,
+    #[cfg(feature = "openssl")]
+    openssl_backend: bool,
+    missing_client_cert: MissingClientCert,
+    diagnostics_host: Option<Box<str>>,
     protocols: Option<Box<[Protocol]>>,
+    alpn_mismatch: AlpnMismatch,
+    tls_versions: (TlsVersion, TlsVersion),
+    handshake_timeout: Option<Duration>,
+    first_request_timeout: Option<Duration>,
+    conn_timeouts: ConnTimeouts,
+    handshake_offload: Option<OffloadConfig>,
+    max_fragment_size: Option<usize>,
+    tls_buffer_limit: Option<usize>,
+    connection_limit: Option<Arc<Semaphore>>,
+    max_callback_failures: Option<u32>,
+    connection_id_header: Option<HeaderName>,
+    load_shed: Option<Arc<dyn LoadShedPolicy>>,
+    client_quota: Option<Arc<ClientQuota>>,
+    identity_mapper: Option<Arc<IdentityMapper>>,
+    usage_sink: Option<Arc<dyn UsageSink>>,
+    identity_store: Option<Arc<dyn IdentityStore>>,
+    on_connection_open: Option<Arc<OpenHook>>,
+    on_connection_close: Option<Arc<CloseHook>>,
+    #[cfg(feature = "tokio")]
+    ocsp: Option<OcspConfig>,
+    #[cfg(feature = "tokio")]
+    revocation_check: Option<RevocationCheck>,
+    #[cfg(feature = "tokio")]
+    passthrough: Option<Arc<Passthrough>>,
+    allowed_sni: Option<Arc<SniAllowlist>>,
+    missing_sni: Option<MissingSni>,
+    #[cfg(feature = "tokio")]
+    missing_sni_fallback: Option<Arc<SniFallback>>,
+    crypto_provider: Option<Arc<CryptoProvider>>,
+    #[cfg(feature = "dangerous-key-log")]
+    key_log: Option<Arc<dyn rustls::KeyLog>>,
+    time_provider: Option<Arc<dyn TimeProvider>>,
+    rotation_window: Duration,
+    accept_workers: Option<WorkerConfig>,
+    http2: Http2Config,
+    http_limits: HttpLimits,
+    material: Option<Arc<Material>>,
+    handle: ServerHandle,
 }
 
 impl MtlServer {
@@ -75,14 +611,26 @@ impl MtlServer {
         server_key_path: Box<str>,
         client_ca_cert_path: Box<str>,
     ) -> Self {
-        let protocols =
-            Some(vec![Protocol::HTTP_1, Protocol::HTTP_2].into_boxed_slice());
-        Self {
+        Self::new_with_protocols(
             server_cert_path,
             server_key_path,
             client_ca_cert_path,
-            protocols,
-        }
+            Protocol::defaults(),
+        )
+    }
+
+    /// Creates a server for ordinary TLS, without client authentication.
+    pub fn new_without_client_auth(
+        server_cert_path: Box<str>,
+        server_key_path: Box<str>,
+    ) -> Self {
+        Self::from_parts(
+            server_cert_path,
+            server_key_path,
+            None,
+            ClientAuth::Disabled,
+            Some(Protocol::defaults()),
+        )
     }
 
     pub fn new_with_protocols(
@@ -91,12 +639,618 @@ impl MtlServer {
         client_ca_cert_path: Box<str>,
         protocols: Box<[Protocol]>,
     ) -> Self {
-        let protocols = Some(protocols);
+        Self::from_parts(
+            server_cert_path,
+            server_key_path,
+            Some(client_ca_cert_path),
+            ClientAuth::Required,
+            Some(protocols),
+        )
+    }
+
+    fn from_parts(
+        server_cert_path: Box<str>,
+        server_key_path: Box<str>,
+        client_ca_cert_path: Option<Box<str>>,
+        client_auth: ClientAuth,
+        protocols: Option<Box<[Protocol]>>,
+    ) -> Self {
         Self {
             server_cert_path,
             server_key_path,
             client_ca_cert_path,
+            cert_chain: None,
+            key: None,
+            store_identity: None,
+            store_client_cas: None,
+            client_auth,
+            strict_client_cas: false,
+            #[cfg(feature = "native-roots")]
+            native_client_roots: false,
+            #[cfg(feature = "openssl")]
+            openssl_backend: false,
+            missing_client_cert: MissingClientCert::FailHandshake,
+            diagnostics_host: None,
             protocols,
+            alpn_mismatch: AlpnMismatch::Reject,
+            tls_versions: (TlsVersion::Tls12, TlsVersion::Tls13),
+            handshake_timeout: None,
+            first_request_timeout: None,
+            conn_timeouts: ConnTimeouts::default(),
+            handshake_offload: None,
+            max_fragment_size: None,
+            tls_buffer_limit: None,
+            connection_limit: None,
+            max_callback_failures: None,
+            connection_id_header: None,
+            load_shed: None,
+            client_quota: None,
+            identity_mapper: None,
+            usage_sink: None,
+            identity_store: None,
+            on_connection_open: None,
+            on_connection_close: None,
+            #[cfg(feature = "tokio")]
+            ocsp: None,
+            #[cfg(feature = "tokio")]
+            revocation_check: None,
+            #[cfg(feature = "tokio")]
+            passthrough: None,
+            allowed_sni: None,
+            missing_sni: None,
+            #[cfg(feature = "tokio")]
+            missing_sni_fallback: None,
+            crypto_provider: None,
+            #[cfg(feature = "dangerous-key-log")]
+            key_log: None,
+            time_provider: None,
+            rotation_window: Duration::from_secs(30),
+            accept_workers: None,
+            http2: Http2Config::default(),
+            http_limits: HttpLimits::default(),
+            material: None,
+            handle: ServerHandle::new(),
+        }
+    }
+
+    /// Serves `chain`, leaf first, instead of the certificate file, for
+    /// callers that already hold parsed certificates. Reloads keep serving
+    /// it.
+    pub fn with_cert_chain(
+        mut self,
+        chain: Vec<CertificateDer<'static>>,
+    ) -> Self {
+        self.cert_chain = Some(chain.into());
+        self
+    }
+
+    /// Uses `key` instead of the private key file, see
+    /// [`with_cert_chain`](Self::with_cert_chain).
+    pub fn with_key(mut self, key: PrivateKeyDer<'static>) -> Self {
+        self.key = Some(Arc::new(key));
+        self
+    }
+
+    pub fn with_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = client_auth;
+        self
+    }
+
+    /// Fails loading the client CA bundle if it contains expired or
+    /// unparsable certificates. By default they are left out with a
+    /// warning and counted in
+    /// [`MetricsSnapshot::client_cas_skipped`].
+    pub fn with_strict_client_cas(mut self) -> Self {
+        self.strict_client_cas = true;
+        self
+    }
+
+    /// Also trusts client certificates issued by the CAs in the operating
+    /// system's trust store. Publicly trusted CAs issue certificates to
+    /// anyone, so pair this with an identity mapper that checks the names.
+    /// The client CA file becomes optional, and the trusted CAs are no
+    /// longer listed in the certificate request.
+    #[cfg(feature = "native-roots")]
+    pub fn with_native_client_roots(mut self) -> Self {
+        self.native_client_roots = true;
+        self
+    }
+
+    /// Terminates TLS with OpenSSL instead of rustls in `serve_service` and
+    /// friends, [`MtlsAcceptor`] and the testing helpers. Settings OpenSSL
+    /// can't honor, like a diagnostics host, ALPN fallback, a max fragment
+    /// size or a time provider, fail building the server with
+    /// [`Error::BackendUnsupportedError`].
+    #[cfg(feature = "openssl")]
+    pub fn with_openssl_backend(mut self) -> Self {
+        self.openssl_backend = true;
+        self
+    }
+
+    /// Chooses how `serve_service` and friends treat clients without a
+    /// certificate when client authentication is required. Connections
+    /// handed to a `serve` callback or returned by
+    /// [`MtlServer::mtls_acceptor`] always fail the handshake.
+    pub fn with_missing_client_cert(
+        mut self,
+        behavior: MissingClientCert,
+    ) -> Self {
+        self.missing_client_cert = behavior;
+        self
+    }
+
+    /// Serves connection diagnostics to clients connecting with the server
+    /// name `host`, e.g. `diagnostics.example.com`. Their certificate is
+    /// not verified during the handshake; every request is answered with a
+    /// JSON report on the certificate the client presented, why it would be
+    /// rejected and which CAs are accepted. The server certificate has to be
+    /// valid for `host`. Applies to `serve_service` and friends.
+    pub fn with_diagnostics_host(mut self, host: Box<str>) -> Self {
+        self.diagnostics_host = Some(host);
+        self
+    }
+
+    /// Chooses what happens when a client offers only ALPN protocols the
+    /// server doesn't enable. Applies to `serve_service` and friends and
+    /// [`MtlServer::mtls_acceptor`]; the acceptor handed to a `serve`
+    /// callback always rejects.
+    pub fn with_alpn_mismatch(mut self, behavior: AlpnMismatch) -> Self {
+        self.alpn_mismatch = behavior;
+        self
+    }
+
+    /// Uses `provider` for the handshake and certificate verification
+    /// instead of the process default installed with
+    /// [`CryptoProvider::install_default`]. Without either, rustls'
+    /// aws-lc-rs provider is used.
+    pub fn with_crypto_provider(
+        mut self,
+        provider: Arc<CryptoProvider>,
+    ) -> Self {
+        self.crypto_provider = Some(provider);
+        self
+    }
+
+    /// Hands the secrets of every TLS session to `key_log`, e.g. an
+    /// in-memory buffer in tests or a secured debugging service, so traffic
+    /// can be decrypted. [`rustls::KeyLogFile`] writes them to the file
+    /// named by `SSLKEYLOGFILE`. Anyone with the secrets can read and
+    /// forge the traffic; never enable this in production.
+    #[cfg(feature = "dangerous-key-log")]
+    pub fn with_dangerous_key_log(
+        mut self,
+        key_log: Arc<dyn rustls::KeyLog>,
+    ) -> Self {
+        self.key_log = Some(key_log);
+        self
+    }
+
+    /// Checks the validity of client certificates against `time_provider`
+    /// instead of the system clock during handshakes, e.g. a
+    /// [`FixedClock`](testing::fixtures::FixedClock) for deterministic
+    /// tests. Not supported by the OpenSSL backend.
+    pub fn with_time_provider(
+        mut self,
+        time_provider: Arc<dyn TimeProvider>,
+    ) -> Self {
+        self.time_provider = Some(time_provider);
+        self
+    }
+
+    fn provider(&self) -> Arc<CryptoProvider> {
+        self.crypto_provider.clone().unwrap_or_else(crypto_provider)
+    }
+
+    /// Restricts the negotiated TLS version to `min..=max`. Both TLS 1.2 and
+    /// TLS 1.3 are enabled by default.
+    pub fn with_tls_versions(
+        mut self,
+        min: TlsVersion,
+        max: TlsVersion,
+    ) -> Self {
+        self.tls_versions = (min, max);
+        self
+    }
+
+    /// Counts handshake failures within `window` of a change of the client
+    /// CAs separately, in
+    /// [`MetricsSnapshot::handshake_failures_after_rotation`], and logs them
+    /// with `after_rotation = true`, so rotation blips can be told apart
+    /// from attacks. 30 seconds by default.
+    pub fn with_rotation_window(mut self, window: Duration) -> Self {
+        self.rotation_window = window;
+        self
+    }
+
+    /// How long [`ServerHandle::schedule_reload`] waits for further calls
+    /// before reloading, 500 milliseconds by default.
+    pub fn with_reload_debounce(self, debounce: Duration) -> Self {
+        *self.handle.reloads.debounce.lock().unwrap() = debounce;
+        self
+    }
+
+    /// Tunes the server for short-lived certificates, e.g. issued by SPIFFE
+    /// or Vault for hours: reloads once `reload_at` of the lifetime of the
+    /// server certificate passed, e.g. `2.0 / 3.0`, retrying until a renewed
+    /// certificate is found, and refuses connections while it is expired
+    /// rather than failing their handshakes. The remaining validity is
+    /// reported in [`MetricsSnapshot::server_cert_remaining_secs`]. A
+    /// `reload_at` outside `(0, 1]` fails building the server with
+    /// [`Error::ReloadPointError`].
+    pub fn with_short_lived_certs(self, reload_at: f64) -> Self {
+        self.handle.renewal.configure(reload_at);
+        self
+    }
+
+    /// Limits how long the TLS handshake may take when the server performs
+    /// it, i.e. for `serve_service` and friends.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections that haven't sent any application data within
+    /// `timeout` of being accepted, e.g. clients that complete the handshake
+    /// but never send a request. Applies to `serve_service` and friends.
+    pub fn with_first_request_timeout(mut self, timeout: Duration) -> Self {
+        self.first_request_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections that neither read nor wrote for `timeout`. Like
+    /// the other connection timeouts, it closes gracefully: HTTP/2 clients
+    /// get a GOAWAY and HTTP/1 connections close after the response in
+    /// flight, which gets the grace set with
+    /// [`with_close_grace`](Self::with_close_grace) to finish. Applies to
+    /// `serve_service` and friends.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.conn_timeouts.idle = Some(timeout);
+        self
+    }
+
+    /// Closes connections gracefully once they were open for `max_age`,
+    /// e.g. so clients reconnect to rebalanced backends or pick up rotated
+    /// certificates.
+    pub fn with_max_connection_age(mut self, max_age: Duration) -> Self {
+        self.conn_timeouts.max_age = Some(max_age);
+        self
+    }
+
+    /// Closes connections whose reads made no progress for `timeout`: the
+    /// client stopped sending a request body, or, as reads are pending
+    /// between requests too, sent no further request. Independent of
+    /// [`with_write_timeout`](Self::with_write_timeout), so a long write
+    /// window for streamed responses doesn't keep idle readers around.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.conn_timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Closes connections whose writes made no progress for `timeout`,
+    /// i.e. clients that stopped reading the response.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.conn_timeouts.write = Some(timeout);
+        self
+    }
+
+    /// How long responses in flight may take to finish once the idle
+    /// timeout or maximum age closed their connection, 10 seconds by
+    /// default. The connection is dropped after it, cutting responses
+    /// still streaming.
+    pub fn with_close_grace(mut self, grace: Duration) -> Self {
+        self.conn_timeouts.grace = grace;
+        self
+    }
+
+    /// Runs TLS handshakes on a dedicated pool of `threads` worker threads,
+    /// so handshake crypto doesn't starve the runtime serving requests. At
+    /// most `max_pending` handshakes are queued on the pool; further
+    /// connections wait for a free slot.
+    pub fn with_handshake_offload(
+        mut self,
+        threads: usize,
+        max_pending: usize,
+    ) -> Self {
+        self.handshake_offload = Some(OffloadConfig {
+            threads,
+            max_pending,
+        });
+        self
+    }
+
+    /// Limits the plaintext carried by a single TLS record to `size` bytes,
+    /// including the 5 byte record header. rustls accepts sizes from 32 to
+    /// 16389; other sizes fail when the TLS configuration is built.
+    pub fn with_max_fragment_size(mut self, size: usize) -> Self {
+        self.max_fragment_size = Some(size);
+        self
+    }
+
+    /// Limits the data rustls buffers for sending on a connection to `limit`
+    /// bytes, 64 KiB by default. Writes beyond the limit wait for the peer
+    /// to read. Applies to `serve_service` and friends.
+    pub fn with_tls_buffer_limit(mut self, limit: usize) -> Self {
+        self.tls_buffer_limit = Some(limit);
+        self
+    }
+
+    /// Tunes the HTTP/2 settings of connections served by `serve_service`
+    /// and friends, e.g. for gRPC workloads.
+    pub fn with_http2_config(mut self, config: Http2Config) -> Self {
+        self.http2 = config;
+        self
+    }
+
+    /// Replaces the default limits on request heads of connections served
+    /// by `serve_service` and friends.
+    pub fn with_http_limits(mut self, limits: HttpLimits) -> Self {
+        self.http_limits = limits;
+        self
+    }
+
+    /// Limits the number of connections served at the same time. Once the
+    /// limit is reached, new connections are left in the listener backlog
+    /// until a connection closes. Connections handed to a `serve` callback
+    /// are not counted.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.connection_limit = Some(Arc::new(Semaphore::new(max_connections)));
+        self
+    }
+
+    /// Pauses accepting connections on all TLS listeners when too many
+    /// connections failed recently, see [`CircuitBreaker`] and
+    /// [`ServerHandle::is_circuit_open`].
+    pub fn with_circuit_breaker(self, config: CircuitBreaker) -> Self {
+        self.handle.breaker.configure(config);
+        self
+    }
+
+    /// Sends [`CertEvent::CertExpiringSoon`] once the server certificate
+    /// expires within `window`, 30 days by default.
+    pub fn with_expiry_warning(self, window: Duration) -> Self {
+        self.handle.events.configure(window);
+        self
+    }
+
+    /// Sets which events are logged at what level, see [`LogPolicy`].
+    pub fn with_log_policy(self, policy: LogPolicy) -> Self {
+        self.handle.logs.configure(policy);
+        self
+    }
+
+    /// Temporarily bans client addresses whose handshakes keep failing
+    /// certificate verification on all TLS listeners, see [`BanPolicy`] and
+    /// [`ServerHandle::banned_addrs`].
+    pub fn with_ban_policy(self, policy: BanPolicy) -> Self {
+        self.handle.bans.configure(policy);
+        self
+    }
+
+    /// Keeps the identities parsed from up to `capacity` recently seen client
+    /// certificates, and the principals they were mapped to, 256 by default,
+    /// so clients reconnecting frequently don't have their certificate parsed
+    /// and mapped on every connection. The hit rate is
+    /// reported in the [`MetricsSnapshot`]. 0 disables the cache.
+    pub fn with_identity_cache(self, capacity: usize) -> Self {
+        self.handle.identity_cache.configure(capacity);
+        self
+    }
+
+    /// Stops [`MtlServer::serve`] with [`Error::CallbackError`] once its
+    /// callback returned an error `max` times in a row.
+    pub fn with_max_callback_failures(mut self, max: u32) -> Self {
+        self.max_callback_failures = Some(max.max(1));
+        self
+    }
+
+    /// Hands accepted connections to `workers` long-lived tasks instead of
+    /// spawning a task per connection. At most `queue_depth` connections
+    /// wait for a worker; once the queue is full, further connections are
+    /// left in the listener backlog.
+    pub fn with_accept_workers(
+        mut self,
+        workers: usize,
+        queue_depth: usize,
+    ) -> Self {
+        self.accept_workers = Some(WorkerConfig {
+            workers,
+            queue_depth,
+        });
+        self
+    }
+
+    /// Adds the connection id to every request as the `name` header, e.g.
+    /// `x-connection-id`, so application logs can be correlated with the
+    /// crate's connection level events. The id is always available as a
+    /// [`ConnectionId`] request extension.
+    pub fn with_connection_id_header(mut self, name: HeaderName) -> Self {
+        self.connection_id_header = Some(name);
+        self
+    }
+
+    /// Consults `policy` for every connection accepted by `serve_service`
+    /// and friends. Shed connections are closed before the TLS handshake and
+    /// counted in [`MetricsSnapshot::connections_shed`].
+    pub fn with_load_shedding<P: LoadShedPolicy>(mut self, policy: P) -> Self {
+        self.load_shed = Some(Arc::new(policy));
+        self
+    }
+
+    /// Limits the connections a single client certificate may hold open at
+    /// the same time to `max`, so one client cannot use up the whole
+    /// [`MtlServer::with_max_connections`] budget. Connections over the quota
+    /// are closed after the handshake. Clients without a certificate are
+    /// not limited.
+    pub fn with_max_connections_per_client(
+        mut self,
+        max: usize,
+        key: QuotaKey,
+    ) -> Self {
+        self.client_quota = Some(Arc::new(ClientQuota::new(max, key)));
+        self
+    }
+
+    /// Maps the client certificate of every connection to an application
+    /// principal. Principals are kept in the identity cache, see
+    /// [`MtlServer::with_identity_cache`], so the mapper runs once per
+    /// certificate rather than per connection. The principal is added as an
+    /// extension to all requests on the connection; a [`Rejection`] closes
+    /// the connection and is counted in
+    /// [`MetricsSnapshot::connections_rejected`]. Connections without a
+    /// client certificate are not mapped.
+    pub fn with_identity_mapper<P, F>(mut self, mapper: F) -> Self
+    where
+        F: Fn(&ClientIdentity) -> Result<P, Rejection> + Send + Sync + 'static,
+        P: Clone + Send + Sync + 'static,
+    {
+        self.identity_mapper = Some(Arc::new(move |identity| {
+            mapper(identity).map(MappedPrincipal::new)
+        }));
+        self
+    }
+
+    /// Keeps connection and request counters per client identity, see
+    /// [`ServerHandle::identity_metrics`]. At most `max_identities` labels
+    /// are tracked; further clients are counted under
+    /// [`OVERFLOW_IDENTITY_LABEL`].
+    pub fn with_identity_metrics(
+        self,
+        label: IdentityLabel,
+        max_identities: usize,
+    ) -> Self {
+        self.handle
+            .metrics
+            .enable_identity_labels(label, max_identities);
+        self
+    }
+
+    /// Reports the bytes and requests of every connection with a client
+    /// certificate to `sink` when it closes, see [`Usage`]. Applies to
+    /// `serve_service` and friends.
+    pub fn with_usage_sink<U: UsageSink>(mut self, sink: U) -> Self {
+        self.usage_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Remembers the certificate every client identity, its SPIFFE ID or
+    /// else its subject, presented last in `store`, and sends
+    /// [`CertEvent::ClientCertChanged`] when it presents another one. A
+    /// certificate from another CA, or older than the previous one, is
+    /// [`CertChange::Suspicious`], logged as a warning and counted in
+    /// [`MetricsSnapshot::suspicious_cert_changes`]. Applies to
+    /// `serve_service` and friends.
+    pub fn with_identity_tracking<S: IdentityStore>(
+        mut self,
+        store: S,
+    ) -> Self {
+        self.identity_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Awaits `hook` with the [`ConnInfo`] of every connection once its
+    /// handshake completed, before the connection policies apply and its
+    /// first request is read, e.g. to register the session. The connection
+    /// is not served until the hook returns. Applies to `serve_service` and
+    /// friends.
+    pub fn with_on_connection_open<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(ConnInfo) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_connection_open =
+            Some(Arc::new(move |conn_info| hook(conn_info).boxed()));
+        self
+    }
+
+    /// Spawns `hook` with the [`ConnInfo`] and the [`CloseReason`] of every
+    /// connection whose handshake completed once it ended, including
+    /// connections dropped by the shutdown deadline, e.g. to deregister the
+    /// session or for audit records. Applies to `serve_service` and friends.
+    pub fn with_on_connection_close<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(ConnInfo, CloseReason) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_connection_close = Some(Arc::new(move |conn_info, reason| {
+            hook(conn_info, reason).boxed()
+        }));
+        self
+    }
+
+    /// Checks the revocation status of client certificates with OCSP after
+    /// the handshake, before the first request is read. Revoked
+    /// certificates are rejected and counted in
+    /// [`MetricsSnapshot::connections_revoked`].
+    #[cfg(feature = "tokio")]
+    pub fn with_ocsp(mut self, config: OcspConfig) -> Self {
+        self.ocsp = Some(config);
+        self
+    }
+
+    /// Asks the [`RevocationChecker`] of `check` whether the client
+    /// certificate was revoked after the handshake, before the first
+    /// request is read, e.g. to enforce revocations kept in a database in
+    /// real time. Runs after OCSP, if both are configured. Revoked
+    /// certificates are rejected and counted in
+    /// [`MetricsSnapshot::connections_revoked`].
+    #[cfg(feature = "tokio")]
+    pub fn with_revocation_check(mut self, check: RevocationCheck) -> Self {
+        self.revocation_check = Some(check);
+        self
+    }
+
+    /// Forwards connections for `server_name` to `backend`, e.g.
+    /// `"10.0.0.5:443"`, without terminating TLS, so the backend handles
+    /// the handshake itself. The server name is read from the ClientHello;
+    /// connections for other names are served locally. Applies to
+    /// `serve_service` and friends.
+    #[cfg(feature = "tokio")]
+    pub fn with_sni_passthrough(
+        mut self,
+        server_name: Box<str>,
+        backend: Box<str>,
+    ) -> Self {
+        let passthrough = self.passthrough.get_or_insert_with(Arc::default);
+        Arc::make_mut(passthrough).insert(&server_name, backend);
+        self
+    }
+
+    /// Only completes handshakes whose SNI matches one of `names`, e.g.
+    /// `["api.example.com", "*.internal.example"]`, where `*` stands for a
+    /// single label. Other connections, including those without SNI unless
+    /// [`MtlServer::with_missing_sni`] says otherwise, are closed after the
+    /// ClientHello, before any certificate is sent. The diagnostics host is
+    /// always allowed. Applies to `serve_service` and
+    /// friends and [`MtlServer::mtls_acceptor`].
+    pub fn with_allowed_sni<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Box<str>>,
+    {
+        let allowed = self.allowed_sni.get_or_insert_with(Arc::default);
+        let allowed = Arc::make_mut(allowed);
+        for name in names {
+            allowed.insert(name.into());
+        }
+        self
+    }
+
+    /// Chooses how clients without SNI are treated, overriding the default
+    /// that depends on [`MtlServer::with_allowed_sni`]. Applies to
+    /// `serve_service` and friends and [`MtlServer::mtls_acceptor`].
+    /// [`MissingSni::Fallback`] is set by `with_missing_sni_fallback`, which
+    /// gives it the service; without one, serving fails with
+    /// [`Error::MissingSniFallbackError`].
+    pub fn with_missing_sni(mut self, policy: MissingSni) -> Self {
+        self.missing_sni = Some(policy);
+        self
+    }
+
+    pub(crate) fn missing_sni(&self) -> MissingSni {
+        match (self.missing_sni, &self.allowed_sni) {
+            (Some(policy), _) => policy,
+            (None, Some(_)) => MissingSni::Reject,
+            (None, None) => MissingSni::Serve,
         }
     }
 
@@ -122,18 +1276,57 @@ impl MtlServer {
     }
 
     fn load_server_cert(&self) -> Result<Vec<CertificateDer<'static>>, Error> {
-        Self::load_cert(&self.server_cert_path)
+        match (&self.material, &self.cert_chain) {
+            (Some(material), _) => Ok(material.server_chain.clone()),
+            (None, Some(chain)) => Ok(pki::order_chain(chain.to_vec())),
+            (None, None) => match &self.store_identity {
+                Some(store) => store.chain(),
+                None => Self::load_cert(&self.server_cert_path)
+                    .map(pki::order_chain),
+            },
+        }
+    }
+
+    /// Whether client CAs come from a file or a store, rather than only
+    /// from the native trust store.
+    fn has_client_cas(&self) -> bool {
+        self.client_ca_cert_path.is_some() || self.store_client_cas.is_some()
     }
 
     fn load_client_ca_cert(
         &self,
     ) -> Result<Vec<CertificateDer<'static>>, Error> {
-        Self::load_cert(&self.client_ca_cert_path)
+        if !self.has_client_cas() {
+            return Err(ClientCaCertMissingError);
+        }
+        if let Some(material) = &self.material {
+            return Ok(material.client_cas.clone());
+        }
+        let mut certs = match &self.client_ca_cert_path {
+            Some(path) => Self::load_cert(path)?,
+            None => Vec::new(),
+        };
+        if let Some(store) = &self.store_client_cas {
+            certs.extend(store.certificates()?);
+        }
+        self.usable_client_cas(certs)
     }
 
-    fn load_server_key(&self) -> Result<PrivateKeyDer<'static>, Error> {
-        let key_file = File::open(self.server_key_path.deref())
-            .map_err(PrivateKeyFileReadError)?;
+    fn load_server_key(&self) -> Result<ServerKey, Error> {
+        match (&self.material, &self.key) {
+            (Some(material), _) => Ok(material.server_key.clone_key()),
+            (None, Some(key)) => Ok(ServerKey::Der(key.clone_key())),
+            (None, None) => match &self.store_identity {
+                Some(store) => store.key().map(ServerKey::Store),
+                None => {
+                    Self::load_key(&self.server_key_path).map(ServerKey::Der)
+                }
+            },
+        }
+    }
+
+    fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+        let key_file = File::open(path).map_err(PrivateKeyFileReadError)?;
         let mut reader = BufReader::new(key_file);
 
         let item = rustls_pemfile::private_key(&mut reader)
@@ -143,55 +1336,586 @@ impl MtlServer {
         Ok(item)
     }
 
-    fn create_tls_config(&self) -> Result<ServerConfig, Error> {
+    fn create_client_verifier(
+        &self,
+        client_auth: ClientAuth,
+    ) -> Result<Arc<dyn ClientCertVerifier>, Error> {
         let mut roots = RootCertStore::empty();
 
-        let client_ca_certs = self.load_client_ca_cert()?;
-        for cert in client_ca_certs {
-            roots.add(cert).map_err(TrustStoreError)?;
+        #[cfg(feature = "native-roots")]
+        let native_roots = self.native_client_roots;
+        #[cfg(not(feature = "native-roots"))]
+        let native_roots = false;
+
+        let mut anchors = Vec::new();
+        #[cfg(feature = "native-roots")]
+        if native_roots {
+            anchors = Self::add_native_roots(&mut roots)?;
         }
+        if !native_roots || self.has_client_cas() {
+            let client_ca_certs = self.load_client_ca_cert()?;
+            for cert in client_ca_certs {
+                roots.add(cert.clone()).map_err(TrustStoreError)?;
+                anchors.push(cert);
+            }
+        }
+        self.handle.set_trust_anchors(&anchors);
+
+        let mut builder = WebPkiClientVerifier::builder_with_provider(
+            roots.into(),
+            self.provider(),
+        );
+        if native_roots {
+            builder = builder.clear_root_hint_subjects();
+        }
+        if client_auth == ClientAuth::Optional {
+            builder = builder.allow_unauthenticated();
+        }
+        let client_verifier =
+            builder.build().map_err(ClientVerifierBuildError)?;
+
+        Ok(client_verifier)
+    }
+
+    #[cfg(feature = "native-roots")]
+    fn add_native_roots(
+        roots: &mut RootCertStore,
+    ) -> Result<Vec<CertificateDer<'static>>, Error> {
+        let native = rustls_native_certs::load_native_certs();
+        for err in &native.errors {
+            warn!("failed loading native root certificates: {}", err);
+        }
+        let (added, ignored) =
+            roots.add_parsable_certificates(native.certs.iter().cloned());
+        if added == 0 {
+            return Err(NativeRootsEmptyError);
+        }
+        debug!(
+            "trusting {} native root certificates, ignored {}",
+            added, ignored
+        );
+        Ok(native.certs)
+    }
+
+    fn create_tls_config(
+        &self,
+        client_auth: ClientAuth,
+    ) -> Result<ServerConfig, Error> {
+        let verifier = match client_auth {
+            ClientAuth::Disabled => {
+                self.handle.set_trust_anchors(&[]);
+                None
+            }
+            ClientAuth::Required | ClientAuth::Optional => {
+                Some(self.create_client_verifier(client_auth)?)
+            }
+        };
+        self.create_tls_config_with(verifier)
+    }
+
+    fn check_tls_versions(&self) -> Result<(), Error> {
+        let (min, max) = self.tls_versions;
+        if min > max {
+            return Err(TlsVersionBoundsError(min, max));
+        }
+        Ok(())
+    }
+
+    fn check_max_fragment_size(&self) -> Result<(), Error> {
+        match self.max_fragment_size {
+            Some(size)
+                if !(MIN_FRAGMENT_SIZE..=MAX_FRAGMENT_SIZE).contains(&size) =>
+            {
+                Err(MaxFragmentSizeError(size))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn enabled_tls_versions(&self) -> Vec<TlsVersion> {
+        let (min, max) = self.tls_versions;
+        [TlsVersion::Tls12, TlsVersion::Tls13]
+            .into_iter()
+            .filter(|x| (min..=max).contains(x))
+            .collect()
+    }
+
+    fn create_tls_config_with(
+        &self,
+        verifier: Option<Arc<dyn ClientCertVerifier>>,
+    ) -> Result<ServerConfig, Error> {
+        self.check_tls_versions()?;
+        let versions: Vec<&'static SupportedProtocolVersion> = self
+            .enabled_tls_versions()
+            .into_iter()
+            .map(TlsVersion::rustls_version)
+            .collect();
+        self.check_max_fragment_size()?;
+        self.handle.renewal.check()?;
+
+        let builder = match &self.time_provider {
+            Some(time_provider) => ServerConfig::builder_with_details(
+                self.provider(),
+                time_provider.clone(),
+            ),
+            None => ServerConfig::builder_with_provider(self.provider()),
+        };
+        let builder = builder
+            .with_protocol_versions(&versions)
+            .map_err(CryptoProviderError)?;
+        let builder = match verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        };
 
-        let client_verifier = WebPkiClientVerifier::builder(roots.into())
-            .build()
-            .map_err(ClientVerifierBuildError)?;
         let server_cert = self.load_server_cert()?;
         let server_key = self.load_server_key()?;
+        self.handle.set_server_chain(&server_cert);
+        if let Some(leaf) = server_cert.first() {
+            self.check_key_type(leaf, &server_key)?;
+        }
 
-        let mut config = ServerConfig::builder()
-            .with_client_cert_verifier(client_verifier)
-            .with_single_cert(server_cert, server_key)
-            .map_err(ServerConfigError)?;
+        let mut config = match server_key {
+            ServerKey::Der(key) => builder
+                .with_single_cert(server_cert, key)
+                .map_err(ServerConfigError)?,
+            ServerKey::Store(key) => {
+                let key = CertifiedKey::new(server_cert, key);
+                builder.with_cert_resolver(Arc::new(SingleCert(Arc::new(key))))
+            }
+        };
 
         if let Some(protocols) = &self.protocols {
             let protocols: Vec<Vec<u8>> =
-                protocols.iter().map(|x| x.0.as_bytes().to_vec()).collect();
+                protocols.iter().map(|x| x.as_bytes().to_vec()).collect();
             config.alpn_protocols = protocols;
         }
+        config.max_fragment_size = self.max_fragment_size;
+        #[cfg(feature = "dangerous-key-log")]
+        if let Some(key_log) = &self.key_log {
+            warn!("logging TLS secrets, sessions can be decrypted");
+            config.key_log = key_log.clone();
+        }
 
         Ok(config)
     }
 
-    pub async fn serve<F>(
+    /// Loads the certificates and builds an acceptor for use with a custom
+    /// accept loop. It keeps its configuration; see
+    /// [`MtlServer::reloadable_acceptor`] for one that follows reloads.
+    #[cfg(feature = "tokio")]
+    pub fn tls_acceptor(&self) -> Result<TlsAcceptor, Error> {
+        let config = self.create_tls_config(self.client_auth)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Accepts TLS connections until shutdown. They are refused while the
+    /// server certificate is expired in short-lived mode.
+    #[cfg(feature = "tokio")]
+    async fn accept_loop<F, Fut>(
+        &self,
+        listener: &impl Accept,
+        mut on_accept: F,
+    ) -> Option<Duration>
+    where
+        F: FnMut(TcpStream, SocketAddr, Option<OwnedSemaphorePermit>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let shutdown = self.handle.shutdown_requested();
+        tokio::pin!(shutdown);
+
+        loop {
+            let permit = match &self.connection_limit {
+                Some(limit) => tokio::select! {
+                    timeout = &mut shutdown => return timeout,
+                    permit = limit.clone().acquire_owned() => permit.ok(),
+                },
+                None => None,
+            };
+
+            let (stream, addr) = tokio::select! {
+                timeout = &mut shutdown => return timeout,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        error!(
+                            "server listener accep error: {:?}",
+                            err
+                        );
+                        continue;
+                    }
+                },
+            };
+
+            if self.handle.bans.is_banned(addr.ip()) {
+                self.handle.metrics.connection_banned();
+                log_event!(
+                    self.handle.logs,
+                    LogEvent::Rejections,
+                    "closing connection from banned {}",
+                    addr
+                );
+                continue;
+            }
+            if self.handle.serving_expired_cert() {
+                self.handle.metrics.connection_refused_expired();
+                log_event!(
+                    self.handle.logs,
+                    LogEvent::Rejections,
+                    "closing connection from {}, the server certificate expired",
+                    addr
+                );
+                continue;
+            }
+
+            // Holds the connection while the circuit is open, leaving the
+            // next ones in the backlog.
+            if let Some(until) = self.handle.breaker.open_until() {
+                tokio::select! {
+                    timeout = &mut shutdown => return timeout,
+                    () = tokio::time::sleep_until(until.into()) => {}
+                }
+            }
+            let dispatch = on_accept(stream, addr, permit);
+
+            tokio::select! {
+                timeout = &mut shutdown => return timeout,
+                () = dispatch => {}
+            }
+        }
+    }
+
+    /// Hands every accepted connection to `callback` with the acceptor of
+    /// the current certificates. The callback may return a `Result`, see
+    /// [`CallbackResult`].
+    #[cfg(feature = "tokio")]
+    pub async fn serve<F, R>(
         &self,
         listener: TcpListener,
         callback: F,
     ) -> Result<(), Error>
     where
-        F: Fn(TcpStream, TlsAcceptor) + 'static,
+        F: Fn(TcpStream, TlsAcceptor) -> R + 'static,
+        R: CallbackResult,
     {
-        let config = self.create_tls_config()?;
-        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let acceptor = self.reloadable_acceptor()?;
+        let _registration = self.start_listening(&listener);
+        let mut failures = 0;
+        let (failed, stopped) = oneshot::channel();
+        let mut failed = Some(failed);
 
-        loop {
-            match listener.accept().await {
-                Ok((stream, _)) => {
-                    let acceptor = acceptor.clone();
-                    callback(stream, acceptor);
-                }
+        let accept_loop = self.accept_loop(&listener, |stream, _, _| {
+            match callback(stream, acceptor.current()).into_result() {
+                Ok(()) => failures = 0,
                 Err(err) => {
-                    tracing::error!("server listener accep error: {:?}", err);
+                    failures += 1;
+                    self.handle.metrics.callback_failed();
+                    warn!("serve callback failed: {}", err);
+                    if self.max_callback_failures == Some(failures) {
+                        if let Some(failed) = failed.take() {
+                            let _ = failed.send(err);
+                        }
+                    }
                 }
-            };
+            }
+            std::future::ready(())
+        });
+        tokio::select! {
+            _ = accept_loop => Ok(()),
+            Ok(source) = stopped => {
+                Err(Error::CallbackError { failures, source })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use rustls::ProtocolVersion;
+
+    fn server(fixtures: &FixtureDir, client_auth: ClientAuth) -> MtlServer {
+        fixtures
+            .server()
+            .with_client_auth(client_auth)
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+    }
+
+    #[tokio::test]
+    async fn required_client_auth_rejects_anonymous_clients() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = server(&fixtures, ClientAuth::Required)
+            .mtls_acceptor()
+            .unwrap();
+
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        assert!(connect_duplex(&acceptor, anonymous, "localhost")
+            .await
+            .is_err());
+        let alice = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+        assert!(!conn.conn_info.peer_certificates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn optional_client_auth_accepts_anonymous_clients() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = server(&fixtures, ClientAuth::Optional)
+            .mtls_acceptor()
+            .unwrap();
+
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        let conn = connect_duplex(&acceptor, anonymous, "localhost")
+            .await
+            .unwrap();
+        assert!(conn.conn_info.peer_certificates().is_empty());
+        let alice = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+        assert!(!conn.conn_info.peer_certificates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn disabled_client_auth_needs_no_client_ca() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = MtlServer::new_without_client_auth(
+            fixtures.file("server.crt"),
+            fixtures.file("server.key"),
+        )
+        .with_time_provider(Arc::new(FixedClock::fixture()))
+        .mtls_acceptor()
+        .unwrap();
+
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        let conn = connect_duplex(&acceptor, anonymous, "localhost")
+            .await
+            .unwrap();
+        assert!(conn.conn_info.peer_certificates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn negotiates_within_the_tls_version_bounds() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = server(&fixtures, ClientAuth::Required)
+            .with_tls_versions(TlsVersion::Tls12, TlsVersion::Tls12)
+            .mtls_acceptor()
+            .unwrap();
+
+        let alice = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+        assert_eq!(
+            conn.conn_info.protocol_version(),
+            Some(ProtocolVersion::TLSv1_2)
+        );
+
+        let inverted = server(&fixtures, ClientAuth::Required)
+            .with_tls_versions(TlsVersion::Tls13, TlsVersion::Tls12);
+        assert!(matches!(
+            inverted.mtls_acceptor(),
+            Err(TlsVersionBoundsError(TlsVersion::Tls13, TlsVersion::Tls12))
+        ));
+    }
+
+    #[test]
+    fn limits_the_tls_fragment_size() {
+        let fixtures = FixtureDir::new().unwrap();
+        let config = server(&fixtures, ClientAuth::Required)
+            .with_max_fragment_size(1024)
+            .create_tls_config(ClientAuth::Required)
+            .unwrap();
+        assert_eq!(config.max_fragment_size, Some(1024));
+
+        let server = server(&fixtures, ClientAuth::Required)
+            .with_max_fragment_size(MIN_FRAGMENT_SIZE - 1);
+        assert!(matches!(
+            server.mtls_acceptor(),
+            Err(MaxFragmentSizeError(31))
+        ));
+    }
+
+    #[test]
+    fn parses_alpn_protocols() {
+        assert_eq!("h2".parse::<Protocol>().unwrap(), Protocol::HTTP_2);
+        assert_eq!(Protocol::new("grpc-exp").unwrap(), Protocol::GRPC_EXP);
+        let custom: Protocol = "acme-tls/1".parse().unwrap();
+        assert_eq!(custom.to_string(), "acme-tls/1");
+        assert_eq!(custom.as_bytes(), b"acme-tls/1");
+        for name in [String::new(), "x".repeat(256)] {
+            assert!(matches!(
+                name.parse::<Protocol>(),
+                Err(ProtocolParseError(x)) if *x == name
+            ));
+        }
+        assert!(Protocol::new("x".repeat(255)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn serves_the_given_chain_and_key() {
+        let fixtures = FixtureDir::new().unwrap();
+        let chain = MtlServer::load_cert(&fixtures.file("server.crt")).unwrap();
+        let key = MtlServer::load_key(&fixtures.file("server.key")).unwrap();
+        let server = MtlServer::new(
+            "missing.crt".into(),
+            "missing.key".into(),
+            fixtures.file("ca.crt"),
+        )
+        .with_cert_chain(chain)
+        .with_key(key)
+        .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let alice = fixtures.client_config("alice").unwrap();
+        connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+
+        let ed25519 =
+            rcgen::KeyPair::generate_for(&rcgen::PKCS_ED25519).unwrap();
+        let key = PrivateKeyDer::try_from(ed25519.serialize_der()).unwrap();
+        let err = server.with_key(key).mtls_acceptor().err().unwrap();
+        assert!(matches!(
+            err,
+            Error::ServerKeyTypeMismatchError { key_file, cert_file, .. }
+                if &*key_file == "given to with_key"
+                    && &*cert_file == "given to with_cert_chain"
+        ));
+    }
+
+    #[test]
+    fn client_auth_needs_a_client_ca() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = MtlServer::new_without_client_auth(
+            fixtures.file("server.crt"),
+            fixtures.file("server.key"),
+        )
+        .with_client_auth(ClientAuth::Required);
+        assert!(matches!(
+            server.mtls_acceptor(),
+            Err(ClientCaCertMissingError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshakes_with_the_given_crypto_provider() {
+        let fixtures = FixtureDir::new().unwrap();
+        let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+        provider.cipher_suites.retain(|x| {
+            x.suite() == rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+        });
+        let acceptor = server(&fixtures, ClientAuth::Required)
+            .with_crypto_provider(Arc::new(provider.clone()))
+            .mtls_acceptor()
+            .unwrap();
+        let alice = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+        assert_eq!(
+            conn.conn_info.cipher_suite(),
+            Some(rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256)
+        );
+
+        provider.cipher_suites.clear();
+        let server = server(&fixtures, ClientAuth::Required)
+            .with_crypto_provider(Arc::new(provider));
+        assert!(matches!(
+            server.mtls_acceptor(),
+            Err(CryptoProviderError(_))
+        ));
+    }
+
+    #[cfg(feature = "dangerous-key-log")]
+    #[derive(Debug, Default)]
+    struct Secrets(std::sync::Mutex<Vec<String>>);
+
+    #[cfg(feature = "dangerous-key-log")]
+    impl rustls::KeyLog for Secrets {
+        fn log(&self, label: &str, _: &[u8], _: &[u8]) {
+            self.0.lock().unwrap().push(label.into());
         }
     }
+
+    #[cfg(feature = "dangerous-key-log")]
+    #[tokio::test]
+    async fn hands_the_session_secrets_to_the_key_log() {
+        let fixtures = FixtureDir::new().unwrap();
+        let secrets = Arc::new(Secrets::default());
+        let acceptor = server(&fixtures, ClientAuth::Required)
+            .with_dangerous_key_log(secrets.clone())
+            .mtls_acceptor()
+            .unwrap();
+        let alice = fixtures.client_config("alice").unwrap();
+        connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+        let labels = secrets.0.lock().unwrap();
+        assert!(labels.contains(&"CLIENT_TRAFFIC_SECRET_0".into()));
+        assert!(labels.contains(&"SERVER_TRAFFIC_SECRET_0".into()));
+    }
+
+    #[tokio::test]
+    async fn stops_serving_after_repeated_callback_failures() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = server(&fixtures, ClientAuth::Required)
+            .with_max_callback_failures(2);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connecting = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            for _ in 0..4 {
+                streams.push(TcpStream::connect(addr).await.unwrap());
+            }
+            streams
+        });
+
+        // Only failures in a row count towards the limit.
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counted = calls.clone();
+        let result = server
+            .serve(listener, move |_, _| {
+                counted.set(counted.get() + 1);
+                match counted.get() {
+                    2 => Ok(()),
+                    _ => Err("refused"),
+                }
+            })
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::CallbackError { failures: 2, ref source })
+                if source.to_string() == "refused"
+        ));
+        assert_eq!(calls.get(), 4);
+        assert_eq!(server.handle().metrics().callback_errors, 3);
+        connecting.await.unwrap();
+    }
+
+    // The environment is shared by the whole process, so every case runs in
+    // this one test.
+    #[cfg(feature = "native-roots")]
+    #[tokio::test]
+    async fn trusts_the_native_roots_for_client_certificates() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = MtlServer::new_without_client_auth(
+            fixtures.file("server.crt"),
+            fixtures.file("server.key"),
+        )
+        .with_client_auth(ClientAuth::Required)
+        .with_native_client_roots()
+        .with_time_provider(Arc::new(FixedClock::fixture()));
+        std::env::remove_var("SSL_CERT_DIR");
+
+        std::env::set_var(
+            "SSL_CERT_FILE",
+            fixtures.file("server.key").as_ref(),
+        );
+        assert!(matches!(server.mtls_acceptor(), Err(NativeRootsEmptyError)));
+
+        std::env::set_var("SSL_CERT_FILE", fixtures.file("ca.crt").as_ref());
+        let verifier =
+            server.create_client_verifier(ClientAuth::Required).unwrap();
+        assert!(verifier.root_hint_subjects().is_empty());
+        let acceptor = server.mtls_acceptor().unwrap();
+        std::env::remove_var("SSL_CERT_FILE");
+        let alice = fixtures.client_config("alice").unwrap();
+        connect_duplex(&acceptor, alice, "localhost").await.unwrap();
+    }
 }
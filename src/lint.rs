@@ -0,0 +1,203 @@
+use crate::{ClientAuth, Error, MtlServer, TlsVersion};
+use rustls_pki_types::CertificateDer;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::prelude::{FromDer, X509Certificate};
+use x509_parser::public_key::PublicKey;
+
+/// Client CAs expiring within this window are reported.
+const CA_EXPIRY_WARNING: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// RSA keys shorter than this are reported.
+const MIN_RSA_BITS: usize = 2048;
+
+/// sha1WithRSAEncryption, its obsolete OIW variant, ecdsa-with-SHA1 and
+/// dsa-with-sha1.
+const SHA1_SIGNATURES: [&str; 4] = [
+    "1.2.840.113549.1.1.5",
+    "1.3.14.3.2.29",
+    "1.2.840.10045.4.1",
+    "1.2.840.10040.4.3",
+];
+
+/// A weakness in the configuration of a server, found by
+/// [`MtlServer::lint`]. None of them stop the server from serving.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum LintWarning {
+    /// TLS 1.2 is enabled. Unless clients need it, restricting the server
+    /// to TLS 1.3 removes a whole class of downgrade and cipher suite
+    /// issues.
+    Tls12Enabled,
+    /// A certificate of the server chain or a client CA has an RSA key
+    /// shorter than 2048 bits.
+    WeakRsaKey { subject: Box<str>, bits: usize },
+    /// A certificate of the server chain is signed with SHA-1.
+    Sha1Signature { subject: Box<str> },
+    /// A client CA expires within 30 days, or already expired.
+    ClientCaExpiring {
+        subject: Box<str>,
+        /// Unix timestamp in seconds.
+        not_after: i64,
+    },
+    /// The server certificate has no subject alternative names, which
+    /// clients require to verify the host name.
+    MissingSubjectAltName { subject: Box<str> },
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintWarning::Tls12Enabled => {
+                f.write_str("TLS 1.2 is enabled, consider TLS 1.3 only")
+            }
+            LintWarning::WeakRsaKey { subject, bits } => {
+                write!(f, "{} has a {} bit RSA key", subject, bits)
+            }
+            LintWarning::Sha1Signature { subject } => {
+                write!(f, "{} is signed with SHA-1", subject)
+            }
+            LintWarning::ClientCaExpiring { subject, not_after } => {
+                write!(f, "client CA {} expires at {}", subject, not_after)
+            }
+            LintWarning::MissingSubjectAltName { subject } => {
+                write!(f, "server certificate {} has no SAN", subject)
+            }
+        }
+    }
+}
+
+fn rsa_bits(cert: &X509Certificate<'_>) -> Option<usize> {
+    match cert.public_key().parsed() {
+        Ok(PublicKey::RSA(key)) => Some(key.key_size()),
+        _ => None,
+    }
+}
+
+fn weak_key(cert: &X509Certificate<'_>) -> Option<LintWarning> {
+    let bits = rsa_bits(cert).filter(|x| *x < MIN_RSA_BITS)?;
+    Some(LintWarning::WeakRsaKey {
+        subject: cert.subject().to_string().into(),
+        bits,
+    })
+}
+
+fn parse<'a>(
+    certs: &'a [CertificateDer<'_>],
+) -> impl Iterator<Item = X509Certificate<'a>> {
+    certs
+        .iter()
+        .filter_map(|x| X509Certificate::from_der(x).ok())
+        .map(|x| x.1)
+}
+
+impl MtlServer {
+    /// Checks the configuration against best practices, e.g. in CI or at
+    /// startup. Fails only if the certificates can't be loaded; weaknesses
+    /// are returned as warnings.
+    pub fn lint(&self) -> Result<Vec<LintWarning>, Error> {
+        let mut warnings = Vec::new();
+        if self.enabled_tls_versions().contains(&TlsVersion::Tls12) {
+            warnings.push(LintWarning::Tls12Enabled);
+        }
+
+        let chain = self.load_server_cert()?;
+        for (i, cert) in parse(&chain).enumerate() {
+            let subject: Box<str> = cert.subject().to_string().into();
+            if i == 0 && !matches!(cert.subject_alternative_name(), Ok(Some(_)))
+            {
+                warnings.push(LintWarning::MissingSubjectAltName {
+                    subject: subject.clone(),
+                });
+            }
+            warnings.extend(weak_key(&cert));
+            // Self-signed certificates are trusted as is, their signature
+            // doesn't matter.
+            let algorithm = cert.signature_algorithm.algorithm.to_id_string();
+            if cert.subject() != cert.issuer()
+                && SHA1_SIGNATURES.contains(&algorithm.as_str())
+            {
+                warnings.push(LintWarning::Sha1Signature { subject });
+            }
+        }
+
+        if self.client_auth != ClientAuth::Disabled {
+            let horizon = SystemTime::now()
+                .checked_add(CA_EXPIRY_WARNING)
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map_or(i64::MAX, |x| x.as_secs() as i64);
+            let anchors = self.client_trust_anchors()?;
+            for cert in parse(&anchors) {
+                warnings.extend(weak_key(&cert));
+                let not_after = cert.validity().not_after.timestamp();
+                if not_after < horizon {
+                    warnings.push(LintWarning::ClientCaExpiring {
+                        subject: cert.subject().to_string().into(),
+                        not_after,
+                    });
+                }
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::FixtureDir;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use x509_parser::time::ASN1Time;
+
+    #[test]
+    fn reports_tls12() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        assert_eq!(server.lint().unwrap(), [LintWarning::Tls12Enabled]);
+        let server =
+            server.with_tls_versions(TlsVersion::Tls13, TlsVersion::Tls13);
+        assert_eq!(server.lint().unwrap(), []);
+    }
+
+    #[test]
+    fn reports_certificates_needing_attention() {
+        let fixtures = FixtureDir::new().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "expiring");
+        // Expired CAs are skipped when loading, so this one expires soon.
+        let not_after = ASN1Time::now().timestamp() + 10 * 24 * 60 * 60;
+        let expiring = ASN1Time::from_timestamp(not_after).unwrap();
+        params.not_after = expiring.to_datetime();
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "no-san");
+        let leaf = params
+            .signed_by(&KeyPair::generate().unwrap(), &ca, &ca_key)
+            .unwrap();
+        let path = fixtures.path();
+        std::fs::write(path.join("server.crt"), leaf.pem()).unwrap();
+        std::fs::write(path.join("ca.crt"), ca.pem()).unwrap();
+
+        let server = fixtures
+            .server()
+            .with_tls_versions(TlsVersion::Tls13, TlsVersion::Tls13);
+        assert_eq!(
+            server.lint().unwrap(),
+            [
+                LintWarning::MissingSubjectAltName {
+                    subject: "CN=no-san".into()
+                },
+                LintWarning::ClientCaExpiring {
+                    subject: "CN=expiring".into(),
+                    not_after,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{ClientAuth, MtlServer, Protocol};
+#[cfg(feature = "tokio")]
+use std::future::Future;
+#[cfg(feature = "tokio")]
+use std::io;
+#[cfg(feature = "tokio")]
+use std::net::SocketAddr;
+#[cfg(all(unix, feature = "tokio"))]
+use std::os::fd::{AsFd, BorrowedFd};
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+use tokio::net::{TcpListener, TcpStream};
+
+/// Settings a listener may override, see [`MtlServer::for_listener`].
+/// Unset values are taken from the server.
+#[derive(Clone, Debug, Default)]
+pub struct ListenerConfig {
+    protocols: Option<Box<[Protocol]>>,
+    client_auth: Option<ClientAuth>,
+    handshake_timeout: Option<Duration>,
+    first_request_timeout: Option<Duration>,
+}
+
+impl ListenerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_protocols(mut self, protocols: Box<[Protocol]>) -> Self {
+        self.protocols = Some(protocols);
+        self
+    }
+
+    pub fn with_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = Some(client_auth);
+        self
+    }
+
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_first_request_timeout(mut self, timeout: Duration) -> Self {
+        self.first_request_timeout = Some(timeout);
+        self
+    }
+}
+
+impl MtlServer {
+    /// Derives a server for another listener, e.g. plain TLS for internal
+    /// probes next to mTLS for partners. It shares the certificates, the
+    /// handle and with it the metrics and shutdown, and the connection
+    /// limit; `config` overrides the rest. Serve each listener with its own
+    /// service on its own server.
+    pub fn for_listener(&self, config: ListenerConfig) -> MtlServer {
+        let mut server = self.clone();
+        if let Some(protocols) = config.protocols {
+            server.protocols = Some(protocols);
+        }
+        if let Some(client_auth) = config.client_auth {
+            server.client_auth = client_auth;
+        }
+        if let Some(timeout) = config.handshake_timeout {
+            server.handshake_timeout = Some(timeout);
+        }
+        if let Some(timeout) = config.first_request_timeout {
+            server.first_request_timeout = Some(timeout);
+        }
+        server
+    }
+}
+
+/// A listening socket the accept loop takes connections from.
+#[cfg(feature = "tokio")]
+pub(crate) trait Accept: Send + Sync {
+    fn accept(
+        &self,
+    ) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + Send;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// The socket, duplicated for [`ServerHandle::hand_over`](crate::ServerHandle::hand_over).
+    #[cfg(unix)]
+    fn as_fd(&self) -> BorrowedFd<'_>;
+}
+
+#[cfg(feature = "tokio")]
+impl Accept for TcpListener {
+    fn accept(
+        &self,
+    ) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + Send {
+        TcpListener::accept(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+
+    #[cfg(unix)]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        AsFd::as_fd(self)
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn derives_servers_sharing_the_handle() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let config = ListenerConfig::new()
+            .with_client_auth(ClientAuth::Disabled)
+            .with_protocols([Protocol::HTTP_2].into())
+            .with_handshake_timeout(Duration::from_secs(3));
+        let plain = server.for_listener(config);
+        assert_eq!(server.client_auth, ClientAuth::Required);
+        assert_eq!(plain.handshake_timeout, Some(Duration::from_secs(3)));
+
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        let acceptor = server.mtls_acceptor().unwrap();
+        let conn = connect_duplex(&acceptor, anonymous.clone(), "localhost");
+        assert!(conn.await.is_err());
+        let acceptor = plain.mtls_acceptor().unwrap();
+        // The handle is shared, so it reports what the last acceptor loaded.
+        assert!(server.handle().pki().trust_anchors.is_empty());
+        let conn = connect_duplex(&acceptor, anonymous, "localhost");
+        assert!(conn.await.unwrap().conn_info.client_identity().is_none());
+
+        let alice = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, alice, "localhost").await;
+        let alpn = conn.unwrap().conn_info.alpn_protocol().map(Vec::from);
+        assert_eq!(alpn.as_deref(), Some(&b"h2"[..]));
+    }
+}
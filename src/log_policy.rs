@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Addresses counted per interval, so a flood from many addresses can't
+/// grow the summary without bounds.
+const MAX_SUMMARY_ADDRS: usize = 4096;
+
+/// Addresses listed in a summary.
+const TOP_ADDRS: usize = 5;
+
+/// The classes of events a [`LogPolicy`] governs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LogEvent {
+    /// The banner describing the certificates a listener serves with.
+    Startup,
+    /// Connections whose handshake completed.
+    Accepts,
+    /// Failed handshakes.
+    Handshakes,
+    /// Successful certificate reloads. Failed ones are always logged as
+    /// warnings.
+    Reloads,
+    /// Connections closed by policy: banned addresses, load shedding, quotas
+    /// and rejected identities.
+    Rejections,
+}
+
+impl LogEvent {
+    const ALL: [Self; 5] = [
+        Self::Startup,
+        Self::Accepts,
+        Self::Handshakes,
+        Self::Reloads,
+        Self::Rejections,
+    ];
+
+    fn default_level(self) -> LogLevel {
+        match self {
+            Self::Startup | Self::Reloads => LogLevel::Info,
+            Self::Accepts | Self::Handshakes | Self::Rejections => {
+                LogLevel::Debug
+            }
+        }
+    }
+}
+
+/// The level an event class is logged at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    /// Not logged at all.
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            1 => Self::Error,
+            2 => Self::Warn,
+            3 => Self::Info,
+            4 => Self::Debug,
+            _ => Self::Off,
+        }
+    }
+}
+
+/// Aggregates failed handshakes, so an attack doesn't flood the disks with
+/// one line per failure: the first `detailed` failures of every interval
+/// are logged in full, then one line per interval summarizes them all by
+/// failure class and the addresses with the most failures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FailureSummary {
+    interval: Duration,
+    detailed: u32,
+}
+
+impl FailureSummary {
+    /// Logs 10 failures per interval in full by default.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval: interval.max(Duration::from_millis(1)),
+            detailed: 10,
+        }
+    }
+
+    pub fn with_detailed(mut self, detailed: u32) -> Self {
+        self.detailed = detailed;
+        self
+    }
+}
+
+/// Which events the server logs and at what level, so busy servers can
+/// keep audit-critical events while sampling noisy ones. Sampled classes
+/// log the first of every `n` events. By default every class is logged at
+/// the level it always was, without sampling.
+///
+/// ```no_run
+/// # use hyper_mtls_server::{LogEvent, LogLevel, LogPolicy};
+/// let policy = LogPolicy::new()
+///     .with_level(LogEvent::Startup, LogLevel::Off)
+///     .with_level(LogEvent::Rejections, LogLevel::Warn)
+///     .with_sampling(LogEvent::Handshakes, 100);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LogPolicy {
+    levels: [LogLevel; 5],
+    sampling: [u32; 5],
+    failure_summary: Option<FailureSummary>,
+}
+
+impl Default for LogPolicy {
+    fn default() -> Self {
+        Self {
+            levels: LogEvent::ALL.map(LogEvent::default_level),
+            sampling: [1; 5],
+            failure_summary: None,
+        }
+    }
+}
+
+impl LogPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_level(mut self, event: LogEvent, level: LogLevel) -> Self {
+        self.levels[event as usize] = level;
+        self
+    }
+
+    /// Logs only the first of every `n` events of the class; 0 and 1 log
+    /// all of them.
+    pub fn with_sampling(mut self, event: LogEvent, n: u32) -> Self {
+        self.sampling[event as usize] = n.max(1);
+        self
+    }
+
+    /// Aggregates failed handshakes instead of logging each of them, see
+    /// [`FailureSummary`].
+    pub fn with_failure_summary(mut self, summary: FailureSummary) -> Self {
+        self.failure_summary = Some(summary);
+        self
+    }
+
+    pub fn level(&self, event: LogEvent) -> LogLevel {
+        self.levels[event as usize]
+    }
+
+    pub fn sampling(&self, event: LogEvent) -> u32 {
+        self.sampling[event as usize]
+    }
+}
+
+/// The policy in effect, shared by everything logging for a server.
+#[derive(Debug)]
+pub(crate) struct Logs {
+    levels: [AtomicU8; 5],
+    sampling: [AtomicU32; 5],
+    counts: [AtomicU64; 5],
+    failures: Mutex<Failures>,
+}
+
+/// The failed handshakes of the current summary interval.
+#[derive(Debug)]
+struct Failures {
+    config: Option<FailureSummary>,
+    started: Instant,
+    total: u64,
+    detailed: u32,
+    reasons: BTreeMap<&'static str, u64>,
+    addrs: HashMap<IpAddr, u64>,
+    ticking: bool,
+}
+
+impl Failures {
+    fn is_due(&self, config: FailureSummary) -> bool {
+        self.started.elapsed() >= config.interval
+    }
+
+    /// Resets the interval, returning the summary line of the one that
+    /// ended, if there were failures it didn't log.
+    fn take(&mut self) -> Option<Summary> {
+        let summary = Summary {
+            total: self.total,
+            suppressed: self.total - u64::from(self.detailed),
+            elapsed: self.started.elapsed(),
+            reasons: std::mem::take(&mut self.reasons),
+            top_addrs: top_addrs(std::mem::take(&mut self.addrs)),
+        };
+        self.started = Instant::now();
+        self.total = 0;
+        self.detailed = 0;
+        (summary.suppressed > 0).then_some(summary)
+    }
+}
+
+struct Summary {
+    total: u64,
+    suppressed: u64,
+    elapsed: Duration,
+    reasons: BTreeMap<&'static str, u64>,
+    top_addrs: Vec<(IpAddr, u64)>,
+}
+
+fn top_addrs(addrs: HashMap<IpAddr, u64>) -> Vec<(IpAddr, u64)> {
+    let mut addrs: Vec<_> = addrs.into_iter().collect();
+    addrs.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    addrs.truncate(TOP_ADDRS);
+    addrs
+}
+
+impl Default for Logs {
+    fn default() -> Self {
+        let logs = Self {
+            levels: Default::default(),
+            sampling: Default::default(),
+            counts: Default::default(),
+            failures: Mutex::new(Failures {
+                config: None,
+                started: Instant::now(),
+                total: 0,
+                detailed: 0,
+                reasons: BTreeMap::new(),
+                addrs: HashMap::new(),
+                ticking: false,
+            }),
+        };
+        logs.configure(LogPolicy::default());
+        logs
+    }
+}
+
+impl Logs {
+    pub(crate) fn configure(&self, policy: LogPolicy) {
+        for event in LogEvent::ALL {
+            let i = event as usize;
+            self.levels[i].store(policy.levels[i] as u8, Ordering::Relaxed);
+            self.sampling[i].store(policy.sampling[i], Ordering::Relaxed);
+        }
+        self.failures.lock().unwrap().config = policy.failure_summary;
+    }
+
+    fn unsampled_level(&self, event: LogEvent) -> LogLevel {
+        let level = self.levels[event as usize].load(Ordering::Relaxed);
+        LogLevel::from_u8(level)
+    }
+
+    /// The level to log an event of the class at, `Off` if it is
+    /// suppressed or sampled out.
+    pub(crate) fn level(&self, event: LogEvent) -> LogLevel {
+        let i = event as usize;
+        let level = self.unsampled_level(event);
+        let sampling = u64::from(self.sampling[i].load(Ordering::Relaxed));
+        if level == LogLevel::Off || sampling <= 1 {
+            return level;
+        }
+        let count = self.counts[i].fetch_add(1, Ordering::Relaxed);
+        match count % sampling {
+            0 => level,
+            _ => LogLevel::Off,
+        }
+    }
+
+    /// Records a failed handshake from `addr`, returning whether to log it
+    /// in full. Summarizes the interval if it ended.
+    pub(crate) fn handshake_failed(
+        &self,
+        reason: &'static str,
+        addr: IpAddr,
+    ) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let Some(config) = failures.config else {
+            return true;
+        };
+        if failures.is_due(config) {
+            let summary = failures.take();
+            drop(failures);
+            self.log_summary(summary);
+            failures = self.failures.lock().unwrap();
+        }
+        failures.total += 1;
+        *failures.reasons.entry(reason).or_default() += 1;
+        let tracked = failures.addrs.len() < MAX_SUMMARY_ADDRS;
+        match failures.addrs.get_mut(&addr) {
+            Some(count) => *count += 1,
+            None if tracked => {
+                failures.addrs.insert(addr, 1);
+            }
+            None => {}
+        }
+        if failures.detailed < config.detailed {
+            failures.detailed += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Summarizes intervals in the background even when no further
+    /// handshakes fail, if summaries are enabled and a tokio runtime is
+    /// running. Otherwise the next failure summarizes the ended interval.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn start_summaries(self: &Arc<Self>) {
+        let mut failures = self.failures.lock().unwrap();
+        let Some(config) = failures.config.filter(|_| !failures.ticking) else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        failures.ticking = true;
+        let mut wait =
+            config.interval.saturating_sub(failures.started.elapsed());
+        let logs = Arc::downgrade(self);
+        runtime.spawn(async move {
+            loop {
+                tokio::time::sleep(wait).await;
+                let Some(logs) = logs.upgrade() else {
+                    break;
+                };
+                let mut failures = logs.failures.lock().unwrap();
+                let Some(config) = failures.config else {
+                    failures.ticking = false;
+                    break;
+                };
+                let summary =
+                    failures.is_due(config).then(|| failures.take()).flatten();
+                // Until the end of the interval, which a failure may have
+                // restarted in the meantime.
+                wait =
+                    config.interval.saturating_sub(failures.started.elapsed());
+                drop(failures);
+                logs.log_summary(summary);
+            }
+        });
+    }
+
+    fn log_summary(&self, summary: Option<Summary>) {
+        let Some(summary) = summary else {
+            return;
+        };
+        log_event!(
+            level: self.unsampled_level(LogEvent::Handshakes),
+            reasons = ?summary.reasons,
+            top_addrs = ?summary.top_addrs,
+            "{} handshakes failed within {:?}, {} not logged individually",
+            summary.total,
+            summary.elapsed,
+            summary.suppressed
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_each_class_at_its_level() {
+        let logs = Logs::default();
+        assert_eq!(logs.level(LogEvent::Startup), LogLevel::Info);
+        assert_eq!(logs.level(LogEvent::Accepts), LogLevel::Debug);
+
+        logs.configure(
+            LogPolicy::new()
+                .with_level(LogEvent::Startup, LogLevel::Off)
+                .with_level(LogEvent::Rejections, LogLevel::Warn)
+                .with_sampling(LogEvent::Startup, 2),
+        );
+        assert_eq!(logs.level(LogEvent::Startup), LogLevel::Off);
+        assert_eq!(logs.level(LogEvent::Rejections), LogLevel::Warn);
+        assert_eq!(logs.level(LogEvent::Reloads), LogLevel::Info);
+    }
+
+    #[test]
+    fn samples_the_first_of_every_n_events() {
+        let logs = Logs::default();
+        logs.configure(LogPolicy::new().with_sampling(LogEvent::Handshakes, 3));
+        let levels: Vec<_> =
+            (0..6).map(|_| logs.level(LogEvent::Handshakes)).collect();
+        assert_eq!(
+            levels,
+            [LogLevel::Debug, LogLevel::Off, LogLevel::Off].repeat(2)
+        );
+        // Other classes are not sampled.
+        assert_eq!(logs.level(LogEvent::Accepts), LogLevel::Debug);
+        assert_eq!(logs.level(LogEvent::Accepts), LogLevel::Debug);
+    }
+
+    #[test]
+    fn details_only_the_first_failures_of_an_interval() {
+        let logs = Logs::default();
+        let addr = IpAddr::from([10, 0, 0, 7]);
+        assert!((0..20).all(|_| logs.handshake_failed("unknown_ca", addr)));
+
+        let interval = Duration::from_millis(20);
+        let summary = FailureSummary::new(interval).with_detailed(2);
+        logs.configure(LogPolicy::new().with_failure_summary(summary));
+        let detailed: Vec<_> = (0..4)
+            .map(|_| logs.handshake_failed("unknown_ca", addr))
+            .collect();
+        assert_eq!(detailed, [true, true, false, false]);
+
+        std::thread::sleep(interval * 2);
+        assert!(logs.handshake_failed("expired", addr));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn summarizes_the_failures_of_an_interval() {
+        let recorder = crate::testing::Recorder::default();
+        let _guard = recorder.install();
+        let logs = Logs::default();
+        let interval = Duration::from_millis(20);
+        let summary = FailureSummary::new(interval).with_detailed(1);
+        logs.configure(LogPolicy::new().with_failure_summary(summary));
+        let first = IpAddr::from([10, 0, 0, 7]);
+        let second = IpAddr::from(std::net::Ipv6Addr::LOCALHOST);
+        logs.handshake_failed("unknown_ca", first);
+        logs.handshake_failed("unknown_ca", first);
+        logs.handshake_failed("expired", second);
+
+        std::thread::sleep(interval * 2);
+        logs.handshake_failed("expired", second);
+        let events = recorder.events("hyper_mtls_server::log_policy");
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].field("reasons"),
+            Some(r#"{"expired": 1, "unknown_ca": 2}"#)
+        );
+        assert_eq!(
+            events[0].field("top_addrs"),
+            Some("[(10.0.0.7, 2), (::1, 1)]")
+        );
+        let message = events[0].field("message").unwrap();
+        assert!(message.starts_with("3 handshakes failed within "));
+        assert!(message.ends_with(", 2 not logged individually"));
+    }
+}
@@ -0,0 +1,527 @@
+use crate::identity::sha256_hex;
+use crate::{ClientIdentity, CloseCounts, CloseReason, Load};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CLOSE_REASONS: [CloseReason; 7] = [
+    CloseReason::ClientClosed,
+    CloseReason::ServerShutdown,
+    CloseReason::IdleTimeout,
+    CloseReason::MaxAge,
+    CloseReason::HandshakeFailed,
+    CloseReason::PolicyRejected,
+    CloseReason::Error,
+];
+
+/// Label used for all clients beyond the cardinality cap.
+pub const OVERFLOW_IDENTITY_LABEL: &str = "other";
+
+/// How a client certificate is turned into a metrics label.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdentityLabel {
+    /// The first 16 hex digits of the SHA-256 of the subject DN, so no
+    /// personal data ends up in the metrics backend.
+    #[default]
+    HashedSubject,
+    /// The SPIFFE ID, or else the first URI or DNS name SAN. Falls back to
+    /// the hashed subject when the certificate has none of these.
+    San,
+}
+
+impl IdentityLabel {
+    fn of(self, identity: &ClientIdentity) -> Box<str> {
+        let san = match self {
+            Self::HashedSubject => None,
+            Self::San => identity
+                .spiffe_id()
+                .or_else(|| identity.uris().next())
+                .or_else(|| identity.dns_names().next()),
+        };
+        match san {
+            Some(san) => san.into(),
+            None => sha256_hex(identity.subject().as_bytes())[..16].into(),
+        }
+    }
+}
+
+/// Counters of a single client identity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct IdentityMetrics {
+    pub label: Box<str>,
+    pub connections_accepted: u64,
+    pub requests: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct IdentityCounters {
+    connections_accepted: AtomicU64,
+    requests: AtomicU64,
+}
+
+impl IdentityCounters {
+    pub(crate) fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct ByIdentity {
+    label: IdentityLabel,
+    max_identities: usize,
+    counters: HashMap<Box<str>, Arc<IdentityCounters>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    connections_accepted: AtomicU64,
+    connections_active: AtomicU64,
+    handshake_failures: AtomicU64,
+    connection_panics: AtomicU64,
+    connections_shed: AtomicU64,
+    handshakes_pending: AtomicU64,
+    connections_over_quota: AtomicU64,
+    connections_revoked: AtomicU64,
+    connections_passed_through: AtomicU64,
+    connections_rejected: AtomicU64,
+    connection_tasks: AtomicU64,
+    handshake_failures_after_rotation: AtomicU64,
+    reloads: AtomicU64,
+    reload_failures: AtomicU64,
+    callback_errors: AtomicU64,
+    circuit_breaker_trips: AtomicU64,
+    clients_banned: AtomicU64,
+    connections_banned: AtomicU64,
+    identity_cache_hits: AtomicU64,
+    identity_cache_misses: AtomicU64,
+    client_cas_skipped: AtomicU64,
+    suspicious_cert_changes: AtomicU64,
+    connections_refused_expired: AtomicU64,
+    /// Unix timestamp, 0 before the server certificate was loaded.
+    server_cert_not_after: AtomicI64,
+    closes: [AtomicU64; CLOSE_REASONS.len()],
+    trust_store_swapped_at: Mutex<Option<Instant>>,
+    by_identity: Mutex<Option<ByIdentity>>,
+}
+
+/// Point in time copy of the server counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MetricsSnapshot {
+    pub connections_accepted: u64,
+    pub connections_active: u64,
+    pub handshake_failures: u64,
+    pub connection_panics: u64,
+    pub connections_shed: u64,
+    pub handshakes_pending: u64,
+    pub connections_over_quota: u64,
+    pub connections_revoked: u64,
+    pub connections_passed_through: u64,
+    /// Connections whose certificate the identity mapper rejected.
+    pub connections_rejected: u64,
+    /// Live tasks serving connections. With accept workers enabled, this is
+    /// the number of workers.
+    pub connection_tasks: u64,
+    /// Handshake failures shortly after the client CAs changed, counted in
+    /// `handshake_failures` as well.
+    pub handshake_failures_after_rotation: u64,
+    /// Successful certificate reloads.
+    pub reloads: u64,
+    /// Failed certificate reloads, which left the configuration unchanged.
+    pub reload_failures: u64,
+    /// Why the connections served by `serve_service` and friends ended.
+    pub connection_closes: CloseCounts,
+    /// Errors returned by the callback of `MtlServer::serve`.
+    pub callback_errors: u64,
+    /// Times the circuit breaker paused accepting connections.
+    pub circuit_breaker_trips: u64,
+    /// Times a client address was banned, see
+    /// [`MtlServer::with_ban_policy`](crate::MtlServer::with_ban_policy).
+    pub clients_banned: u64,
+    /// Connections from banned addresses, closed without a handshake.
+    pub connections_banned: u64,
+    /// Client identities taken from the cache instead of parsing the
+    /// certificate, see
+    /// [`MtlServer::with_identity_cache`](crate::MtlServer::with_identity_cache).
+    pub identity_cache_hits: u64,
+    /// Client certificates parsed because their identity wasn't cached. The
+    /// hit rate is `identity_cache_hits / (hits + misses)`.
+    pub identity_cache_misses: u64,
+    /// Expired, unparsable or duplicate certificates left out of the client
+    /// CA bundle when it was last loaded.
+    pub client_cas_skipped: u64,
+    /// Known client identities that presented a certificate from another
+    /// CA or an older one, see
+    /// [`MtlServer::with_identity_tracking`](crate::MtlServer::with_identity_tracking).
+    pub suspicious_cert_changes: u64,
+    /// Connections closed without a handshake because the server
+    /// certificate expired, see
+    /// [`MtlServer::with_short_lived_certs`](crate::MtlServer::with_short_lived_certs).
+    pub connections_refused_expired: u64,
+    /// Seconds until the server certificate in effect expires, negative once
+    /// it did. `None` before it was loaded.
+    pub server_cert_remaining_secs: Option<i64>,
+}
+
+/// Counts a connection as active until dropped.
+#[derive(Debug)]
+pub(crate) struct ActiveConnection {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics
+            .connections_active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a handshake as pending until dropped.
+#[derive(Debug)]
+pub(crate) struct PendingHandshake {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        self.metrics
+            .handshakes_pending
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a connection task as live until dropped.
+#[derive(Debug)]
+pub(crate) struct LiveTask {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for LiveTask {
+    fn drop(&mut self) {
+        self.metrics
+            .connection_tasks
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub(crate) fn connection_accepted(self: &Arc<Self>) -> ActiveConnection {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection {
+            metrics: self.clone(),
+        }
+    }
+
+    pub(crate) fn handshake_started(self: &Arc<Self>) -> PendingHandshake {
+        self.handshakes_pending.fetch_add(1, Ordering::Relaxed);
+        PendingHandshake {
+            metrics: self.clone(),
+        }
+    }
+
+    pub(crate) fn task_spawned(self: &Arc<Self>) -> LiveTask {
+        self.connection_tasks.fetch_add(1, Ordering::Relaxed);
+        LiveTask {
+            metrics: self.clone(),
+        }
+    }
+
+    pub(crate) fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_failed_after_rotation(&self) {
+        self.handshake_failures_after_rotation
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self, reason: CloseReason) {
+        let index = CLOSE_REASONS.iter().position(|x| *x == reason);
+        if let Some(index) = index {
+            self.closes[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn reloaded(&self, success: bool) {
+        let counter = match success {
+            true => &self.reloads,
+            false => &self.reload_failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn callback_failed(&self) {
+        self.callback_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn circuit_opened(&self) {
+        self.circuit_breaker_trips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_banned(&self) {
+        self.clients_banned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_banned(&self) {
+        self.connections_banned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn identity_cache_hit(&self) {
+        self.identity_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn identity_cache_miss(&self) {
+        self.identity_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_cas_loaded(&self, skipped: u64) {
+        self.client_cas_skipped.store(skipped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn suspicious_cert_change(&self) {
+        self.suspicious_cert_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_refused_expired(&self) {
+        self.connections_refused_expired
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn server_cert_loaded(&self, not_after: Option<i64>) {
+        self.server_cert_not_after
+            .store(not_after.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    fn server_cert_remaining_secs(&self) -> Option<i64> {
+        let not_after = self.server_cert_not_after.load(Ordering::Relaxed);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs() as i64);
+        (not_after != 0).then(|| not_after - now)
+    }
+
+    pub(crate) fn server_cert_expired(&self) -> bool {
+        self.server_cert_remaining_secs().is_some_and(|x| x < 0)
+    }
+
+    pub(crate) fn trust_store_swapped(&self) {
+        *self.trust_store_swapped_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Whether the client CAs changed less than `window` ago.
+    pub(crate) fn within_rotation(&self, window: Duration) -> bool {
+        self.trust_store_swapped_at
+            .lock()
+            .unwrap()
+            .is_some_and(|x| x.elapsed() < window)
+    }
+
+    pub(crate) fn connection_panicked(&self) {
+        self.connection_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_shed(&self) {
+        self.connections_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_over_quota(&self) {
+        self.connections_over_quota.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_revoked(&self) {
+        self.connections_revoked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_passed_through(&self) {
+        self.connections_passed_through
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn enable_identity_labels(
+        &self,
+        label: IdentityLabel,
+        max_identities: usize,
+    ) {
+        *self.by_identity.lock().unwrap() = Some(ByIdentity {
+            label,
+            max_identities,
+            counters: HashMap::new(),
+        });
+    }
+
+    /// Counts a connection for `identity` and returns the counters to use
+    /// for its requests, or `None` when identity labels are disabled.
+    pub(crate) fn identity_connection(
+        &self,
+        identity: &ClientIdentity,
+    ) -> Option<Arc<IdentityCounters>> {
+        let mut by_identity = self.by_identity.lock().unwrap();
+        let by_identity = by_identity.as_mut()?;
+
+        let mut label = by_identity.label.of(identity);
+        if !by_identity.counters.contains_key(&label)
+            && by_identity.counters.len() >= by_identity.max_identities
+        {
+            label = OVERFLOW_IDENTITY_LABEL.into();
+        }
+        let counters = by_identity.counters.entry(label).or_default().clone();
+        counters
+            .connections_accepted
+            .fetch_add(1, Ordering::Relaxed);
+        Some(counters)
+    }
+
+    pub(crate) fn identity_snapshot(&self) -> Vec<IdentityMetrics> {
+        let by_identity = self.by_identity.lock().unwrap();
+        let Some(by_identity) = by_identity.as_ref() else {
+            return Vec::new();
+        };
+        by_identity
+            .counters
+            .iter()
+            .map(|(label, counters)| IdentityMetrics {
+                label: label.clone(),
+                connections_accepted: counters
+                    .connections_accepted
+                    .load(Ordering::Relaxed),
+                requests: counters.requests.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    pub(crate) fn load(&self) -> Load {
+        Load {
+            active_connections: self.connections_active.load(Ordering::Relaxed),
+            pending_handshakes: self.handshakes_pending.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        let mut connection_closes = CloseCounts::default();
+        for (reason, count) in CLOSE_REASONS.iter().zip(&self.closes) {
+            connection_closes.add(*reason, count.load(Ordering::Relaxed));
+        }
+        MetricsSnapshot {
+            connections_accepted: self
+                .connections_accepted
+                .load(Ordering::Relaxed),
+            connections_active: self.connections_active.load(Ordering::Relaxed),
+            handshake_failures: self.handshake_failures.load(Ordering::Relaxed),
+            connection_panics: self.connection_panics.load(Ordering::Relaxed),
+            connections_shed: self.connections_shed.load(Ordering::Relaxed),
+            handshakes_pending: self.handshakes_pending.load(Ordering::Relaxed),
+            connections_over_quota: self
+                .connections_over_quota
+                .load(Ordering::Relaxed),
+            connections_revoked: self
+                .connections_revoked
+                .load(Ordering::Relaxed),
+            connections_passed_through: self
+                .connections_passed_through
+                .load(Ordering::Relaxed),
+            connections_rejected: self
+                .connections_rejected
+                .load(Ordering::Relaxed),
+            connection_tasks: self.connection_tasks.load(Ordering::Relaxed),
+            handshake_failures_after_rotation: self
+                .handshake_failures_after_rotation
+                .load(Ordering::Relaxed),
+            reloads: self.reloads.load(Ordering::Relaxed),
+            reload_failures: self.reload_failures.load(Ordering::Relaxed),
+            connection_closes,
+            callback_errors: self.callback_errors.load(Ordering::Relaxed),
+            circuit_breaker_trips: self
+                .circuit_breaker_trips
+                .load(Ordering::Relaxed),
+            clients_banned: self.clients_banned.load(Ordering::Relaxed),
+            connections_banned: self.connections_banned.load(Ordering::Relaxed),
+            identity_cache_hits: self
+                .identity_cache_hits
+                .load(Ordering::Relaxed),
+            identity_cache_misses: self
+                .identity_cache_misses
+                .load(Ordering::Relaxed),
+            client_cas_skipped: self.client_cas_skipped.load(Ordering::Relaxed),
+            suspicious_cert_changes: self
+                .suspicious_cert_changes
+                .load(Ordering::Relaxed),
+            connections_refused_expired: self
+                .connections_refused_expired
+                .load(Ordering::Relaxed),
+            server_cert_remaining_secs: self.server_cert_remaining_secs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{ALICE_CERT, BOB_CERT};
+
+    fn identity(pem: &str) -> ClientIdentity {
+        let mut pem = pem.as_bytes();
+        let cert = rustls_pemfile::certs(&mut pem).next().unwrap().unwrap();
+        ClientIdentity::from_cert(&cert).unwrap()
+    }
+
+    #[test]
+    fn counts_connections_and_requests_per_identity() {
+        let metrics = Metrics::default();
+        let alice = identity(ALICE_CERT);
+        assert!(metrics.identity_connection(&alice).is_none());
+
+        metrics.enable_identity_labels(IdentityLabel::San, 10);
+        metrics.identity_connection(&alice).unwrap();
+        let counters = metrics.identity_connection(&alice).unwrap();
+        counters.request();
+        counters.request();
+        counters.request();
+
+        let snapshot = metrics.identity_snapshot();
+        assert_eq!(
+            snapshot,
+            [IdentityMetrics {
+                label: "spiffe://example.org/alice".into(),
+                connections_accepted: 2,
+                requests: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn hashes_the_subject_by_default() {
+        let metrics = Metrics::default();
+        metrics.enable_identity_labels(IdentityLabel::default(), 10);
+        let alice = identity(ALICE_CERT);
+        metrics.identity_connection(&alice).unwrap();
+
+        let label = sha256_hex(alice.subject().as_bytes());
+        let snapshot = metrics.identity_snapshot();
+        assert_eq!(&*snapshot[0].label, &label[..16]);
+    }
+
+    #[test]
+    fn counts_clients_over_the_cap_together() {
+        let metrics = Metrics::default();
+        metrics.enable_identity_labels(IdentityLabel::HashedSubject, 1);
+        let [alice, bob] = [ALICE_CERT, BOB_CERT].map(identity);
+        metrics.identity_connection(&alice).unwrap();
+        metrics.identity_connection(&bob).unwrap();
+        metrics.identity_connection(&alice).unwrap();
+
+        let snapshot = metrics.identity_snapshot();
+        let overflow = snapshot
+            .iter()
+            .find(|x| &*x.label == OVERFLOW_IDENTITY_LABEL);
+        assert_eq!(overflow.unwrap().connections_accepted, 1);
+        assert_eq!(snapshot.len(), 2);
+    }
+}
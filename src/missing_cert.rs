@@ -0,0 +1,134 @@
+use crate::{ClientAuth, MtlServer};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode};
+use std::convert::Infallible;
+
+const MESSAGE: &str = "This server requires a client certificate, but your \
+    browser or client did not present one. Install a certificate issued for \
+    this service and try again.";
+
+/// What `serve_service` and friends do with clients that present no
+/// certificate although [`ClientAuth::Required`](crate::ClientAuth) is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MissingClientCert {
+    /// Fail the handshake with a TLS alert.
+    #[default]
+    FailHandshake,
+    /// Complete the handshake and answer every request with status `496`
+    /// and an explanation as HTML, JSON or plain text, depending on the
+    /// `Accept` header. Browsers show the page instead of a TLS error.
+    ErrorResponse,
+}
+
+impl MtlServer {
+    pub(crate) fn missing_cert_response(&self) -> bool {
+        self.client_auth == ClientAuth::Required
+            && self.missing_client_cert == MissingClientCert::ErrorResponse
+    }
+}
+
+pub(crate) async fn respond(
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let accept = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    let (content_type, body) = if accept.contains("text/html") {
+        let body = format!(
+            "<!DOCTYPE html>\n<html><head><title>Client certificate \
+             required</title></head><body><h1>Client certificate \
+             required</h1><p>{}</p></body></html>\n",
+            MESSAGE
+        );
+        ("text/html; charset=utf-8", body)
+    } else if accept.contains("application/json") {
+        let body = format!(
+            "{{\"error\":\"client_certificate_required\",\"message\":\"{}\"}}",
+            MESSAGE
+        );
+        ("application/json", body)
+    } else {
+        ("text/plain; charset=utf-8", format!("{}\n", MESSAGE))
+    };
+
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() =
+        StatusCode::from_u16(496).expect("496 is a valid status code");
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::testing::send;
+    use http_body_util::{BodyExt, Empty};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn answers_clients_without_a_certificate_with_496() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_missing_client_cert(MissingClientCert::ErrorResponse);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Full::<Bytes>::from("hello")))
+            });
+            server.serve_service(listener, service).await
+        });
+        let get = |accept| {
+            let req = Request::get("/").header(ACCEPT, accept);
+            req.body(Empty::<Bytes>::new()).unwrap()
+        };
+
+        let anonymous = fixtures.anonymous_client_config().unwrap();
+        for (accept, content_type) in [
+            ("text/html,*/*", "text/html; charset=utf-8"),
+            ("application/json", "application/json"),
+            ("*/*", "text/plain; charset=utf-8"),
+        ] {
+            let response =
+                send(anonymous.clone(), addr, get(accept)).await.unwrap();
+            assert_eq!(response.status().as_u16(), 496);
+            assert_eq!(response.headers()[CONTENT_TYPE], content_type);
+            let body = response.into_body().collect().await.unwrap();
+            let body = String::from_utf8(body.to_bytes().to_vec()).unwrap();
+            assert!(body.contains(MESSAGE));
+        }
+        let alice = fixtures.client_config("alice").unwrap();
+        let response = send(alice, addr, get("*/*")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "hello");
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_the_handshake_by_default() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        assert!(!server.missing_cert_response());
+        let server = server
+            .with_client_auth(ClientAuth::Optional)
+            .with_missing_client_cert(MissingClientCert::ErrorResponse);
+        assert!(!server.missing_cert_response());
+    }
+}
@@ -0,0 +1,907 @@
+use crate::der::{self, Element, Reader};
+use crate::identity::sha256_hex;
+use crate::{ConnInfo, Error, MtlServer, RevocationPolicy, RevocationStatus};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls_pki_types::CertificateDer;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::OnceCell;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use x509_parser::time::ASN1Time;
+
+// 1.3.6.1.5.5.7.48.1.1
+const OCSP_BASIC: &[u8] =
+    &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+// 1.3.14.3.2.26
+const SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+// 2.16.840.1.101.3.4.2.1
+const SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const GENERALIZED_TIME: u8 = 0x18;
+const CERT_GOOD: u8 = der::context_primitive(0);
+const CERT_REVOKED: u8 = der::context(1);
+const CERT_UNKNOWN: u8 = der::context_primitive(2);
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+const CLOCK_SKEW: i64 = 5 * 60;
+
+#[derive(Clone, Debug)]
+pub struct OcspConfig {
+    responder: Option<Uri>,
+    timeout: Duration,
+    policy: RevocationPolicy,
+    max_cache_ttl: Duration,
+}
+
+impl Default for OcspConfig {
+    fn default() -> Self {
+        Self {
+            responder: None,
+            timeout: Duration::from_secs(2),
+            policy: RevocationPolicy::default(),
+            max_cache_ttl: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl OcspConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queries `responder` instead of the URL in the certificate's
+    /// Authority Information Access extension. Only `http://` responders
+    /// are supported.
+    pub fn with_responder(mut self, responder: Uri) -> Self {
+        self.responder = Some(responder);
+        self
+    }
+
+    /// Limits how long a connection waits for the responder, 2 seconds by
+    /// default. A timeout is handled according to the revocation policy.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Decides about certificates whose status can't be determined,
+    /// [`RevocationPolicy::AllowWithWarning`] by default.
+    pub fn with_revocation_policy(mut self, policy: RevocationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Responses are cached until their `nextUpdate`, but no longer than
+    /// `ttl`, 1 hour by default.
+    pub fn with_max_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.max_cache_ttl = ttl;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Cached {
+    status: RevocationStatus,
+    expires: Instant,
+}
+
+/// The outcome of a lookup, shared by the handshakes waiting for it.
+type Lookup = Result<RevocationStatus, (io::ErrorKind, Box<str>)>;
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) struct OcspChecker {
+    config: OcspConfig,
+    issuers: RwLock<Vec<CertificateDer<'static>>>,
+    algorithms: WebPkiSupportedAlgorithms,
+    cache: Mutex<HashMap<Box<str>, Cached>>,
+    /// Lookups in progress, so concurrent handshakes with the same
+    /// certificate query the responder once.
+    pending: Mutex<HashMap<Box<str>, Arc<OnceCell<Lookup>>>>,
+}
+
+impl OcspChecker {
+    /// Switches to reloaded client CAs, dropping cached responses.
+    pub(crate) fn set_issuers(&self, issuers: Vec<CertificateDer<'static>>) {
+        *self.issuers.write().unwrap() = issuers;
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Returns whether the connection may proceed.
+    pub(crate) async fn allows(&self, conn_info: &ConnInfo) -> bool {
+        let chain = conn_info.peer_certificates();
+        if chain.is_empty() {
+            return true;
+        }
+
+        let failure = match self.status(chain).await {
+            Ok(RevocationStatus::Good) => return true,
+            Ok(RevocationStatus::Revoked) => {
+                debug!("client certificate is revoked");
+                return false;
+            }
+            Ok(RevocationStatus::Unknown) => {
+                invalid("certificate unknown to the OCSP responder")
+            }
+            Err(err) => err,
+        };
+        self.config.policy.allows_unavailable(&failure)
+    }
+
+    async fn status(
+        &self,
+        chain: &[CertificateDer<'static>],
+    ) -> io::Result<RevocationStatus> {
+        let key: Box<str> = sha256_hex(&chain[0]).into();
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            if cached.expires > Instant::now() {
+                return Ok(cached.status);
+            }
+        }
+
+        let lookup = self
+            .pending
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let status = lookup
+            .get_or_init(|| async {
+                self.lookup(&key, chain)
+                    .await
+                    .map_err(|err| (err.kind(), err.to_string().into()))
+            })
+            .await
+            .clone();
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(&key).is_some_and(|x| Arc::ptr_eq(x, &lookup)) {
+            pending.remove(&key);
+        }
+        status.map_err(|(kind, msg)| io::Error::new(kind, String::from(msg)))
+    }
+
+    /// Queries the responder, caching the response.
+    async fn lookup(
+        &self,
+        key: &str,
+        chain: &[CertificateDer<'static>],
+    ) -> io::Result<RevocationStatus> {
+        let (_, leaf) = X509Certificate::from_der(&chain[0])
+            .map_err(|_| invalid("unparsable client certificate"))?;
+        let issuers = self.issuers.read().unwrap().clone();
+        let issuer = self.issuer(chain, &issuers).ok_or_else(|| {
+            invalid("verified issuer of the client certificate not found")
+        })?;
+        let (_, issuer) = X509Certificate::from_der(issuer)
+            .map_err(|_| invalid("unparsable issuer certificate"))?;
+        let responder = match &self.config.responder {
+            Some(responder) => responder.clone(),
+            None => responder_from_aia(&leaf)
+                .ok_or_else(|| invalid("no OCSP responder in certificate"))?,
+        };
+
+        let request = encode_request(&leaf, &issuer);
+        let response = tokio::time::timeout(
+            self.config.timeout,
+            fetch(&responder, request),
+        )
+        .await
+        .map_err(|_| {
+            io::Error::new(io::ErrorKind::TimedOut, "OCSP timed out")
+        })??;
+        let (status, next_update) =
+            self.parse_response(&response, &leaf, &issuer)?;
+
+        let now = ASN1Time::now().timestamp();
+        let ttl = next_update
+            .map(|x| Duration::from_secs(x.saturating_sub(now).max(0) as u64))
+            .unwrap_or(self.config.max_cache_ttl)
+            .min(self.config.max_cache_ttl);
+        let cached = Cached {
+            status,
+            expires: Instant::now() + ttl,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, x| x.expires > Instant::now());
+        cache.insert(key.into(), cached);
+        Ok(status)
+    }
+
+    /// The issuer of the leaf of `chain`: one of the client CAs, or an
+    /// intermediate the client sent that they signed, directly or through
+    /// further such intermediates. The request hashes and the response
+    /// signature are keyed on the issuer, so it must never be a
+    /// certificate the client made up.
+    fn issuer<'a>(
+        &self,
+        chain: &'a [CertificateDer<'static>],
+        anchors: &'a [CertificateDer<'static>],
+    ) -> Option<&'a CertificateDer<'static>> {
+        let mut trusted: Vec<_> = anchors.iter().collect();
+        let mut intermediates: Vec<_> = chain[1..]
+            .iter()
+            .filter(|x| {
+                X509Certificate::from_der(x).is_ok_and(|(_, x)| x.is_ca())
+            })
+            .collect();
+        while let Some(i) = intermediates.iter().position(|cert| {
+            trusted.iter().any(|issuer| self.signed_by(cert, issuer))
+        }) {
+            trusted.push(intermediates.swap_remove(i));
+        }
+        trusted
+            .into_iter()
+            .find(|issuer| self.signed_by(&chain[0], issuer))
+    }
+
+    fn signed_by(&self, cert: &[u8], issuer: &[u8]) -> bool {
+        let (Ok((_, parsed)), Ok((_, issuer))) = (
+            X509Certificate::from_der(cert),
+            X509Certificate::from_der(issuer),
+        ) else {
+            return false;
+        };
+        parsed.issuer().as_raw() == issuer.subject().as_raw()
+            && Reader::new(cert)
+                .expect(der::SEQUENCE)
+                .is_some_and(|x| self.issued_by(&x, &issuer))
+    }
+
+    fn parse_response(
+        &self,
+        response: &[u8],
+        leaf: &X509Certificate<'_>,
+        issuer: &X509Certificate<'_>,
+    ) -> io::Result<(RevocationStatus, Option<i64>)> {
+        let malformed = || invalid("malformed OCSP response");
+
+        let mut outer = Reader::new(response)
+            .expect(der::SEQUENCE)
+            .ok_or_else(malformed)?
+            .reader();
+        let response_status =
+            outer.expect(der::ENUMERATED).ok_or_else(malformed)?;
+        if response_status.contents != [0] {
+            return Err(invalid("OCSP responder returned an error status"));
+        }
+        let mut response_bytes = outer
+            .expect(der::context(0))
+            .and_then(|x| x.reader().expect(der::SEQUENCE))
+            .ok_or_else(malformed)?
+            .reader();
+        let response_type = response_bytes.expect(der::OID);
+        if response_type.map(|x| x.contents) != Some(OCSP_BASIC) {
+            return Err(invalid("unsupported OCSP response type"));
+        }
+        let basic = response_bytes
+            .expect(der::OCTET_STRING)
+            .and_then(|x| Reader::new(x.contents).expect(der::SEQUENCE))
+            .ok_or_else(malformed)?;
+
+        let mut basic = basic.reader();
+        let tbs = basic.expect(der::SEQUENCE).ok_or_else(malformed)?;
+        let algorithm = basic.expect(der::SEQUENCE).ok_or_else(malformed)?;
+        let signature = basic
+            .expect(der::BIT_STRING)
+            .and_then(|x| der::bit_string(&x))
+            .ok_or_else(malformed)?;
+        let certs = basic.optional(der::context(0));
+
+        let issuer_spki = issuer.public_key().raw;
+        let signed_by_issuer =
+            self.verify(issuer_spki, algorithm.contents, tbs.raw, signature);
+        if !signed_by_issuer
+            && !self
+                .signed_by_delegate(certs, issuer, &algorithm, &tbs, signature)
+        {
+            return Err(invalid("OCSP response signature is invalid"));
+        }
+
+        let mut data = tbs.reader();
+        data.optional(der::context(0));
+        data.read().ok_or_else(malformed)?;
+        data.expect(GENERALIZED_TIME).ok_or_else(malformed)?;
+        let mut responses =
+            data.expect(der::SEQUENCE).ok_or_else(malformed)?.reader();
+
+        while !responses.is_empty() {
+            let mut single = responses
+                .expect(der::SEQUENCE)
+                .ok_or_else(malformed)?
+                .reader();
+            let cert_id = single.expect(der::SEQUENCE).ok_or_else(malformed)?;
+            if !cert_id_matches(&cert_id, leaf, issuer).ok_or_else(malformed)? {
+                continue;
+            }
+
+            let status = match single.read().map(|x| x.tag) {
+                Some(CERT_GOOD) => RevocationStatus::Good,
+                Some(CERT_REVOKED) => RevocationStatus::Revoked,
+                Some(CERT_UNKNOWN) => RevocationStatus::Unknown,
+                _ => return Err(malformed()),
+            };
+            let this_update = single
+                .expect(GENERALIZED_TIME)
+                .and_then(|x| time(&x))
+                .ok_or_else(malformed)?;
+            let next_update = match single.optional(der::context(0)) {
+                Some(x) => Some(
+                    x.reader()
+                        .expect(GENERALIZED_TIME)
+                        .and_then(|x| time(&x))
+                        .ok_or_else(malformed)?,
+                ),
+                None => None,
+            };
+
+            let now = ASN1Time::now().timestamp();
+            if this_update > now + CLOCK_SKEW {
+                return Err(invalid("OCSP response is not yet valid"));
+            }
+            if next_update.is_some_and(|x| x < now - CLOCK_SKEW) {
+                return Err(invalid("OCSP response is stale"));
+            }
+            return Ok((status, next_update));
+        }
+        Err(invalid("OCSP response does not cover the certificate"))
+    }
+
+    /// Checks a response signed by a responder certificate that the issuer
+    /// delegated OCSP signing to.
+    fn signed_by_delegate(
+        &self,
+        certs: Option<Element<'_>>,
+        issuer: &X509Certificate<'_>,
+        algorithm: &Element<'_>,
+        tbs: &Element<'_>,
+        signature: &[u8],
+    ) -> bool {
+        let Some(mut certs) = certs
+            .and_then(|x| x.reader().expect(der::SEQUENCE))
+            .map(|x| x.reader())
+        else {
+            return false;
+        };
+
+        while let Some(cert) = certs.read() {
+            let Ok((_, responder)) = X509Certificate::from_der(cert.raw) else {
+                continue;
+            };
+            let ocsp_signing = responder
+                .extended_key_usage()
+                .ok()
+                .flatten()
+                .is_some_and(|x| x.value.ocsp_signing);
+            if !ocsp_signing
+                || responder.issuer().as_raw() != issuer.subject().as_raw()
+                || !responder.validity().is_valid()
+                || !self.issued_by(&cert, issuer)
+            {
+                continue;
+            }
+            if self.verify(
+                responder.public_key().raw,
+                algorithm.contents,
+                tbs.raw,
+                signature,
+            ) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn issued_by(
+        &self,
+        cert: &Element<'_>,
+        issuer: &X509Certificate<'_>,
+    ) -> bool {
+        let mut cert = cert.reader();
+        let (Some(tbs), Some(algorithm), Some(signature)) = (
+            cert.expect(der::SEQUENCE),
+            cert.expect(der::SEQUENCE),
+            cert.expect(der::BIT_STRING),
+        ) else {
+            return false;
+        };
+        let Some(signature) = der::bit_string(&signature) else {
+            return false;
+        };
+        self.verify(
+            issuer.public_key().raw,
+            algorithm.contents,
+            tbs.raw,
+            signature,
+        )
+    }
+
+    fn verify(
+        &self,
+        spki: &[u8],
+        algorithm: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        let Some(spki) = Reader::new(spki).expect(der::SEQUENCE) else {
+            return false;
+        };
+        let mut spki = spki.reader();
+        let (Some(key_algorithm), Some(key)) =
+            (spki.expect(der::SEQUENCE), spki.expect(der::BIT_STRING))
+        else {
+            return false;
+        };
+        let Some(key) = der::bit_string(&key) else {
+            return false;
+        };
+
+        self.algorithms.all.iter().any(|x| {
+            x.public_key_alg_id().as_ref() == key_algorithm.contents
+                && x.signature_alg_id().as_ref() == algorithm
+                && x.verify_signature(key, message, signature).is_ok()
+        })
+    }
+}
+
+fn time(element: &Element<'_>) -> Option<i64> {
+    let (_, time) = ASN1Time::from_der(element.raw).ok()?;
+    Some(time.timestamp())
+}
+
+fn responder_from_aia(cert: &X509Certificate<'_>) -> Option<Uri> {
+    cert.extensions().iter().find_map(|ext| {
+        let ParsedExtension::AuthorityInfoAccess(aia) = ext.parsed_extension()
+        else {
+            return None;
+        };
+        aia.iter().find_map(|desc| match &desc.access_location {
+            GeneralName::URI(uri)
+                if desc.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP =>
+            {
+                uri.parse().ok()
+            }
+            _ => None,
+        })
+    })
+}
+
+/// The issuer name and key hashes of a CertID with the hash algorithm
+/// `oid`, `None` for unsupported algorithms.
+fn issuer_hashes(
+    oid: &[u8],
+    issuer: &X509Certificate<'_>,
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    let name = issuer.subject().as_raw();
+    let key = &issuer.public_key().subject_public_key.data;
+    match oid {
+        SHA1 => Some((Sha1::digest(name).to_vec(), Sha1::digest(key).to_vec())),
+        SHA256 => {
+            Some((Sha256::digest(name).to_vec(), Sha256::digest(key).to_vec()))
+        }
+        _ => None,
+    }
+}
+
+/// Whether a CertID identifies `leaf` as issued by `issuer`: the same
+/// serial number and the hashes of the issuer's name and key. `None` when
+/// it is malformed.
+fn cert_id_matches(
+    cert_id: &Element<'_>,
+    leaf: &X509Certificate<'_>,
+    issuer: &X509Certificate<'_>,
+) -> Option<bool> {
+    let mut cert_id = cert_id.reader();
+    let oid = cert_id.expect(der::SEQUENCE)?.reader().expect(der::OID)?;
+    let name_hash = cert_id.expect(der::OCTET_STRING)?;
+    let key_hash = cert_id.expect(der::OCTET_STRING)?;
+    let serial = cert_id.expect(der::INTEGER)?;
+    let Some((name, key)) = issuer_hashes(oid.contents, issuer) else {
+        return Some(false);
+    };
+    Some(
+        serial.contents == leaf.raw_serial()
+            && name_hash.contents == name
+            && key_hash.contents == key,
+    )
+}
+
+fn encode_request(
+    leaf: &X509Certificate<'_>,
+    issuer: &X509Certificate<'_>,
+) -> Vec<u8> {
+    let hash_algorithm = der::sequence(&[
+        &der::encode(der::OID, SHA1),
+        &der::encode(der::NULL, &[]),
+    ]);
+    let (name_hash, key_hash) =
+        issuer_hashes(SHA1, issuer).expect("SHA-1 is supported");
+    let cert_id = der::sequence(&[
+        &hash_algorithm,
+        &der::encode(der::OCTET_STRING, &name_hash),
+        &der::encode(der::OCTET_STRING, &key_hash),
+        &der::encode(der::INTEGER, leaf.raw_serial()),
+    ]);
+
+    let request = der::sequence(&[&cert_id]);
+    let request_list = der::sequence(&[&request]);
+    let tbs_request = der::sequence(&[&request_list]);
+    der::sequence(&[&tbs_request])
+}
+
+async fn fetch(responder: &Uri, request: Vec<u8>) -> io::Result<Vec<u8>> {
+    if responder.scheme_str() != Some("http") {
+        return Err(invalid("only http:// OCSP responders are supported"));
+    }
+    let authority = responder
+        .authority()
+        .ok_or_else(|| invalid("OCSP responder URL without host"))?;
+    let stream = TcpStream::connect((
+        authority.host(),
+        authority.port_u16().unwrap_or(80),
+    ))
+    .await?;
+
+    let (mut sender, conn) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(io::Error::other)?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            debug!("OCSP connection error: {:?}", err);
+        }
+    });
+
+    let path = responder.path_and_query().map_or("/", |x| x.as_str());
+    let request = Request::post(path)
+        .header(HOST, authority.as_str())
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(Full::new(Bytes::from(request)))
+        .map_err(io::Error::other)?;
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(io::Error::other)?;
+    if !response.status().is_success() {
+        return Err(io::Error::other(format!(
+            "OCSP responder returned {}",
+            response.status()
+        )));
+    }
+
+    let body = Limited::new(response.into_body(), MAX_RESPONSE_SIZE)
+        .collect()
+        .await
+        .map_err(io::Error::other)?;
+    Ok(body.to_bytes().to_vec())
+}
+
+impl MtlServer {
+    pub(crate) fn create_ocsp_checker(
+        &self,
+    ) -> Result<Option<OcspChecker>, Error> {
+        let Some(config) = &self.ocsp else {
+            return Ok(None);
+        };
+        let algorithms = self.provider().signature_verification_algorithms;
+
+        Ok(Some(OcspChecker {
+            config: config.clone(),
+            issuers: RwLock::new(self.ocsp_issuers()?),
+            algorithms,
+            cache: Mutex::default(),
+            pending: Mutex::default(),
+        }))
+    }
+
+    pub(crate) fn ocsp_issuers(
+        &self,
+    ) -> Result<Vec<CertificateDer<'static>>, Error> {
+        if self.has_client_cas() {
+            self.load_client_ca_cert()
+        } else {
+            Ok(Vec::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{ALICE_CERT, CA_CERT, INTERMEDIATE_CERT};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::SignatureScheme;
+    use rustls_pki_types::PrivateKeyDer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    // 1.2.840.10045.4.3.2
+    const ECDSA_SHA256: &[u8] =
+        &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+    const PAST: &[u8] = b"20200101000000Z";
+    const FUTURE: &[u8] = b"21000101000000Z";
+
+    struct Pki {
+        ca_key: KeyPair,
+        ca: CertificateDer<'static>,
+        leaf: CertificateDer<'static>,
+    }
+
+    impl Pki {
+        fn generate() -> Self {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca_key = KeyPair::generate().unwrap();
+            let ca = params.self_signed(&ca_key).unwrap();
+            let leaf_params =
+                CertificateParams::new(vec!["client".into()]).unwrap();
+            let leaf = leaf_params
+                .signed_by(&KeyPair::generate().unwrap(), &ca, &ca_key)
+                .unwrap();
+            Self {
+                ca_key,
+                ca: ca.der().clone(),
+                leaf: leaf.der().clone(),
+            }
+        }
+
+        fn parsed(&self) -> (X509Certificate<'_>, X509Certificate<'_>) {
+            let (_, leaf) = X509Certificate::from_der(&self.leaf).unwrap();
+            let (_, ca) = X509Certificate::from_der(&self.ca).unwrap();
+            (leaf, ca)
+        }
+
+        fn cert_id(&self, hash: &[u8]) -> Vec<u8> {
+            let (leaf, ca) = self.parsed();
+            let (name_hash, key_hash) = issuer_hashes(hash, &ca).unwrap();
+            cert_id(hash, &name_hash, &key_hash, leaf.raw_serial())
+        }
+    }
+
+    fn cert_id(
+        hash: &[u8],
+        name_hash: &[u8],
+        key_hash: &[u8],
+        serial: &[u8],
+    ) -> Vec<u8> {
+        der::sequence(&[
+            &der::sequence(&[
+                &der::encode(der::OID, hash),
+                &der::encode(der::NULL, &[]),
+            ]),
+            &der::encode(der::OCTET_STRING, name_hash),
+            &der::encode(der::OCTET_STRING, key_hash),
+            &der::encode(der::INTEGER, serial),
+        ])
+    }
+
+    fn good() -> Vec<u8> {
+        der::encode(CERT_GOOD, &[])
+    }
+
+    fn revoked() -> Vec<u8> {
+        der::encode(CERT_REVOKED, &der::encode(GENERALIZED_TIME, PAST))
+    }
+
+    /// A successful basic OCSP response with a single response, signed by
+    /// `key`.
+    fn response(cert_id: &[u8], status: &[u8], key: &KeyPair) -> Vec<u8> {
+        let single = der::sequence(&[
+            cert_id,
+            status,
+            &der::encode(GENERALIZED_TIME, PAST),
+            &der::encode(
+                der::context(0),
+                &der::encode(GENERALIZED_TIME, FUTURE),
+            ),
+        ]);
+        let tbs = der::sequence(&[
+            &der::encode(
+                der::context(2),
+                &der::encode(der::OCTET_STRING, &[0; 20]),
+            ),
+            &der::encode(GENERALIZED_TIME, PAST),
+            &der::sequence(&[&single]),
+        ]);
+        let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+        let signature = crate::crypto_provider()
+            .key_provider
+            .load_private_key(key)
+            .unwrap()
+            .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256])
+            .unwrap()
+            .sign(&tbs)
+            .unwrap();
+        let basic = der::sequence(&[
+            &tbs,
+            &der::sequence(&[&der::encode(der::OID, ECDSA_SHA256)]),
+            &der::encode(der::BIT_STRING, &[&[0], &signature[..]].concat()),
+        ]);
+        der::sequence(&[
+            &der::encode(der::ENUMERATED, &[0]),
+            &der::encode(
+                der::context(0),
+                &der::sequence(&[
+                    &der::encode(der::OID, OCSP_BASIC),
+                    &der::encode(der::OCTET_STRING, &basic),
+                ]),
+            ),
+        ])
+    }
+
+    fn checker(issuers: Vec<CertificateDer<'static>>) -> OcspChecker {
+        OcspChecker {
+            config: OcspConfig::default(),
+            issuers: RwLock::new(issuers),
+            algorithms: crate::crypto_provider()
+                .signature_verification_algorithms,
+            cache: Mutex::default(),
+            pending: Mutex::default(),
+        }
+    }
+
+    fn parse(pki: &Pki, response: &[u8]) -> io::Result<RevocationStatus> {
+        let (leaf, ca) = pki.parsed();
+        let checker = checker(vec![pki.ca.clone()]);
+        checker
+            .parse_response(response, &leaf, &ca)
+            .map(|(status, _)| status)
+    }
+
+    fn pem(pem: &str) -> Vec<CertificateDer<'static>> {
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn request_identifies_the_certificate_by_sha1_cert_id() {
+        let pki = Pki::generate();
+        let (leaf, ca) = pki.parsed();
+        let request = encode_request(&leaf, &ca);
+
+        let mut request = Reader::new(&request);
+        let cert_id = [der::SEQUENCE; 4]
+            .iter()
+            .try_fold(request.expect(der::SEQUENCE).unwrap(), |x, tag| {
+                x.reader().expect(*tag)
+            })
+            .unwrap();
+        assert!(request.is_empty());
+        assert_eq!(cert_id.raw, pki.cert_id(SHA1));
+        assert_eq!(cert_id_matches(&cert_id, &leaf, &ca), Some(true));
+    }
+
+    #[test]
+    fn parses_good_and_revoked_responses() {
+        let pki = Pki::generate();
+        let (leaf, ca) = pki.parsed();
+        let good = response(&pki.cert_id(SHA1), &good(), &pki.ca_key);
+        let (status, next_update) = checker(Vec::new())
+            .parse_response(&good, &leaf, &ca)
+            .unwrap();
+        assert_eq!(status, RevocationStatus::Good);
+        assert_eq!(next_update, Some(4102444800));
+
+        let revoked = response(&pki.cert_id(SHA1), &revoked(), &pki.ca_key);
+        assert_eq!(parse(&pki, &revoked).unwrap(), RevocationStatus::Revoked);
+    }
+
+    #[test]
+    fn accepts_sha256_cert_ids() {
+        let pki = Pki::generate();
+        let good = response(&pki.cert_id(SHA256), &good(), &pki.ca_key);
+        assert_eq!(parse(&pki, &good).unwrap(), RevocationStatus::Good);
+    }
+
+    #[test]
+    fn ignores_responses_for_other_cert_ids() {
+        let pki = Pki::generate();
+        let (leaf, ca) = pki.parsed();
+        let (name_hash, key_hash) = issuer_hashes(SHA1, &ca).unwrap();
+        let serial = leaf.raw_serial();
+        let other_serial = [&[0x01][..], serial].concat();
+        for cert_id in [
+            cert_id(SHA1, &name_hash, &key_hash, &other_serial),
+            cert_id(SHA1, &[0; 20], &key_hash, serial),
+            cert_id(SHA1, &name_hash, &[0; 20], serial),
+            cert_id(OCSP_BASIC, &name_hash, &key_hash, serial),
+        ] {
+            let response = response(&cert_id, &good(), &pki.ca_key);
+            let err = parse(&pki, &response).unwrap_err();
+            assert!(err.to_string().contains("does not cover"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_responses_signed_by_another_key() {
+        let pki = Pki::generate();
+        let other = KeyPair::generate().unwrap();
+        let response = response(&pki.cert_id(SHA1), &good(), &other);
+        let err = parse(&pki, &response).unwrap_err();
+        assert!(err.to_string().contains("signature is invalid"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_error_statuses_and_garbage() {
+        let pki = Pki::generate();
+        let unauthorized =
+            der::sequence(&[&der::encode(der::ENUMERATED, &[6])]);
+        assert!(parse(&pki, &unauthorized).is_err());
+        assert!(parse(&pki, b"not der").is_err());
+
+        let good = response(&pki.cert_id(SHA1), &good(), &pki.ca_key);
+        for end in [1, 10, good.len() / 2, good.len() - 1] {
+            assert!(parse(&pki, &good[..end]).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesces_concurrent_lookups() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 503 Busy\r\ncontent-length: 0\r\n\r\n",
+                        )
+                        .await;
+                });
+            }
+        });
+
+        let pki = Pki::generate();
+        let mut checker = checker(vec![pki.ca.clone()]);
+        let responder = format!("http://{}/", addr).parse().unwrap();
+        checker.config = OcspConfig::new().with_responder(responder);
+        let chain = [pki.leaf.clone()];
+        let (a, b) =
+            tokio::join!(checker.status(&chain), checker.status(&chain));
+        assert!(a.unwrap_err().to_string().contains("503"));
+        assert!(b.unwrap_err().to_string().contains("503"));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert!(checker.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn takes_the_issuer_from_verified_certificates_only() {
+        let anchors = pem(CA_CERT);
+        let intermediate = pem(INTERMEDIATE_CERT).remove(0);
+        let alice = pem(ALICE_CERT).remove(0);
+        let checker = checker(anchors.clone());
+
+        let chain = [alice.clone(), intermediate.clone()];
+        assert_eq!(checker.issuer(&chain, &anchors), Some(&intermediate));
+
+        // Without the intermediate the issuer isn't known.
+        assert_eq!(checker.issuer(&chain[..1], &anchors), None);
+
+        // An intermediate with the same name but a key of the client's.
+        let params =
+            CertificateParams::from_ca_cert_der(&intermediate).unwrap();
+        let forged = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+        let forged = forged.der().clone();
+        let chain = [alice.clone(), forged.clone(), intermediate.clone()];
+        assert_eq!(checker.issuer(&chain, &anchors), Some(&intermediate));
+        assert_eq!(checker.issuer(&[alice, forged], &anchors), None);
+    }
+}
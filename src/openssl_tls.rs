@@ -0,0 +1,430 @@
+use crate::alpn::AlpnMismatch;
+use crate::backend::TlsBackend;
+use crate::conn::Session;
+use crate::handshake::HandshakeError;
+use crate::sni::{MissingSni, SniAllowlist};
+use crate::Error::{BackendUnsupportedError, OpenSslConfigError};
+use crate::{ClientAuth, Error, MtlServer, TlsVersion};
+use openssl::pkey::PKey;
+use openssl::ssl::{
+    AlpnError, NameType, SniError, Ssl, SslAcceptor, SslAcceptorBuilder,
+    SslAlert, SslMethod, SslRef, SslVerifyMode, SslVersion,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::X509;
+use rustls::{CipherSuite, ProtocolVersion};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslStream;
+
+// X509_V_ERR_* verification results mapped to handshake errors.
+const UNABLE_TO_GET_ISSUER_CERT: i32 = 2;
+const CERT_SIGNATURE_FAILURE: i32 = 7;
+const CERT_NOT_YET_VALID: i32 = 9;
+const CERT_HAS_EXPIRED: i32 = 10;
+const DEPTH_ZERO_SELF_SIGNED_CERT: i32 = 18;
+const SELF_SIGNED_CERT_IN_CHAIN: i32 = 19;
+const UNABLE_TO_GET_ISSUER_CERT_LOCALLY: i32 = 20;
+const UNABLE_TO_VERIFY_LEAF_SIGNATURE: i32 = 21;
+const CERT_REVOKED: i32 = 23;
+
+/// Forwards a line in the `SSLKEYLOGFILE` format OpenSSL produces, `LABEL
+/// <client random> <secret>` in hex, to a rustls key log.
+#[cfg(feature = "dangerous-key-log")]
+fn log_key_line(key_log: &dyn rustls::KeyLog, line: &str) {
+    let unhex = |hex: &str| -> Option<Vec<u8>> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect()
+    };
+    let mut parts = line.split(' ');
+    let (Some(label), Some(client_random), Some(secret), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return;
+    };
+    if let (Some(client_random), Some(secret)) =
+        (unhex(client_random), unhex(secret))
+    {
+        key_log.log(label, &client_random, &secret);
+    }
+}
+
+/// The OpenSSL backend, selected with `with_openssl_backend`.
+pub(crate) struct OpenSsl;
+
+#[derive(Clone)]
+pub(crate) struct OpenSslConfig {
+    acceptor: SslAcceptor,
+    client_auth: ClientAuth,
+    sni: SniCheck,
+}
+
+impl OpenSslConfig {
+    /// Classifies a failed handshake the way rustls errors are.
+    fn error(&self, ssl: &SslRef, err: openssl::ssl::Error) -> HandshakeError {
+        if let Some(err) = err.io_error() {
+            return HandshakeError::Io(io::Error::new(
+                err.kind(),
+                err.to_string(),
+            ));
+        }
+        if !self.sni.allows(ssl.servername(NameType::HOST_NAME)) {
+            return HandshakeError::ServerNameRejected;
+        }
+        match ssl.verify_result().as_raw() {
+            0 if ssl.peer_certificate().is_none()
+                && self.client_auth == ClientAuth::Required =>
+            {
+                HandshakeError::NoClientCert
+            }
+            UNABLE_TO_GET_ISSUER_CERT
+            | DEPTH_ZERO_SELF_SIGNED_CERT
+            | SELF_SIGNED_CERT_IN_CHAIN
+            | UNABLE_TO_GET_ISSUER_CERT_LOCALLY
+            | UNABLE_TO_VERIFY_LEAF_SIGNATURE => HandshakeError::UnknownCa,
+            CERT_SIGNATURE_FAILURE => HandshakeError::BadSignature,
+            CERT_NOT_YET_VALID => HandshakeError::NotValidYet,
+            CERT_HAS_EXPIRED => HandshakeError::Expired,
+            CERT_REVOKED => HandshakeError::Revoked,
+            _ => HandshakeError::OpenSsl(err),
+        }
+    }
+
+    async fn accept<IO>(self, stream: IO) -> io::Result<SslStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let ssl =
+            Ssl::new(self.acceptor.context()).map_err(io::Error::other)?;
+        let mut stream =
+            SslStream::new(ssl, stream).map_err(io::Error::other)?;
+        if let Err(err) = Pin::new(&mut stream).accept().await {
+            let err = self.error(stream.ssl(), err);
+            return Err(io::Error::other(err));
+        }
+        Ok(stream)
+    }
+}
+
+impl TlsVersion {
+    fn openssl_version(self) -> SslVersion {
+        match self {
+            Self::Tls12 => SslVersion::TLS1_2,
+            Self::Tls13 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+impl MtlServer {
+    /// Rejects the settings the OpenSSL backend can't honor, rather than
+    /// serving with weaker semantics than configured.
+    fn check_openssl_settings(&self, diagnostics: bool) -> Result<(), Error> {
+        if diagnostics && self.diagnostics_host.is_some() {
+            return Err(BackendUnsupportedError("diagnostics host"));
+        }
+        if self.alpn_mismatch == AlpnMismatch::FallBack {
+            return Err(BackendUnsupportedError("ALPN fallback"));
+        }
+        if self.max_fragment_size.is_some() {
+            return Err(BackendUnsupportedError("max fragment size"));
+        }
+        if self.time_provider.is_some() {
+            return Err(BackendUnsupportedError("time provider"));
+        }
+        if self.store_identity.is_some() {
+            return Err(BackendUnsupportedError("a key kept in a store"));
+        }
+        Ok(())
+    }
+
+    fn create_openssl_config(
+        &self,
+        client_auth: ClientAuth,
+        diagnostics: bool,
+    ) -> Result<OpenSslConfig, Error> {
+        self.check_openssl_settings(diagnostics)?;
+        self.check_tls_versions()?;
+        self.handle.renewal.check()?;
+        let (min, max) = self.tls_versions;
+
+        let mut builder =
+            SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+                .map_err(OpenSslConfigError)?;
+        builder
+            .set_min_proto_version(Some(min.openssl_version()))
+            .map_err(OpenSslConfigError)?;
+        builder
+            .set_max_proto_version(Some(max.openssl_version()))
+            .map_err(OpenSslConfigError)?;
+
+        let server_cert = self.load_server_cert()?;
+        let server_key = self.load_server_key()?;
+        self.handle.set_server_chain(&server_cert);
+        if let Some(leaf) = server_cert.first() {
+            self.check_key_type(leaf, &server_key)?;
+        }
+        // Without a leaf, checking the private key fails below.
+        for (i, cert) in server_cert.iter().enumerate() {
+            let cert = x509(cert)?;
+            let added = match i {
+                0 => builder.set_certificate(&cert),
+                _ => builder.add_extra_chain_cert(cert),
+            };
+            added.map_err(OpenSslConfigError)?;
+        }
+        let key = match server_key.der() {
+            Some(PrivateKeyDer::Pkcs8(key)) => {
+                PKey::private_key_from_pkcs8(key.secret_pkcs8_der())
+            }
+            Some(key) => PKey::private_key_from_der(key.secret_der()),
+            None => {
+                return Err(BackendUnsupportedError("a key kept in a store"))
+            }
+        };
+        let key = key.map_err(OpenSslConfigError)?;
+        builder.set_private_key(&key).map_err(OpenSslConfigError)?;
+        builder.check_private_key().map_err(OpenSslConfigError)?;
+
+        match client_auth {
+            ClientAuth::Disabled => {
+                self.handle.set_trust_anchors(&[]);
+                builder.set_verify(SslVerifyMode::NONE);
+            }
+            ClientAuth::Required | ClientAuth::Optional => {
+                let mut mode = SslVerifyMode::PEER;
+                if client_auth == ClientAuth::Required {
+                    mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+                }
+                builder.set_verify(mode);
+                self.set_openssl_client_cas(&mut builder)?;
+            }
+        }
+
+        if let Some(protocols) = &self.protocols {
+            let protocols = protocols.clone();
+            builder.set_alpn_select_callback(move |_, client| {
+                // The first of our protocols the client offers, like rustls.
+                protocols
+                    .iter()
+                    .find_map(|x| offered(client, x.as_bytes()))
+                    .ok_or(AlpnError::ALERT_FATAL)
+            });
+        }
+
+        let sni = SniCheck {
+            allowed: self.allowed_sni.clone(),
+            missing: self.missing_sni(),
+        };
+        if sni.allowed.is_some() || !sni.missing.completes_handshake() {
+            let check = sni.clone();
+            builder.set_servername_callback(move |ssl, alert| {
+                let server_name = ssl.servername(NameType::HOST_NAME);
+                if check.allows(server_name) {
+                    return Ok(());
+                }
+                debug!("rejected handshake for server name {:?}", server_name);
+                *alert = SslAlert::UNRECOGNIZED_NAME;
+                Err(SniError::ALERT_FATAL)
+            });
+        }
+
+        #[cfg(feature = "dangerous-key-log")]
+        if let Some(key_log) = self.key_log.clone() {
+            warn!("logging TLS secrets, sessions can be decrypted");
+            builder.set_keylog_callback(move |_, line| {
+                log_key_line(&*key_log, line);
+            });
+        }
+
+        Ok(OpenSslConfig {
+            acceptor: builder.build(),
+            client_auth,
+            sni,
+        })
+    }
+
+    /// Trusts the client CA certificates, or the system trust store, and
+    /// sends their subjects as hints like the rustls verifier does.
+    fn set_openssl_client_cas(
+        &self,
+        builder: &mut SslAcceptorBuilder,
+    ) -> Result<(), Error> {
+        #[cfg(feature = "native-roots")]
+        let native_roots = self.native_client_roots;
+        #[cfg(not(feature = "native-roots"))]
+        let native_roots = false;
+
+        let mut store = X509StoreBuilder::new().map_err(OpenSslConfigError)?;
+        let mut hints = Stack::new().map_err(OpenSslConfigError)?;
+        if native_roots {
+            store.set_default_paths().map_err(OpenSslConfigError)?;
+        }
+        let mut anchors = Vec::new();
+        if !native_roots || self.has_client_cas() {
+            for cert in self.load_client_ca_cert()? {
+                let ca = x509(&cert)?;
+                if !native_roots {
+                    let subject = ca.subject_name().to_owned();
+                    hints
+                        .push(subject.map_err(OpenSslConfigError)?)
+                        .map_err(OpenSslConfigError)?;
+                }
+                store.add_cert(ca).map_err(OpenSslConfigError)?;
+                anchors.push(cert);
+            }
+        }
+        self.handle.set_trust_anchors(&anchors);
+        builder.set_cert_store(store.build());
+        builder.set_client_ca_list(hints);
+        Ok(())
+    }
+}
+
+/// The SNI allowlist, checked in the servername callback.
+#[derive(Clone)]
+struct SniCheck {
+    allowed: Option<Arc<SniAllowlist>>,
+    missing: MissingSni,
+}
+
+impl SniCheck {
+    fn allows(&self, server_name: Option<&str>) -> bool {
+        match (server_name, &self.allowed) {
+            (Some(name), Some(allowed)) => allowed.allows(name),
+            (Some(_), None) => true,
+            (None, _) => self.missing.completes_handshake(),
+        }
+    }
+}
+
+impl TlsBackend for OpenSsl {
+    type Config = OpenSslConfig;
+    type Stream<IO>
+        = SslStream<IO>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn config(
+        server: &MtlServer,
+        client_auth: ClientAuth,
+        diagnostics: bool,
+    ) -> Result<OpenSslConfig, Error> {
+        server.create_openssl_config(client_auth, diagnostics)
+    }
+
+    fn accept<IO>(
+        config: OpenSslConfig,
+        stream: IO,
+    ) -> impl Future<Output = io::Result<SslStream<IO>>> + Send
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        config.accept(stream)
+    }
+
+    fn session<IO>(stream: &SslStream<IO>) -> Session
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let ssl = stream.ssl();
+        // On the server side, OpenSSL leaves the leaf out of the chain.
+        let leaf = ssl.peer_certificate();
+        let chain = ssl.peer_cert_chain().into_iter().flatten();
+        let peer_certificates = leaf
+            .iter()
+            .map(|x| &**x)
+            .chain(chain)
+            .filter_map(|x| x.to_der().ok())
+            .map(CertificateDer::from)
+            .collect();
+        let protocol_version = ssl.version2().and_then(|x| match x {
+            SslVersion::TLS1_2 => Some(ProtocolVersion::TLSv1_2),
+            SslVersion::TLS1_3 => Some(ProtocolVersion::TLSv1_3),
+            _ => None,
+        });
+        let cipher_suite = ssl
+            .current_cipher()
+            .map(|x| CipherSuite::from(u16::from_be_bytes(x.protocol_id())));
+        Session {
+            peer_certificates,
+            alpn_protocol: ssl.selected_alpn_protocol().map(Vec::from),
+            server_name: ssl.servername(NameType::HOST_NAME).map(Box::from),
+            protocol_version,
+            cipher_suite,
+        }
+    }
+
+    fn is_diagnostic<IO>(_: &OpenSslConfig, _: &SslStream<IO>) -> bool
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        false
+    }
+
+    /// OpenSSL has no limit on buffered plaintext to set.
+    fn set_buffer_limit<IO>(_: &mut SslStream<IO>, _: usize)
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+    }
+}
+
+/// `protocol` within the wire format ALPN list the client sent.
+fn offered<'a>(mut client: &'a [u8], protocol: &[u8]) -> Option<&'a [u8]> {
+    while let Some((&len, rest)) = client.split_first() {
+        let (name, rest) = rest.split_at_checked(len as usize)?;
+        if name == protocol {
+            return Some(name);
+        }
+        client = rest;
+    }
+    None
+}
+
+fn x509(cert: &CertificateDer<'_>) -> Result<X509, Error> {
+    X509::from_der(cert).map_err(OpenSslConfigError)
+}
+
+#[cfg(all(test, feature = "dangerous-key-log"))]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    type Secret = (String, Vec<u8>, Vec<u8>);
+
+    #[derive(Debug, Default)]
+    struct Secrets(Mutex<Vec<Secret>>);
+
+    impl rustls::KeyLog for Secrets {
+        fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+            let secret = (label.into(), client_random.into(), secret.into());
+            self.0.lock().unwrap().push(secret);
+        }
+    }
+
+    #[test]
+    fn forwards_key_log_lines() {
+        let secrets = Secrets::default();
+        log_key_line(&secrets, "CLIENT_RANDOM 0a0B ff00");
+        // Malformed lines are dropped.
+        for line in [
+            "CLIENT_RANDOM 0a0b",
+            "CLIENT_RANDOM 0a0b ff00 ff",
+            "CLIENT_RANDOM 0x0b ff00",
+            "CLIENT_RANDOM 0a0 ff00",
+        ] {
+            log_key_line(&secrets, line);
+        }
+        assert_eq!(
+            *secrets.0.lock().unwrap(),
+            [("CLIENT_RANDOM".into(), vec![10, 11], vec![255, 0])]
+        );
+    }
+}
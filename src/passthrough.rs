@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_RECORD_LEN: usize = 16 * 1024 + 2048;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Server names whose connections are forwarded without terminating TLS.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Passthrough {
+    routes: HashMap<Box<str>, Box<str>>,
+}
+
+impl Passthrough {
+    pub(crate) fn insert(&mut self, server_name: &str, backend: Box<str>) {
+        self.routes
+            .insert(server_name.to_ascii_lowercase().into(), backend);
+    }
+
+    /// Peeks at the ClientHello and returns the backend for its server
+    /// name, leaving the stream untouched for a local handshake otherwise.
+    pub(crate) async fn route(
+        &self,
+        stream: &TcpStream,
+        timeout: Option<Duration>,
+    ) -> Option<&str> {
+        let peek = peek_server_name(stream);
+        let peeked = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, peek)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => peek.await,
+        };
+        let server_name = match peeked {
+            Ok(server_name) => server_name?,
+            Err(err) => {
+                debug!("error peeking at ClientHello: {:?}", err);
+                return None;
+            }
+        };
+        self.routes
+            .get(server_name.to_ascii_lowercase().as_str())
+            .map(|x| x.as_ref())
+    }
+}
+
+/// Forwards the raw stream to `backend` until either side closes.
+pub(crate) async fn proxy(
+    mut stream: TcpStream,
+    backend: &str,
+) -> io::Result<()> {
+    let mut upstream = TcpStream::connect(backend).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
+
+async fn peek_server_name(stream: &TcpStream) -> io::Result<Option<String>> {
+    let mut buf = vec![0; RECORD_HEADER_LEN];
+    let mut wanted = RECORD_HEADER_LEN;
+    loop {
+        let n = stream.peek(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if n < wanted {
+            // Peeking returns immediately while any data is buffered, so
+            // wait a little for the rest of the record to arrive.
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            continue;
+        }
+        if wanted == RECORD_HEADER_LEN {
+            if buf[0] != CONTENT_TYPE_HANDSHAKE {
+                return Ok(None);
+            }
+            let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            wanted = (RECORD_HEADER_LEN + len).min(MAX_RECORD_LEN);
+            buf.resize(wanted, 0);
+            continue;
+        }
+        return Ok(parse_server_name(&buf[RECORD_HEADER_LEN..]));
+    }
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+fn parse_server_name(handshake: &[u8]) -> Option<String> {
+    let mut hello = Cursor(handshake);
+    if hello.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    hello.take(3)?; // length, possibly beyond the first record
+    hello.take(2 + 32)?; // legacy version, random
+    hello.vec8()?; // session id
+    hello.vec16()?; // cipher suites
+    hello.vec8()?; // compression methods
+
+    let mut extensions = Cursor(hello.vec16()?);
+    while let Some(kind) = extensions.u16() {
+        let data = extensions.vec16()?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Cursor(Cursor(data).vec16()?);
+        while let Some(name_type) = names.u8() {
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(String::from);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::FixtureDir;
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConnection;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// The first TLS record a client connecting to `server_name` sends.
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let fixtures = FixtureDir::new().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let server_name = ServerName::try_from(server_name.to_owned());
+        let mut conn =
+            ClientConnection::new(config, server_name.unwrap()).unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    /// Routes a connection whose client sent `data`.
+    async fn route(passthrough: &Passthrough, data: &[u8]) -> Option<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.write_all(data).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let timeout = Some(Duration::from_secs(5));
+        passthrough.route(&stream, timeout).await.map(String::from)
+    }
+
+    #[test]
+    fn parses_the_server_name_of_a_client_hello() {
+        let hello = client_hello("backend.example");
+        let server_name = parse_server_name(&hello[RECORD_HEADER_LEN..]);
+        assert_eq!(server_name.as_deref(), Some("backend.example"));
+        // IP addresses are not sent as server names.
+        let hello = client_hello("127.0.0.1");
+        assert_eq!(parse_server_name(&hello[RECORD_HEADER_LEN..]), None);
+        assert_eq!(parse_server_name(&hello[RECORD_HEADER_LEN..40]), None);
+    }
+
+    #[tokio::test]
+    async fn routes_by_server_name() {
+        let mut passthrough = Passthrough::default();
+        passthrough.insert("Backend.Example", "10.0.0.1:443".into());
+
+        let hello = client_hello("backend.example");
+        let backend = route(&passthrough, &hello).await;
+        assert_eq!(backend.as_deref(), Some("10.0.0.1:443"));
+        let hello = client_hello("localhost");
+        assert_eq!(route(&passthrough, &hello).await, None);
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(route(&passthrough, request).await, None);
+    }
+}
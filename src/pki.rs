@@ -0,0 +1,647 @@
+use crate::der::{self, Reader};
+use crate::identity::sha256_hex;
+use crate::store::ServerKey;
+use crate::Error::{
+    ClientCaExpiredError, ClientCaParseError, ServerConfigError,
+    ServerKeyMismatchError, ServerKeyTypeMismatchError,
+};
+use crate::{Error, MtlServer, ServerHandle};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use x509_parser::extensions::ParsedExtension;
+use x509_parser::oid_registry::{OID_SIG_ED25519, OID_SIG_ED448};
+use x509_parser::prelude::{FromDer, SubjectPublicKeyInfo, X509Certificate};
+use x509_parser::public_key::PublicKey;
+
+/// A certificate loaded by the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct CertificateInfo {
+    pub subject: Box<str>,
+    pub issuer: Box<str>,
+    /// Unix timestamp in seconds.
+    pub not_before: i64,
+    /// Unix timestamp in seconds.
+    pub not_after: i64,
+    /// Lowercase hex SHA-256 of the DER encoded certificate.
+    pub fingerprint: Box<str>,
+    /// Lowercase hex subject key identifier, if the certificate has one.
+    pub subject_key_id: Option<Box<str>>,
+    /// The key type, e.g. `EC P-256` or `RSA 2048`.
+    pub public_key: Box<str>,
+}
+
+impl CertificateInfo {
+    pub(crate) fn from_cert(cert: &CertificateDer<'_>) -> Option<Self> {
+        let (_, parsed) = X509Certificate::from_der(cert).ok()?;
+        let subject_key_id = parsed.extensions().iter().find_map(|x| {
+            match x.parsed_extension() {
+                ParsedExtension::SubjectKeyIdentifier(id) => {
+                    Some(hex(id.0).into())
+                }
+                _ => None,
+            }
+        });
+
+        Some(Self {
+            subject: parsed.subject().to_string().into(),
+            issuer: parsed.issuer().to_string().into(),
+            not_before: parsed.validity().not_before.timestamp(),
+            not_after: parsed.validity().not_after.timestamp(),
+            fingerprint: sha256_hex(cert).into(),
+            subject_key_id,
+            public_key: describe_key(parsed.public_key()),
+        })
+    }
+}
+
+fn describe_key(spki: &SubjectPublicKeyInfo<'_>) -> Box<str> {
+    let algorithm = &spki.algorithm.algorithm;
+    if *algorithm == OID_SIG_ED25519 {
+        return "Ed25519".into();
+    }
+    if *algorithm == OID_SIG_ED448 {
+        return "Ed448".into();
+    }
+    match spki.parsed() {
+        Ok(PublicKey::RSA(key)) => format!("RSA {}", key.key_size()).into(),
+        Ok(PublicKey::EC(point)) => match point.key_size() {
+            256 => "EC P-256".into(),
+            384 => "EC P-384".into(),
+            528 => "EC P-521".into(),
+            _ => "EC".into(),
+        },
+        _ => algorithm.to_id_string().into(),
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// The certificates the server loaded most recently. With the `serde`
+/// feature it can be serialized, e.g. for an admin endpoint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct PkiInfo {
+    /// The server certificate chain, leaf first.
+    pub server_chain: Vec<CertificateInfo>,
+    /// The CAs client certificates are verified against.
+    pub trust_anchors: Vec<CertificateInfo>,
+}
+
+fn infos(certs: &[CertificateDer<'_>]) -> Vec<CertificateInfo> {
+    certs
+        .iter()
+        .filter_map(CertificateInfo::from_cert)
+        .collect()
+}
+
+impl ServerHandle {
+    /// The certificates in effect, as loaded when serving started or the
+    /// last acceptor was created. Empty before that.
+    pub fn pki(&self) -> PkiInfo {
+        self.pki.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_server_chain(&self, certs: &[CertificateDer<'_>]) {
+        let chain = infos(certs);
+        self.metrics
+            .server_cert_loaded(chain.first().map(|x| x.not_after));
+        self.pki.lock().unwrap().server_chain = chain;
+    }
+
+    /// Records the client CAs, noting a rotation when they replace a
+    /// different set.
+    pub(crate) fn set_trust_anchors(&self, certs: &[CertificateDer<'_>]) {
+        let anchors = infos(certs);
+        let previous = std::mem::replace(
+            &mut self.pki.lock().unwrap().trust_anchors,
+            anchors.clone(),
+        );
+        self.anchors_replaced(&previous, &anchors);
+    }
+
+    /// Records the certificates staged by a reload.
+    pub(crate) fn replace_pki(&self, pki: PkiInfo) {
+        let anchors = pki.trust_anchors.clone();
+        self.metrics
+            .server_cert_loaded(pki.server_chain.first().map(|x| x.not_after));
+        let previous = std::mem::replace(&mut *self.pki.lock().unwrap(), pki);
+        self.anchors_replaced(&previous.trust_anchors, &anchors);
+    }
+
+    fn anchors_replaced(
+        &self,
+        previous: &[CertificateInfo],
+        anchors: &[CertificateInfo],
+    ) {
+        let fingerprints = |x: &[CertificateInfo]| {
+            x.iter().map(|x| x.fingerprint.clone()).collect::<Vec<_>>()
+        };
+        if !previous.is_empty()
+            && !anchors.is_empty()
+            && fingerprints(previous) != fingerprints(anchors)
+        {
+            info!(client_cas = anchors.len(), "client CAs changed");
+            self.metrics.trust_store_swapped();
+        }
+    }
+}
+
+/// Puts the server chain in the order TLS requires, leaf first and each
+/// certificate followed by its issuer, warning when it wasn't, e.g. because
+/// the PEM file lists the root first. Certificates that aren't part of the
+/// leaf's chain go last. Chains that don't parse are left alone.
+pub(crate) fn order_chain(
+    chain: Vec<CertificateDer<'static>>,
+) -> Vec<CertificateDer<'static>> {
+    let parsed: Option<Vec<_>> = chain
+        .iter()
+        .map(|x| X509Certificate::from_der(x).ok().map(|x| x.1))
+        .collect();
+    let Some(parsed) = parsed else {
+        return chain;
+    };
+    let issues = |issuer: &X509Certificate<'_>, cert: &X509Certificate<'_>| {
+        issuer.subject().as_raw() == cert.issuer().as_raw()
+            && issuer.subject().as_raw() != cert.subject().as_raw()
+    };
+    // The leaf issued none of the others; prefer end-entity certificates
+    // should unrelated ones be mixed in.
+    let leaves: Vec<usize> = (0..parsed.len())
+        .filter(|i| !parsed.iter().any(|x| issues(&parsed[*i], x)))
+        .collect();
+    let Some(&leaf) = leaves
+        .iter()
+        .find(|i| !parsed[**i].is_ca())
+        .or(leaves.first())
+    else {
+        return chain;
+    };
+
+    let mut order = vec![leaf];
+    while let Some(issuer) = (0..parsed.len()).find(|i| {
+        !order.contains(i)
+            && issues(&parsed[*i], &parsed[order[order.len() - 1]])
+    }) {
+        order.push(issuer);
+    }
+    let unrelated: Vec<usize> =
+        (0..parsed.len()).filter(|i| !order.contains(i)).collect();
+    if !unrelated.is_empty() {
+        warn!(
+            certificates = ?unrelated
+                .iter()
+                .map(|i| parsed[*i].subject().to_string())
+                .collect::<Vec<_>>(),
+            "server chain has certificates not issuing the leaf"
+        );
+    }
+    order.extend(unrelated);
+    if order.iter().copied().eq(0..parsed.len()) {
+        return chain;
+    }
+
+    warn!(
+        leaf = %parsed[leaf].subject(),
+        "server chain is out of order, serving it reordered leaf first"
+    );
+    let mut chain: Vec<_> = chain.into_iter().map(Some).collect();
+    order.iter().filter_map(|i| chain[*i].take()).collect()
+}
+
+// Key algorithm and named curve OIDs, DER encoded.
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 1, 1, 1];
+const RSASSA_PSS: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 1, 1, 10];
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 2, 1];
+const P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 3, 1, 7];
+const P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const P521: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const ED448: &[u8] = &[0x2b, 0x65, 0x71];
+
+/// The algorithm of a key pair, as far as it decides whether a key can
+/// belong to a certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct KeyAlgorithm<'a> {
+    oid: &'a [u8],
+    /// The named curve of EC keys, if stated.
+    curve: Option<&'a [u8]>,
+}
+
+impl<'a> KeyAlgorithm<'a> {
+    /// Reads an AlgorithmIdentifier, as found in SubjectPublicKeyInfo and
+    /// PKCS#8.
+    fn read(reader: &mut Reader<'a>) -> Option<Self> {
+        let mut identifier = reader.expect(der::SEQUENCE)?.reader();
+        let oid = identifier.expect(der::OID)?.contents;
+        let curve = identifier.optional(der::OID).map(|x| x.contents);
+        // RSA-PSS certificates are commonly paired with plain RSA keys.
+        let oid = if oid == RSASSA_PSS {
+            RSA_ENCRYPTION
+        } else {
+            oid
+        };
+        Some(Self { oid, curve })
+    }
+
+    fn of_cert(cert: &'a X509Certificate<'a>) -> Option<Self> {
+        let mut spki = Reader::new(cert.public_key().raw);
+        Self::read(&mut spki.expect(der::SEQUENCE)?.reader())
+    }
+
+    fn of_key(key: &'a PrivateKeyDer<'_>) -> Option<Self> {
+        match key {
+            PrivateKeyDer::Pkcs1(_) => Some(Self {
+                oid: RSA_ENCRYPTION,
+                curve: None,
+            }),
+            PrivateKeyDer::Sec1(key) => {
+                let mut reader = Reader::new(key.secret_sec1_der());
+                let mut key = reader.expect(der::SEQUENCE)?.reader();
+                key.expect(der::INTEGER)?;
+                key.expect(der::OCTET_STRING)?;
+                let curve = key
+                    .optional(der::context(0))
+                    .and_then(|x| x.reader().expect(der::OID))
+                    .map(|x| x.contents);
+                Some(Self {
+                    oid: EC_PUBLIC_KEY,
+                    curve,
+                })
+            }
+            PrivateKeyDer::Pkcs8(key) => {
+                let mut reader = Reader::new(key.secret_pkcs8_der());
+                let mut key = reader.expect(der::SEQUENCE)?.reader();
+                key.expect(der::INTEGER)?;
+                Self::read(&mut key)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a key of this algorithm can belong to a certificate of
+    /// `other`. Curves only count when both state one.
+    fn matches(&self, other: &Self) -> bool {
+        self.oid == other.oid
+            && match (self.curve, other.curve) {
+                (Some(a), Some(b)) if self.oid == EC_PUBLIC_KEY => a == b,
+                _ => true,
+            }
+    }
+
+    fn name(&self) -> Box<str> {
+        match (self.oid, self.curve) {
+            (RSA_ENCRYPTION, _) => "RSA".into(),
+            (EC_PUBLIC_KEY, Some(P256)) => "EC P-256".into(),
+            (EC_PUBLIC_KEY, Some(P384)) => "EC P-384".into(),
+            (EC_PUBLIC_KEY, Some(P521)) => "EC P-521".into(),
+            (EC_PUBLIC_KEY, _) => "EC".into(),
+            (ED25519, _) => "Ed25519".into(),
+            (ED448, _) => "Ed448".into(),
+            _ => "unknown".into(),
+        }
+    }
+}
+
+impl MtlServer {
+    /// Leaves expired, unparsable and duplicate certificates out of the
+    /// client CA bundle, or fails on the first two in strict mode. Reports
+    /// end-entity certificates, which usually got into the bundle by
+    /// mistake.
+    pub(crate) fn usable_client_cas(
+        &self,
+        certs: Vec<CertificateDer<'static>>,
+    ) -> Result<Vec<CertificateDer<'static>>, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs() as i64);
+        let mut usable = Vec::with_capacity(certs.len());
+        let mut skipped = 0;
+        for (i, cert) in certs.into_iter().enumerate() {
+            let parsed = match X509Certificate::from_der(&cert) {
+                Ok((_, parsed)) => parsed,
+                Err(_) if self.strict_client_cas => {
+                    return Err(ClientCaParseError(i + 1));
+                }
+                Err(err) => {
+                    warn!(
+                        "skipping client CA #{} that can't be parsed: {}",
+                        i + 1,
+                        err
+                    );
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let subject = parsed.subject().to_string();
+            if parsed.validity().not_after.timestamp() < now {
+                if self.strict_client_cas {
+                    return Err(ClientCaExpiredError(subject.into()));
+                }
+                warn!("skipping expired client CA {}", subject);
+                skipped += 1;
+                continue;
+            }
+            if usable.contains(&cert) {
+                warn!("skipping duplicate client CA {}", subject);
+                skipped += 1;
+                continue;
+            }
+            if !parsed.is_ca() {
+                let server = parsed
+                    .extended_key_usage()
+                    .ok()
+                    .flatten()
+                    .is_some_and(|x| x.value.server_auth);
+                let kind = if server {
+                    "a server certificate"
+                } else if parsed.subject() == parsed.issuer() {
+                    "a self-signed end-entity certificate"
+                } else {
+                    "an end-entity certificate"
+                };
+                warn!(
+                    "client CA bundle contains {}, which is {} rather than a CA",
+                    subject, kind
+                );
+            }
+            usable.push(cert);
+        }
+        self.handle.metrics.client_cas_loaded(skipped);
+        Ok(usable)
+    }
+
+    /// Fails if `key` is of another algorithm than the key of `cert`, e.g.
+    /// an RSA key for an EC certificate, naming both and their files. rustls
+    /// and OpenSSL only report that the key doesn't match. Keys kept in a
+    /// store are left to the signing check.
+    pub(crate) fn check_key_type(
+        &self,
+        cert: &CertificateDer<'_>,
+        key: &ServerKey,
+    ) -> Result<(), Error> {
+        let (Some(key), Ok((_, parsed))) =
+            (key.der(), X509Certificate::from_der(cert))
+        else {
+            return Ok(());
+        };
+        let (Some(cert_algorithm), Some(key_algorithm)) =
+            (KeyAlgorithm::of_cert(&parsed), KeyAlgorithm::of_key(key))
+        else {
+            return Ok(());
+        };
+        if key_algorithm.matches(&cert_algorithm) {
+            return Ok(());
+        }
+        Err(ServerKeyTypeMismatchError {
+            key: key_algorithm.name(),
+            key_file: match self.key {
+                Some(_) => "given to with_key".into(),
+                None => self.server_key_path.clone(),
+            },
+            cert: describe_key(parsed.public_key()),
+            cert_file: match self.cert_chain {
+                Some(_) => "given to with_cert_chain".into(),
+                None => self.server_cert_path.clone(),
+            },
+        })
+    }
+
+    /// Fails unless `key` belongs to `cert`, by signing a message with the
+    /// key and verifying the signature with the certificate's public key.
+    pub(crate) fn check_key_pair(
+        &self,
+        cert: &CertificateDer<'_>,
+        key: &ServerKey,
+    ) -> Result<(), Error> {
+        const MESSAGE: &[u8] = b"hyper-mtls-server key pair check";
+        self.check_key_type(cert, key)?;
+        let provider = self.provider();
+        let algorithms = provider.signature_verification_algorithms;
+        let signer = key
+            .signing_key(&provider)?
+            .choose_scheme(&algorithms.supported_schemes());
+        // rustls reports keys and certificates it can't use by itself.
+        let (Some(signer), Ok((_, parsed))) =
+            (signer, X509Certificate::from_der(cert))
+        else {
+            return Ok(());
+        };
+
+        let signature = signer.sign(MESSAGE).map_err(ServerConfigError)?;
+        let public_key = &parsed.public_key().subject_public_key.data;
+        let verified = algorithms
+            .mapping
+            .iter()
+            .filter(|x| x.0 == signer.scheme())
+            .flat_map(|x| x.1.iter())
+            .any(|x| {
+                x.verify_signature(public_key, MESSAGE, &signature).is_ok()
+            });
+        if !verified {
+            return Err(ServerKeyMismatchError(
+                parsed.subject().to_string().into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::order_chain;
+    use crate::testing::fixtures::{
+        FixtureDir, ALICE_CERT, CA_CERT, INTERMEDIATE_CERT, SERVER_CERT,
+    };
+    use crate::Error::{
+        ClientCaExpiredError, ClientCaParseError, ServerKeyTypeMismatchError,
+    };
+    use crate::{ClientAuth, MtlServer, PkiInfo};
+    use rcgen::{
+        date_time_ymd, BasicConstraints, CertificateParams, DnType, IsCa,
+        KeyPair, PKCS_ECDSA_P384_SHA384, PKCS_ED25519,
+    };
+    use rustls_pki_types::CertificateDer;
+
+    fn certs(pem: &str) -> Vec<CertificateDer<'static>> {
+        let mut pem = pem.as_bytes();
+        rustls_pemfile::certs(&mut pem)
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn lists_the_loaded_certificates() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let handle = server.handle();
+        assert_eq!(handle.pki(), PkiInfo::default());
+
+        server.mtls_acceptor().unwrap();
+        let pki = handle.pki();
+        let [leaf, intermediate] = &pki.server_chain[..] else {
+            panic!("unexpected server chain {:?}", pki.server_chain);
+        };
+        assert!(leaf.subject.ends_with("CN=localhost"));
+        assert_eq!(leaf.issuer, intermediate.subject);
+        assert_eq!(leaf.subject_key_id, None);
+        // 2025-01-01 to 2125-01-01.
+        assert_eq!((leaf.not_before, leaf.not_after), (1735689600, 4891363200));
+        let [anchor] = &pki.trust_anchors[..] else {
+            panic!("unexpected trust anchors {:?}", pki.trust_anchors);
+        };
+        assert!(anchor.subject.ends_with("CN=Fixture Root CA"));
+        assert_eq!(
+            &*anchor.fingerprint,
+            "0df1e7bb76cdabe4212d90d89923ef08ba3d604052de3bcabf91069bf73b5287"
+        );
+        assert_eq!(
+            anchor.subject_key_id.as_deref(),
+            Some("996de5a81798e99bae09bb95b949e8427d7b1b70")
+        );
+
+        let server = server.with_client_auth(ClientAuth::Disabled);
+        server.mtls_acceptor().unwrap();
+        assert!(handle.pki().trust_anchors.is_empty());
+    }
+
+    #[test]
+    fn skips_expired_and_unparsable_client_cas_unless_strict() {
+        let fixtures = FixtureDir::new().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "expired");
+        params.not_after = date_time_ymd(2020, 1, 1);
+        let key = KeyPair::generate().unwrap();
+        let expired = params.self_signed(&key).unwrap().der().clone();
+        let ca = certs(CA_CERT).remove(0);
+        let garbage = CertificateDer::from(vec![1, 2, 3]);
+
+        let server = fixtures.server();
+        let bundle = vec![expired.clone(), ca.clone(), garbage.clone()];
+        let usable = server.usable_client_cas(bundle).unwrap();
+        assert_eq!(usable, std::slice::from_ref(&ca));
+        assert_eq!(server.handle().metrics().client_cas_skipped, 2);
+
+        let server = server.with_strict_client_cas();
+        assert!(matches!(
+            server.usable_client_cas(vec![ca.clone(), expired]),
+            Err(ClientCaExpiredError(x)) if &*x == "CN=expired"
+        ));
+        assert!(matches!(
+            server.usable_client_cas(vec![ca, garbage]),
+            Err(ClientCaParseError(2))
+        ));
+    }
+
+    #[test]
+    fn skipped_client_cas_reflect_the_last_load() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let bundle = [CA_CERT, CA_CERT, INTERMEDIATE_CERT].concat();
+        let path = fixtures.path().join("bundle.crt");
+        std::fs::write(&path, bundle).unwrap();
+        let certs = MtlServer::load_cert(path.to_str().unwrap()).unwrap();
+
+        for _ in 0..3 {
+            let usable = server.usable_client_cas(certs.clone()).unwrap();
+            assert_eq!(usable.len(), 2);
+            assert_eq!(server.handle().metrics().client_cas_skipped, 1);
+        }
+
+        server.usable_client_cas(certs[..1].to_vec()).unwrap();
+        assert_eq!(server.handle().metrics().client_cas_skipped, 0);
+    }
+
+    #[test]
+    fn names_both_algorithms_of_mismatched_keys() {
+        let fixtures = FixtureDir::new().unwrap();
+        let key_file = fixtures.file("server.key");
+        for (algorithm, name) in [
+            (&PKCS_ECDSA_P384_SHA384, "EC P-384"),
+            (&PKCS_ED25519, "Ed25519"),
+        ] {
+            let key = KeyPair::generate_for(algorithm).unwrap();
+            std::fs::write(&*key_file, key.serialize_pem()).unwrap();
+            let err = fixtures.server().mtls_acceptor().err().unwrap();
+            let ServerKeyTypeMismatchError {
+                key,
+                key_file: file,
+                cert,
+                cert_file,
+            } = err
+            else {
+                panic!("unexpected error {:?}", err);
+            };
+            assert_eq!((&*key, &*cert), (name, "EC P-256"));
+            assert_eq!(file, key_file);
+            assert_eq!(cert_file, fixtures.file("server.crt"));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn reports_end_entity_client_cas() {
+        let recorder = crate::testing::Recorder::default();
+        let _guard = recorder.install();
+        let fixtures = FixtureDir::new().unwrap();
+        let bundle = [CA_CERT, SERVER_CERT, ALICE_CERT].concat();
+        let bundle = certs(&bundle);
+        let usable = fixtures.server().usable_client_cas(bundle).unwrap();
+        // Reported, but still trusted as configured.
+        assert_eq!(usable.len(), 4);
+
+        let events = recorder.events("hyper_mtls_server::pki");
+        let messages: Vec<_> =
+            events.iter().filter_map(|x| x.field("message")).collect();
+        // Both chains end with the intermediate.
+        let [server, alice, duplicate] = &messages[..] else {
+            panic!("unexpected warnings {:?}", messages);
+        };
+        assert!(server.contains("CN=localhost"));
+        assert!(
+            server.ends_with("which is a server certificate rather than a CA")
+        );
+        assert!(alice.contains("CN=alice"));
+        assert!(alice
+            .ends_with("which is an end-entity certificate rather than a CA"));
+        assert!(duplicate.starts_with("skipping duplicate client CA"));
+    }
+
+    #[test]
+    fn orders_the_server_chain_leaf_first() {
+        let [leaf, intermediate] = &certs(SERVER_CERT)[..] else {
+            panic!("unexpected fixture chain");
+        };
+        let ca = &certs(CA_CERT)[0];
+        let ordered = vec![leaf.clone(), intermediate.clone(), ca.clone()];
+        assert_eq!(order_chain(ordered.clone()), ordered);
+        let reversed = ordered.iter().rev().cloned().collect();
+        assert_eq!(order_chain(reversed), ordered);
+
+        // Certificates outside the leaf's chain go last.
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let unrelated = params.self_signed(&key).unwrap().der().clone();
+        let chain = vec![unrelated.clone(), intermediate.clone(), leaf.clone()];
+        assert_eq!(
+            order_chain(chain),
+            [leaf.clone(), intermediate.clone(), unrelated]
+        );
+
+        // Chains that don't parse are left alone.
+        let garbage = vec![CertificateDer::from(vec![1, 2, 3]), leaf.clone()];
+        assert_eq!(order_chain(garbage.clone()), garbage);
+    }
+}
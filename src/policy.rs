@@ -0,0 +1,175 @@
+use crate::diagnostics::json_string;
+use crate::identity::sha256_hex;
+use crate::{ClientAuth, Error, MtlServer, TlsVersion};
+#[cfg(feature = "native-roots")]
+use rustls::RootCertStore;
+use rustls_pki_types::CertificateDer;
+
+/// The TLS policy a server enforces, for tools that detect configuration
+/// drift between deployments or scan them for weak settings. Rendered as
+/// JSON by [`to_json`](Self::to_json); with the `serde` feature it can be
+/// serialized directly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct TlsPolicy {
+    pub tls_versions: Vec<TlsVersion>,
+    /// IANA names of the enabled cipher suites in order of preference, e.g.
+    /// `TLS13_AES_256_GCM_SHA384`. Empty on the OpenSSL backend, which
+    /// leaves the suites to OpenSSL.
+    pub cipher_suites: Vec<Box<str>>,
+    pub alpn_protocols: Vec<Box<str>>,
+    pub client_auth: ClientAuth,
+    /// Lowercase hex SHA-256 of the CAs trusted to issue client
+    /// certificates.
+    pub trust_anchors: Vec<Box<str>>,
+}
+
+impl TlsPolicy {
+    /// Renders the policy as a JSON object with the same fields, versions
+    /// as `"1.2"` and `"1.3"`.
+    pub fn to_json(&self) -> String {
+        let versions = self.tls_versions.iter().map(|x| match x {
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        });
+        let client_auth = match self.client_auth {
+            ClientAuth::Required => "required",
+            ClientAuth::Optional => "optional",
+            ClientAuth::Disabled => "disabled",
+        };
+
+        format!(
+            "{{\"tls_versions\":{},\"cipher_suites\":{},\
+             \"alpn_protocols\":{},\"client_auth\":{},\"trust_anchors\":{}}}",
+            json_array(versions),
+            json_array(self.cipher_suites.iter().map(|x| &**x)),
+            json_array(self.alpn_protocols.iter().map(|x| &**x)),
+            json_string(client_auth),
+            json_array(self.trust_anchors.iter().map(|x| &**x)),
+        )
+    }
+}
+
+fn json_array<'a>(values: impl IntoIterator<Item = &'a str>) -> String {
+    let values: Vec<String> = values.into_iter().map(json_string).collect();
+    format!("[{}]", values.join(","))
+}
+
+impl MtlServer {
+    /// The TLS policy serving would enforce, loading the client CAs to
+    /// fingerprint them. Call it again after a reload to see the new CAs.
+    pub fn tls_policy(&self) -> Result<TlsPolicy, Error> {
+        self.check_tls_versions()?;
+        let tls_versions = self.enabled_tls_versions();
+        let trust_anchors = match self.client_auth {
+            ClientAuth::Disabled => Vec::new(),
+            ClientAuth::Required | ClientAuth::Optional => self
+                .client_trust_anchors()?
+                .iter()
+                .map(|x| sha256_hex(x).into())
+                .collect(),
+        };
+        Ok(TlsPolicy {
+            cipher_suites: self.cipher_suite_names(&tls_versions),
+            tls_versions,
+            alpn_protocols: self.alpn_names(),
+            client_auth: self.client_auth,
+            trust_anchors,
+        })
+    }
+
+    pub(crate) fn client_trust_anchors(
+        &self,
+    ) -> Result<Vec<CertificateDer<'static>>, Error> {
+        #[cfg(feature = "native-roots")]
+        let native_roots = self.native_client_roots;
+        #[cfg(not(feature = "native-roots"))]
+        let native_roots = false;
+
+        let mut anchors = Vec::new();
+        #[cfg(feature = "native-roots")]
+        if native_roots {
+            anchors = Self::add_native_roots(&mut RootCertStore::empty())?;
+        }
+        if !native_roots || self.has_client_cas() {
+            anchors.extend(self.load_client_ca_cert()?);
+        }
+        Ok(anchors)
+    }
+
+    fn cipher_suite_names(&self, versions: &[TlsVersion]) -> Vec<Box<str>> {
+        #[cfg(feature = "openssl")]
+        if self.openssl_backend {
+            return Vec::new();
+        }
+        let versions: Vec<_> = versions
+            .iter()
+            .map(|x| x.rustls_version().version)
+            .collect();
+        self.provider()
+            .cipher_suites
+            .iter()
+            .filter(|x| versions.contains(&x.version().version))
+            .map(|x| match x.suite().as_str() {
+                Some(name) => name.into(),
+                None => format!("{:?}", x.suite()).into(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::FixtureDir;
+
+    const CA_SHA256: &str =
+        "0df1e7bb76cdabe4212d90d89923ef08ba3d604052de3bcabf91069bf73b5287";
+
+    #[test]
+    fn describes_the_enforced_policy() {
+        let fixtures = FixtureDir::new().unwrap();
+        let policy = fixtures
+            .server()
+            .with_tls_versions(TlsVersion::Tls13, TlsVersion::Tls13)
+            .tls_policy()
+            .unwrap();
+        assert_eq!(policy.tls_versions, [TlsVersion::Tls13]);
+        assert!(!policy.cipher_suites.is_empty());
+        assert!(policy.cipher_suites.iter().all(|x| x.starts_with("TLS13_")));
+        assert_eq!(policy.alpn_protocols, ["http/1.1".into(), "h2".into()]);
+        assert_eq!(policy.client_auth, ClientAuth::Required);
+        assert_eq!(policy.trust_anchors, [CA_SHA256.into()]);
+
+        let policy = MtlServer::new_without_client_auth(
+            fixtures.file("server.crt"),
+            fixtures.file("server.key"),
+        )
+        .tls_policy()
+        .unwrap();
+        assert_eq!(policy.tls_versions.len(), 2);
+        assert!(policy.trust_anchors.is_empty());
+    }
+
+    #[test]
+    fn renders_the_policy_as_json() {
+        let policy = TlsPolicy {
+            tls_versions: vec![TlsVersion::Tls12, TlsVersion::Tls13],
+            cipher_suites: vec!["TLS13_AES_256_GCM_SHA384".into()],
+            alpn_protocols: vec!["h2".into()],
+            client_auth: ClientAuth::Optional,
+            trust_anchors: vec![CA_SHA256.into()],
+        };
+        assert_eq!(
+            policy.to_json(),
+            format!(
+                "{{\"tls_versions\":[\"1.2\",\"1.3\"],\
+                 \"cipher_suites\":[\"TLS13_AES_256_GCM_SHA384\"],\
+                 \"alpn_protocols\":[\"h2\"],\"client_auth\":\"optional\",\
+                 \"trust_anchors\":[\"{}\"]}}",
+                CA_SHA256
+            )
+        );
+    }
+}
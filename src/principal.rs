@@ -0,0 +1,107 @@
+use crate::ClientIdentity;
+use hyper::http::Extensions;
+use std::fmt;
+use std::sync::Arc;
+
+/// Returned by an identity mapper to refuse a client certificate. The
+/// connection is closed after the handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    reason: Box<str>,
+}
+
+impl Rejection {
+    pub fn new(reason: impl Into<Box<str>>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+pub(crate) type IdentityMapper =
+    dyn Fn(&ClientIdentity) -> Result<MappedPrincipal, Rejection> + Send + Sync;
+
+/// The application principal of a connection, with its type erased.
+#[derive(Clone)]
+pub(crate) struct MappedPrincipal(Arc<dyn Fn(&mut Extensions) + Send + Sync>);
+
+impl MappedPrincipal {
+    pub(crate) fn new<P: Clone + Send + Sync + 'static>(principal: P) -> Self {
+        Self(Arc::new(move |extensions| {
+            extensions.insert(principal.clone());
+        }))
+    }
+
+    pub(crate) fn insert_into(&self, extensions: &mut Extensions) {
+        (self.0)(extensions)
+    }
+}
+
+impl fmt::Debug for MappedPrincipal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedPrincipal").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::testing::send;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::{Bytes, Incoming};
+    use hyper::{Request, Response};
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    #[derive(Clone)]
+    struct User(&'static str);
+
+    #[tokio::test]
+    async fn adds_the_principal_to_requests_or_rejects_the_client() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_identity_mapper(|identity| match identity.common_name() {
+                Some("alice") => Ok(User("user-1")),
+                _ => Err(Rejection::new("unknown client")),
+            });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|req: Request<Incoming>| {
+                let User(user) = req.extensions().get().unwrap();
+                let body = Full::<Bytes>::from(*user);
+                async move { Ok::<_, Infallible>(Response::new(body)) }
+            });
+            server.serve_service(listener, service).await
+        });
+
+        let get = || Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let response = send(config, addr, get()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "user-1");
+        let config = fixtures.client_config("bob").unwrap();
+        assert!(send(config, addr, get()).await.is_err());
+        assert_eq!(handle.metrics().connections_rejected, 1);
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+}
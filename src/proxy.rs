@@ -0,0 +1,299 @@
+use crate::Error::ProxyUpstreamError;
+use crate::{ConnInfo, Error, MtlsConnector};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, HOST};
+use hyper::http::uri::{Authority, PathAndQuery, Scheme};
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+pub type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+const X_FORWARDED_CLIENT_CERT: HeaderName =
+    HeaderName::from_static("x-forwarded-client-cert");
+
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Forwards requests to an upstream server over a new mutual TLS
+/// connection, passing the identity of the original client in the
+/// `x-forwarded-client-cert` (XFCC) header. Any XFCC header sent by the
+/// client is replaced. Upstream failures are answered with
+/// `502 Bad Gateway`.
+#[derive(Clone)]
+pub struct ReverseProxy {
+    client: Client<MtlsConnector, Incoming>,
+    scheme: Scheme,
+    authority: Authority,
+}
+
+impl ReverseProxy {
+    /// `upstream` is the `https://host:port` base URI to forward to. The
+    /// proxy authenticates with the client certificate and key at the given
+    /// paths and trusts upstream certificates issued by the CA at
+    /// `upstream_ca_cert_path`.
+    pub fn new(
+        upstream: Uri,
+        client_cert_path: &str,
+        client_key_path: &str,
+        upstream_ca_cert_path: &str,
+    ) -> Result<Self, Error> {
+        let connector = MtlsConnector::new(
+            client_cert_path.into(),
+            client_key_path.into(),
+            upstream_ca_cert_path.into(),
+        )?;
+        Self::with_connector(upstream, connector)
+    }
+
+    /// Like [`ReverseProxy::new`], with a connector that may be shared
+    /// with other clients and reloaded.
+    pub fn with_connector(
+        upstream: Uri,
+        connector: MtlsConnector,
+    ) -> Result<Self, Error> {
+        let authority = match (upstream.scheme_str(), upstream.authority()) {
+            (Some("https"), Some(authority)) => authority.clone(),
+            _ => return Err(ProxyUpstreamError),
+        };
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+
+        Ok(Self {
+            client,
+            scheme: Scheme::HTTPS,
+            authority,
+        })
+    }
+
+    fn upstream_request(
+        &self,
+        mut req: Request<Incoming>,
+    ) -> Result<Request<Incoming>, hyper::http::Error> {
+        let path = req
+            .uri()
+            .path_and_query()
+            .cloned()
+            .unwrap_or_else(|| PathAndQuery::from_static("/"));
+        *req.uri_mut() = Uri::builder()
+            .scheme(self.scheme.clone())
+            .authority(self.authority.clone())
+            .path_and_query(path)
+            .build()?;
+
+        let headers = req.headers_mut();
+        let connection_headers: Vec<HeaderName> = headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .filter_map(|x| x.trim().parse().ok())
+            .collect();
+        for name in connection_headers {
+            headers.remove(name);
+        }
+        for name in HOP_BY_HOP {
+            headers.remove(name);
+        }
+        headers.remove(HOST);
+        headers.remove(X_FORWARDED_CLIENT_CERT);
+
+        let xfcc = req.extensions().get::<ConnInfo>().and_then(xfcc);
+        if let Some(xfcc) = xfcc {
+            req.headers_mut().insert(X_FORWARDED_CLIENT_CERT, xfcc);
+        }
+        Ok(req)
+    }
+}
+
+/// Formats the client identity the way Envoy does, e.g.
+/// `Hash=...;Subject="CN=client";URI=spiffe://...`.
+fn xfcc(conn_info: &ConnInfo) -> Option<HeaderValue> {
+    let identity = conn_info.client_identity()?;
+    let quote = |x: &str| x.replace('\\', "\\\\").replace('"', "\\\"");
+
+    let mut value = format!(
+        "Hash={};Subject=\"{}\"",
+        identity.fingerprint(),
+        quote(identity.subject())
+    );
+    for uri in identity.uris() {
+        value.push_str(&format!(";URI={}", uri));
+    }
+    for dns in identity.dns_names() {
+        value.push_str(&format!(";DNS={}", dns));
+    }
+    HeaderValue::from_str(&value).ok()
+}
+
+fn bad_gateway() -> Response<ProxyBody> {
+    let body = Empty::new().map_err(|never| match never {}).boxed();
+    let mut response = Response::new(body);
+    *response.status_mut() = StatusCode::BAD_GATEWAY;
+    response
+}
+
+impl Service<Request<Incoming>> for ReverseProxy {
+    type Response = Response<ProxyBody>;
+    type Error = Infallible;
+    type Future = Pin<
+        Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let req = self.upstream_request(req);
+        let client = self.client.clone();
+        Box::pin(async move {
+            let req = match req {
+                Ok(req) => req,
+                Err(err) => {
+                    debug!("error building upstream request: {:?}", err);
+                    return Ok(bad_gateway());
+                }
+            };
+            match client.request(req).await {
+                Ok(response) => Ok(response.map(|x| x.boxed())),
+                Err(err) => {
+                    debug!("error forwarding request: {:?}", err);
+                    Ok(bad_gateway())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::testing::send;
+    use http_body_util::Full;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    /// Serves `service` on an ephemeral port for the rest of the test.
+    async fn serve<S>(fixtures: &FixtureDir, service: S) -> SocketAddr
+    where
+        S: Service<Request<Incoming>, Response = Response<ProxyBody>>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _: Result<(), Error> =
+                server.serve_service(listener, service).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn forwards_the_client_identity_upstream() {
+        let fixtures = FixtureDir::new().unwrap();
+        // Describes the request of the proxy.
+        let upstream = service_fn(|req: Request<Incoming>| async move {
+            let xfcc = req.headers().get(X_FORWARDED_CLIENT_CERT);
+            let xfcc = xfcc.map(|x| x.to_str().unwrap()).unwrap_or("none");
+            let hop = req.headers().contains_key("x-hop");
+            let body = format!("{} {} {}", req.uri(), hop, xfcc);
+            let body = Full::from(body).map_err(|never| match never {});
+            Ok::<_, Infallible>(Response::new(body.boxed()))
+        });
+        let upstream = serve(&fixtures, upstream).await;
+        let upstream = format!("https://localhost:{}", upstream.port());
+        let proxy = ReverseProxy::new(
+            upstream.parse().unwrap(),
+            &fixtures.file("alice.crt"),
+            &fixtures.file("alice.key"),
+            &fixtures.file("ca.crt"),
+        )
+        .unwrap();
+        let proxy = serve(&fixtures, proxy).await;
+
+        let config = fixtures.client_config("bob").unwrap();
+        let req = Request::get("/orders?id=1")
+            .header(X_FORWARDED_CLIENT_CERT, "Hash=forged")
+            .header(CONNECTION, "x-hop")
+            .header("x-hop", "1")
+            .body(Empty::new())
+            .unwrap();
+        let response = send(config, proxy, req).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        let mut parts = body.splitn(3, ' ');
+        assert_eq!(parts.next(), Some("/orders?id=1"));
+        // Headers named in `Connection` are not forwarded.
+        assert_eq!(parts.next(), Some("false"));
+        let xfcc = parts.next().unwrap();
+        assert!(xfcc.starts_with("Hash="), "{}", xfcc);
+        assert!(!xfcc.contains("forged"));
+        assert!(xfcc.contains("CN=bob"));
+    }
+
+    #[tokio::test]
+    async fn answers_bad_gateway_when_upstream_is_down() {
+        let fixtures = FixtureDir::new().unwrap();
+        // Nothing listens on the port once the listener is dropped.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let upstream = format!("https://localhost:{}", port);
+        let proxy = ReverseProxy::new(
+            upstream.parse().unwrap(),
+            &fixtures.file("alice.crt"),
+            &fixtures.file("alice.key"),
+            &fixtures.file("ca.crt"),
+        )
+        .unwrap();
+        let proxy = serve(&fixtures, proxy).await;
+
+        let config = fixtures.client_config("bob").unwrap();
+        let req = Request::get("/").body(Empty::new()).unwrap();
+        let response = send(config, proxy, req).await.unwrap();
+        assert_eq!(response.status(), 502);
+    }
+
+    #[test]
+    fn requires_an_https_upstream() {
+        let fixtures = FixtureDir::new().unwrap();
+        let proxy = ReverseProxy::new(
+            "http://localhost:8080".parse().unwrap(),
+            &fixtures.file("alice.crt"),
+            &fixtures.file("alice.key"),
+            &fixtures.file("ca.crt"),
+        );
+        assert!(matches!(proxy, Err(ProxyUpstreamError)));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::ClientIdentity;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// What makes two connections belong to the same client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuotaKey {
+    /// The SHA-256 fingerprint of the client certificate, so a reissued
+    /// certificate starts with a fresh quota.
+    #[default]
+    Fingerprint,
+    /// The subject distinguished name, shared by all certificates issued
+    /// to the same client.
+    Subject,
+}
+
+impl QuotaKey {
+    pub(crate) fn of(self, identity: &ClientIdentity) -> &str {
+        match self {
+            Self::Fingerprint => identity.fingerprint(),
+            Self::Subject => identity.subject(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ClientQuota {
+    max: usize,
+    key: QuotaKey,
+    active: Mutex<HashMap<Box<str>, usize>>,
+}
+
+/// Holds one of the client's connection slots until dropped.
+#[derive(Debug)]
+pub(crate) struct QuotaSlot {
+    quota: Arc<ClientQuota>,
+    key: Box<str>,
+}
+
+impl Drop for QuotaSlot {
+    fn drop(&mut self) {
+        let mut active = self.quota.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl ClientQuota {
+    pub(crate) fn new(max: usize, key: QuotaKey) -> Self {
+        Self {
+            max,
+            key,
+            active: Mutex::default(),
+        }
+    }
+
+    /// Returns `None` when the client already has `max` connections open.
+    pub(crate) fn acquire(
+        self: &Arc<Self>,
+        identity: &ClientIdentity,
+    ) -> Option<QuotaSlot> {
+        let key = self.key.of(identity);
+        let mut active = self.active.lock().unwrap();
+        if active.get(key).copied().unwrap_or(0) >= self.max {
+            return None;
+        }
+        *active.entry(key.into()).or_default() += 1;
+
+        Some(QuotaSlot {
+            quota: self.clone(),
+            key: key.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{ALICE_CERT, BOB_CERT};
+
+    fn identity(pem: &str) -> ClientIdentity {
+        let mut pem = pem.as_bytes();
+        let cert = rustls_pemfile::certs(&mut pem).next().unwrap().unwrap();
+        ClientIdentity::from_cert(&cert).unwrap()
+    }
+
+    #[test]
+    fn limits_the_connections_of_each_client() {
+        let quota = Arc::new(ClientQuota::new(2, QuotaKey::Fingerprint));
+        let [alice, bob] = [ALICE_CERT, BOB_CERT].map(identity);
+
+        let first = quota.acquire(&alice).unwrap();
+        let _second = quota.acquire(&alice).unwrap();
+        assert!(quota.acquire(&alice).is_none());
+        // Other clients have their own quota.
+        let _bob = quota.acquire(&bob).unwrap();
+
+        drop(first);
+        let _third = quota.acquire(&alice).unwrap();
+        assert!(quota.acquire(&alice).is_none());
+    }
+
+    #[test]
+    fn forgets_clients_without_connections() {
+        let quota = Arc::new(ClientQuota::new(1, QuotaKey::Subject));
+        let alice = identity(ALICE_CERT);
+
+        let slot = quota.acquire(&alice).unwrap();
+        assert_eq!(quota.active.lock().unwrap()[alice.subject()], 1);
+        drop(slot);
+        assert!(quota.active.lock().unwrap().is_empty());
+    }
+}
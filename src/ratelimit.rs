@@ -0,0 +1,333 @@
+use crate::{ConnInfo, QuotaKey, Requirement};
+use futures_util::future::{ready, Either, Ready};
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::{Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Buckets are pruned once there are this many, and then whenever their
+/// number doubled.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// A token bucket allowing `requests` per `period` on average, and bursts of
+/// the same size unless set with [`with_burst`](Self::with_burst).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl RateLimit {
+    /// # Panics
+    ///
+    /// If `requests` or `period` is zero.
+    pub fn new(requests: u32, period: Duration) -> Self {
+        assert!(requests > 0, "rate limit of zero requests");
+        assert!(!period.is_zero(), "rate limit over a zero period");
+        Self {
+            per_second: f64::from(requests) / period.as_secs_f64(),
+            burst: f64::from(requests),
+        }
+    }
+
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = f64::from(burst.max(1));
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens = (self.tokens
+            + elapsed.as_secs_f64() * self.limit.per_second)
+            .min(self.limit.burst);
+        self.updated = now;
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = (1.0 - self.tokens) / self.limit.per_second;
+        Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<Box<str>, Bucket>,
+    prune_at: usize,
+}
+
+impl Buckets {
+    /// Drops the buckets that refilled completely, which behave like new
+    /// ones.
+    fn prune(&mut self, now: Instant) {
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.limit.burst
+        });
+        self.prune_at = (self.buckets.len() * 2).max(PRUNE_THRESHOLD);
+    }
+}
+
+/// Rate limits requests per verified client identity with a token bucket,
+/// so a noisy client is throttled without affecting others. Identities
+/// meeting a role's [`Requirement`] get that role's limit, the first
+/// matching role wins; all others get the default limit. Requests over the
+/// limit are answered with `429 Too Many Requests` and a `Retry-After`
+/// header. Requests without a client certificate are let through, so
+/// combine it with [`AuthorizationLayer`](crate::AuthorizationLayer) where
+/// they aren't allowed. Clones share the buckets.
+///
+/// ```no_run
+/// # use hyper_mtls_server::{RateLimit, RateLimitLayer, Requirement};
+/// # use std::time::Duration;
+/// let layer = RateLimitLayer::new(RateLimit::new(10, Duration::from_secs(1)))
+///     .with_role(
+///         Requirement::OrganizationalUnit("partners".into()),
+///         RateLimit::new(100, Duration::from_secs(1)),
+///     );
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimitLayer {
+    default: RateLimit,
+    roles: Arc<Vec<(Requirement, RateLimit)>>,
+    key: QuotaKey,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(default: RateLimit) -> Self {
+        Self {
+            default,
+            roles: Arc::default(),
+            key: QuotaKey::default(),
+            buckets: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                prune_at: PRUNE_THRESHOLD,
+            })),
+        }
+    }
+
+    /// Applies `limit` to identities meeting `requirement`.
+    pub fn with_role(
+        mut self,
+        requirement: Requirement,
+        limit: RateLimit,
+    ) -> Self {
+        Arc::make_mut(&mut self.roles).push((requirement, limit));
+        self
+    }
+
+    /// What makes requests belong to the same client, the certificate
+    /// fingerprint by default.
+    pub fn with_key(mut self, key: QuotaKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Takes a token for the client of the request, or returns how long
+    /// until one is available.
+    fn take(&self, conn_info: Option<&ConnInfo>) -> Result<(), Duration> {
+        let Some(identity) = conn_info.and_then(|x| x.client_identity()) else {
+            return Ok(());
+        };
+        let limit = self
+            .roles
+            .iter()
+            .find(|(requirement, _)| requirement.allows(identity))
+            .map_or(self.default, |(_, limit)| *limit);
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.buckets.len() >= buckets.prune_at {
+            buckets.prune(now);
+        }
+        let bucket = buckets
+            .buckets
+            .entry(self.key.of(identity).into())
+            .or_insert(Bucket {
+                limit,
+                tokens: limit.burst,
+                updated: now,
+            });
+        bucket.limit = limit;
+        bucket.take(now).inspect_err(|_| {
+            debug!("rate limited {}", identity.subject());
+        })
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimited<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimited {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimited<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimited<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response<ResBody>, S::Error>>, S::Future>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let conn_info = req.extensions().get::<ConnInfo>();
+        if let Err(wait) = self.layer.take(conn_info) {
+            let mut response = Response::new(ResBody::default());
+            *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after(wait));
+            return Either::Left(ready(Ok(response)));
+        }
+        Either::Right(self.inner.call(req))
+    }
+}
+
+/// `wait` in whole seconds, rounded up and at least one.
+fn retry_after(wait: Duration) -> HeaderValue {
+    let seconds = wait
+        .as_secs()
+        .saturating_add(u64::from(wait.subsec_nanos() > 0));
+    HeaderValue::from(seconds.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    fn bucket(limit: RateLimit, now: Instant) -> Bucket {
+        Bucket {
+            limit,
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    #[test]
+    fn allows_bursts_then_the_rate() {
+        let now = Instant::now();
+        let limit = RateLimit::new(2, Duration::from_secs(1)).with_burst(5);
+        let mut bucket = bucket(limit, now);
+        for _ in 0..5 {
+            assert_eq!(bucket.take(now), Ok(()));
+        }
+        assert_eq!(bucket.take(now), Err(Duration::from_millis(500)));
+
+        let later = now + Duration::from_millis(500);
+        assert_eq!(bucket.take(later), Ok(()));
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn refills_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket =
+            bucket(RateLimit::new(10, Duration::from_secs(10)), now);
+        for _ in 0..10 {
+            bucket.take(now).unwrap();
+        }
+        bucket.refill(now + Duration::from_secs(3));
+        assert_eq!(bucket.tokens, 3.0);
+        bucket.refill(now + Duration::from_secs(3600));
+        assert_eq!(bucket.tokens, 10.0);
+        // Time going backwards doesn't take tokens.
+        bucket.refill(now);
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "zero requests")]
+    fn rejects_a_zero_rate() {
+        RateLimit::new(0, Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "zero period")]
+    fn rejects_a_zero_period() {
+        RateLimit::new(1, Duration::ZERO);
+    }
+
+    #[test]
+    fn rounds_retry_after_up() {
+        let seconds = |wait| retry_after(wait).to_str().unwrap().to_owned();
+        assert_eq!(seconds(Duration::ZERO), "1");
+        assert_eq!(seconds(Duration::from_millis(1)), "1");
+        assert_eq!(seconds(Duration::from_secs(2)), "2");
+        assert_eq!(seconds(Duration::from_millis(2001)), "3");
+        assert_eq!(seconds(Duration::MAX), u64::MAX.to_string());
+    }
+
+    #[tokio::test]
+    async fn answers_too_many_requests() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        let conn_info = conn.unwrap().conn_info;
+
+        let layer =
+            RateLimitLayer::new(RateLimit::new(1, Duration::from_secs(60)));
+        let service = layer.layer(service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(String::new()))
+        }));
+        let request = || {
+            let mut req = Request::new(());
+            req.extensions_mut().insert(conn_info.clone());
+            req
+        };
+        let response = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+
+        // Requests without a client certificate aren't limited.
+        let response = service.oneshot(Request::new(())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::graceful::serve_until;
+use crate::serve::{drain, Tasks};
+use crate::trace::Instrument;
+use crate::{ConnectionId, Error, MtlServer};
+use hyper::body::Incoming;
+use hyper::header::{HOST, LOCATION};
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// The status redirects are answered with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RedirectStatus {
+    /// `301 Moved Permanently`, which old clients may follow with a GET.
+    MovedPermanently,
+    /// `302 Found`, which old clients may follow with a GET.
+    Found,
+    /// `307 Temporary Redirect`.
+    TemporaryRedirect,
+    /// `308 Permanent Redirect`.
+    #[default]
+    PermanentRedirect,
+}
+
+impl RedirectStatus {
+    fn status_code(self) -> StatusCode {
+        match self {
+            Self::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            Self::Found => StatusCode::FOUND,
+            Self::TemporaryRedirect => StatusCode::TEMPORARY_REDIRECT,
+            Self::PermanentRedirect => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpsRedirect {
+    https_port: u16,
+    host: Option<Box<str>>,
+    status: RedirectStatus,
+}
+
+impl Default for HttpsRedirect {
+    fn default() -> Self {
+        Self {
+            https_port: 443,
+            host: None,
+            status: RedirectStatus::default(),
+        }
+    }
+}
+
+impl HttpsRedirect {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_https_port(mut self, https_port: u16) -> Self {
+        self.https_port = https_port;
+        self
+    }
+
+    /// Redirects to `host` instead of the host the request was sent to.
+    pub fn with_host(mut self, host: Box<str>) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// `308 Permanent Redirect` by default.
+    pub fn with_status(mut self, status: RedirectStatus) -> Self {
+        self.status = status;
+        self
+    }
+
+    fn location<B>(&self, req: &Request<B>) -> Option<String> {
+        let host = match &self.host {
+            Some(host) => host.to_string(),
+            None => {
+                let authority = req
+                    .headers()
+                    .get(HOST)
+                    .and_then(|x| x.to_str().ok())
+                    .or_else(|| req.uri().authority().map(|x| x.as_str()))?;
+                let authority: hyper::http::uri::Authority =
+                    authority.parse().ok()?;
+                authority.host().to_string()
+            }
+        };
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|x| x.as_str())
+            .unwrap_or("/");
+
+        match self.https_port {
+            443 => Some(format!("https://{}{}", host, path)),
+            port => Some(format!("https://{}:{}{}", host, port, path)),
+        }
+    }
+
+    fn response<B>(&self, req: &Request<B>) -> Response<String> {
+        let mut response = Response::new(String::new());
+        match self.location(req) {
+            Some(location) => match location.parse() {
+                Ok(location) => {
+                    *response.status_mut() = self.status.status_code();
+                    response.headers_mut().insert(LOCATION, location);
+                }
+                Err(_) => *response.status_mut() = StatusCode::BAD_REQUEST,
+            },
+            None => *response.status_mut() = StatusCode::BAD_REQUEST,
+        }
+        response
+    }
+}
+
+impl MtlServer {
+    /// Serves plain HTTP on `listener`, redirecting every request to its
+    /// https:// equivalent. Shuts down together with the TLS listener when
+    /// the server handle is told to. Bans, the circuit breaker and the
+    /// connection limit only apply to the TLS listener.
+    pub async fn serve_redirect(
+        &self,
+        listener: TcpListener,
+        redirect: HttpsRedirect,
+    ) -> Result<(), Error> {
+        let redirect = Arc::new(redirect);
+        let mut tasks = Tasks::new(self.handle.metrics.clone());
+        let shutdown = self.handle.shutdown_requested();
+        tokio::pin!(shutdown);
+
+        let timeout = loop {
+            let (stream, addr) = tokio::select! {
+                timeout = &mut shutdown => break timeout,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        error!("redirect listener accept error: {:?}", err);
+                        continue;
+                    }
+                },
+            };
+
+            let redirect = redirect.clone();
+            let service = service_fn(move |req: Request<Incoming>| {
+                let response = redirect.response(&req);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            let close = self.handle.close_requested();
+            let span = debug_span!(
+                "http_redirect",
+                conn_id = %ConnectionId::new(),
+                remote_addr = %addr
+            );
+
+            let task = async move {
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let conn =
+                    builder.serve_connection(TokioIo::new(stream), service);
+                if let (Err(err), _) = serve_until(conn, close).await {
+                    debug!("error serving redirect: {:?}", err);
+                }
+            };
+            tasks.spawn(task.instrument(span));
+        };
+        drop(listener);
+
+        drain(tasks, timeout).await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::FixtureDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn redirects_past_the_connection_limit() {
+        let fixtures = FixtureDir::new().unwrap();
+        // The TLS listener wouldn't accept a single connection.
+        let server = fixtures.server().with_max_connections(0);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let redirect = HttpsRedirect::new().with_https_port(8443);
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve_redirect(listener, redirect).await }
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /a?b HTTP/1.1\r\nhost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = vec![0; 1024];
+        let n = stream.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..n]);
+        assert!(response.starts_with("HTTP/1.1 308"), "{}", response);
+        assert!(
+            response.contains("location: https://example.com:8443/a?b"),
+            "{}",
+            response
+        );
+
+        server.handle().shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn answers_with_the_configured_status() {
+        let req = Request::get("/").header(HOST, "example.com").body(());
+        let req = req.unwrap();
+        let redirect = HttpsRedirect::new();
+        let response = redirect.response(&req);
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "https://example.com/");
+
+        let redirect = redirect.with_status(RedirectStatus::Found);
+        let response = redirect.response(&req);
+        assert_eq!(response.status(), StatusCode::FOUND);
+    }
+}
@@ -0,0 +1,762 @@
+use crate::grpc::{
+    read_varint, ready, refusal, GrpcBody, INVALID_ARGUMENT, MAX_REQUEST_SIZE,
+    NOT_FOUND, UNIMPLEMENTED,
+};
+use crate::Error::DescriptorSetParseError;
+use crate::{Error, Requirement};
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use http_body_util::{BodyExt, Either};
+use hyper::body::{Body, Buf, Bytes};
+use hyper::{Request, Response};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+const V1_PATH: &str =
+    "/grpc.reflection.v1.ServerReflection/ServerReflectionInfo";
+const V1ALPHA_PATH: &str =
+    "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo";
+
+/// Answers `grpc.reflection.v1.ServerReflection` and its `v1alpha`
+/// predecessor in front of a gRPC service, so tools like `grpcurl` can list
+/// its services and describe its messages. It knows the files of the
+/// descriptor sets given to
+/// [`with_file_descriptor_set`](Self::with_file_descriptor_set), e.g. the
+/// ones `tonic-build` writes. Only verified clients may use it unless
+/// [`allow_without_client_cert`](Self::allow_without_client_cert) is set, or
+/// [`with_requirement`](Self::with_requirement) narrows it down further.
+///
+/// ```no_run
+/// # use hyper_mtls_server::GrpcReflectionLayer;
+/// # use tower::ServiceBuilder;
+/// # const FILE_DESCRIPTOR_SET: &[u8] = &[];
+/// # fn example<S>(router: S) -> Result<(), Box<dyn std::error::Error>> {
+/// let reflection = GrpcReflectionLayer::new()
+///     .with_file_descriptor_set(FILE_DESCRIPTOR_SET)?;
+/// let service = ServiceBuilder::new().layer(reflection).service(router);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct GrpcReflectionLayer {
+    index: Arc<Index>,
+    requirement: Option<Requirement>,
+}
+
+impl Default for GrpcReflectionLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GrpcReflectionLayer {
+    /// Knows no files until given a descriptor set.
+    pub fn new() -> Self {
+        Self {
+            index: Arc::default(),
+            requirement: Some(Requirement::ClientCert),
+        }
+    }
+
+    /// Describes the files of the encoded `google.protobuf.FileDescriptorSet`
+    /// `set`, which should include the files they import. Files already
+    /// known from another set are kept.
+    ///
+    /// Fails with [`Error::DescriptorSetParseError`] if `set` can't be
+    /// decoded.
+    pub fn with_file_descriptor_set(
+        mut self,
+        set: &[u8],
+    ) -> Result<Self, Error> {
+        Arc::make_mut(&mut self.index)
+            .add_set(set)
+            .ok_or(DescriptorSetParseError)?;
+        Ok(self)
+    }
+
+    /// Lets clients without a verified certificate use reflection.
+    pub fn allow_without_client_cert(mut self) -> Self {
+        self.requirement = None;
+        self
+    }
+
+    /// Only lets clients whose certificate meets `requirement` use
+    /// reflection. Others get `PERMISSION_DENIED`, clients without a
+    /// certificate `UNAUTHENTICATED`.
+    pub fn with_requirement(mut self, requirement: Requirement) -> Self {
+        self.requirement = Some(requirement);
+        self
+    }
+}
+
+impl<S> Layer<S> for GrpcReflectionLayer {
+    type Service = GrpcReflection<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcReflection {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GrpcReflection<S> {
+    inner: S,
+    layer: GrpcReflectionLayer,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for GrpcReflection<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ReqBody: Body + Send + 'static,
+    ReqBody::Data: Send,
+    ResBody: Body<Data = Bytes> + Send + 'static,
+{
+    type Response = Response<Either<ResBody, GrpcBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, S::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let path = req.uri().path();
+        if path != V1_PATH && path != V1ALPHA_PATH {
+            return self
+                .inner
+                .call(req)
+                .map(|x| x.map(|x| x.map(Either::Left)))
+                .boxed();
+        }
+
+        if let Some(code) = refusal(self.layer.requirement.as_ref(), &req) {
+            return ready(GrpcBody::status(code));
+        }
+        let answers = answers(self.layer.index.clone(), req.into_body());
+        ready(GrpcBody::stream(answers))
+    }
+}
+
+/// Answers each `ServerReflectionRequest` of the request stream as it
+/// arrives. Ends with `INVALID_ARGUMENT` on a malformed or oversized frame,
+/// and `UNIMPLEMENTED` on a compressed one.
+fn answers<B>(
+    index: Arc<Index>,
+    body: B,
+) -> BoxStream<'static, Result<Bytes, u8>>
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+{
+    let state = Some((Box::pin(body), Vec::new()));
+    stream::unfold(state, move |state| {
+        let index = index.clone();
+        async move {
+            let (mut body, mut buf) = state?;
+            loop {
+                if let Some(&[compressed, a, b, c, d]) = buf.first_chunk::<5>()
+                {
+                    let len = u32::from_be_bytes([a, b, c, d]) as usize;
+                    if compressed != 0 {
+                        return Some((Err(UNIMPLEMENTED), None));
+                    }
+                    if len > MAX_REQUEST_SIZE {
+                        return Some((Err(INVALID_ARGUMENT), None));
+                    }
+                    if buf.len() >= 5 + len {
+                        let answer = index.answer(&buf[5..5 + len]);
+                        buf.drain(..5 + len);
+                        return Some((Ok(answer), Some((body, buf))));
+                    }
+                }
+                match body.frame().await {
+                    None if buf.is_empty() => return None,
+                    None | Some(Err(_)) => {
+                        return Some((Err(INVALID_ARGUMENT), None))
+                    }
+                    Some(Ok(frame)) => {
+                        if let Ok(mut data) = frame.into_data() {
+                            while data.has_remaining() {
+                                buf.extend_from_slice(data.chunk());
+                                data.advance(data.chunk().len());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// The files of the descriptor sets, and where each symbol and extension is
+/// declared.
+#[derive(Clone, Debug, Default)]
+struct Index {
+    files: HashMap<Box<str>, File>,
+    /// The file declaring each fully qualified service, method, message,
+    /// enum and extension.
+    symbols: HashMap<Box<str>, Box<str>>,
+    /// The file declaring each extension by extended message and number.
+    extensions: HashMap<Box<str>, BTreeMap<u32, Box<str>>>,
+    services: BTreeSet<Box<str>>,
+}
+
+#[derive(Clone, Debug)]
+struct File {
+    /// The encoded `FileDescriptorProto`.
+    proto: Bytes,
+    dependencies: Vec<Box<str>>,
+}
+
+impl Index {
+    fn add_set(&mut self, set: &[u8]) -> Option<()> {
+        for (number, field) in fields(set)? {
+            if number == 1 {
+                self.add_file(field.bytes()?)?;
+            }
+        }
+        Some(())
+    }
+
+    fn add_file(&mut self, proto: &[u8]) -> Option<()> {
+        let fields = fields(proto)?;
+        let name: Box<str> = string(&fields, 1)?.into();
+        if self.files.contains_key(&name) {
+            return Some(());
+        }
+        let package = string(&fields, 2).unwrap_or("");
+        for (number, field) in &fields {
+            let value = field.bytes();
+            match number {
+                4 => self.add_message(&name, package, value?)?,
+                5 => drop(self.add_symbol(&name, package, value?)?),
+                6 => self.add_service(&name, package, value?)?,
+                7 => self.add_extension(&name, package, value?)?,
+                _ => {}
+            }
+        }
+        let dependencies = fields
+            .iter()
+            .filter(|(number, _)| *number == 3)
+            .map(|(_, x)| x.string().map(Box::from))
+            .collect::<Option<_>>()?;
+        let file = File {
+            proto: Bytes::copy_from_slice(proto),
+            dependencies,
+        };
+        self.files.insert(name, file);
+        Some(())
+    }
+
+    /// Adds the enum or message `proto` declared in `scope`, returning its
+    /// fully qualified name.
+    fn add_symbol(
+        &mut self,
+        file: &str,
+        scope: &str,
+        proto: &[u8],
+    ) -> Option<Box<str>> {
+        let name = qualify(scope, string(&fields(proto)?, 1)?);
+        self.symbols.insert(name.clone(), file.into());
+        Some(name)
+    }
+
+    fn add_message(
+        &mut self,
+        file: &str,
+        scope: &str,
+        proto: &[u8],
+    ) -> Option<()> {
+        let name = self.add_symbol(file, scope, proto)?;
+        for (number, field) in fields(proto)? {
+            let value = field.bytes();
+            match number {
+                3 => self.add_message(file, &name, value?)?,
+                4 => drop(self.add_symbol(file, &name, value?)?),
+                6 => self.add_extension(file, &name, value?)?,
+                _ => {}
+            }
+        }
+        Some(())
+    }
+
+    fn add_service(
+        &mut self,
+        file: &str,
+        scope: &str,
+        proto: &[u8],
+    ) -> Option<()> {
+        let name = self.add_symbol(file, scope, proto)?;
+        for (number, field) in fields(proto)? {
+            if number == 2 {
+                self.add_symbol(file, &name, field.bytes()?)?;
+            }
+        }
+        self.services.insert(name);
+        Some(())
+    }
+
+    fn add_extension(
+        &mut self,
+        file: &str,
+        scope: &str,
+        proto: &[u8],
+    ) -> Option<()> {
+        self.add_symbol(file, scope, proto)?;
+        let fields = fields(proto)?;
+        let extendee = string(&fields, 2)?;
+        let number = fields.iter().find_map(|x| match x {
+            (3, Field::Varint(x)) => u32::try_from(*x).ok(),
+            _ => None,
+        })?;
+        self.extensions
+            .entry(extendee.trim_start_matches('.').into())
+            .or_default()
+            .insert(number, file.into());
+        Some(())
+    }
+
+    /// The framed `ServerReflectionResponse` to the encoded `request`.
+    fn answer(&self, request: &[u8]) -> Bytes {
+        let mut response = Vec::new();
+        let result = match fields(request) {
+            Some(fields) => {
+                if let Some(host) = string(&fields, 1) {
+                    put_bytes(&mut response, 1, host.as_bytes());
+                }
+                put_bytes(&mut response, 2, request);
+                self.lookup(&fields)
+            }
+            None => Err((INVALID_ARGUMENT, "malformed request".into())),
+        };
+        match result {
+            Ok((number, message)) => put_bytes(&mut response, number, &message),
+            Err((code, reason)) => {
+                let mut error = Vec::new();
+                put_varint(&mut error, 1, u64::from(code));
+                put_bytes(&mut error, 2, reason.as_bytes());
+                put_bytes(&mut response, 7, &error);
+            }
+        }
+        let mut frame = vec![0];
+        frame.extend_from_slice(&(response.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&response);
+        frame.into()
+    }
+
+    /// The field of the response to the fields of a request, and its
+    /// encoded message, or the error code and message.
+    fn lookup(
+        &self,
+        request: &[(u64, Field<'_>)],
+    ) -> Result<(u64, Vec<u8>), (u8, String)> {
+        let query = request.iter().find_map(|(number, field)| {
+            (3..=7)
+                .contains(number)
+                .then_some((*number, field.bytes()?))
+        });
+        let not_found = |what: &str| (NOT_FOUND, format!("{} not found", what));
+        let invalid = || (INVALID_ARGUMENT, "malformed request".to_owned());
+        let Some((number, value)) = query else {
+            return Err(invalid());
+        };
+        let value_str = || std::str::from_utf8(value).map_err(|_| invalid());
+        match number {
+            3 => {
+                let name = value_str()?;
+                self.files.get(name).ok_or_else(|| not_found(name))?;
+                Ok((4, self.file_response(name)))
+            }
+            4 => {
+                let symbol = value_str()?;
+                let file = self.symbols.get(symbol);
+                let file = file.ok_or_else(|| not_found(symbol))?;
+                Ok((4, self.file_response(file)))
+            }
+            5 => {
+                let fields = fields(value).ok_or_else(invalid)?;
+                let extendee = string(&fields, 1).ok_or_else(invalid)?;
+                let number = fields.iter().find_map(|x| match x {
+                    (2, Field::Varint(x)) => u32::try_from(*x).ok(),
+                    _ => None,
+                });
+                let file =
+                    self.extensions.get(extendee).and_then(|x| x.get(&number?));
+                let file = file.ok_or_else(|| not_found("extension"))?;
+                Ok((4, self.file_response(file)))
+            }
+            6 => {
+                let extendee = value_str()?;
+                if !self.symbols.contains_key(extendee) {
+                    return Err(not_found(extendee));
+                }
+                let mut numbers = Vec::new();
+                let extensions = self.extensions.get(extendee);
+                for (number, _) in extensions.into_iter().flatten() {
+                    write_varint(&mut numbers, u64::from(*number));
+                }
+                let mut message = Vec::new();
+                put_bytes(&mut message, 1, extendee.as_bytes());
+                put_bytes(&mut message, 2, &numbers);
+                Ok((5, message))
+            }
+            7 => {
+                let mut message = Vec::new();
+                for name in &self.services {
+                    let mut service = Vec::new();
+                    put_bytes(&mut service, 1, name.as_bytes());
+                    put_bytes(&mut message, 1, &service);
+                }
+                Ok((6, message))
+            }
+            _ => Err((UNIMPLEMENTED, "unknown request".into())),
+        }
+    }
+
+    /// A `FileDescriptorResponse` with the file `name`, followed by the files
+    /// it imports, directly or not.
+    fn file_response(&self, name: &str) -> Vec<u8> {
+        let mut message = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![name];
+        while let Some(name) = pending.pop() {
+            let Some(file) = self.files.get(name) else {
+                continue;
+            };
+            if seen.insert(name) {
+                put_bytes(&mut message, 1, &file.proto);
+                pending.extend(file.dependencies.iter().rev().map(|x| &**x));
+            }
+        }
+        message
+    }
+}
+
+/// A field of an encoded protobuf message; fixed size fields are skipped.
+#[derive(Debug)]
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Field<'a> {
+    fn bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Self::Bytes(x) => Some(x),
+            Self::Varint(_) => None,
+        }
+    }
+
+    fn string(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.bytes()?).ok()
+    }
+}
+
+/// The numbered fields of the encoded message `message`.
+fn fields(mut message: &[u8]) -> Option<Vec<(u64, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let (key, rest) = read_varint(message)?;
+        let (field, rest) = match key & 7 {
+            0 => {
+                let (value, rest) = read_varint(rest)?;
+                (Some(Field::Varint(value)), rest)
+            }
+            1 => (None, rest.get(8..)?),
+            2 => {
+                let (len, rest) = read_varint(rest)?;
+                let len = usize::try_from(len).ok()?;
+                (Some(Field::Bytes(rest.get(..len)?)), rest.get(len..)?)
+            }
+            5 => (None, rest.get(4..)?),
+            _ => return None,
+        };
+        fields.extend(field.map(|x| (key >> 3, x)));
+        message = rest;
+    }
+    Some(fields)
+}
+
+/// The first string field numbered `number`.
+fn string<'a>(fields: &[(u64, Field<'a>)], number: u64) -> Option<&'a str> {
+    let field = fields.iter().find(|(x, _)| *x == number)?;
+    field.1.string()
+}
+
+fn qualify(scope: &str, name: &str) -> Box<str> {
+    match scope {
+        "" => name.into(),
+        _ => format!("{}.{}", scope, name).into(),
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Writes the varint field numbered `number`.
+fn put_varint(buf: &mut Vec<u8>, number: u64, value: u64) {
+    write_varint(buf, number << 3);
+    write_varint(buf, value);
+}
+
+/// Writes the length delimited field numbered `number`.
+fn put_bytes(buf: &mut Vec<u8>, number: u64, value: &[u8]) {
+    write_varint(buf, number << 3 | 2);
+    write_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::ConnInfo;
+    use http_body_util::{Empty, StreamBody};
+    use hyper::body::Frame;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    async fn alice() -> ConnInfo {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        let conn = connect_duplex(&acceptor, config, "localhost").await;
+        conn.unwrap().conn_info
+    }
+
+    fn message(fields: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut message = Vec::new();
+        for (number, value) in fields {
+            put_bytes(&mut message, *number, value);
+        }
+        message
+    }
+
+    /// `greeter.proto`, importing `common.proto` and extending its message
+    /// with field 100.
+    fn descriptor_set() -> Vec<u8> {
+        let common = message(&[
+            (1, b"common.proto"),
+            (2, b"example"),
+            (4, &message(&[(1, b"Empty")])),
+        ]);
+        let mut extension = message(&[(1, b"note"), (2, b".example.Empty")]);
+        put_varint(&mut extension, 3, 100);
+        let hello = message(&[(1, b"Hello"), (3, &message(&[(1, b"Inner")]))]);
+        let greeter =
+            message(&[(1, b"Greeter"), (2, &message(&[(1, b"SayHello")]))]);
+        let file = message(&[
+            (1, b"greeter.proto"),
+            (2, b"example"),
+            (3, b"common.proto"),
+            (4, &hello),
+            (6, &greeter),
+            (7, &extension),
+        ]);
+        message(&[(1, &file), (1, &common)])
+    }
+
+    fn layer() -> GrpcReflectionLayer {
+        let set = descriptor_set();
+        GrpcReflectionLayer::new()
+            .with_file_descriptor_set(&set)
+            .unwrap()
+    }
+
+    /// A framed `ServerReflectionRequest` setting `field` to `value`.
+    fn request(field: u64, value: &[u8]) -> Vec<u8> {
+        let request = message(&[(1, b"localhost"), (field, value)]);
+        let mut frame = vec![0];
+        frame.extend_from_slice(&(request.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&request);
+        frame
+    }
+
+    /// The responses to `requests`, sent in small pieces to `path`, and the
+    /// `grpc-status` ending them.
+    async fn call(
+        layer: &GrpcReflectionLayer,
+        path: &str,
+        requests: &[Vec<u8>],
+        conn_info: Option<&ConnInfo>,
+    ) -> (Vec<Vec<u8>>, u16) {
+        let bytes = requests.concat();
+        let chunks = bytes
+            .chunks(3)
+            .map(|x| {
+                Ok::<_, Infallible>(Frame::data(Bytes::copy_from_slice(x)))
+            })
+            .collect::<Vec<_>>();
+        let body = StreamBody::new(stream::iter(chunks));
+        let mut req = Request::post(path).body(body).unwrap();
+        if let Some(conn_info) = conn_info {
+            req.extensions_mut().insert(conn_info.clone());
+        }
+        let inner = service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+        });
+        let response = layer.layer(inner).oneshot(req).await.unwrap();
+        let Either::Right(mut body) = response.into_body() else {
+            panic!("passed through to the inner service");
+        };
+        let mut responses = Vec::new();
+        while let Some(frame) = body.frame().await {
+            match frame.unwrap().into_data() {
+                Ok(message) => responses.push(message[5..].to_vec()),
+                Err(frame) => {
+                    let trailers = frame.into_trailers().unwrap();
+                    let status = trailers["grpc-status"].to_str().unwrap();
+                    return (responses, status.parse().unwrap());
+                }
+            }
+        }
+        panic!("no grpc-status");
+    }
+
+    /// The message of the response field numbered `number`.
+    fn answer(response: &[u8], number: u64) -> Vec<(u64, Field<'_>)> {
+        let fields = fields(response).unwrap();
+        let (_, answer) = fields.into_iter().find(|x| x.0 == number).unwrap();
+        super::fields(answer.bytes().unwrap()).unwrap()
+    }
+
+    /// The names of the files of a `FileDescriptorResponse`.
+    fn files(response: &[u8]) -> Vec<&str> {
+        let files = answer(response, 4);
+        let files = files.iter().map(|(_, x)| x.bytes().unwrap());
+        files
+            .map(|x| string(&fields(x).unwrap(), 1).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lists_services() {
+        let alice = alice().await;
+        let list = [request(7, b"")];
+        for path in [V1_PATH, V1ALPHA_PATH] {
+            let (responses, status) =
+                call(&layer(), path, &list, Some(&alice)).await;
+            assert_eq!(status, 0);
+            let response = fields(&responses[0]).unwrap();
+            assert_eq!(string(&response, 1), Some("localhost"));
+            assert_eq!(response[1].1.bytes(), Some(&list[0][5..]));
+            let services = answer(&responses[0], 6);
+            let service = fields(services[0].1.bytes().unwrap()).unwrap();
+            assert_eq!(services.len(), 1);
+            assert_eq!(string(&service, 1), Some("example.Greeter"));
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_files_with_their_dependencies() {
+        let requests = [
+            request(3, b"greeter.proto"),
+            request(4, b"example.Hello.Inner"),
+            request(4, b"example.Greeter.SayHello"),
+            request(4, b"example.Empty"),
+        ];
+        let alice = alice().await;
+        let (responses, status) =
+            call(&layer(), V1_PATH, &requests, Some(&alice)).await;
+        assert_eq!(status, 0);
+        let files = responses.iter().map(|x| files(x)).collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                vec!["greeter.proto", "common.proto"],
+                vec!["greeter.proto", "common.proto"],
+                vec!["greeter.proto", "common.proto"],
+                vec!["common.proto"],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_extensions() {
+        let mut extension = message(&[(1, b"example.Empty")]);
+        put_varint(&mut extension, 2, 100);
+        let requests = [
+            request(5, &extension),
+            request(6, b"example.Empty"),
+            request(6, b"example.Hello"),
+        ];
+        let alice = alice().await;
+        let (responses, _) =
+            call(&layer(), V1_PATH, &requests, Some(&alice)).await;
+        assert_eq!(files(&responses[0]), ["greeter.proto", "common.proto"]);
+        let numbers = answer(&responses[1], 5);
+        assert_eq!(string(&numbers, 1), Some("example.Empty"));
+        assert_eq!(numbers[1].1.bytes(), Some(&[100][..]));
+        let numbers = answer(&responses[2], 5);
+        assert_eq!(numbers[1].1.bytes(), Some(&[][..]));
+    }
+
+    #[tokio::test]
+    async fn answers_unknown_names_and_goes_on() {
+        let requests = [
+            request(3, b"missing.proto"),
+            request(4, b"example.Missing"),
+            request(6, b"example.Missing"),
+            request(3, b"common.proto"),
+        ];
+        let alice = alice().await;
+        let (responses, status) =
+            call(&layer(), V1_PATH, &requests, Some(&alice)).await;
+        assert_eq!(status, 0);
+        for response in &responses[..3] {
+            let error = answer(response, 7);
+            assert!(matches!(error[0], (1, Field::Varint(5))), "{:?}", error);
+        }
+        assert_eq!(files(&responses[3]), ["common.proto"]);
+    }
+
+    #[tokio::test]
+    async fn ends_the_stream_on_a_compressed_request() {
+        let mut compressed = request(7, b"");
+        compressed[0] = 1;
+        let requests = [request(7, b""), compressed];
+        let alice = alice().await;
+        let (responses, status) =
+            call(&layer(), V1_PATH, &requests, Some(&alice)).await;
+        assert_eq!((responses.len(), status), (1, 12));
+    }
+
+    #[tokio::test]
+    async fn needs_a_client_cert() {
+        let requests = [request(7, b"")];
+        let (responses, status) =
+            call(&layer(), V1_PATH, &requests, None).await;
+        assert_eq!((responses.len(), status), (0, 16));
+
+        let layer = layer().allow_without_client_cert();
+        let (responses, status) = call(&layer, V1_PATH, &requests, None).await;
+        assert_eq!((responses.len(), status), (1, 0));
+    }
+
+    #[test]
+    fn rejects_malformed_descriptor_sets() {
+        let set =
+            GrpcReflectionLayer::new().with_file_descriptor_set(b"\x0a\xff");
+        assert!(matches!(set, Err(DescriptorSetParseError)));
+    }
+}
@@ -0,0 +1,491 @@
+#[cfg(feature = "tokio")]
+use crate::backend::{Backend, TlsBackend};
+#[cfg(feature = "tokio")]
+use crate::handshake::Handshaker;
+#[cfg(feature = "tokio")]
+use crate::ocsp::OcspChecker;
+use crate::startup::check_validity;
+use crate::store::ServerKey;
+#[cfg(feature = "tokio")]
+use crate::ClientAuth;
+use crate::{CertEvent, Error, LogEvent, MtlServer, ServerHandle};
+use rustls_pki_types::CertificateDer;
+use std::error::Error as _;
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+#[cfg(feature = "tokio")]
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "tokio")]
+use tokio::sync::watch;
+#[cfg(feature = "tokio")]
+use tokio_rustls::TlsAcceptor;
+
+/// The files of one reload, read once so that every config built from them
+/// agrees.
+pub(crate) struct Material {
+    pub(crate) server_chain: Vec<CertificateDer<'static>>,
+    pub(crate) server_key: ServerKey,
+    pub(crate) client_cas: Vec<CertificateDer<'static>>,
+}
+
+pub(crate) type Commit = Box<dyn FnOnce() + Send>;
+
+/// Something built from the certificate files that a reload replaces.
+pub(crate) trait Reload: Send + Sync {
+    /// The server it was created from, whose files a reload reads.
+    fn server(&self) -> &MtlServer;
+
+    /// Builds the replacement from `material` without applying it,
+    /// returning the step that does.
+    fn stage(&self, material: &Arc<Material>) -> Result<Commit, Error>;
+}
+
+/// The outcome of the most recent reload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct ReloadStatus {
+    /// Unix timestamp in seconds.
+    pub at: i64,
+    /// Why the reload failed, `None` if it succeeded.
+    pub error: Option<Box<str>>,
+}
+
+impl ReloadStatus {
+    fn new(result: &Result<(), Error>) -> Self {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs() as i64);
+        let error = result.as_ref().err().map(|err| {
+            let mut message = err.to_string();
+            let mut source = err.source();
+            while let Some(err) = source {
+                message = format!("{}: {}", message, err);
+                source = err.source();
+            }
+            message.into()
+        });
+        Self { at, error }
+    }
+}
+
+/// The acceptors created from a server, held weakly so that dropping an
+/// acceptor unregisters it, and the reload bookkeeping.
+pub(crate) struct Reloads {
+    reloaders: Mutex<Vec<Weak<dyn Reload>>>,
+    pub(crate) debounce: Mutex<Duration>,
+    scheduled: AtomicU64,
+    last: Mutex<Option<ReloadStatus>>,
+}
+
+impl Default for Reloads {
+    fn default() -> Self {
+        Self {
+            reloaders: Mutex::default(),
+            debounce: Mutex::new(Duration::from_millis(500)),
+            scheduled: AtomicU64::new(0),
+            last: Mutex::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Reloads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reloads")
+            .field("last", &self.last)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerHandle {
+    pub(crate) fn register_reload(&self, reload: &Arc<dyn Reload>) {
+        let mut reloaders = self.reloads.reloaders.lock().unwrap();
+        reloaders.retain(|x| x.strong_count() > 0);
+        reloaders.push(Arc::downgrade(reload));
+    }
+
+    /// Rereads the server certificate, key and client CAs and switches every
+    /// acceptor created from this server to them, as one transaction: all
+    /// files are loaded and validated, including that the key belongs to
+    /// the certificate, before anything switches. On failure the previous
+    /// configuration stays in effect and the error is returned. Established
+    /// connections keep the configuration they were accepted with.
+    ///
+    /// The acceptor returned by [`MtlServer::tls_acceptor`] and the
+    /// diagnostics report are not reloaded; see
+    /// [`MtlServer::reloadable_acceptor`] instead of the former.
+    pub fn reload(&self) -> Result<(), Error> {
+        // Held throughout, so concurrent reloads don't interleave.
+        let reloaders = self.reloads.reloaders.lock().unwrap();
+        self.events.send(CertEvent::ReloadStarted);
+        let reloaders: Vec<_> =
+            reloaders.iter().filter_map(Weak::upgrade).collect();
+        // Servers sharing a handle share the files, so they are read once.
+        let staged: Result<Vec<Commit>, Error> = match reloaders.first() {
+            Some(first) => first.server().load_material().and_then(|x| {
+                reloaders.iter().map(|reload| reload.stage(&x)).collect()
+            }),
+            None => Ok(Vec::new()),
+        };
+        let result = match staged {
+            Ok(staged) => {
+                staged.into_iter().for_each(|commit| commit());
+                log_event!(
+                    self.logs,
+                    LogEvent::Reloads,
+                    "reloaded the certificates"
+                );
+                Ok(())
+            }
+            Err(err) => {
+                warn!(
+                    "certificate reload failed, keeping the previous \
+                     configuration: {}",
+                    err
+                );
+                Err(err)
+            }
+        };
+        self.metrics.reloaded(result.is_ok());
+        let status = ReloadStatus::new(&result);
+        match &status.error {
+            None => {
+                self.events.send(CertEvent::reloaded(&self.pki()));
+                self.check_expiry();
+            }
+            Some(error) => self.events.send(CertEvent::ReloadFailed {
+                error: error.clone(),
+            }),
+        }
+        *self.reloads.last.lock().unwrap() = Some(status);
+        result
+    }
+
+    /// Reloads once no further call arrived for the debounce period set
+    /// with [`MtlServer::with_reload_debounce`], for file watchers that
+    /// report several events per update. Has to be called within a tokio
+    /// runtime.
+    #[cfg(feature = "tokio")]
+    pub fn schedule_reload(&self) {
+        let scheduled = self.reloads.scheduled.fetch_add(1, Ordering::SeqCst);
+        let debounce = *self.reloads.debounce.lock().unwrap();
+        let handle = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(debounce).await;
+            if handle.reloads.scheduled.load(Ordering::SeqCst) == scheduled + 1
+            {
+                let _ =
+                    tokio::task::spawn_blocking(move || handle.reload()).await;
+            }
+        });
+    }
+
+    /// The outcome of the most recent reload, `None` before the first.
+    pub fn last_reload(&self) -> Option<ReloadStatus> {
+        self.reloads.last.lock().unwrap().clone()
+    }
+}
+
+impl MtlServer {
+    /// Loads and validates the files of a reload.
+    pub(crate) fn load_material(&self) -> Result<Arc<Material>, Error> {
+        let server_chain = self.load_server_cert()?;
+        let server_key = self.load_server_key()?;
+        let client_cas = if self.has_client_cas() {
+            self.load_client_ca_cert()?
+        } else {
+            Vec::new()
+        };
+        if let Some(leaf) = server_chain.first() {
+            check_validity(leaf)?;
+            self.check_key_pair(leaf, &server_key)?;
+        }
+
+        Ok(Arc::new(Material {
+            server_chain,
+            server_key,
+            client_cas,
+        }))
+    }
+
+    /// A copy of the server that builds its configs from `material`. It
+    /// records what it loaded on a handle of its own, for the commit to
+    /// publish.
+    pub(crate) fn staged(&self, material: &Arc<Material>) -> MtlServer {
+        let mut server = self.clone();
+        server.material = Some(material.clone());
+        server.handle = ServerHandle::new();
+        server
+    }
+}
+
+/// Reloads the TLS configs and OCSP issuers of an [`MtlsAcceptor`].
+///
+/// [`MtlsAcceptor`]: crate::MtlsAcceptor
+#[cfg(feature = "tokio")]
+pub(crate) struct AcceptorReload {
+    pub(crate) server: MtlServer,
+    pub(crate) client_auth: ClientAuth,
+    pub(crate) diagnostics: bool,
+    pub(crate) handshaker: Handshaker,
+    pub(crate) ocsp: Option<Arc<OcspChecker>>,
+}
+
+#[cfg(feature = "tokio")]
+impl Reload for AcceptorReload {
+    fn server(&self) -> &MtlServer {
+        &self.server
+    }
+
+    fn stage(&self, material: &Arc<Material>) -> Result<Commit, Error> {
+        let staged = self.server.staged(material);
+        let config =
+            Backend::config(&staged, self.client_auth, self.diagnostics)?;
+        let issuers = staged.ocsp_issuers()?;
+
+        let handshaker = self.handshaker.clone();
+        let ocsp = self.ocsp.clone();
+        let handle = self.server.handle.clone();
+        Ok(Box::new(move || {
+            handshaker.set_config(config);
+            if let Some(ocsp) = ocsp {
+                ocsp.set_issuers(issuers);
+            }
+            handle.replace_pki(staged.handle.pki());
+        }))
+    }
+}
+
+/// A [`TlsAcceptor`] that follows [`ServerHandle::reload`], for custom accept
+/// loops built on tokio-rustls directly. Take the current acceptor for every
+/// connection; clones share the state.
+///
+/// ```no_run
+/// # use hyper_mtls_server::MtlServer;
+/// # use tokio::net::TcpListener;
+/// # async fn example(server: MtlServer, listener: TcpListener) -> Result<(), Box<dyn std::error::Error>> {
+/// let acceptor = server.reloadable_acceptor()?;
+/// loop {
+///     let (stream, _) = listener.accept().await?;
+///     let tls = acceptor.current().accept(stream).await?;
+///     // ...
+/// }
+/// # }
+/// ```
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    current: watch::Receiver<TlsAcceptor>,
+    _reload: Arc<dyn Reload>,
+}
+
+#[cfg(feature = "tokio")]
+impl ReloadableAcceptor {
+    /// The acceptor for the configuration in effect.
+    pub fn current(&self) -> TlsAcceptor {
+        self.current.borrow().clone()
+    }
+
+    /// Waits until a reload replaced the configuration since the last call,
+    /// or since this acceptor was created or cloned.
+    pub async fn changed(&mut self) {
+        // The sender is owned by `self`, so it can't be dropped.
+        let _ = self.current.changed().await;
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl fmt::Debug for ReloadableAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadableAcceptor").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "tokio")]
+struct TlsAcceptorReload {
+    server: MtlServer,
+    current: watch::Sender<TlsAcceptor>,
+}
+
+#[cfg(feature = "tokio")]
+impl Reload for TlsAcceptorReload {
+    fn server(&self) -> &MtlServer {
+        &self.server
+    }
+
+    fn stage(&self, material: &Arc<Material>) -> Result<Commit, Error> {
+        let staged = self.server.staged(material);
+        let acceptor = staged.tls_acceptor()?;
+
+        let current = self.current.clone();
+        let handle = self.server.handle.clone();
+        Ok(Box::new(move || {
+            current.send_replace(acceptor);
+            handle.replace_pki(staged.handle.pki());
+        }))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl MtlServer {
+    /// Loads the certificates and builds a [`ReloadableAcceptor`], the
+    /// acceptor `serve` hands to its callback.
+    pub fn reloadable_acceptor(&self) -> Result<ReloadableAcceptor, Error> {
+        let (current, receiver) = watch::channel(self.tls_acceptor()?);
+        let reload: Arc<dyn Reload> = Arc::new(TlsAcceptorReload {
+            server: self.clone(),
+            current,
+        });
+        self.handle.register_reload(&reload);
+
+        Ok(ReloadableAcceptor {
+            current: receiver,
+            _reload: reload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixtureDir, SERVER_CERT, SERVER_KEY};
+
+    struct Recorder {
+        server: MtlServer,
+        staged: Mutex<Vec<Arc<Material>>>,
+    }
+
+    impl Reload for Recorder {
+        fn server(&self) -> &MtlServer {
+            &self.server
+        }
+
+        fn stage(&self, material: &Arc<Material>) -> Result<Commit, Error> {
+            self.staged.lock().unwrap().push(material.clone());
+            Ok(Box::new(|| {}))
+        }
+    }
+
+    #[test]
+    fn stages_every_reloader_from_one_load() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let recorders: Vec<Arc<Recorder>> = (0..2)
+            .map(|_| {
+                Arc::new(Recorder {
+                    server: server.clone(),
+                    staged: Mutex::default(),
+                })
+            })
+            .collect();
+        for recorder in &recorders {
+            let reload: Arc<dyn Reload> = recorder.clone();
+            server.handle.register_reload(&reload);
+        }
+
+        server.handle.reload().unwrap();
+        let first = recorders[0].staged.lock().unwrap()[0].clone();
+        let second = recorders[1].staged.lock().unwrap()[0].clone();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn failed_load_stages_nothing() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let recorder = Arc::new(Recorder {
+            server: server.clone(),
+            staged: Mutex::default(),
+        });
+        let reload: Arc<dyn Reload> = recorder.clone();
+        server.handle.register_reload(&reload);
+
+        std::fs::write(fixtures.file("server.key").as_ref(), "").unwrap();
+        assert!(server.handle.reload().is_err());
+        assert!(recorder.staged.lock().unwrap().is_empty());
+        assert!(server.handle.last_reload().unwrap().error.is_some());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn debounces_scheduled_reloads() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_reload_debounce(Duration::from_millis(50));
+        let recorder = Arc::new(Recorder {
+            server: server.clone(),
+            staged: Mutex::default(),
+        });
+        let reload: Arc<dyn Reload> = recorder.clone();
+        let handle = server.handle();
+        handle.register_reload(&reload);
+        assert_eq!(handle.last_reload(), None);
+
+        for _ in 0..3 {
+            handle.schedule_reload();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(recorder.staged.lock().unwrap().len(), 1);
+        assert_eq!(handle.last_reload().unwrap().error, None);
+
+        std::fs::write(fixtures.file("server.key").as_ref(), "").unwrap();
+        handle.schedule_reload();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let metrics = handle.metrics();
+        assert_eq!((metrics.reloads, metrics.reload_failures), (1, 1));
+        assert!(handle.last_reload().unwrap().error.is_some());
+    }
+
+    /// Whether a client with `config` completes a handshake with
+    /// `acceptor`.
+    #[cfg(feature = "tokio")]
+    async fn accepts(
+        config: Arc<rustls::ClientConfig>,
+        acceptor: TlsAcceptor,
+    ) -> bool {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server_name = "localhost".try_into().unwrap();
+        let connector = tokio_rustls::TlsConnector::from(config);
+        let (client, server) = tokio::join!(
+            connector.connect(server_name, client),
+            acceptor.accept(server)
+        );
+        client.is_ok() && server.is_ok()
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn the_reloadable_acceptor_follows_reloads() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server();
+        let handle = server.handle();
+        let mut acceptor = server.reloadable_acceptor().unwrap();
+        let alice = fixtures.client_config("alice").unwrap();
+        assert!(accepts(alice.clone(), acceptor.current()).await);
+
+        // Trusting only the server certificate rejects alice.
+        let end = "-----END CERTIFICATE-----\n";
+        let leaf = SERVER_CERT.split_inclusive(end).next().unwrap();
+        std::fs::write(fixtures.file("ca.crt").as_ref(), leaf).unwrap();
+        std::fs::write(fixtures.file("server.key").as_ref(), "").unwrap();
+        assert!(handle.reload().is_err());
+        assert!(accepts(alice.clone(), acceptor.current()).await);
+
+        std::fs::write(fixtures.file("server.key").as_ref(), SERVER_KEY)
+            .unwrap();
+        handle.reload().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), acceptor.changed())
+            .await
+            .unwrap();
+        assert!(!accepts(alice.clone(), acceptor.current()).await);
+
+        drop(acceptor);
+        handle.reload().unwrap();
+        let reloaders = handle.reloads.reloaders.lock().unwrap();
+        assert!(reloaders.iter().all(|x| x.strong_count() == 0));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::Error::ReloadPointError;
+use crate::{CertificateInfo, Error, ServerHandle};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bounds of the interval reloading is retried at once it is due, until a
+/// renewed certificate shows up.
+const MIN_RETRY: i64 = 1;
+const MAX_RETRY: i64 = 60;
+/// Bound of the interval once reloads keep finding the same certificate.
+const MAX_BACKOFF: i64 = 15 * 60;
+
+#[derive(Debug, Default)]
+struct State {
+    /// The fraction of the lifetime after which to reload.
+    reload_at: Option<f64>,
+    ticking: bool,
+    expired: bool,
+    /// Reloads in a row that found the same certificate, doubling the retry
+    /// interval each.
+    unchanged: u32,
+    /// The expiry of the certificate the last reload found.
+    last_not_after: Option<i64>,
+}
+
+/// Reloads short-lived server certificates, e.g. SPIFFE or Vault issued
+/// ones valid for hours, before they expire, see
+/// [`MtlServer::with_short_lived_certs`](crate::MtlServer::with_short_lived_certs).
+#[derive(Debug, Default)]
+pub(crate) struct Renewal {
+    state: Mutex<State>,
+}
+
+impl Renewal {
+    pub(crate) fn configure(&self, reload_at: f64) {
+        self.state.lock().unwrap().reload_at = Some(reload_at);
+    }
+
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        match self.state.lock().unwrap().reload_at {
+            Some(x) if !(x > 0.0 && x <= 1.0) => Err(ReloadPointError(x)),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().reload_at.is_some()
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs() as i64)
+}
+
+/// When `reload_at` of the lifetime of `leaf` passed.
+fn due(leaf: &CertificateInfo, reload_at: f64) -> i64 {
+    let lifetime = leaf.not_after - leaf.not_before;
+    leaf.not_before + (lifetime as f64 * reload_at) as i64
+}
+
+/// Until the reload of `leaf` is due, or until it is retried once it is,
+/// backing off after `unchanged` reloads found the same certificate.
+fn wait(
+    leaf: &CertificateInfo,
+    reload_at: f64,
+    unchanged: u32,
+    now: i64,
+) -> i64 {
+    match due(leaf, reload_at) - now {
+        wait if wait > 0 => wait,
+        _ => {
+            let retry = ((leaf.not_after - leaf.not_before) / 20)
+                .clamp(MIN_RETRY, MAX_RETRY);
+            retry
+                .saturating_mul(1 << unchanged.min(16))
+                .min(MAX_BACKOFF.max(retry))
+        }
+    }
+}
+
+impl ServerHandle {
+    /// Whether connections are refused because the server certificate
+    /// expired in short-lived mode.
+    pub(crate) fn serving_expired_cert(&self) -> bool {
+        self.renewal.is_enabled() && self.metrics.server_cert_expired()
+    }
+
+    /// Sleeps until the server certificate is due for a reload, then
+    /// reloads, as long as a listener is open. Does nothing outside of a
+    /// runtime or unless enabled.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn start_renewal(&self) {
+        let mut state = self.renewal.state.lock().unwrap();
+        if state.reload_at.is_none() || state.ticking {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        state.ticking = true;
+        drop(state);
+        let handle = self.clone();
+        runtime.spawn(async move {
+            loop {
+                let wait = handle.renewal_wait();
+                tokio::select! {
+                    _ = handle.shutdown_requested() => break,
+                    () = tokio::time::sleep(wait) => {}
+                }
+                if !handle.is_listening() {
+                    break;
+                }
+                if handle.renewal_due() {
+                    let reloading = handle.clone();
+                    let _ =
+                        tokio::task::spawn_blocking(move || reloading.reload())
+                            .await;
+                    handle.renewal_reloaded();
+                }
+            }
+            handle.renewal.state.lock().unwrap().ticking = false;
+        });
+    }
+
+    fn renewal_wait(&self) -> Duration {
+        let pki = self.pki.lock().unwrap();
+        let state = self.renewal.state.lock().unwrap();
+        let wait = match (pki.server_chain.first(), state.reload_at) {
+            (Some(leaf), Some(reload_at)) => {
+                wait(leaf, reload_at, state.unchanged, now())
+            }
+            _ => MAX_RETRY,
+        };
+        Duration::from_secs(wait as u64)
+    }
+
+    /// Whether the server certificate reached the reload point of its
+    /// lifetime, logging once it expired.
+    fn renewal_due(&self) -> bool {
+        let pki = self.pki.lock().unwrap();
+        let mut state = self.renewal.state.lock().unwrap();
+        let (Some(leaf), Some(reload_at)) =
+            (pki.server_chain.first(), state.reload_at)
+        else {
+            return false;
+        };
+        let now = now();
+        let expired = leaf.not_after < now;
+        if expired && !state.expired {
+            error!(
+                subject = &*leaf.subject,
+                not_after = leaf.not_after,
+                "server certificate expired, refusing connections until it \
+                 is renewed"
+            );
+        }
+        state.expired = expired;
+        let due = due(leaf, reload_at) <= now;
+        if due && state.unchanged == 0 {
+            info!(
+                subject = &*leaf.subject,
+                not_after = leaf.not_after,
+                "server certificate due for renewal, reloading"
+            );
+        }
+        due
+    }
+
+    /// Counts reloads that found the same certificate again, to back off.
+    fn renewal_reloaded(&self) {
+        let pki = self.pki.lock().unwrap();
+        let mut state = self.renewal.state.lock().unwrap();
+        let not_after = pki.server_chain.first().map(|x| x.not_after);
+        if not_after.is_some() && not_after == state.last_not_after {
+            state.unchanged = state.unchanged.saturating_add(1);
+            debug!(
+                unchanged = state.unchanged,
+                "server certificate not renewed yet, backing off"
+            );
+        } else {
+            state.unchanged = 0;
+        }
+        state.last_not_after = not_after;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(not_before: i64, not_after: i64) -> CertificateInfo {
+        CertificateInfo {
+            subject: "CN=server".into(),
+            issuer: "CN=ca".into(),
+            not_before,
+            not_after,
+            fingerprint: "".into(),
+            subject_key_id: None,
+            public_key: "EC P-256".into(),
+        }
+    }
+
+    #[test]
+    fn waits_until_due() {
+        let leaf = leaf(0, 3600);
+        assert_eq!(wait(&leaf, 0.5, 0, 1000), 800);
+    }
+
+    #[test]
+    fn backs_off_while_unchanged() {
+        // One hour: retried every 3 minutes, capped at 60 seconds.
+        let hour = leaf(0, 3600);
+        assert_eq!(wait(&hour, 0.5, 0, 2000), 60);
+        assert_eq!(wait(&hour, 0.5, 1, 2000), 120);
+        assert_eq!(wait(&hour, 0.5, 3, 2000), 480);
+        assert_eq!(wait(&hour, 0.5, 10, 2000), MAX_BACKOFF);
+        assert_eq!(wait(&hour, 0.5, u32::MAX, 2000), MAX_BACKOFF);
+
+        let short = leaf(0, 20);
+        assert_eq!(wait(&short, 0.5, 0, 15), MIN_RETRY);
+    }
+
+    #[test]
+    fn rejects_reload_points_outside_the_lifetime() {
+        let renewal = Renewal::default();
+        assert!(renewal.check().is_ok());
+        for reload_at in [0.0, -0.5, 1.5, f64::NAN] {
+            renewal.configure(reload_at);
+            assert!(matches!(renewal.check(), Err(ReloadPointError(_))));
+        }
+        for reload_at in [0.1, 2.0 / 3.0, 1.0] {
+            renewal.configure(reload_at);
+            assert!(renewal.check().is_ok());
+        }
+    }
+}
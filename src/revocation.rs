@@ -0,0 +1,404 @@
+use crate::ocsp::OcspChecker;
+use crate::pki::hex;
+use crate::{ClientIdentity, ConnInfo, Error, MtlServer};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// What to do with a client certificate whose revocation status cannot be
+/// determined, e.g. because the responder is down or only stale data is
+/// available. Certificates known to be revoked are always rejected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RevocationPolicy {
+    /// Accept the connection silently.
+    Allow,
+    /// Accept the connection and log a warning.
+    #[default]
+    AllowWithWarning,
+    /// Close the connection.
+    Deny,
+}
+
+impl RevocationPolicy {
+    pub(crate) fn allows_unavailable(self, reason: &dyn fmt::Display) -> bool {
+        match self {
+            Self::Allow => {
+                debug!("revocation status unavailable: {}", reason);
+                true
+            }
+            Self::AllowWithWarning => {
+                warn!("revocation status unavailable, allowing: {}", reason);
+                true
+            }
+            Self::Deny => {
+                warn!("revocation status unavailable, rejecting: {}", reason);
+                false
+            }
+        }
+    }
+}
+
+/// The revocation status of a certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RevocationStatus {
+    Good,
+    Revoked,
+    /// Handled according to the [`RevocationPolicy`].
+    Unknown,
+}
+
+/// The client certificate a [`RevocationChecker`] is asked about.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RevocationQuery {
+    pub identity: ClientIdentity,
+    /// Lowercase hex serial number.
+    pub serial: Box<str>,
+    /// The issuer distinguished name.
+    pub issuer: Box<str>,
+}
+
+impl RevocationQuery {
+    fn new(conn_info: &ConnInfo) -> Option<Self> {
+        let identity = conn_info.client_identity()?.clone();
+        let leaf = conn_info.peer_certificates().first()?;
+        let (_, parsed) = X509Certificate::from_der(leaf).ok()?;
+        Some(Self {
+            identity,
+            serial: hex(parsed.tbs_certificate.raw_serial()).into(),
+            issuer: parsed.issuer().to_string().into(),
+        })
+    }
+}
+
+/// Looks up whether a client certificate was revoked, e.g. in a database or
+/// an internal API, for organizations that don't publish CRLs or OCSP.
+/// Implemented for async closures.
+pub trait RevocationChecker: Send + Sync + 'static {
+    fn check(
+        &self,
+        query: RevocationQuery,
+    ) -> BoxFuture<'static, Result<RevocationStatus, BoxError>>;
+}
+
+impl<F, Fut, E> RevocationChecker for F
+where
+    F: Fn(RevocationQuery) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<RevocationStatus, E>> + Send + 'static,
+    E: Into<BoxError>,
+{
+    fn check(
+        &self,
+        query: RevocationQuery,
+    ) -> BoxFuture<'static, Result<RevocationStatus, BoxError>> {
+        self(query).map(|x| x.map_err(Into::into)).boxed()
+    }
+}
+
+/// Consults a [`RevocationChecker`] for every client certificate, see
+/// [`MtlServer::with_revocation_check`](crate::MtlServer::with_revocation_check).
+#[derive(Clone)]
+pub struct RevocationCheck {
+    checker: Arc<dyn RevocationChecker>,
+    timeout: Duration,
+    policy: RevocationPolicy,
+    cache_ttl: Duration,
+}
+
+impl fmt::Debug for RevocationCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RevocationCheck")
+            .field("timeout", &self.timeout)
+            .field("policy", &self.policy)
+            .field("cache_ttl", &self.cache_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RevocationCheck {
+    pub fn new<C: RevocationChecker>(checker: C) -> Self {
+        Self {
+            checker: Arc::new(checker),
+            timeout: Duration::from_secs(2),
+            policy: RevocationPolicy::default(),
+            cache_ttl: Duration::from_secs(60),
+        }
+    }
+
+    /// Limits how long a connection waits for the checker, 2 seconds by
+    /// default. A timeout is handled according to the revocation policy.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Decides about certificates whose status can't be determined,
+    /// [`RevocationPolicy::AllowWithWarning`] by default.
+    pub fn with_revocation_policy(mut self, policy: RevocationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// How long an answer is reused for the same certificate, 1 minute by
+    /// default. Failures aren't cached; 0 disables the cache.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Cached {
+    status: RevocationStatus,
+    expires: Instant,
+}
+
+/// A [`RevocationCheck`] with the answers it got recently.
+pub(crate) struct CheckedRevocations {
+    check: RevocationCheck,
+    cache: Mutex<HashMap<Box<str>, Cached>>,
+}
+
+impl CheckedRevocations {
+    pub(crate) fn new(check: RevocationCheck) -> Self {
+        Self {
+            check,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Returns whether the connection may proceed.
+    pub(crate) async fn allows(&self, conn_info: &ConnInfo) -> bool {
+        let Some(query) = RevocationQuery::new(conn_info) else {
+            return true;
+        };
+        let fingerprint: Box<str> = query.identity.fingerprint().into();
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&fingerprint)
+            .and_then(|x| (x.expires > Instant::now()).then_some(x.status));
+        let status = match cached {
+            Some(status) => Ok(status),
+            None => {
+                let status = tokio::time::timeout(
+                    self.check.timeout,
+                    self.check.checker.check(query),
+                )
+                .await
+                .unwrap_or_else(|_| Err("revocation check timed out".into()));
+                if let Ok(status) = status {
+                    self.cache(fingerprint, status);
+                }
+                status
+            }
+        };
+        match status {
+            Ok(RevocationStatus::Good) => true,
+            Ok(RevocationStatus::Revoked) => {
+                debug!("client certificate is revoked");
+                false
+            }
+            Ok(RevocationStatus::Unknown) => self
+                .check
+                .policy
+                .allows_unavailable(&"certificate unknown to the checker"),
+            Err(err) => self.check.policy.allows_unavailable(&err),
+        }
+    }
+
+    fn cache(&self, fingerprint: Box<str>, status: RevocationStatus) {
+        if self.check.cache_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, x| x.expires > now);
+        let expires = now + self.check.cache_ttl;
+        cache.insert(fingerprint, Cached { status, expires });
+    }
+}
+
+/// The checks a client certificate passes after the handshake: OCSP, then
+/// the [`RevocationCheck`]. Shared by the acceptors so they agree on which
+/// clients are revoked.
+#[derive(Clone)]
+pub(crate) struct Revocations {
+    pub(crate) ocsp: Option<Arc<OcspChecker>>,
+    checked: Option<Arc<CheckedRevocations>>,
+}
+
+impl Revocations {
+    #[cfg(feature = "futures-io")]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ocsp.is_none() && self.checked.is_none()
+    }
+
+    /// Whether neither OCSP nor the revocation checker reject the client
+    /// certificate.
+    pub(crate) async fn allows(&self, conn_info: &ConnInfo) -> bool {
+        if let Some(ocsp) = &self.ocsp {
+            if !ocsp.allows(conn_info).await {
+                return false;
+            }
+        }
+        match &self.checked {
+            Some(checked) => checked.allows(conn_info).await,
+            None => true,
+        }
+    }
+}
+
+impl MtlServer {
+    pub(crate) fn create_revocations(&self) -> Result<Revocations, Error> {
+        Ok(Revocations {
+            ocsp: self.create_ocsp_checker()?.map(Arc::new),
+            checked: self
+                .revocation_check
+                .clone()
+                .map(|x| Arc::new(CheckedRevocations::new(x))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tokio")]
+    use crate::testing::{
+        connect_duplex,
+        fixtures::{FixedClock, FixtureDir},
+    };
+
+    #[test]
+    fn decides_about_unavailable_statuses() {
+        let reason = "responder timed out";
+        assert!(RevocationPolicy::Allow.allows_unavailable(&reason));
+        assert!(RevocationPolicy::AllowWithWarning.allows_unavailable(&reason));
+        assert!(!RevocationPolicy::Deny.allows_unavailable(&reason));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn warns_unless_allowed_silently() {
+        use crate::testing::Recorder;
+
+        let recorder = Recorder::default();
+        let _guard = recorder.install();
+        for policy in [
+            RevocationPolicy::Allow,
+            RevocationPolicy::AllowWithWarning,
+            RevocationPolicy::Deny,
+        ] {
+            policy.allows_unavailable(&"responder timed out");
+        }
+
+        let levels = recorder
+            .events("hyper_mtls_server::revocation")
+            .into_iter()
+            .map(|x| x.level);
+        assert_eq!(
+            levels.collect::<Vec<_>>(),
+            [
+                tracing::Level::DEBUG,
+                tracing::Level::WARN,
+                tracing::Level::WARN
+            ]
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn connect(server: MtlServer, fixtures: &FixtureDir) -> bool {
+        let acceptor = server
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .mtls_acceptor()
+            .unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        connect_duplex(&acceptor, config, "localhost").await.is_ok()
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn rejects_certificates_the_checker_reports_revoked() {
+        let fixtures = FixtureDir::new().unwrap();
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let checker = {
+            let queries = queries.clone();
+            move |query: RevocationQuery| {
+                queries.lock().unwrap().push(query);
+                async { Ok::<_, BoxError>(RevocationStatus::Revoked) }
+            }
+        };
+        let server = fixtures
+            .server()
+            .with_revocation_check(RevocationCheck::new(checker));
+        assert!(!connect(server.clone(), &fixtures).await);
+        assert_eq!(server.handle().metrics().connections_revoked, 1);
+
+        let queries = queries.lock().unwrap();
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0].identity.subject().ends_with("CN=alice"));
+        assert!(queries[0].issuer.contains("Intermediate"));
+        assert!(!queries[0].serial.is_empty());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn caches_answers_and_applies_the_policy_to_failures() {
+        let fixtures = FixtureDir::new().unwrap();
+        let calls = Arc::new(Mutex::new(0));
+        let counting = |status| {
+            let calls = calls.clone();
+            move |_| {
+                *calls.lock().unwrap() += 1;
+                async move { Ok::<_, BoxError>(status) }
+            }
+        };
+        let check = RevocationCheck::new(counting(RevocationStatus::Good));
+        let server = fixtures.server().with_revocation_check(check);
+        assert!(connect(server.clone(), &fixtures).await);
+        // Every acceptor has its own cache.
+        let acceptor = server
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .mtls_acceptor()
+            .unwrap();
+        for _ in 0..2 {
+            let config = fixtures.client_config("alice").unwrap();
+            let conn = connect_duplex(&acceptor, config, "localhost").await;
+            assert!(conn.is_ok());
+        }
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        let check = RevocationCheck::new(counting(RevocationStatus::Unknown))
+            .with_revocation_policy(RevocationPolicy::Deny);
+        let server = fixtures.server().with_revocation_check(check);
+        assert!(!connect(server, &fixtures).await);
+
+        let failing = |_| async { Err::<RevocationStatus, _>("unreachable") };
+        let check = RevocationCheck::new(failing);
+        let server = fixtures.server().with_revocation_check(check.clone());
+        assert!(connect(server, &fixtures).await);
+        let check = check.with_revocation_policy(RevocationPolicy::Deny);
+        let server = fixtures.server().with_revocation_check(check);
+        assert!(!connect(server, &fixtures).await);
+
+        let slow = |_| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok::<_, BoxError>(RevocationStatus::Good)
+        };
+        let check = RevocationCheck::new(slow)
+            .with_timeout(Duration::from_millis(10))
+            .with_revocation_policy(RevocationPolicy::Deny);
+        let server = fixtures.server().with_revocation_check(check);
+        assert!(!connect(server, &fixtures).await);
+    }
+}
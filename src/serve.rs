@@ -0,0 +1,1121 @@
+use crate::breaker::Breaker;
+use crate::close::Closing;
+use crate::deadline::{ConnTimeouts, FirstByteDeadline, StallTimeouts};
+use crate::diagnostics::Diagnostics;
+use crate::hooks::{CloseHook, OpenHook};
+use crate::listener::Accept;
+use crate::metrics::{IdentityCounters, Metrics};
+use crate::passthrough::proxy;
+use crate::principal::IdentityMapper;
+use crate::principal::MappedPrincipal;
+use crate::quota::ClientQuota;
+use crate::sni::{FallbackFuture, SniFallback};
+use crate::trace::{Instrument, Span};
+use crate::usage::{CountingStream, UsageCounters, UsageReport, UsageSink};
+use crate::workers::WorkerPool;
+use crate::{
+    missing_cert, CloseReason, ConnInfo, ConnectionId, Error, Http2Config,
+    HttpLimits, IdentityStore, LogEvent, MissingSni, MtlServer, MtlsAcceptor,
+    ServerHandle,
+};
+use futures_util::future::{ready, Either, Map, MapOk, Ready};
+use futures_util::{FutureExt, Stream, StreamExt, TryFutureExt};
+use http_body_util::Empty;
+use hyper::body::{Body, Incoming};
+use hyper::header::{HeaderName, HeaderValue, CONNECTION};
+use hyper::rt::Executor;
+use hyper::service::service_fn;
+use hyper::{Request, Response, Version};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use std::any::Any;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Instant;
+use tower_service::Service;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The tasks serving the connections of a listener, so shutdown can wait
+/// for them and panics are not lost.
+pub(crate) struct Tasks {
+    set: JoinSet<()>,
+    metrics: Arc<Metrics>,
+}
+
+impl Tasks {
+    pub(crate) fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            set: JoinSet::new(),
+            metrics,
+        }
+    }
+
+    pub(crate) fn spawn<F>(&mut self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        while let Some(result) = self.set.try_join_next() {
+            self.joined(result);
+        }
+        let live = self.metrics.task_spawned();
+        self.set.spawn(async move {
+            let _live = live;
+            task.await
+        });
+    }
+
+    async fn join_all(&mut self) {
+        while let Some(result) = self.set.join_next().await {
+            self.joined(result);
+        }
+    }
+
+    fn joined(&self, result: Result<(), JoinError>) {
+        let Err(err) = result else {
+            return;
+        };
+        if let Ok(panic) = err.try_into_panic() {
+            self.metrics.connection_panicked();
+            error!(
+                "connection task panicked: {}",
+                panic_message(panic.as_ref())
+            );
+        }
+    }
+}
+
+/// Waits for open connections to finish, aborting them once `timeout` has
+/// passed.
+pub(crate) async fn drain(mut tasks: Tasks, timeout: Option<Duration>) {
+    let wait = tasks.join_all();
+    match timeout {
+        Some(timeout) => {
+            if tokio::time::timeout(timeout, wait).await.is_err() {
+                warn!("graceful shutdown timed out, dropping connections");
+                tasks.set.shutdown().await;
+            }
+        }
+        None => wait.await,
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&'static str>() {
+        Some(msg) => msg,
+        None => match panic.downcast_ref::<String>() {
+            Some(msg) => msg,
+            None => "unknown panic payload",
+        },
+    }
+}
+
+/// Runs `future`, turning a panic into a log entry and a metrics count
+/// instead of losing it with the task.
+pub(crate) async fn catch_panic<F: Future>(
+    future: F,
+    metrics: &Metrics,
+) -> Option<F::Output> {
+    match AssertUnwindSafe(future).catch_unwind().await {
+        Ok(output) => Some(output),
+        Err(panic) => {
+            metrics.connection_panicked();
+            error!(
+                "connection task panicked: {}",
+                panic_message(panic.as_ref())
+            );
+            None
+        }
+    }
+}
+
+/// Spawns the tasks hyper creates for a connection, e.g. HTTP/2 streams,
+/// with the connection span and panic isolation.
+#[derive(Clone, Debug)]
+struct ConnExecutor {
+    metrics: Arc<Metrics>,
+    breaker: Arc<Breaker>,
+}
+
+impl<F> Executor<F> for ConnExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, future: F) {
+        let metrics = self.metrics.clone();
+        let breaker = self.breaker.clone();
+        let task = async move {
+            let output = catch_panic(future, &metrics).await;
+            if output.is_none() {
+                breaker.failed(&metrics);
+            }
+            output
+        };
+        tokio::spawn(task.instrument(Span::current()));
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ConnService<S> {
+    inner: S,
+    conn_info: ConnInfo,
+    id_header: Option<(HeaderName, HeaderValue)>,
+    identity_counters: Option<Arc<IdentityCounters>>,
+    usage: Option<Arc<UsageCounters>>,
+    principal: Option<MappedPrincipal>,
+    limits: HttpLimits,
+}
+
+/// The application's response body, or the empty body of a request rejected
+/// by the [`HttpLimits`].
+type ConnBody<B> = http_body_util::Either<B, Empty<<B as Body>::Data>>;
+
+fn app_response<B: Body>(response: Response<B>) -> Response<ConnBody<B>> {
+    response.map(ConnBody::Left)
+}
+
+impl<S> ConnService<S> {
+    fn new(
+        inner: S,
+        conn_info: ConnInfo,
+        id_header: Option<HeaderName>,
+        identity_counters: Option<Arc<IdentityCounters>>,
+        usage: Option<Arc<UsageCounters>>,
+        principal: Option<MappedPrincipal>,
+        limits: HttpLimits,
+    ) -> Self {
+        let id_header = id_header.and_then(|name| {
+            let value = HeaderValue::from_str(&conn_info.id().to_string());
+            value.ok().map(|value| (name, value))
+        });
+        Self {
+            inner,
+            conn_info,
+            id_header,
+            identity_counters,
+            usage,
+            principal,
+            limits,
+        }
+    }
+}
+
+impl<S, B, R> Service<Request<B>> for ConnService<S>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+    R: Body,
+{
+    type Response = Response<ConnBody<R>>;
+    type Error = S::Error;
+    type Future = Either<
+        Map<
+            Ready<Self::Response>,
+            fn(Self::Response) -> Result<Self::Response, S::Error>,
+        >,
+        MapOk<S::Future, fn(Response<R>) -> Self::Response>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        if let Some(status) = self.limits.reject(&req) {
+            debug!("rejected request head with status {}", status);
+            let mut response = Response::new(ConnBody::Right(Empty::new()));
+            *response.status_mut() = status;
+            if req.version() < Version::HTTP_2 {
+                response
+                    .headers_mut()
+                    .insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+            return Either::Left(ready(response).map(Ok as fn(_) -> _));
+        }
+        if let Some(counters) = &self.identity_counters {
+            counters.request();
+        }
+        if let Some(usage) = &self.usage {
+            usage.request();
+        }
+        if let Some((name, value)) = &self.id_header {
+            req.headers_mut().insert(name.clone(), value.clone());
+        }
+        req.extensions_mut().insert(self.conn_info.id());
+        req.extensions_mut().insert(self.conn_info.clone());
+        if let Some(principal) = &self.principal {
+            principal.insert_into(req.extensions_mut());
+        }
+        Either::Right(self.inner.call(req).map_ok(app_response as fn(_) -> _))
+    }
+}
+
+/// Answers `req` with the service for clients without SNI.
+fn answer(
+    fallback: &Arc<SniFallback>,
+    conn_info: &ConnInfo,
+    mut req: Request<Incoming>,
+) -> FallbackFuture {
+    req.extensions_mut().insert(conn_info.id());
+    req.extensions_mut().insert(conn_info.clone());
+    fallback(req)
+}
+
+/// Everything a connection needs from the server after it was accepted,
+/// cloned into every connection task.
+#[derive(Clone)]
+struct ConnHandler<M> {
+    acceptor: MtlsAcceptor,
+    diagnostics: Option<Arc<Diagnostics>>,
+    missing_cert_response: bool,
+    sni_fallback: Option<Arc<SniFallback>>,
+    make_service: M,
+    id_header: Option<HeaderName>,
+    client_quota: Option<Arc<ClientQuota>>,
+    identity_mapper: Option<Arc<IdentityMapper>>,
+    usage_sink: Option<Arc<dyn UsageSink>>,
+    identity_store: Option<Arc<dyn IdentityStore>>,
+    on_open: Option<Arc<OpenHook>>,
+    on_close: Option<Arc<CloseHook>>,
+    http2: Http2Config,
+    limits: HttpLimits,
+    timeouts: ConnTimeouts,
+    metrics: Arc<Metrics>,
+    handle: ServerHandle,
+}
+
+impl<M> ConnHandler<M> {
+    fn builder<X>(&self, executor: X) -> auto::Builder<X> {
+        let mut builder = auto::Builder::new(executor);
+        self.http2.apply(&mut builder);
+        self.limits.apply(&mut builder);
+        builder
+    }
+}
+
+impl<M, Fut, S, E, B> ConnHandler<M>
+where
+    M: Fn(ConnInfo) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<S, E>> + Send + 'static,
+    E: Into<BoxError>,
+    S: Service<Request<Incoming>, Response = Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    /// Performs the handshake and serves HTTP on `stream` until it closes.
+    async fn serve<IO>(
+        self,
+        stream: IO,
+        id: ConnectionId,
+        addr: SocketAddr,
+        deadline: Option<Instant>,
+        close: impl Future<Output = ()>,
+    ) where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut closing = Closing::new(&self.handle);
+        let accepted = self.acceptor.accept_with_id(stream, id, addr);
+        let (stream, conn_info) = match accepted.await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                let logs = &self.handle.logs;
+                if logs.handshake_failed(err.label(), addr.ip()) {
+                    log_event!(
+                        logs,
+                        LogEvent::Handshakes,
+                        reason = err.label(),
+                        alert = err.alert_sent(),
+                        "error accepting mTLS: {}",
+                        err
+                    );
+                }
+                closing.set(CloseReason::HandshakeFailed);
+                return;
+            }
+        };
+        log_event!(
+            self.handle.logs,
+            LogEvent::Accepts,
+            client = conn_info.client_identity().map(|x| x.subject()),
+            "accepted mTLS connection from {}",
+            addr
+        );
+        closing.on_close =
+            self.on_close.clone().map(|x| (x, conn_info.clone()));
+        let registration = self.handle.connections.register(conn_info.clone());
+        let close = registration.close_requested(close);
+        let accepted = Instant::now();
+        if let Some(on_open) = &self.on_open {
+            on_open(conn_info.clone()).await;
+        }
+
+        let diagnostics = self
+            .diagnostics
+            .as_ref()
+            .filter(|_| self.acceptor.is_diagnostic(&stream));
+        if let Some(diagnostics) = diagnostics {
+            let diagnostics = diagnostics.clone();
+            let service = service_fn(move |_| {
+                let response = diagnostics.respond(&conn_info);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            let builder = self.builder(TokioExecutor::new());
+            let conn = builder.serve_connection(TokioIo::new(stream), service);
+            let expire = self.timeouts.expired(accepted, None);
+            closing.serve(conn, close, expire).await;
+            return;
+        }
+
+        let no_sni = conn_info.server_name().is_none();
+        if let Some(fallback) = self.sni_fallback.clone().filter(|_| no_sni) {
+            let service =
+                service_fn(move |req| answer(&fallback, &conn_info, req));
+            let builder = self.builder(TokioExecutor::new());
+            let conn = builder.serve_connection(TokioIo::new(stream), service);
+            let expire = self.timeouts.expired(accepted, None);
+            closing.serve(conn, close, expire).await;
+            return;
+        }
+
+        if self.missing_cert_response
+            && conn_info.peer_certificates().is_empty()
+        {
+            let builder = self.builder(TokioExecutor::new());
+            let conn = builder.serve_connection(
+                TokioIo::new(stream),
+                service_fn(missing_cert::respond),
+            );
+            let expire = self.timeouts.expired(accepted, None);
+            closing.serve(conn, close, expire).await;
+            return;
+        }
+
+        let identity = conn_info.client_identity();
+        if let (Some(store), Some(identity), Some(leaf)) = (
+            &self.identity_store,
+            identity,
+            conn_info.peer_certificates().first(),
+        ) {
+            self.handle.track_identity(&**store, identity, leaf);
+        }
+        let _slot = match (&self.client_quota, identity) {
+            (Some(quota), Some(identity)) => match quota.acquire(identity) {
+                Some(slot) => Some(slot),
+                None => {
+                    self.metrics.connection_over_quota();
+                    log_event!(
+                        self.handle.logs,
+                        LogEvent::Rejections,
+                        "client {} is over its connection quota",
+                        identity.subject()
+                    );
+                    closing.set(CloseReason::PolicyRejected);
+                    return;
+                }
+            },
+            _ => None,
+        };
+        let mapped = self
+            .identity_mapper
+            .as_ref()
+            .and_then(|mapper| conn_info.principal(mapper).zip(identity));
+        let principal = match mapped {
+            Some((mapped, identity)) => match mapped {
+                Ok(principal) => Some(principal),
+                Err(rejection) => {
+                    self.metrics.connection_rejected();
+                    log_event!(
+                        self.handle.logs,
+                        LogEvent::Rejections,
+                        "client {} rejected: {}",
+                        identity.subject(),
+                        rejection
+                    );
+                    closing.set(CloseReason::PolicyRejected);
+                    return;
+                }
+            },
+            _ => None,
+        };
+        let identity_counters =
+            identity.and_then(|x| self.metrics.identity_connection(x));
+        closing.usage = self
+            .usage_sink
+            .clone()
+            .and_then(|sink| UsageReport::new(sink, &conn_info));
+        let usage_counters = closing.usage.as_ref().map(UsageReport::counters);
+
+        let executor = ConnExecutor {
+            metrics: self.metrics.clone(),
+            breaker: self.handle.breaker.clone(),
+        };
+        let builder = self.builder(executor);
+        let service = match (self.make_service)(conn_info.clone()).await {
+            Ok(service) => TowerToHyperService::new(ConnService::new(
+                service,
+                conn_info,
+                self.id_header,
+                identity_counters,
+                usage_counters.clone(),
+                principal,
+                self.limits,
+            )),
+            Err(err) => {
+                debug!("error building service: {:?}", err.into());
+                closing.set(CloseReason::Error);
+                return;
+            }
+        };
+
+        let activity = self.timeouts.activity();
+        let conn = builder.serve_connection_with_upgrades(
+            TokioIo::new(FirstByteDeadline::new(
+                StallTimeouts::new(
+                    CountingStream::new(stream, usage_counters),
+                    &self.timeouts,
+                ),
+                deadline,
+                activity.clone(),
+            )),
+            service,
+        );
+        let expire = self.timeouts.expired(accepted, activity);
+        closing.serve(conn, close, expire).await;
+    }
+}
+
+impl MtlServer {
+    fn conn_handler<M>(
+        &self,
+        make_service: M,
+    ) -> Result<ConnHandler<M>, Error> {
+        self.http2.check()?;
+        self.http_limits.check()?;
+        let sni_fallback = match self.missing_sni() {
+            MissingSni::Fallback => Some(
+                self.missing_sni_fallback
+                    .clone()
+                    .ok_or(Error::MissingSniFallbackError)?,
+            ),
+            _ => None,
+        };
+        Ok(ConnHandler {
+            acceptor: self.create_mtls_acceptor(true)?,
+            diagnostics: self.create_diagnostics()?.map(Arc::new),
+            missing_cert_response: self.missing_cert_response(),
+            sni_fallback,
+            make_service,
+            id_header: self.connection_id_header.clone(),
+            client_quota: self.client_quota.clone(),
+            identity_mapper: self.identity_mapper.clone(),
+            usage_sink: self.usage_sink.clone(),
+            identity_store: self.identity_store.clone(),
+            on_open: self.on_connection_open.clone(),
+            on_close: self.on_connection_close.clone(),
+            http2: self.http2.clone(),
+            limits: self.http_limits,
+            timeouts: self.conn_timeouts,
+            metrics: self.handle.metrics.clone(),
+            handle: self.handle.clone(),
+        })
+    }
+
+    pub async fn serve_service<S, B>(
+        &self,
+        listener: TcpListener,
+        service: S,
+    ) -> Result<(), Error>
+    where
+        S: Service<Request<Incoming>, Response = Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        self.serve_make_service(listener, move |_| {
+            let service = service.clone();
+            async move { Ok::<_, Infallible>(service) }
+        })
+        .await
+    }
+
+    /// Serves `service` on an ephemeral port of 127.0.0.1 in a spawned task,
+    /// e.g. for integration tests. Returns once the server is listening,
+    /// with its address and the handle to shut it down; errors building the
+    /// server are returned rather than lost with the task.
+    pub async fn serve_ephemeral<S, B>(
+        self,
+        service: S,
+    ) -> Result<(SocketAddr, ServerHandle), Error>
+    where
+        S: Service<Request<Incoming>, Response = Response<B>>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .map_err(Error::EphemeralBindError)?;
+        let handle = self.handle();
+        let mut task =
+            tokio::spawn(
+                async move { self.serve_service(listener, service).await },
+            );
+        tokio::select! {
+            addr = handle.listening() => Ok((addr, handle)),
+            result = &mut task => match result {
+                Ok(Err(err)) => Err(err),
+                Ok(Ok(())) => Err(Error::NotListeningError),
+                Err(err) => std::panic::resume_unwind(err.into_panic()),
+            },
+        }
+    }
+
+    /// Like [`MtlServer::serve_service`], but builds the service once per
+    /// connection after the handshake, so values derived from the client
+    /// identity can be resolved up front. Returning an error from
+    /// `make_service` closes the connection. The [`ConnInfo`] and
+    /// [`ConnectionId`] are also added to the extensions of every request.
+    pub async fn serve_make_service<M, Fut, S, E, B>(
+        &self,
+        listener: TcpListener,
+        make_service: M,
+    ) -> Result<(), Error>
+    where
+        M: Fn(ConnInfo) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = Result<S, E>> + Send + 'static,
+        E: Into<BoxError>,
+        S: Service<Request<Incoming>, Response = Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        self.serve_accepting(listener, make_service).await
+    }
+
+    /// [`MtlServer::serve_make_service`] on any listener.
+    pub(crate) async fn serve_accepting<L, M, Fut, S, E, B>(
+        &self,
+        listener: L,
+        make_service: M,
+    ) -> Result<(), Error>
+    where
+        L: Accept,
+        M: Fn(ConnInfo) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = Result<S, E>> + Send + 'static,
+        E: Into<BoxError>,
+        S: Service<Request<Incoming>, Response = Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        let handler = self.conn_handler(make_service)?;
+        let metrics = self.handle.metrics.clone();
+        let mut tasks = Tasks::new(metrics.clone());
+        let workers = self
+            .accept_workers
+            .map(|config| WorkerPool::new(config, &mut tasks));
+        let _registration = self.start_listening(&listener);
+
+        let timeout = self
+            .accept_loop(&listener, |stream, addr, permit| {
+                if let Some(policy) = &self.load_shed {
+                    if policy.should_shed(&metrics.load()) {
+                        metrics.connection_shed();
+                        log_event!(
+                            self.handle.logs,
+                            LogEvent::Rejections,
+                            "shedding connection from {}",
+                            addr
+                        );
+                        return Either::Left(std::future::ready(()));
+                    }
+                }
+
+                let handler = handler.clone();
+                let passthrough = self.passthrough.clone();
+                let peek_timeout = self.handshake_timeout;
+                let close = self.handle.close_requested();
+                let metrics = metrics.clone();
+                let task_metrics = metrics.clone();
+                let active = metrics.connection_accepted();
+                let id = ConnectionId::new();
+                let deadline =
+                    self.first_request_timeout.map(|x| Instant::now() + x);
+                let span = debug_span!(
+                    "mtls_connection",
+                    conn_id = %id,
+                    remote_addr = %addr
+                );
+
+                let task = async move {
+                    let _permit = permit;
+                    let _active = active;
+                    if let Some(passthrough) = &passthrough {
+                        let route = passthrough.route(&stream, peek_timeout);
+                        if let Some(backend) = route.await {
+                            metrics.connection_passed_through();
+                            if let Err(err) = proxy(stream, backend).await {
+                                debug!(
+                                    "error proxying to {}: {:?}",
+                                    backend, err
+                                );
+                            }
+                            return;
+                        }
+                    }
+                    handler.serve(stream, id, addr, deadline, close).await;
+                };
+                let task = async move {
+                    catch_panic(task, &task_metrics).await;
+                };
+                let task = task.instrument(span);
+                match &workers {
+                    Some(workers) => {
+                        let workers = workers.clone();
+                        Either::Right(async move { workers.submit(task).await })
+                    }
+                    None => {
+                        tasks.spawn(task);
+                        Either::Left(std::future::ready(()))
+                    }
+                }
+            })
+            .await;
+        drop(listener);
+        drop(workers);
+
+        drain(tasks, timeout).await;
+
+        Ok(())
+    }
+
+    /// Serves connections established elsewhere, e.g. the streams of a
+    /// yamux session or connections accepted through an SSH or SOCKS
+    /// tunnel, with the same handshake and HTTP handling as
+    /// [`MtlServer::serve_service`]. Every item is a stream and the address
+    /// to report as its remote address. Returns once `streams` ended and its
+    /// connections are closed, or after a shutdown requested through the
+    /// handle. The connection limit and load shedding are not applied.
+    pub async fn serve_streams<St, IO, S, B>(
+        &self,
+        streams: St,
+        service: S,
+    ) -> Result<(), Error>
+    where
+        St: Stream<Item = (IO, SocketAddr)>,
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        S: Service<Request<Incoming>, Response = Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        let handler = self.conn_handler(move |_| {
+            let service = service.clone();
+            async move { Ok::<_, Infallible>(service) }
+        })?;
+        let metrics = self.handle.metrics.clone();
+        let mut tasks = Tasks::new(metrics.clone());
+        let shutdown = self.handle.shutdown_requested();
+        tokio::pin!(shutdown);
+        tokio::pin!(streams);
+
+        let ended = loop {
+            let next = tokio::select! {
+                timeout = &mut shutdown => break Err(timeout),
+                next = streams.next() => next,
+            };
+            let Some((stream, addr)) = next else {
+                break Ok(());
+            };
+
+            let handler = handler.clone();
+            let close = self.handle.close_requested();
+            let task_metrics = metrics.clone();
+            let active = metrics.connection_accepted();
+            let id = ConnectionId::new();
+            let deadline =
+                self.first_request_timeout.map(|x| Instant::now() + x);
+            let span = debug_span!(
+                "mtls_connection",
+                conn_id = %id,
+                remote_addr = %addr
+            );
+            let task = async move {
+                let _active = active;
+                handler.serve(stream, id, addr, deadline, close).await;
+            };
+            tasks.spawn(
+                async move {
+                    catch_panic(task, &task_metrics).await;
+                }
+                .instrument(span),
+            );
+        };
+
+        let timeout = match ended {
+            Err(timeout) => timeout,
+            Ok(()) => tokio::select! {
+                timeout = &mut shutdown => timeout,
+                () = tasks.join_all() => return Ok(()),
+            },
+        };
+        drain(tasks, timeout).await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::testing::send;
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Bytes;
+    use rustls::pki_types::ServerName;
+    use tokio::sync::Notify;
+    use tokio::task::JoinHandle;
+    use tokio_rustls::TlsConnector;
+    use tower::service_fn;
+
+    type Serving = JoinHandle<Result<(), Error>>;
+
+    fn server(fixtures: &FixtureDir) -> MtlServer {
+        fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+    }
+
+    /// Serves `service` on an ephemeral port until shut down.
+    async fn serve<S, B>(
+        server: MtlServer,
+        service: S,
+    ) -> (SocketAddr, ServerHandle, Serving)
+    where
+        S: Service<Request<Incoming>, Response = Response<B>>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            server.serve_service(listener, service).await
+        });
+        (addr, handle, serving)
+    }
+
+    fn get(path: &str) -> Request<Empty<Bytes>> {
+        Request::get(path).body(Empty::new()).unwrap()
+    }
+
+    /// Answers once `release` is notified, after notifying `started`.
+    fn held(
+        started: Arc<Notify>,
+        release: Arc<Notify>,
+    ) -> impl Service<
+        Request<Incoming>,
+        Response = Response<Full<Bytes>>,
+        Error = Infallible,
+        Future = impl Send,
+    > + Clone {
+        service_fn(move |_| {
+            let (started, release) = (started.clone(), release.clone());
+            async move {
+                started.notify_one();
+                release.notified().await;
+                Ok::<_, Infallible>(Response::new(Full::from("done")))
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_lets_requests_in_flight_complete() {
+        let fixtures = FixtureDir::new().unwrap();
+        let (started, release) = (Arc::default(), Arc::<Notify>::default());
+        let service = held(Arc::clone(&started), release.clone());
+        let (addr, handle, serving) = serve(server(&fixtures), service).await;
+
+        let config = fixtures.client_config("alice").unwrap();
+        let request = tokio::spawn(send(config, addr, get("/")));
+        started.notified().await;
+        handle.graceful_shutdown(Some(Duration::from_secs(10)));
+        tokio::task::yield_now().await;
+        assert!(!serving.is_finished());
+
+        release.notify_one();
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(response.status(), 200);
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_drops_connections_after_the_timeout() {
+        let fixtures = FixtureDir::new().unwrap();
+        let started = Arc::<Notify>::default();
+        let service = held(started.clone(), Arc::default());
+        let (addr, handle, serving) = serve(server(&fixtures), service).await;
+
+        let config = fixtures.client_config("alice").unwrap();
+        let request = tokio::spawn(send(config, addr, get("/")));
+        started.notified().await;
+        handle.graceful_shutdown(Some(Duration::from_millis(50)));
+        serving.await.unwrap().unwrap();
+        assert!(request.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn builds_a_service_per_connection_for_its_client() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = server(&fixtures);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            let make_service = |conn_info: ConnInfo| async move {
+                let identity = conn_info.client_identity().unwrap();
+                if identity.common_name() != Some("alice") {
+                    return Err("not alice");
+                }
+                Ok(service_fn(move |req: Request<Incoming>| {
+                    let same = req.extensions().get::<ConnInfo>().unwrap().id()
+                        == conn_info.id();
+                    let body = Full::<Bytes>::from(format!("alice {}", same));
+                    async move { Ok::<_, Infallible>(Response::new(body)) }
+                }))
+            };
+            server.serve_make_service(listener, make_service).await
+        });
+
+        let config = fixtures.client_config("alice").unwrap();
+        let response = send(config, addr, get("/")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "alice true");
+        let config = fixtures.client_config("bob").unwrap();
+        assert!(send(config, addr, get("/")).await.is_err());
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn adds_the_connection_id_to_requests() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = server(&fixtures)
+            .with_connection_id_header(HeaderName::from_static("x-conn-id"));
+        let service = service_fn(|req: Request<Incoming>| {
+            let header = req.headers()["x-conn-id"].to_str().unwrap();
+            let id = req.extensions().get::<ConnectionId>().unwrap();
+            let body = Full::<Bytes>::from(format!("{} {}", header, id));
+            async move { Ok::<_, Infallible>(Response::new(body)) }
+        });
+        let (addr, handle, serving) = serve(server, service).await;
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let config = fixtures.client_config("alice").unwrap();
+            let response = send(config, addr, get("/")).await.unwrap();
+            let body = response.into_body().collect().await.unwrap();
+            let body = String::from_utf8(body.to_bytes().to_vec()).unwrap();
+            let (header, id) = body.split_once(' ').unwrap();
+            assert_eq!(header, id);
+            ids.push(id.to_owned());
+        }
+        assert_ne!(ids[0], ids[1]);
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_closes_only_its_connection() {
+        let fixtures = FixtureDir::new().unwrap();
+        let service = service_fn(|req: Request<Incoming>| async move {
+            if req.uri().path() == "/panic" {
+                panic!("handler panicked");
+            }
+            Ok::<_, Infallible>(Response::new(Full::<Bytes>::from("ok")))
+        });
+        let (addr, handle, serving) = serve(server(&fixtures), service).await;
+
+        let config = fixtures.client_config("alice").unwrap();
+        assert!(send(config.clone(), addr, get("/panic")).await.is_err());
+        let response = send(config, addr, get("/")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let metrics = handle.metrics();
+        assert_eq!(metrics.connection_panics, 1);
+        assert_eq!(metrics.connections_accepted, 2);
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn returns_once_the_connection_tasks_finished() {
+        let fixtures = FixtureDir::new().unwrap();
+        let (started, release) = (Arc::default(), Arc::<Notify>::default());
+        let service = held(Arc::clone(&started), release.clone());
+        let (addr, handle, serving) = serve(server(&fixtures), service).await;
+
+        let config = fixtures.client_config("alice").unwrap();
+        let finished = tokio::spawn(send(config.clone(), addr, get("/")));
+        started.notified().await;
+        // Held until the shutdown timeout aborts its task.
+        let aborted = tokio::spawn(send(config, addr, get("/")));
+        started.notified().await;
+        release.notify_one();
+        finished.await.unwrap().unwrap();
+        assert_eq!(handle.metrics().connection_tasks, 2);
+
+        handle.graceful_shutdown(Some(Duration::from_millis(50)));
+        serving.await.unwrap().unwrap();
+        assert_eq!(handle.metrics().connection_tasks, 0);
+        assert!(aborted.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn runs_the_hooks_of_each_connection() {
+        let fixtures = FixtureDir::new().unwrap();
+        let (events, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let (opened, closed) = (events.clone(), events);
+        let server = server(&fixtures)
+            .with_on_connection_open(move |conn_info| {
+                let _ = opened.send((conn_info.id(), None));
+                async {}
+            })
+            .with_on_connection_close(move |conn_info, reason| {
+                let _ = closed.send((conn_info.id(), Some(reason)));
+                async {}
+            });
+        let service = service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Full::<Bytes>::from("ok")))
+        });
+        let (addr, handle, serving) = serve(server, service).await;
+
+        let config = fixtures.client_config("alice").unwrap();
+        let response = send(config, addr, get("/")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let (id, reason) = received.recv().await.unwrap();
+        assert_eq!(reason, None);
+        assert_eq!(
+            received.recv().await.unwrap(),
+            (id, Some(crate::CloseReason::ClientClosed))
+        );
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_on_an_ephemeral_port() {
+        let fixtures = FixtureDir::new().unwrap();
+        let service = service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Full::<Bytes>::from("ok")))
+        });
+        let (addr, handle) =
+            server(&fixtures).serve_ephemeral(service).await.unwrap();
+        assert!(addr.ip().is_loopback());
+        let config = fixtures.client_config("alice").unwrap();
+        let response = send(config, addr, get("/")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        handle.shutdown();
+
+        // Errors building the server are returned.
+        std::fs::remove_file(&*fixtures.file("ca.crt")).unwrap();
+        assert!(server(&fixtures).serve_ephemeral(service).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_established_streams() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = server(&fixtures);
+        let handle = server.handle();
+        let (tunnel, served) = tokio::io::duplex(64 * 1024);
+        let peer = SocketAddr::from(([10, 0, 0, 7], 4000));
+        let streams = futures_util::stream::iter([(served, peer)]);
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|req: Request<Incoming>| {
+                let conn_info = req.extensions().get::<ConnInfo>().unwrap();
+                let body = Full::<Bytes>::from(format!(
+                    "{} {}",
+                    conn_info.client_identity().unwrap().common_name().unwrap(),
+                    conn_info.remote_addr()
+                ));
+                async move { Ok::<_, Infallible>(Response::new(body)) }
+            });
+            server.serve_streams(streams, service).await
+        });
+
+        let config = fixtures.client_config("alice").unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let tls = TlsConnector::from(config)
+            .connect(server_name, tunnel)
+            .await
+            .unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(tls))
+                .await
+                .unwrap();
+        let conn = tokio::spawn(conn);
+        let response = sender.send_request(get("/")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "alice 10.0.0.7:4000");
+        assert_eq!(handle.metrics().connections_accepted, 1);
+
+        // Serving returns once the only connection is closed.
+        drop(sender);
+        conn.await.unwrap().unwrap();
+        serving.await.unwrap().unwrap();
+    }
+}
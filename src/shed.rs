@@ -0,0 +1,126 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Server load at the moment a new connection is accepted, before it is
+/// counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Load {
+    pub active_connections: u64,
+    pub pending_handshakes: u64,
+}
+
+/// Decides whether a new connection is closed right after accept, before
+/// any handshake work is spent on it.
+pub trait LoadShedPolicy: Send + Sync + 'static {
+    fn should_shed(&self, load: &Load) -> bool;
+}
+
+impl<F> LoadShedPolicy for F
+where
+    F: Fn(&Load) -> bool + Send + Sync + 'static,
+{
+    fn should_shed(&self, load: &Load) -> bool {
+        self(load)
+    }
+}
+
+type Probe = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// Sheds connections once any of the configured limits is reached or the
+/// probe reports pressure.
+#[derive(Clone, Default)]
+pub struct LoadShedThresholds {
+    max_active_connections: Option<u64>,
+    max_pending_handshakes: Option<u64>,
+    probe: Option<Probe>,
+}
+
+impl LoadShedThresholds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_active_connections(mut self, max: u64) -> Self {
+        self.max_active_connections = Some(max);
+        self
+    }
+
+    pub fn with_max_pending_handshakes(mut self, max: u64) -> Self {
+        self.max_pending_handshakes = Some(max);
+        self
+    }
+
+    /// Called for every accepted connection, e.g. to check memory usage.
+    /// Returning `true` sheds the connection. It should be cheap.
+    pub fn with_probe<F>(mut self, probe: F) -> Self
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        self.probe = Some(Arc::new(probe));
+        self
+    }
+}
+
+impl fmt::Debug for LoadShedThresholds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoadShedThresholds")
+            .field("max_active_connections", &self.max_active_connections)
+            .field("max_pending_handshakes", &self.max_pending_handshakes)
+            .field("probe", &self.probe.is_some())
+            .finish()
+    }
+}
+
+impl LoadShedPolicy for LoadShedThresholds {
+    fn should_shed(&self, load: &Load) -> bool {
+        let exceeds =
+            |limit: Option<u64>, value| limit.is_some_and(|x| value >= x);
+
+        exceeds(self.max_active_connections, load.active_connections)
+            || exceeds(self.max_pending_handshakes, load.pending_handshakes)
+            || self.probe.as_ref().is_some_and(|probe| probe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn load(active_connections: u64, pending_handshakes: u64) -> Load {
+        Load {
+            active_connections,
+            pending_handshakes,
+        }
+    }
+
+    #[test]
+    fn sheds_once_a_threshold_is_reached() {
+        let policy = LoadShedThresholds::new()
+            .with_max_active_connections(10)
+            .with_max_pending_handshakes(2);
+        assert!(!policy.should_shed(&load(9, 1)));
+        assert!(policy.should_shed(&load(10, 0)));
+        assert!(policy.should_shed(&load(0, 2)));
+        assert!(!LoadShedThresholds::new().should_shed(&load(1000, 1000)));
+    }
+
+    #[test]
+    fn sheds_while_the_probe_reports_pressure() {
+        let pressure = Arc::new(AtomicBool::new(false));
+        let probe = pressure.clone();
+        let policy = LoadShedThresholds::new()
+            .with_probe(move || probe.load(Ordering::Relaxed));
+        assert!(!policy.should_shed(&load(0, 0)));
+        pressure.store(true, Ordering::Relaxed);
+        assert!(policy.should_shed(&load(0, 0)));
+    }
+
+    #[test]
+    fn closures_are_policies() {
+        let policy = |load: &Load| load.active_connections > 1;
+        assert!(!policy.should_shed(&load(1, 0)));
+        assert!(policy.should_shed(&load(2, 0)));
+    }
+}
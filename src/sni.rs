@@ -0,0 +1,277 @@
+/// What the server does with clients that send no SNI, typically because
+/// they connect by IP address. An IP address sent as SNI is treated as no
+/// SNI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MissingSni {
+    /// Complete the handshake with the server certificate. The default
+    /// without an SNI allowlist.
+    Serve,
+    /// Close the connection after the ClientHello. The default with an SNI
+    /// allowlist.
+    Reject,
+    /// Complete the handshake with the server certificate and answer the
+    /// client with the service given to
+    /// [`MtlServer::with_missing_sni_fallback`](crate::MtlServer::with_missing_sni_fallback)
+    /// rather than the application's. Connections from
+    /// [`MtlServer::mtls_acceptor`](crate::MtlServer::mtls_acceptor) are
+    /// handed over like with `Serve`.
+    Fallback,
+}
+
+impl MissingSni {
+    /// Whether handshakes without SNI are completed.
+    pub(crate) fn completes_handshake(self) -> bool {
+        self != Self::Reject
+    }
+}
+
+/// The server names handshakes are accepted for, see
+/// [`MtlServer::with_allowed_sni`](crate::MtlServer::with_allowed_sni).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SniAllowlist {
+    patterns: Vec<Box<str>>,
+}
+
+impl SniAllowlist {
+    pub(crate) fn insert(&mut self, pattern: Box<str>) {
+        self.patterns.push(pattern);
+    }
+
+    pub(crate) fn allows(&self, server_name: &str) -> bool {
+        self.patterns.iter().any(|x| matches(x, server_name))
+    }
+}
+
+/// `*.example.com` matches exactly one label in place of the asterisk, like
+/// a wildcard certificate.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => name.split_once('.').is_some_and(|(label, rest)| {
+            !label.is_empty() && rest.eq_ignore_ascii_case(suffix)
+        }),
+        None => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+/// Answering clients without SNI with a service of their own.
+#[cfg(feature = "tokio")]
+mod fallback {
+    use super::MissingSni;
+    use crate::MtlServer;
+    use futures_util::future::BoxFuture;
+    use futures_util::{FutureExt, TryFutureExt};
+    use http_body_util::combinators::UnsyncBoxBody;
+    use http_body_util::BodyExt;
+    use hyper::body::{Body, Bytes, Incoming};
+    use hyper::{Request, Response};
+    use hyper_util::service::TowerToHyperService;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tower_service::Service;
+
+    type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// The service answering clients without SNI, see
+    /// [`MissingSni::Fallback`].
+    pub(crate) type SniFallback =
+        dyn Fn(Request<Incoming>) -> FallbackFuture + Send + Sync;
+
+    pub(crate) type FallbackBody = UnsyncBoxBody<Bytes, FallbackError>;
+
+    /// An error of the fallback service or its body. This and
+    /// [`FallbackFuture`] are named types rather than a `BoxError` and a
+    /// `BoxFuture`, which would keep the connection futures from being
+    /// `Send`.
+    #[derive(thiserror::Error, Debug)]
+    #[error(transparent)]
+    pub(crate) struct FallbackError(BoxError);
+
+    pub(crate) struct FallbackFuture(
+        BoxFuture<'static, Result<Response<FallbackBody>, FallbackError>>,
+    );
+
+    impl Future for FallbackFuture {
+        type Output = Result<Response<FallbackBody>, FallbackError>;
+
+        fn poll(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Self::Output> {
+            self.0.as_mut().poll(cx)
+        }
+    }
+
+    impl MtlServer {
+        /// Answers clients without SNI with `service`, see
+        /// [`MissingSni::Fallback`], e.g. with a page naming the hosts
+        /// served. Like the diagnostics endpoint, such connections skip the
+        /// connection policies; requests get the
+        /// [`ConnInfo`](crate::ConnInfo) extension. Applies to
+        /// `serve_service` and friends.
+        pub fn with_missing_sni_fallback<S, B>(mut self, service: S) -> Self
+        where
+            S: Service<Request<Incoming>, Response = Response<B>>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+            S::Future: Send + 'static,
+            S::Error: Into<BoxError>,
+            B: Body<Data = Bytes> + Send + 'static,
+            B::Error: Into<BoxError>,
+        {
+            let service = TowerToHyperService::new(service);
+            self.missing_sni = Some(MissingSni::Fallback);
+            self.missing_sni_fallback = Some(Arc::new(move |req| {
+                let response = hyper::service::Service::call(&service, req)
+                    .map_ok(|x| {
+                        x.map(|x| {
+                            x.map_err(|x| FallbackError(x.into()))
+                                .boxed_unsync()
+                        })
+                    })
+                    .map_err(|x| FallbackError(x.into()));
+                FallbackFuture(response.boxed())
+            }));
+            self
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) use fallback::{FallbackFuture, SniFallback};
+
+/// Fails the handshake of a connection whose server name isn't allowed.
+#[derive(thiserror::Error, Debug)]
+#[error("server name not allowed")]
+pub(crate) struct ServerNameRejected;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::{Error, MtlServer};
+    use futures_util::stream;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::{Bytes, Incoming};
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+    use rustls::pki_types::ServerName;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::DuplexStream;
+    use tokio_rustls::TlsConnector;
+    use tower::service_fn;
+
+    fn respond(
+        body: &'static str,
+    ) -> impl Fn(
+        Request<Incoming>,
+    )
+        -> std::future::Ready<Result<Response<Full<Bytes>>, Infallible>>
+           + Clone {
+        move |_| std::future::ready(Ok(Response::new(Full::from(body))))
+    }
+
+    /// The body of `GET /` over a TLS connection to `server_name`.
+    async fn get(
+        fixtures: &FixtureDir,
+        stream: DuplexStream,
+        server_name: &str,
+    ) -> String {
+        let config = fixtures.client_config("alice").unwrap();
+        let server_name = ServerName::try_from(server_name.to_owned());
+        let tls = TlsConnector::from(config)
+            .connect(server_name.unwrap(), stream)
+            .await
+            .unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(tls))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let req = Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        String::from_utf8(body.to_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn wildcards_match_a_single_label() {
+        let mut allowlist = SniAllowlist::default();
+        allowlist.insert("api.example.com".into());
+        allowlist.insert("*.internal.example".into());
+        assert!(allowlist.allows("API.example.com"));
+        assert!(allowlist.allows("db.internal.example"));
+        assert!(allowlist.allows("db.Internal.Example"));
+        assert!(!allowlist.allows("internal.example"));
+        assert!(!allowlist.allows(".internal.example"));
+        assert!(!allowlist.allows("a.db.internal.example"));
+        assert!(!allowlist.allows("www.example.com"));
+        assert!(!SniAllowlist::default().allows("api.example.com"));
+    }
+
+    #[tokio::test]
+    async fn rejects_server_names_outside_the_allowlist() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let alice = fixtures.client_config("alice").unwrap();
+        let connect = |server: &MtlServer| {
+            let acceptor = server.mtls_acceptor().unwrap();
+            let alice = alice.clone();
+            async move {
+                let conn = connect_duplex(&acceptor, alice, "localhost");
+                conn.await.is_ok()
+            }
+        };
+
+        let allowed = server.clone().with_allowed_sni(["LOCALHOST"]);
+        assert!(connect(&allowed).await);
+        let wildcard = server.clone().with_allowed_sni(["*.localhost"]);
+        assert!(!connect(&wildcard).await);
+        let both = wildcard.with_allowed_sni(["localhost"]);
+        assert!(connect(&both).await);
+    }
+
+    #[tokio::test]
+    async fn answers_clients_without_sni_with_the_fallback() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_allowed_sni(["localhost"])
+            .with_missing_sni_fallback(service_fn(respond("fallback")));
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let (by_name, server_by_name) = tokio::io::duplex(64 * 1024);
+        let (by_ip, server_by_ip) = tokio::io::duplex(64 * 1024);
+        let streams =
+            stream::iter([(server_by_name, addr), (server_by_ip, addr)]);
+        let serving = tokio::spawn(async move {
+            let service = service_fn(respond("application"));
+            server.serve_streams(streams, service).await
+        });
+
+        assert_eq!(get(&fixtures, by_name, "localhost").await, "application");
+        assert_eq!(get(&fixtures, by_ip, "127.0.0.1").await, "fallback");
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn needs_a_fallback_service() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures.server().with_missing_sni(MissingSni::Fallback);
+        let streams = stream::empty::<(DuplexStream, SocketAddr)>();
+        let service = service_fn(respond(""));
+        let err = server.serve_streams(streams, service).await.unwrap_err();
+        assert!(matches!(err, Error::MissingSniFallbackError), "{:?}", err);
+        // The acceptor hands such connections over like with Serve.
+        assert!(MtlServer::mtls_acceptor(&server).is_ok());
+    }
+}
@@ -0,0 +1,201 @@
+#[cfg(feature = "tokio")]
+use crate::handle::ListenerRegistration;
+#[cfg(feature = "tokio")]
+use crate::listener::Accept;
+use crate::log_policy::Logs;
+use crate::Error::{ServerCertExpiredError, ServerCertNotYetValidError};
+use crate::{
+    CertificateInfo, ClientAuth, Error, LogEvent, MtlServer, TlsVersion,
+};
+use rustls_pki_types::CertificateDer;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a server is about to serve with, reported once when serving starts
+/// to help spot a wrong certificate being deployed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct StartupInfo {
+    /// The leaf of the server chain, `None` if it couldn't be parsed.
+    pub server_cert: Option<CertificateInfo>,
+    pub client_auth: ClientAuth,
+    /// Number of CAs trusted to issue client certificates.
+    pub client_ca_count: usize,
+    pub alpn_protocols: Vec<Box<str>>,
+    pub tls_versions: Vec<TlsVersion>,
+}
+
+impl StartupInfo {
+    fn log(&self, logs: &Logs) {
+        let cert = self.server_cert.as_ref();
+        log_event!(
+            logs,
+            LogEvent::Startup,
+            subject = cert.map(|x| &*x.subject),
+            issuer = cert.map(|x| &*x.issuer),
+            not_after = cert.map(|x| x.not_after),
+            fingerprint = cert.map(|x| &*x.fingerprint),
+            key = cert.map(|x| &*x.public_key),
+            client_auth = ?self.client_auth,
+            client_cas = self.client_ca_count,
+            alpn = ?self.alpn_protocols,
+            tls_versions = ?self.tls_versions,
+            "serving with the loaded certificates"
+        );
+    }
+}
+
+/// Fails if `cert` is outside its validity period.
+pub(crate) fn check_validity(cert: &CertificateDer<'_>) -> Result<(), Error> {
+    let Some(cert) = CertificateInfo::from_cert(cert) else {
+        return Ok(());
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs() as i64);
+    if now > cert.not_after {
+        return Err(ServerCertExpiredError(cert.subject));
+    } else if now < cert.not_before {
+        return Err(ServerCertNotYetValidError(cert.subject));
+    }
+    Ok(())
+}
+
+fn collect<T>(errors: &mut Vec<Error>, result: Result<T, Error>) -> Option<T> {
+    result.map_err(|x| errors.push(x)).ok()
+}
+
+impl MtlServer {
+    /// Loads, parses and validates everything serving needs without binding
+    /// a socket, e.g. for CI or a `--check` flag. Returns the report that
+    /// serving would log, or every problem found.
+    pub fn check(&self) -> Result<StartupInfo, Vec<Error>> {
+        let mut errors = Vec::new();
+        collect(&mut errors, self.check_tls_versions());
+        collect(&mut errors, self.check_max_fragment_size());
+        collect(&mut errors, self.handle.renewal.check());
+        collect(&mut errors, self.http2.check());
+        collect(&mut errors, self.http_limits.check());
+        let chain = collect(&mut errors, self.load_server_cert());
+        let key = collect(&mut errors, self.load_server_key());
+        if self.client_auth != ClientAuth::Disabled {
+            collect(&mut errors, self.create_client_verifier(self.client_auth));
+        }
+
+        if let Some(leaf) = chain.as_ref().and_then(|x| x.first()) {
+            collect(&mut errors, check_validity(leaf));
+            if let Some(key) = &key {
+                collect(&mut errors, self.check_key_pair(leaf, key));
+            }
+        }
+
+        // Everything loads; let rustls validate the combination.
+        if errors.is_empty() {
+            collect(&mut errors, self.create_tls_config(self.client_auth));
+            #[cfg(feature = "tokio")]
+            collect(&mut errors, self.create_ocsp_checker());
+        }
+
+        if errors.is_empty() {
+            Ok(self.startup_info())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Builds the report from the certificates loaded last.
+    pub(crate) fn startup_info(&self) -> StartupInfo {
+        let pki = self.handle.pki();
+        StartupInfo {
+            server_cert: pki.server_chain.into_iter().next(),
+            client_auth: self.client_auth,
+            client_ca_count: pki.trust_anchors.len(),
+            alpn_protocols: self.alpn_names(),
+            tls_versions: self.enabled_tls_versions(),
+        }
+    }
+
+    pub(crate) fn alpn_names(&self) -> Vec<Box<str>> {
+        self.protocols
+            .iter()
+            .flat_map(|x| x.iter())
+            .map(|x| x.as_str().into())
+            .collect()
+    }
+
+    /// Publishes the listener on the handle and reports the startup info.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn start_listening(
+        &self,
+        listener: &impl Accept,
+    ) -> ListenerRegistration {
+        let info = self.startup_info();
+        info.log(&self.handle.logs);
+        let registration = self.handle.register_listener(listener, info);
+        self.handle.logs.start_summaries();
+        self.handle.start_expiry_checks();
+        self.handle.start_renewal();
+        registration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use http_body_util::Empty;
+    use hyper::body::Bytes;
+    use hyper::Response;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn reports_what_serving_started_with() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_tls_versions(TlsVersion::Tls13, TlsVersion::Tls13);
+        let handle = server.handle();
+        assert_eq!(handle.startup_info(), None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+            });
+            server.serve_service(listener, service).await
+        });
+        handle.listening().await;
+
+        let info = handle.startup_info().unwrap();
+        let cert = info.server_cert.unwrap();
+        assert!(cert.subject.ends_with("CN=localhost"));
+        assert_eq!(&*cert.public_key, "EC P-256");
+        assert_eq!(info.client_auth, ClientAuth::Required);
+        assert_eq!(info.client_ca_count, 1);
+        assert_eq!(info.alpn_protocols, ["http/1.1".into(), "h2".into()]);
+        assert_eq!(info.tls_versions, [TlsVersion::Tls13]);
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn checks_everything_serving_needs() {
+        let fixtures = FixtureDir::new().unwrap();
+        let info = fixtures.server().check().unwrap();
+        assert_eq!(info.client_ca_count, 1);
+
+        std::fs::remove_file(fixtures.path().join("server.key")).unwrap();
+        let errors = fixtures
+            .server()
+            .with_tls_versions(TlsVersion::Tls13, TlsVersion::Tls12)
+            .check()
+            .unwrap_err();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(matches!(errors[0], Error::TlsVersionBoundsError(..)));
+    }
+}
@@ -0,0 +1,307 @@
+use crate::Error;
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::fmt;
+use std::sync::Arc;
+
+/// A server identity kept in an operating system store rather than in
+/// files. The private key usually can't be exported, so the store signs
+/// the handshakes.
+pub(crate) trait StoreIdentity: fmt::Debug + Send + Sync {
+    /// The certificate chain, leaf first.
+    fn chain(&self) -> Result<Vec<CertificateDer<'static>>, Error>;
+
+    /// The key of the leaf, signing through the store.
+    fn key(&self) -> Result<Arc<dyn SigningKey>, Error>;
+}
+
+/// Client CA certificates kept in an operating system store.
+pub(crate) trait StoreCas: fmt::Debug + Send + Sync {
+    fn certificates(&self) -> Result<Vec<CertificateDer<'static>>, Error>;
+}
+
+/// The server's private key, as loaded from a file or given to `with_key`,
+/// or kept in a store.
+#[derive(Debug)]
+pub(crate) enum ServerKey {
+    Der(PrivateKeyDer<'static>),
+    Store(Arc<dyn SigningKey>),
+}
+
+impl ServerKey {
+    pub(crate) fn clone_key(&self) -> Self {
+        match self {
+            Self::Der(key) => Self::Der(key.clone_key()),
+            Self::Store(key) => Self::Store(key.clone()),
+        }
+    }
+
+    /// The key's encoding, `None` for a key kept in a store.
+    pub(crate) fn der(&self) -> Option<&PrivateKeyDer<'static>> {
+        match self {
+            Self::Der(key) => Some(key),
+            Self::Store(_) => None,
+        }
+    }
+
+    pub(crate) fn signing_key(
+        &self,
+        provider: &CryptoProvider,
+    ) -> Result<Arc<dyn SigningKey>, Error> {
+        match self {
+            Self::Der(key) => provider
+                .key_provider
+                .load_private_key(key.clone_key())
+                .map_err(Error::ServerConfigError),
+            Self::Store(key) => Ok(key.clone()),
+        }
+    }
+}
+
+/// Always serves the same chain, for keys rustls can't load itself.
+#[derive(Debug)]
+pub(crate) struct SingleCert(pub(crate) Arc<CertifiedKey>);
+
+impl ResolvesServerCert for SingleCert {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+#[cfg(any(
+    test,
+    all(windows, feature = "windows-store"),
+    all(target_os = "macos", feature = "keychain")
+))]
+pub(crate) use signing::{KeyKind, StoreKey, StoreSigner};
+
+/// Whether `cert` signed itself, as roots do.
+#[cfg(any(
+    all(windows, feature = "windows-store"),
+    all(target_os = "macos", feature = "keychain")
+))]
+pub(crate) fn is_self_signed(cert: &CertificateDer<'_>) -> bool {
+    use x509_parser::prelude::{FromDer, X509Certificate};
+
+    X509Certificate::from_der(cert)
+        .is_ok_and(|(_, x)| x.subject().as_raw() == x.issuer().as_raw())
+}
+
+#[cfg(any(
+    test,
+    all(windows, feature = "windows-store"),
+    all(target_os = "macos", feature = "keychain")
+))]
+mod signing {
+    use rustls::sign::{Signer, SigningKey};
+    use rustls::{SignatureAlgorithm, SignatureScheme};
+    use rustls_pki_types::CertificateDer;
+    use std::fmt;
+    use std::sync::Arc;
+    use x509_parser::oid_registry::{
+        OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_NIST_EC_P384,
+        OID_PKCS1_RSAENCRYPTION,
+    };
+    use x509_parser::prelude::{FromDer, X509Certificate};
+
+    /// The keys stores can sign with.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub(crate) enum KeyKind {
+        Rsa,
+        EcP256,
+        EcP384,
+    }
+
+    impl KeyKind {
+        /// The kind of the key `cert` certifies, `None` if stores can't
+        /// sign with it.
+        pub(crate) fn of_cert(cert: &CertificateDer<'_>) -> Option<Self> {
+            let (_, cert) = X509Certificate::from_der(cert).ok()?;
+            let algorithm = &cert.public_key().algorithm;
+            if algorithm.algorithm == OID_PKCS1_RSAENCRYPTION {
+                return Some(Self::Rsa);
+            }
+            if algorithm.algorithm != OID_KEY_TYPE_EC_PUBLIC_KEY {
+                return None;
+            }
+            let curve = algorithm.parameters.as_ref()?.as_oid().ok()?;
+            match curve {
+                x if x == OID_EC_P256 => Some(Self::EcP256),
+                x if x == OID_NIST_EC_P384 => Some(Self::EcP384),
+                _ => None,
+            }
+        }
+
+        /// The schemes the key signs with, most preferred first.
+        fn schemes(self) -> &'static [SignatureScheme] {
+            match self {
+                Self::Rsa => &[
+                    SignatureScheme::RSA_PSS_SHA512,
+                    SignatureScheme::RSA_PSS_SHA384,
+                    SignatureScheme::RSA_PSS_SHA256,
+                    SignatureScheme::RSA_PKCS1_SHA512,
+                    SignatureScheme::RSA_PKCS1_SHA384,
+                    SignatureScheme::RSA_PKCS1_SHA256,
+                ],
+                Self::EcP256 => &[SignatureScheme::ECDSA_NISTP256_SHA256],
+                Self::EcP384 => &[SignatureScheme::ECDSA_NISTP384_SHA384],
+            }
+        }
+    }
+
+    /// Signs with a key kept in a store, in a scheme of its [`KeyKind`].
+    /// ECDSA signatures are DER encoded, as TLS expects.
+    pub(crate) trait StoreSigner: fmt::Debug + Send + Sync {
+        fn sign(
+            &self,
+            scheme: SignatureScheme,
+            message: &[u8],
+        ) -> Result<Vec<u8>, rustls::Error>;
+    }
+
+    /// A rustls signing key backed by a store.
+    #[derive(Debug)]
+    pub(crate) struct StoreKey {
+        kind: KeyKind,
+        signer: Arc<dyn StoreSigner>,
+    }
+
+    impl StoreKey {
+        pub(crate) fn new(kind: KeyKind, signer: Arc<dyn StoreSigner>) -> Self {
+            Self { kind, signer }
+        }
+    }
+
+    impl SigningKey for StoreKey {
+        fn choose_scheme(
+            &self,
+            offered: &[SignatureScheme],
+        ) -> Option<Box<dyn Signer>> {
+            let scheme =
+                self.kind.schemes().iter().find(|x| offered.contains(x))?;
+            Some(Box::new(StoreSchemeSigner {
+                scheme: *scheme,
+                signer: self.signer.clone(),
+            }))
+        }
+
+        fn algorithm(&self) -> SignatureAlgorithm {
+            match self.kind {
+                KeyKind::Rsa => SignatureAlgorithm::RSA,
+                KeyKind::EcP256 | KeyKind::EcP384 => SignatureAlgorithm::ECDSA,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct StoreSchemeSigner {
+        scheme: SignatureScheme,
+        signer: Arc<dyn StoreSigner>,
+    }
+
+    impl Signer for StoreSchemeSigner {
+        fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+            self.signer.sign(self.scheme, message)
+        }
+
+        fn scheme(&self) -> SignatureScheme {
+            self.scheme
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::connect_duplex;
+    use crate::testing::fixtures::{
+        FixedClock, FixtureDir, ALICE_KEY, SERVER_KEY,
+    };
+    use crate::MtlServer;
+    use rustls::SignatureScheme;
+
+    /// Signs with a software key, standing in for a store.
+    #[derive(Debug)]
+    struct SoftwareSigner(Arc<dyn SigningKey>);
+
+    impl StoreSigner for SoftwareSigner {
+        fn sign(
+            &self,
+            scheme: SignatureScheme,
+            message: &[u8],
+        ) -> Result<Vec<u8>, rustls::Error> {
+            let signer = self.0.choose_scheme(&[scheme]).unwrap();
+            assert_eq!(signer.scheme(), scheme);
+            signer.sign(message)
+        }
+    }
+
+    #[derive(Debug)]
+    struct SoftwareStore {
+        chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    }
+
+    impl StoreIdentity for SoftwareStore {
+        fn chain(&self) -> Result<Vec<CertificateDer<'static>>, Error> {
+            Ok(self.chain.clone())
+        }
+
+        fn key(&self) -> Result<Arc<dyn SigningKey>, Error> {
+            let provider = rustls::crypto::aws_lc_rs::default_provider();
+            let key = ServerKey::Der(self.key.clone_key());
+            let kind = KeyKind::of_cert(&self.chain[0]).unwrap();
+            let signer = SoftwareSigner(key.signing_key(&provider)?);
+            Ok(Arc::new(StoreKey::new(kind, Arc::new(signer))))
+        }
+    }
+
+    fn store(fixtures: &FixtureDir) -> SoftwareStore {
+        let path = fixtures.path().join("server.crt");
+        let mut pem = SERVER_KEY.as_bytes();
+        SoftwareStore {
+            chain: MtlServer::load_cert(path.to_str().unwrap()).unwrap(),
+            key: rustls_pemfile::private_key(&mut pem).unwrap().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_an_identity_signing_through_a_store() {
+        let fixtures = FixtureDir::new().unwrap();
+        let mut server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        server.store_identity = Some(Arc::new(store(&fixtures)));
+        // Nothing may be read from the files.
+        std::fs::write(&*fixtures.file("server.crt"), "").unwrap();
+        std::fs::write(&*fixtures.file("server.key"), "").unwrap();
+
+        server.check().unwrap();
+        let acceptor = server.mtls_acceptor().unwrap();
+        let config = fixtures.client_config("alice").unwrap();
+        connect_duplex(&acceptor, config, "localhost")
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_a_store_key_of_another_certificate() {
+        let fixtures = FixtureDir::new().unwrap();
+        let mut store = store(&fixtures);
+        let mut pem = ALICE_KEY.as_bytes();
+        store.key = rustls_pemfile::private_key(&mut pem).unwrap().unwrap();
+        let mut server = fixtures.server();
+        server.store_identity = Some(Arc::new(store));
+
+        let errors = server.check().err().unwrap();
+        assert!(
+            errors
+                .iter()
+                .any(|x| matches!(x, Error::ServerKeyMismatchError(_))),
+            "{:?}",
+            errors
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Helpers for smoke tests and readiness probes of the TLS stack itself,
+//! and for unit tests of connection handling without sockets.
+
+pub mod fixtures;
+
+use crate::Error::{
+    ClientConfigError, ProbeConnectError, ProbeRequestError, TrustStoreError,
+};
+use crate::{
+    crypto_provider, CertificateInfo, ConnInfo, Error, HandshakeError,
+    MtlServer, MtlsAcceptor, Protocol, ServerTlsStream, TlsVersion,
+};
+use http_body_util::Empty;
+use hyper::body::Bytes;
+use hyper::header::HOST;
+use hyper::{Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::pki_types::ServerName;
+use rustls::{CipherSuite, ClientConfig, ProtocolVersion, RootCertStore};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::net::TcpStream;
+use tokio_rustls::{client, TlsConnector};
+
+/// A client configuration presenting `client_cert` and verifying servers
+/// against `ca`, offering HTTP/2 and HTTP/1.1.
+pub fn client_config(
+    client_cert: &str,
+    client_key: &str,
+    ca: &str,
+) -> Result<Arc<ClientConfig>, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in MtlServer::load_cert(ca)? {
+        roots.add(cert).map_err(TrustStoreError)?;
+    }
+    let cert_chain = MtlServer::load_cert(client_cert)?;
+    let key = MtlServer::load_key(client_key)?;
+    let mut config = ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(ClientConfigError)?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(cert_chain, key)
+        .map_err(ClientConfigError)?;
+    config.alpn_protocols = [Protocol::HTTP_2, Protocol::HTTP_1]
+        .iter()
+        .map(|x| x.as_bytes().to_vec())
+        .collect();
+    Ok(Arc::new(config))
+}
+
+/// Both ends of a connection established by [`connect_duplex`].
+#[non_exhaustive]
+pub struct DuplexConnection {
+    /// The server side, as `serve_service` would receive it.
+    pub server: ServerTlsStream<DuplexStream>,
+    pub conn_info: ConnInfo,
+    pub client: client::TlsStream<DuplexStream>,
+}
+
+/// Connects a client to `acceptor` over an in-memory pipe instead of a
+/// socket, so connection handling can be unit tested deterministically.
+/// The client verifies the server certificate for `server_name`; the
+/// connection appears to come from `127.0.0.1:0`.
+///
+/// ```no_run
+/// # use http_body_util::Empty;
+/// # use hyper::body::{Bytes, Incoming};
+/// # use hyper::server::conn::http1;
+/// # use hyper::service::service_fn;
+/// # use hyper::{Request, Response};
+/// # use hyper_mtls_server::{testing, ConnInfo, MtlServer};
+/// # use hyper_util::rt::TokioIo;
+/// # use std::convert::Infallible;
+/// # async fn my_handler(
+/// #     _: Request<Incoming>,
+/// #     _: ConnInfo,
+/// # ) -> Result<Response<Empty<Bytes>>, Infallible> {
+/// #     Ok(Response::new(Empty::new()))
+/// # }
+/// # async fn example(server: MtlServer) -> Result<(), Box<dyn std::error::Error>> {
+/// let acceptor = server.mtls_acceptor()?;
+/// let config = testing::client_config("alice.crt", "alice.key", "ca.crt")?;
+/// let conn = testing::connect_duplex(&acceptor, config, "localhost").await?;
+/// let conn_info = conn.conn_info;
+/// let service = service_fn(move |req| my_handler(req, conn_info.clone()));
+/// tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(conn.server), service));
+/// # Ok(())
+/// # }
+/// ```
+pub async fn connect_duplex(
+    acceptor: &MtlsAcceptor,
+    config: Arc<ClientConfig>,
+    server_name: &str,
+) -> Result<DuplexConnection, HandshakeError> {
+    let server_name = ServerName::try_from(server_name.to_string())
+        .map_err(|x| io::Error::new(io::ErrorKind::InvalidInput, x))?;
+    let (client, server) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+    let remote_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+
+    let (client, server) = tokio::join!(
+        TlsConnector::from(config).connect(server_name, client),
+        acceptor.accept(server, remote_addr),
+    );
+    let (server, conn_info) = server?;
+    Ok(DuplexConnection {
+        server,
+        conn_info,
+        client: client?,
+    })
+}
+
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// What a [`probe`] negotiated with the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ProbeReport {
+    pub tls_version: Option<TlsVersion>,
+    pub cipher_suite: Option<CipherSuite>,
+    pub alpn_protocol: Option<Box<str>>,
+    /// The chain the server presented, leaf first.
+    pub server_chain: Vec<CertificateInfo>,
+    /// Status of a `GET /` sent over the connection.
+    pub status: StatusCode,
+    /// Time from connecting until the handshake completed.
+    pub handshake_time: Duration,
+}
+
+/// Connects to `addr` (`host:port`), performs a mutual TLS handshake with
+/// the given client certificate and sends `GET /`. The server certificate
+/// is verified against `ca` and the host of `addr`.
+///
+/// A client certificate the server rejects fails the request rather than
+/// the handshake with TLS 1.3, so only a response proves it was accepted.
+pub async fn probe(
+    addr: &str,
+    client_cert: &str,
+    client_key: &str,
+    ca: &str,
+) -> Result<ProbeReport, Error> {
+    let config = client_config(client_cert, client_key, ca)?;
+
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|x| ProbeConnectError(io::Error::other(x)))?;
+
+    let start = Instant::now();
+    let stream = TcpStream::connect(addr).await.map_err(ProbeConnectError)?;
+    let stream = TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await
+        .map_err(ProbeConnectError)?;
+    let handshake_time = start.elapsed();
+
+    let (_, conn) = stream.get_ref();
+    let tls_version = match conn.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => Some(TlsVersion::Tls12),
+        Some(ProtocolVersion::TLSv1_3) => Some(TlsVersion::Tls13),
+        _ => None,
+    };
+    let cipher_suite = conn.negotiated_cipher_suite().map(|x| x.suite());
+    let alpn_protocol = conn
+        .alpn_protocol()
+        .map(|x| String::from_utf8_lossy(x).into());
+    let server_chain = conn
+        .peer_certificates()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(CertificateInfo::from_cert)
+        .collect();
+
+    let status = request(stream, host, alpn_protocol.as_deref())
+        .await
+        .map_err(ProbeRequestError)?;
+
+    Ok(ProbeReport {
+        tls_version,
+        cipher_suite,
+        alpn_protocol,
+        server_chain,
+        status,
+        handshake_time,
+    })
+}
+
+async fn request(
+    stream: client::TlsStream<TcpStream>,
+    host: &str,
+    alpn_protocol: Option<&str>,
+) -> Result<StatusCode, hyper::Error> {
+    let io = TokioIo::new(stream);
+    let response = if alpn_protocol == Some(Protocol::HTTP_2.as_str()) {
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), io)
+                .await?;
+        tokio::spawn(conn);
+        let request = Request::get(format!("https://{}/", host))
+            .body(Empty::<Bytes>::new())
+            .expect("request is valid");
+        sender.send_request(request).await?
+    } else {
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(io).await?;
+        tokio::spawn(conn);
+        let request = Request::get("/")
+            .header(HOST, host)
+            .body(Empty::<Bytes>::new())
+            .expect("request is valid");
+        sender.send_request(request).await?
+    };
+    Ok(response.status())
+}
+
+/// Sends `req` over HTTP/1.1 to the server listening on `addr`, presenting
+/// the client certificate of `config` and verifying the server as
+/// `localhost`.
+#[cfg(test)]
+pub(crate) async fn send(
+    config: Arc<ClientConfig>,
+    addr: SocketAddr,
+    req: Request<Empty<Bytes>>,
+) -> Result<
+    hyper::Response<hyper::body::Incoming>,
+    Box<dyn std::error::Error + Send + Sync>,
+> {
+    let stream = TcpStream::connect(addr).await?;
+    let server_name = ServerName::try_from("localhost")?;
+    let stream = TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await?;
+    let (mut sender, conn) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(conn);
+    Ok(sender.send_request(req).await?)
+}
+
+/// An event recorded by [`Recorder`], with its fields formatted.
+#[cfg(all(test, feature = "tracing"))]
+#[derive(Debug)]
+pub(crate) struct RecordedEvent {
+    pub(crate) target: String,
+    pub(crate) level: tracing::Level,
+    pub(crate) fields: std::collections::BTreeMap<String, String>,
+}
+
+#[cfg(all(test, feature = "tracing"))]
+impl RecordedEvent {
+    pub(crate) fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+/// A `tracing` subscriber recording the events logged while the guard of
+/// [`Recorder::install`] is alive, on the current thread only.
+#[cfg(all(test, feature = "tracing"))]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Recorder {
+    events: Arc<std::sync::Mutex<Vec<RecordedEvent>>>,
+}
+
+#[cfg(all(test, feature = "tracing"))]
+impl Recorder {
+    pub(crate) fn install(&self) -> tracing::subscriber::DefaultGuard {
+        tracing::subscriber::set_default(self.clone())
+    }
+
+    /// The recorded events of `target`.
+    pub(crate) fn events(&self, target: &str) -> Vec<RecordedEvent> {
+        let mut events = self.events.lock().unwrap();
+        let (matching, rest) =
+            events.drain(..).partition(|x| x.target == target);
+        *events = rest;
+        matching
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+impl tracing::Subscriber for Recorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::Id {
+        tracing::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &tracing::Id, _: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &tracing::Id, _: &tracing::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        struct Fields<'a>(&'a mut std::collections::BTreeMap<String, String>);
+
+        impl tracing::field::Visit for Fields<'_> {
+            fn record_str(
+                &mut self,
+                field: &tracing::field::Field,
+                value: &str,
+            ) {
+                self.0.insert(field.name().into(), value.into());
+            }
+
+            fn record_debug(
+                &mut self,
+                field: &tracing::field::Field,
+                value: &dyn std::fmt::Debug,
+            ) {
+                self.0.insert(field.name().into(), format!("{:?}", value));
+            }
+        }
+
+        let mut fields = std::collections::BTreeMap::new();
+        event.record(&mut Fields(&mut fields));
+        self.events.lock().unwrap().push(RecordedEvent {
+            target: event.metadata().target().into(),
+            level: *event.metadata().level(),
+            fields,
+        });
+    }
+
+    fn enter(&self, _: &tracing::Id) {}
+
+    fn exit(&self, _: &tracing::Id) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir, SERVER_CERT};
+    use crate::Error::ProbeConnectError;
+    use std::convert::Infallible;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn probes_a_server() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                let mut response = hyper::Response::new(Empty::<Bytes>::new());
+                *response.status_mut() = StatusCode::NO_CONTENT;
+                Ok::<_, Infallible>(response)
+            });
+            server.serve_service(listener, service).await
+        });
+        let addr = format!("localhost:{}", port);
+        let alice = (fixtures.file("alice.crt"), fixtures.file("alice.key"));
+
+        let report = probe(&addr, &alice.0, &alice.1, &fixtures.file("ca.crt"))
+            .await
+            .unwrap();
+        assert_eq!(report.tls_version, Some(TlsVersion::Tls13));
+        assert!(report.cipher_suite.is_some());
+        assert_eq!(report.alpn_protocol.as_deref(), Some("http/1.1"));
+        assert_eq!(report.server_chain.len(), 2);
+        assert!(report.server_chain[0].subject.ends_with("CN=localhost"));
+        assert_eq!(report.status, StatusCode::NO_CONTENT);
+
+        // Without the root CA the server can't be verified.
+        let ca = fixtures.path().join("leaf.crt");
+        let end = "-----END CERTIFICATE-----\n";
+        let leaf = SERVER_CERT.split_inclusive(end).next().unwrap();
+        std::fs::write(&ca, leaf).unwrap();
+        let ca = ca.to_str().unwrap();
+        let err = probe(&addr, &alice.0, &alice.1, ca).await.unwrap_err();
+        assert!(matches!(err, ProbeConnectError(_)), "{:?}", err);
+
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn connects_in_memory() {
+        let fixtures = FixtureDir::new().unwrap();
+        let acceptor = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .mtls_acceptor()
+            .unwrap();
+        let alice = fixtures.client_config("alice").unwrap();
+
+        let conn = connect_duplex(&acceptor, alice.clone(), "localhost");
+        let DuplexConnection {
+            mut server,
+            conn_info,
+            mut client,
+        } = conn.await.unwrap();
+        let identity = conn_info.client_identity().unwrap();
+        assert_eq!(identity.common_name(), Some("alice"));
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        server.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        let conn = connect_duplex(&acceptor, alice, "not a server name");
+        assert!(conn.await.is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! A small PKI shared by tests, identical across tests and runs: a root CA,
+//! an intermediate CA, a server certificate for `localhost`, `127.0.0.1`
+//! and `::1`, and the clients `alice` and `bob`, whose certificate [`CRL`]
+//! revokes. All certificates are valid from 2025 to 2125, and
+//! [`FixedClock`] keeps handshakes from depending on the current time.
+//!
+//! ```no_run
+//! # use hyper_mtls_server::testing::fixtures::{FixedClock, FixtureDir};
+//! # use std::sync::Arc;
+//! # fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let fixtures = FixtureDir::new()?;
+//! let server = fixtures.server().with_time_provider(Arc::new(FixedClock::fixture()));
+//! let config = fixtures.client_config("alice")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::testing::client_config;
+use crate::Error::{ClientConfigError, TrustStoreError};
+use crate::{crypto_provider, Error, MtlServer};
+use rustls::pki_types::UnixTime;
+use rustls::time_provider::TimeProvider;
+use rustls::{ClientConfig, RootCertStore};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The root CA, trusted by servers and clients.
+pub const CA_CERT: &str = include_str!("fixtures/ca.crt");
+/// The CA that issued the server and client certificates.
+pub const INTERMEDIATE_CERT: &str = include_str!("fixtures/intermediate.crt");
+/// The server certificate followed by the intermediate.
+pub const SERVER_CERT: &str = include_str!("fixtures/server.crt");
+pub const SERVER_KEY: &str = include_str!("fixtures/server.key");
+/// The certificate of `alice`, `spiffe://example.org/alice`, followed by
+/// the intermediate.
+pub const ALICE_CERT: &str = include_str!("fixtures/alice.crt");
+pub const ALICE_KEY: &str = include_str!("fixtures/alice.key");
+/// The certificate of `bob`, `spiffe://example.org/bob`, followed by the
+/// intermediate. Revoked by [`CRL`].
+pub const BOB_CERT: &str = include_str!("fixtures/bob.crt");
+pub const BOB_KEY: &str = include_str!("fixtures/bob.key");
+/// The revocation list of the intermediate, revoking `bob`.
+pub const CRL: &str = include_str!("fixtures/intermediate.crl");
+
+/// The fixtures by the file name [`FixtureDir`] writes them to.
+const FILES: [(&str, &str); 9] = [
+    ("ca.crt", CA_CERT),
+    ("intermediate.crt", INTERMEDIATE_CERT),
+    ("server.crt", SERVER_CERT),
+    ("server.key", SERVER_KEY),
+    ("alice.crt", ALICE_CERT),
+    ("alice.key", ALICE_KEY),
+    ("bob.crt", BOB_CERT),
+    ("bob.key", BOB_KEY),
+    ("intermediate.crl", CRL),
+];
+
+/// 2030-01-01T00:00:00Z, within the validity of every fixture.
+const FIXTURE_TIME: Duration = Duration::from_secs(1_893_456_000);
+
+/// A clock standing still, for [`MtlServer::with_time_provider`] and
+/// rustls configurations, so certificate validity is checked the same way
+/// on every run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedClock(UnixTime);
+
+impl FixedClock {
+    pub fn at(time: UnixTime) -> Self {
+        Self(time)
+    }
+
+    /// 2030-01-01, when all fixtures are valid.
+    pub fn fixture() -> Self {
+        Self(UnixTime::since_unix_epoch(FIXTURE_TIME))
+    }
+}
+
+impl TimeProvider for FixedClock {
+    fn current_time(&self) -> Option<UnixTime> {
+        Some(self.0)
+    }
+}
+
+/// The fixtures written to a fresh temporary directory, for APIs taking
+/// paths. The directory is removed on drop.
+#[derive(Debug)]
+pub struct FixtureDir {
+    dir: PathBuf,
+}
+
+impl FixtureDir {
+    pub fn new() -> io::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "hyper-mtls-server-fixtures-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let fixtures = Self { dir };
+        for (name, contents) in FILES {
+            std::fs::write(fixtures.dir.join(name), contents)?;
+        }
+        Ok(fixtures)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The path of a fixture, e.g. `alice.crt` or `intermediate.crl`.
+    pub fn file(&self, name: &str) -> Box<str> {
+        self.dir.join(name).to_string_lossy().into()
+    }
+
+    /// A server with the fixture certificate, trusting the root CA.
+    pub fn server(&self) -> MtlServer {
+        MtlServer::new(
+            self.file("server.crt"),
+            self.file("server.key"),
+            self.file("ca.crt"),
+        )
+    }
+
+    /// A client configuration presenting the certificate of `client`,
+    /// `alice` or `bob`, see [`client_config`].
+    pub fn client_config(
+        &self,
+        client: &str,
+    ) -> Result<Arc<ClientConfig>, Error> {
+        client_config(
+            &self.file(&format!("{}.crt", client)),
+            &self.file(&format!("{}.key", client)),
+            &self.file("ca.crt"),
+        )
+    }
+
+    /// A client configuration trusting the root CA without presenting a
+    /// certificate.
+    pub fn anonymous_client_config(&self) -> Result<Arc<ClientConfig>, Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in MtlServer::load_cert(&self.file("ca.crt"))? {
+            roots.add(cert).map_err(TrustStoreError)?;
+        }
+        let config = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(ClientConfigError)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Arc::new(config))
+    }
+}
+
+impl Drop for FixtureDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
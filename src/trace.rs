@@ -0,0 +1,237 @@
+// Logging goes through `tracing` with the `tracing` feature, the default,
+// through the `log` crate with only the `log` feature, and nowhere without
+// either. The macros take the `tracing` syntax, a subset of it without
+// `tracing`: an optional target, `name = value` fields with the `%` and `?`
+// sigils, and a format string. Spans only exist with `tracing`.
+
+#[cfg(all(feature = "tokio", feature = "tracing"))]
+pub(crate) use tracing::{Instrument, Span};
+
+#[cfg(feature = "tracing")]
+macro_rules! event {
+    ($level:ident, $($args:tt)+) => {
+        tracing::$level!($($args)+)
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! debug_span {
+    ($($args:tt)+) => {
+        tracing::debug_span!($($args)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+    ($level:ident, target: $target:expr, $($args:tt)+) => {
+        fields!($level, $target, [] $($args)+)
+    };
+    ($level:ident, $($args:tt)+) => {
+        fields!($level, module_path!(), [] $($args)+)
+    };
+}
+
+/// Collects the fields of an event, then emits it with the message.
+#[cfg(not(feature = "tracing"))]
+macro_rules! fields {
+    ($level:ident, $target:expr, [$($fields:tt)*]
+        $name:ident = %$value:expr $(, $($rest:tt)*)?) => {
+        fields!($level, $target, [$($fields)*
+            ($name, $crate::trace::Display(&$value))] $($($rest)*)?)
+    };
+    ($level:ident, $target:expr, [$($fields:tt)*]
+        $name:ident = ?$value:expr $(, $($rest:tt)*)?) => {
+        fields!($level, $target, [$($fields)*
+            ($name, $crate::trace::Debug(&$value))] $($($rest)*)?)
+    };
+    ($level:ident, $target:expr, [$($fields:tt)*]
+        $name:ident = $value:expr $(, $($rest:tt)*)?) => {
+        fields!($level, $target, [$($fields)*
+            ($name, $crate::trace::Debug(&$value))] $($($rest)*)?)
+    };
+    ($level:ident, $target:expr, [$(($name:ident, $value:expr))*]
+        $($message:tt)*) => {{
+        #[allow(unused_imports)]
+        use $crate::trace::display;
+        #[cfg(feature = "log")]
+        log::$level!(
+            target: $target,
+            "{}{}",
+            message!($($message)*),
+            $crate::trace::Fields(&[$((
+                stringify!($name),
+                &$value as &dyn std::fmt::Display
+            )),*])
+        );
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = ($target, message!($($message)*), $(&$value,)*);
+        }
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! message {
+    () => {
+        format_args!("")
+    };
+    ($($message:tt)+) => {
+        format_args!($($message)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_span {
+    ($name:expr $(, $($fields:tt)*)?) => {{
+        if false {
+            fields!(debug, $name, [] $($($fields)*)?);
+        }
+        $crate::trace::Span
+    }};
+}
+
+/// Logs an event of a [`LogEvent`](crate::LogEvent) class at the level the
+/// server's policy sets for it, if any.
+macro_rules! log_event {
+    (level: $level:expr, $($args:tt)+) => {
+        match $level {
+            $crate::LogLevel::Off => {}
+            $crate::LogLevel::Error => event!(error, $($args)+),
+            $crate::LogLevel::Warn => event!(warn, $($args)+),
+            $crate::LogLevel::Info => event!(info, $($args)+),
+            $crate::LogLevel::Debug => event!(debug, $($args)+),
+        }
+    };
+    ($logs:expr, $event:expr, $($args:tt)+) => {
+        log_event!(level: $logs.level($event), $($args)+)
+    };
+}
+
+macro_rules! debug {
+    ($($args:tt)+) => {
+        event!(debug, $($args)+)
+    };
+}
+
+macro_rules! info {
+    ($($args:tt)+) => {
+        event!(info, $($args)+)
+    };
+}
+
+macro_rules! warn {
+    ($($args:tt)+) => {
+        event!(warn, $($args)+)
+    };
+}
+
+macro_rules! error {
+    ($($args:tt)+) => {
+        event!(error, $($args)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+mod fallback {
+    use std::fmt;
+    use std::future::Future;
+
+    /// Stands in for `tracing::Span`, carrying nothing.
+    #[derive(Clone, Debug)]
+    pub(crate) struct Span;
+
+    impl Span {
+        pub(crate) fn current() -> Self {
+            Span
+        }
+    }
+
+    pub(crate) trait Instrument: Future + Sized {
+        fn instrument(self, _: Span) -> Self {
+            self
+        }
+    }
+
+    impl<F: Future> Instrument for F {}
+
+    /// Formats a field with `Display`, like `tracing::field::display`.
+    pub(crate) struct Display<T>(pub(crate) T);
+
+    impl<T: fmt::Display> fmt::Display for Display<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl<T: fmt::Display> fmt::Debug for Display<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    pub(crate) fn display<T: fmt::Display>(value: T) -> Display<T> {
+        Display(value)
+    }
+
+    /// Formats a field with `Debug`.
+    pub(crate) struct Debug<T>(pub(crate) T);
+
+    impl<T: fmt::Debug> fmt::Display for Debug<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    /// Appends the fields to the message as `name=value`.
+    #[cfg(feature = "log")]
+    pub(crate) struct Fields<'a>(
+        pub(crate) &'a [(&'static str, &'a dyn fmt::Display)],
+    );
+
+    #[cfg(feature = "log")]
+    impl fmt::Display for Fields<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (name, value) in self.0 {
+                write!(f, " {}={}", name, value)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(feature = "log", not(feature = "tracing")))]
+pub(crate) use fallback::Fields;
+#[cfg(not(feature = "tracing"))]
+pub(crate) use fallback::{display, Debug, Display};
+#[cfg(all(feature = "tokio", not(feature = "tracing")))]
+pub(crate) use fallback::{Instrument, Span};
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn logs_events_with_their_fields_through_tracing() {
+        let recorder = crate::testing::Recorder::default();
+        let _guard = recorder.install();
+        let name = "alice";
+        info!(target: "mtls::test", client = %name, count = 2, "hello {}", 1);
+
+        let events = recorder.events("mtls::test");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, tracing::Level::INFO);
+        assert_eq!(events[0].field("client"), Some("alice"));
+        assert_eq!(events[0].field("count"), Some("2"));
+        assert_eq!(events[0].field("message"), Some("hello 1"));
+    }
+
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    #[test]
+    fn appends_the_fields_to_log_messages() {
+        let name = "alice";
+        let fields = super::Fields(&[
+            ("client", &super::Display(&name)),
+            ("count", &super::Debug(&Some(2))),
+        ]);
+        assert_eq!(fields.to_string(), " client=alice count=Some(2)");
+    }
+}
@@ -0,0 +1,224 @@
+use crate::listener::Accept;
+use crate::{Error, MtlServer};
+use hyper::body::{Body, Incoming};
+use hyper::{Request, Response};
+use socket2::SockRef;
+use std::convert::Infallible;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::thread;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tower_service::Service;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type Accepted = io::Result<(std::net::TcpStream, SocketAddr)>;
+
+/// Accepted connections waiting for the accept loop, per thread.
+const QUEUE_DEPTH: usize = 64;
+
+/// A listener accepting connections through io_uring on dedicated threads,
+/// for servers taking more connections than one accept loop keeps up with.
+/// Experimental and Linux only; the kernel has to allow io_uring. The
+/// accepted sockets are handed to the tokio runtime, so the TLS and HTTP
+/// I/O still goes through epoll: tokio-rustls and hyper read and write
+/// through `AsyncRead` and `AsyncWrite`, which the completion based sockets
+/// of tokio-uring don't implement. Serve it with
+/// [`MtlServer::serve_service_uring`].
+///
+/// ```no_run
+/// # use http_body_util::Empty;
+/// # use hyper::body::{Bytes, Incoming};
+/// # use hyper::{Request, Response};
+/// # use hyper_mtls_server::{MtlServer, UringListener};
+/// # use std::convert::Infallible;
+/// # async fn example(server: MtlServer) -> Result<(), Box<dyn std::error::Error>> {
+/// # let service = tower::service_fn(|_: Request<Incoming>| async {
+/// #     Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+/// # });
+/// let listener = std::net::TcpListener::bind("0.0.0.0:8443")?;
+/// let listener = UringListener::new(listener, 4)?;
+/// server.serve_service_uring(listener, service).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct UringListener {
+    listener: std::net::TcpListener,
+    accepted: Mutex<mpsc::Receiver<Accepted>>,
+}
+
+impl UringListener {
+    /// Accepts the connections of `listener` on `threads` threads, each
+    /// with its own ring. Fails if a ring can't be set up, e.g. because
+    /// io_uring is disabled.
+    pub fn new(
+        listener: std::net::TcpListener,
+        threads: usize,
+    ) -> io::Result<Self> {
+        let threads = threads.max(1);
+        listener.set_nonblocking(true)?;
+        let (sender, accepted) = mpsc::channel(threads * QUEUE_DEPTH);
+        let uring = Self {
+            listener,
+            accepted: Mutex::new(accepted),
+        };
+        for i in 0..threads {
+            let listener = uring.listener.try_clone()?;
+            let sender = sender.clone();
+            let (started, starting) = std::sync::mpsc::sync_channel(1);
+            thread::Builder::new()
+                .name(format!("mtls-uring-accept-{}", i))
+                .spawn(move || {
+                    let builder = tokio_uring::builder();
+                    match tokio_uring::Runtime::new(&builder) {
+                        Ok(runtime) => {
+                            let _ = started.send(Ok(()));
+                            runtime.block_on(accept(listener, sender));
+                        }
+                        Err(err) => {
+                            let _ = started.send(Err(err));
+                        }
+                    }
+                })?;
+            starting.recv().map_err(io::Error::other)??;
+        }
+        Ok(uring)
+    }
+}
+
+/// Accepts connections until the listener is dropped.
+async fn accept(
+    listener: std::net::TcpListener,
+    sender: mpsc::Sender<Accepted>,
+) {
+    let listener = tokio_uring::net::TcpListener::from_std(listener);
+    loop {
+        let accepted = listener.accept().await.and_then(|(stream, addr)| {
+            // SAFETY: the stream owns the descriptor while it is borrowed.
+            let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+            // tokio-uring streams can't give up their socket, so hand over
+            // a duplicate.
+            let stream = std::net::TcpStream::from(fd.try_clone_to_owned()?);
+            stream.set_nonblocking(true)?;
+            Ok((stream, addr))
+        });
+        if sender.send(accepted).await.is_err() {
+            return;
+        }
+    }
+}
+
+impl Drop for UringListener {
+    fn drop(&mut self) {
+        self.accepted.get_mut().close();
+        // Wakes up the threads waiting in accept, which then find the queue
+        // closed.
+        let _ = SockRef::from(&self.listener).shutdown(Shutdown::Read);
+    }
+}
+
+impl Accept for UringListener {
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let accepted = self.accepted.lock().await.recv().await;
+        let accepted = accepted.unwrap_or_else(|| {
+            Err(io::Error::other("the uring accept threads stopped"))
+        });
+        let (stream, addr) = accepted?;
+        Ok((TcpStream::from_std(stream)?, addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+impl MtlServer {
+    /// Like [`MtlServer::serve_service`], accepting through io_uring, see
+    /// [`UringListener`].
+    pub async fn serve_service_uring<S, B>(
+        &self,
+        listener: UringListener,
+        service: S,
+    ) -> Result<(), Error>
+    where
+        S: Service<Request<Incoming>, Response = Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        self.serve_accepting(listener, move |_| {
+            let service = service.clone();
+            async move { Ok::<_, Infallible>(service) }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::Bytes;
+    use hyper_util::rt::TokioIo;
+    use rustls::pki_types::ServerName;
+    use std::sync::Arc;
+    use tokio_rustls::TlsConnector;
+    use tower::service_fn;
+
+    /// The body of `GET /` over a TLS connection to `addr`.
+    async fn get(fixtures: &FixtureDir, addr: SocketAddr) -> String {
+        let config = fixtures.client_config("alice").unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let tls = TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await
+            .unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(tls))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let req = Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        let body = response.into_body().collect().await.unwrap();
+        String::from_utf8(body.to_bytes().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_connections_accepted_through_io_uring() {
+        let fixtures = FixtureDir::new().unwrap();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener = UringListener::new(listener, 2).unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Full::<Bytes>::from("uring")))
+            });
+            server.serve_service_uring(listener, service).await
+        });
+
+        let addr = handle.listening().await;
+        for _ in 0..3 {
+            assert_eq!(get(&fixtures, addr).await, "uring");
+        }
+        assert_eq!(handle.metrics().connections_accepted, 3);
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+    }
+}
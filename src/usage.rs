@@ -0,0 +1,257 @@
+use crate::{ClientIdentity, CloseReason, ConnInfo, ConnectionId};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// What a client used during one connection, reported when it closes.
+/// Bytes are application data, i.e. after decryption.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Usage<'a> {
+    pub identity: &'a ClientIdentity,
+    pub connection_id: ConnectionId,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub requests: u64,
+    pub duration: Duration,
+    pub close_reason: CloseReason,
+}
+
+/// Receives the usage of every connection with a client certificate, e.g.
+/// for billing or chargeback. Called from the connection task as it ends,
+/// so hand the record off rather than blocking.
+pub trait UsageSink: Send + Sync + 'static {
+    fn record(&self, usage: &Usage<'_>);
+}
+
+impl<F> UsageSink for F
+where
+    F: Fn(&Usage<'_>) + Send + Sync + 'static,
+{
+    fn record(&self, usage: &Usage<'_>) {
+        self(usage)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct UsageCounters {
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    requests: AtomicU64,
+}
+
+impl UsageCounters {
+    pub(crate) fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The usage of a connection, reported to the sink as it closes.
+pub(crate) struct UsageReport {
+    sink: Arc<dyn UsageSink>,
+    conn_info: ConnInfo,
+    counters: Arc<UsageCounters>,
+    started: Instant,
+}
+
+impl UsageReport {
+    /// Returns `None` for connections without a client certificate.
+    pub(crate) fn new(
+        sink: Arc<dyn UsageSink>,
+        conn_info: &ConnInfo,
+    ) -> Option<Self> {
+        conn_info.client_identity()?;
+        Some(Self {
+            sink,
+            conn_info: conn_info.clone(),
+            counters: Arc::default(),
+            started: Instant::now(),
+        })
+    }
+
+    pub(crate) fn counters(&self) -> Arc<UsageCounters> {
+        self.counters.clone()
+    }
+
+    pub(crate) fn report(self, close_reason: CloseReason) {
+        let Some(identity) = self.conn_info.client_identity() else {
+            return;
+        };
+        self.sink.record(&Usage {
+            identity,
+            connection_id: self.conn_info.id(),
+            bytes_received: self
+                .counters
+                .bytes_received
+                .load(Ordering::Relaxed),
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            requests: self.counters.requests.load(Ordering::Relaxed),
+            duration: self.started.elapsed(),
+            close_reason,
+        });
+    }
+}
+
+/// Counts the bytes read and written, if there are counters.
+pub(crate) struct CountingStream<S> {
+    inner: S,
+    counters: Option<Arc<UsageCounters>>,
+}
+
+impl<S> CountingStream<S> {
+    pub(crate) fn new(inner: S, counters: Option<Arc<UsageCounters>>) -> Self {
+        Self { inner, counters }
+    }
+
+    fn count_written(&self, poll: &Poll<io::Result<usize>>) {
+        if let (Some(counters), Poll::Ready(Ok(written))) =
+            (&self.counters, poll)
+        {
+            counters
+                .bytes_sent
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Some(counters) = &self.counters {
+            let read = (buf.filled().len() - filled) as u64;
+            counters.bytes_received.fetch_add(read, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.count_written(&poll);
+        poll
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.count_written(&poll);
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{FixedClock, FixtureDir};
+    use crate::ClientAuth;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::Bytes;
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+    use rustls::pki_types::ServerName;
+    use std::convert::Infallible;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_rustls::TlsConnector;
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn reports_the_usage_of_identified_clients() {
+        let fixtures = FixtureDir::new().unwrap();
+        let (records, mut recorded) = mpsc::unbounded_channel();
+        let server = fixtures
+            .server()
+            .with_time_provider(Arc::new(FixedClock::fixture()))
+            .with_client_auth(ClientAuth::Optional)
+            .with_usage_sink(move |usage: &Usage<'_>| {
+                let name = usage.identity.common_name().map(String::from);
+                let _ = records.send((
+                    name,
+                    usage.requests,
+                    usage.bytes_received,
+                    usage.bytes_sent,
+                    usage.close_reason,
+                ));
+            });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server.handle();
+        let serving = tokio::spawn(async move {
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Full::<Bytes>::from("0123")))
+            });
+            server.serve_service(listener, service).await
+        });
+
+        for client in ["alice", "anonymous"] {
+            let config = match client {
+                "alice" => fixtures.client_config("alice").unwrap(),
+                _ => fixtures.anonymous_client_config().unwrap(),
+            };
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let tls = TlsConnector::from(config)
+                .connect(server_name, stream)
+                .await
+                .unwrap();
+            let (mut sender, conn) =
+                hyper::client::conn::http1::handshake(TokioIo::new(tls))
+                    .await
+                    .unwrap();
+            let conn = tokio::spawn(conn);
+            for _ in 0..2 {
+                let req =
+                    Request::get("/").body(Empty::<Bytes>::new()).unwrap();
+                let response = sender.send_request(req).await.unwrap();
+                response.into_body().collect().await.unwrap();
+            }
+            drop(sender);
+            conn.await.unwrap().unwrap();
+        }
+
+        let (name, requests, received, sent, reason) =
+            recorded.recv().await.unwrap();
+        assert_eq!(name.as_deref(), Some("alice"));
+        assert_eq!(requests, 2);
+        assert!(received > 0 && sent > 0);
+        assert_eq!(reason, CloseReason::ClientClosed);
+        handle.shutdown();
+        serving.await.unwrap().unwrap();
+        assert!(recorded.try_recv().is_err());
+    }
+}
@@ -0,0 +1,548 @@
+use crate::der;
+use crate::store::{
+    is_self_signed, KeyKind, StoreCas, StoreIdentity, StoreKey, StoreSigner,
+};
+use crate::Error::WindowsStoreError;
+use crate::{Error, MtlServer};
+use rustls::sign::SigningKey;
+use rustls::SignatureScheme;
+use rustls_pki_types::CertificateDer;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::ffi::c_void;
+use std::sync::Arc;
+use std::{fmt, io, mem, ptr, slice};
+use windows_sys::core::{BOOL, HRESULT, PCWSTR};
+use windows_sys::Win32::Security::Cryptography::{
+    CertCloseStore, CertEnumCertificatesInStore, CertFindCertificateInStore,
+    CertFreeCertificateChain, CertFreeCertificateContext,
+    CertGetCertificateChain, CertOpenStore, CryptAcquireCertificatePrivateKey,
+    NCryptFreeObject, NCryptSignHash, BCRYPT_PAD_PKCS1, BCRYPT_PAD_PSS,
+    BCRYPT_PKCS1_PADDING_INFO, BCRYPT_PSS_PADDING_INFO,
+    BCRYPT_SHA256_ALGORITHM, BCRYPT_SHA384_ALGORITHM, BCRYPT_SHA512_ALGORITHM,
+    CERT_CHAIN_CONTEXT, CERT_CHAIN_PARA, CERT_CONTEXT, CERT_FIND_SHA1_HASH,
+    CERT_FIND_SUBJECT_STR_W, CERT_KEY_SPEC, CERT_NCRYPT_KEY_SPEC,
+    CERT_STORE_OPEN_EXISTING_FLAG, CERT_STORE_PROV_SYSTEM_W,
+    CERT_STORE_READONLY_FLAG, CERT_SYSTEM_STORE_CURRENT_USER,
+    CERT_SYSTEM_STORE_LOCAL_MACHINE, CRYPT_ACQUIRE_ONLY_NCRYPT_KEY_FLAG,
+    CRYPT_ACQUIRE_SILENT_FLAG, CRYPT_INTEGER_BLOB, HCERTSTORE,
+    NCRYPT_KEY_HANDLE, NCRYPT_SILENT_FLAG, PKCS_7_ASN_ENCODING,
+    X509_ASN_ENCODING,
+};
+
+/// Which of the system store collections a [`WindowsStore`] belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreLocation {
+    /// The stores of the account the server runs as, `Cert:\CurrentUser`.
+    CurrentUser,
+    /// The stores shared by all accounts, `Cert:\LocalMachine`. Services
+    /// need read access to the private key, granted in `certlm.msc`.
+    LocalMachine,
+}
+
+/// A system certificate store, e.g. `My` (Personal) or `Root`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WindowsStore {
+    location: StoreLocation,
+    name: Box<str>,
+}
+
+impl WindowsStore {
+    pub fn current_user(name: impl Into<Box<str>>) -> Self {
+        Self {
+            location: StoreLocation::CurrentUser,
+            name: name.into(),
+        }
+    }
+
+    pub fn local_machine(name: impl Into<Box<str>>) -> Self {
+        Self {
+            location: StoreLocation::LocalMachine,
+            name: name.into(),
+        }
+    }
+
+    fn open(&self) -> Result<Store, Error> {
+        let location = match self.location {
+            StoreLocation::CurrentUser => CERT_SYSTEM_STORE_CURRENT_USER,
+            StoreLocation::LocalMachine => CERT_SYSTEM_STORE_LOCAL_MACHINE,
+        };
+        let flags =
+            location | CERT_STORE_OPEN_EXISTING_FLAG | CERT_STORE_READONLY_FLAG;
+        let name = wide(&self.name);
+        // SAFETY: the name is a NUL terminated UTF-16 string that outlives
+        // the call.
+        let handle = unsafe {
+            CertOpenStore(
+                CERT_STORE_PROV_SYSTEM_W,
+                0,
+                0,
+                flags,
+                name.as_ptr().cast(),
+            )
+        };
+        if handle.is_null() {
+            return Err(os_error(format!("failed opening store {}", self)));
+        }
+        Ok(Store(handle))
+    }
+}
+
+impl fmt::Display for WindowsStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}\\{}", self.location, self.name)
+    }
+}
+
+/// How to find the server certificate in a [`WindowsStore`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertSelector {
+    /// The SHA-1 thumbprint in hex, as `certlm.msc` and `Get-ChildItem
+    /// Cert:\` show it. Spaces and colons are ignored.
+    Thumbprint(Box<str>),
+    /// The first certificate whose subject contains the string, ignoring
+    /// case.
+    Subject(Box<str>),
+}
+
+impl fmt::Display for CertSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Thumbprint(x) => write!(f, "thumbprint {}", x),
+            Self::Subject(x) => write!(f, "subject {:?}", x),
+        }
+    }
+}
+
+impl MtlServer {
+    /// Serves the certificate `selector` finds in `store` and its chain
+    /// instead of the certificate and key files. The key stays in the
+    /// store, which signs the handshakes, so non-exportable keys work.
+    /// Reloads look the certificate up again, picking up renewals that
+    /// keep the subject. Only RSA, P-256 and P-384 keys are supported.
+    pub fn with_windows_store_identity(
+        mut self,
+        store: WindowsStore,
+        selector: CertSelector,
+    ) -> Self {
+        self.store_identity =
+            Some(Arc::new(WindowsIdentity { store, selector }));
+        self
+    }
+
+    /// Also trusts the client certificates issued by the certificates in
+    /// `store`, e.g. a store holding only the client CAs. The client CA
+    /// file becomes optional.
+    pub fn with_windows_store_client_cas(
+        mut self,
+        store: WindowsStore,
+    ) -> Self {
+        self.store_client_cas = Some(Arc::new(store));
+        self
+    }
+}
+
+#[derive(Debug)]
+struct WindowsIdentity {
+    store: WindowsStore,
+    selector: CertSelector,
+}
+
+impl WindowsIdentity {
+    fn find(&self) -> Result<(Store, Cert), Error> {
+        let store = self.store.open()?;
+        let encoding = X509_ASN_ENCODING | PKCS_7_ASN_ENCODING;
+        let context = match &self.selector {
+            CertSelector::Thumbprint(hex) => {
+                let mut hash = thumbprint(hex)?;
+                let blob = CRYPT_INTEGER_BLOB {
+                    cbData: hash.len() as u32,
+                    pbData: hash.as_mut_ptr(),
+                };
+                // SAFETY: the blob points to the hash, which outlives the
+                // call.
+                unsafe {
+                    CertFindCertificateInStore(
+                        store.0,
+                        encoding,
+                        0,
+                        CERT_FIND_SHA1_HASH,
+                        ptr::addr_of!(blob).cast(),
+                        ptr::null(),
+                    )
+                }
+            }
+            CertSelector::Subject(subject) => {
+                let subject = wide(subject);
+                // SAFETY: the subject is a NUL terminated UTF-16 string that
+                // outlives the call.
+                unsafe {
+                    CertFindCertificateInStore(
+                        store.0,
+                        encoding,
+                        0,
+                        CERT_FIND_SUBJECT_STR_W,
+                        subject.as_ptr().cast(),
+                        ptr::null(),
+                    )
+                }
+            }
+        };
+        if context.is_null() {
+            return Err(WindowsStoreError {
+                what: format!(
+                    "no certificate with {} in store {}",
+                    self.selector, self.store
+                )
+                .into(),
+                source: io::ErrorKind::NotFound.into(),
+            });
+        }
+        Ok((store, Cert(context)))
+    }
+}
+
+impl StoreIdentity for WindowsIdentity {
+    fn chain(&self) -> Result<Vec<CertificateDer<'static>>, Error> {
+        let (store, cert) = self.find()?;
+        let para = CERT_CHAIN_PARA {
+            cbSize: mem::size_of::<CERT_CHAIN_PARA>() as u32,
+            // SAFETY: all-zero is a valid CERT_CHAIN_PARA, requesting no
+            // particular usage.
+            ..unsafe { mem::zeroed() }
+        };
+        let mut context: *mut CERT_CHAIN_CONTEXT = ptr::null_mut();
+        // SAFETY: the certificate and store are open, and the chain context
+        // is freed below.
+        let built: BOOL = unsafe {
+            CertGetCertificateChain(
+                ptr::null_mut(),
+                cert.0,
+                ptr::null(),
+                store.0,
+                &para,
+                0,
+                ptr::null(),
+                &mut context,
+            )
+        };
+        if built == 0 {
+            return Err(os_error(format!(
+                "failed building the chain of the certificate with {}",
+                self.selector
+            )));
+        }
+        // SAFETY: a built chain context holds at least one simple chain,
+        // whose elements are certificate contexts, leaf first.
+        let mut chain: Vec<CertificateDer<'static>> = unsafe {
+            let simple = &**(*context).rgpChain;
+            let elements = slice::from_raw_parts(
+                simple.rgpElement,
+                simple.cElement as usize,
+            );
+            elements
+                .iter()
+                .map(|x| encoded((**x).pCertContext))
+                .collect()
+        };
+        // SAFETY: the context came from CertGetCertificateChain.
+        unsafe { CertFreeCertificateChain(context) };
+
+        // Clients already have the root, as with chain files.
+        if chain.len() > 1 && chain.last().is_some_and(is_self_signed) {
+            chain.pop();
+        }
+        Ok(chain)
+    }
+
+    fn key(&self) -> Result<Arc<dyn SigningKey>, Error> {
+        let (_, cert) = self.find()?;
+        let leaf = encoded(cert.0);
+        let kind =
+            KeyKind::of_cert(&leaf).ok_or_else(|| WindowsStoreError {
+                what: format!(
+                "the key of the certificate with {} is not RSA, P-256 or P-384",
+                self.selector
+            )
+                .into(),
+                source: io::ErrorKind::Unsupported.into(),
+            })?;
+
+        let mut handle = 0;
+        let mut spec: CERT_KEY_SPEC = 0;
+        let mut owned: BOOL = 0;
+        // SAFETY: the certificate is open and the out pointers are valid.
+        let acquired = unsafe {
+            CryptAcquireCertificatePrivateKey(
+                cert.0,
+                CRYPT_ACQUIRE_ONLY_NCRYPT_KEY_FLAG | CRYPT_ACQUIRE_SILENT_FLAG,
+                ptr::null(),
+                &mut handle,
+                &mut spec,
+                &mut owned,
+            )
+        };
+        if acquired == 0 || spec != CERT_NCRYPT_KEY_SPEC {
+            return Err(os_error(format!(
+                "failed acquiring the private key of the certificate with {}",
+                self.selector
+            )));
+        }
+        let signer = CngKey {
+            handle,
+            owned: owned != 0,
+            kind,
+            _cert: cert,
+        };
+        Ok(Arc::new(StoreKey::new(kind, Arc::new(signer))))
+    }
+}
+
+impl StoreCas for WindowsStore {
+    fn certificates(&self) -> Result<Vec<CertificateDer<'static>>, Error> {
+        let store = self.open()?;
+        let mut certs = Vec::new();
+        let mut context: *const CERT_CONTEXT = ptr::null();
+        loop {
+            // SAFETY: passing the previous context frees it.
+            context = unsafe { CertEnumCertificatesInStore(store.0, context) };
+            if context.is_null() {
+                break;
+            }
+            certs.push(encoded(context));
+        }
+        Ok(certs)
+    }
+}
+
+/// A CNG key, signing with `NCryptSignHash`.
+struct CngKey {
+    handle: NCRYPT_KEY_HANDLE,
+    /// Whether the handle is ours to free, rather than cached by the
+    /// certificate context.
+    owned: bool,
+    kind: KeyKind,
+    /// Keeps a cached handle alive.
+    _cert: Cert,
+}
+
+impl fmt::Debug for CngKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CngKey").field("kind", &self.kind).finish()
+    }
+}
+
+impl StoreSigner for CngKey {
+    fn sign(
+        &self,
+        scheme: SignatureScheme,
+        message: &[u8],
+    ) -> Result<Vec<u8>, rustls::Error> {
+        use SignatureScheme::*;
+
+        let (hash, algorithm): (Vec<u8>, PCWSTR) = match scheme {
+            RSA_PSS_SHA256 | RSA_PKCS1_SHA256 | ECDSA_NISTP256_SHA256 => {
+                (Sha256::digest(message).to_vec(), BCRYPT_SHA256_ALGORITHM)
+            }
+            RSA_PSS_SHA384 | RSA_PKCS1_SHA384 | ECDSA_NISTP384_SHA384 => {
+                (Sha384::digest(message).to_vec(), BCRYPT_SHA384_ALGORITHM)
+            }
+            RSA_PSS_SHA512 | RSA_PKCS1_SHA512 => {
+                (Sha512::digest(message).to_vec(), BCRYPT_SHA512_ALGORITHM)
+            }
+            _ => {
+                return Err(rustls::Error::General(format!(
+                    "signature scheme {:?} is not supported",
+                    scheme
+                )));
+            }
+        };
+        let pss = BCRYPT_PSS_PADDING_INFO {
+            pszAlgId: algorithm,
+            cbSalt: hash.len() as u32,
+        };
+        let pkcs1 = BCRYPT_PKCS1_PADDING_INFO {
+            pszAlgId: algorithm,
+        };
+        let (padding, flags): (*const c_void, u32) = match scheme {
+            RSA_PSS_SHA256 | RSA_PSS_SHA384 | RSA_PSS_SHA512 => {
+                (ptr::addr_of!(pss).cast(), BCRYPT_PAD_PSS)
+            }
+            RSA_PKCS1_SHA256 | RSA_PKCS1_SHA384 | RSA_PKCS1_SHA512 => {
+                (ptr::addr_of!(pkcs1).cast(), BCRYPT_PAD_PKCS1)
+            }
+            _ => (ptr::null(), 0),
+        };
+        let flags = flags | NCRYPT_SILENT_FLAG;
+
+        // The first call reports the size of the signature.
+        let mut len = 0;
+        // SAFETY: the padding info and hash outlive the call, and a null
+        // output buffer only queries the size.
+        let result = unsafe {
+            NCryptSignHash(
+                self.handle,
+                padding,
+                hash.as_ptr(),
+                hash.len() as u32,
+                ptr::null_mut(),
+                0,
+                &mut len,
+                flags,
+            )
+        };
+        check(result)?;
+        let mut signature = vec![0; len as usize];
+        // SAFETY: as above, with an output buffer of the reported size.
+        let result = unsafe {
+            NCryptSignHash(
+                self.handle,
+                padding,
+                hash.as_ptr(),
+                hash.len() as u32,
+                signature.as_mut_ptr(),
+                len,
+                &mut len,
+                flags,
+            )
+        };
+        check(result)?;
+        signature.truncate(len as usize);
+
+        match self.kind {
+            KeyKind::Rsa => Ok(signature),
+            KeyKind::EcP256 | KeyKind::EcP384 => {
+                der::ecdsa_signature(&signature).ok_or_else(|| {
+                    rustls::Error::General(
+                        "CNG returned a malformed signature".into(),
+                    )
+                })
+            }
+        }
+    }
+}
+
+impl Drop for CngKey {
+    fn drop(&mut self) {
+        if self.owned {
+            // SAFETY: the handle came from CryptAcquireCertificatePrivateKey,
+            // which left freeing it to us.
+            unsafe { NCryptFreeObject(self.handle) };
+        }
+    }
+}
+
+// SAFETY: CNG key handles may be used from any thread, and concurrent
+// NCryptSignHash calls on one handle are allowed. The certificate context
+// is reference counted and not changed after the find.
+unsafe impl Send for CngKey {}
+unsafe impl Sync for CngKey {}
+
+/// An open certificate store, closed on drop.
+struct Store(HCERTSTORE);
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from CertOpenStore. Certificate contexts
+        // keep the store alive as long as they need it.
+        unsafe { CertCloseStore(self.0, 0) };
+    }
+}
+
+/// A certificate context, freed on drop.
+struct Cert(*const CERT_CONTEXT);
+
+impl Drop for Cert {
+    fn drop(&mut self) {
+        // SAFETY: the context came from a find, which left freeing it to us.
+        unsafe { CertFreeCertificateContext(self.0) };
+    }
+}
+
+/// The DER encoding of the certificate `context`.
+fn encoded(context: *const CERT_CONTEXT) -> CertificateDer<'static> {
+    // SAFETY: the caller keeps the context open, whose encoding is
+    // cbCertEncoded bytes long.
+    let der = unsafe {
+        slice::from_raw_parts(
+            (*context).pbCertEncoded,
+            (*context).cbCertEncoded as usize,
+        )
+    };
+    CertificateDer::from(der.to_vec())
+}
+
+fn thumbprint(hex: &str) -> Result<Vec<u8>, Error> {
+    let digits: Vec<u8> =
+        hex.bytes().filter(|x| !matches!(x, b' ' | b':')).collect();
+    let hash: Option<Vec<u8>> = digits
+        .chunks(2)
+        .map(|x| {
+            let x = std::str::from_utf8(x).ok()?;
+            u8::from_str_radix(x, 16).ok()
+        })
+        .collect();
+    match hash {
+        Some(hash)
+            if digits.len() == 40
+                && digits.iter().all(u8::is_ascii_hexdigit) =>
+        {
+            Ok(hash)
+        }
+        _ => Err(WindowsStoreError {
+            what: format!("invalid SHA-1 thumbprint {:?}", hex).into(),
+            source: io::ErrorKind::InvalidInput.into(),
+        }),
+    }
+}
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+fn os_error(what: String) -> Error {
+    WindowsStoreError {
+        what: what.into(),
+        source: io::Error::last_os_error(),
+    }
+}
+
+fn check(result: HRESULT) -> Result<(), rustls::Error> {
+    if result < 0 {
+        let err = io::Error::from_raw_os_error(result);
+        return Err(rustls::Error::General(format!(
+            "signing with the store key failed: {}",
+            err
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_thumbprints() {
+        let hex = "a9:09 50 2D d8 2a e4 14 33 e6 f8 38 86 b0 0d 42 77 a3 2a 7b";
+        let hash = thumbprint(hex).unwrap();
+        assert_eq!(hash.len(), 20);
+        assert_eq!(hash[..3], [0xa9, 0x09, 0x50]);
+
+        for hex in ["", "a909", &"+1".repeat(20), &"zz".repeat(20)] {
+            let err = thumbprint(hex).err().unwrap();
+            assert!(matches!(err, WindowsStoreError { .. }), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn reports_a_missing_certificate() {
+        let identity = WindowsIdentity {
+            store: WindowsStore::current_user("My"),
+            selector: CertSelector::Thumbprint("00".repeat(20).into()),
+        };
+        let Err(WindowsStoreError { source, .. }) = identity.chain() else {
+            panic!("found a certificate with an all-zero thumbprint");
+        };
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,108 @@
+#[cfg(feature = "tokio")]
+use crate::serve::Tasks;
+#[cfg(feature = "tokio")]
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::future::Future;
+use std::pin::Pin;
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+#[cfg(feature = "tokio")]
+use tokio::sync::{mpsc, Mutex};
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WorkerConfig {
+    pub(crate) workers: usize,
+    pub(crate) queue_depth: usize,
+}
+
+/// A fixed set of tasks sharing the accepted connections. Each worker
+/// drives its connections concurrently, picking up a new one from the
+/// queue whenever it is polled.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub(crate) struct WorkerPool {
+    sender: mpsc::Sender<Job>,
+}
+
+#[cfg(feature = "tokio")]
+impl WorkerPool {
+    pub(crate) fn new(config: WorkerConfig, tasks: &mut Tasks) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_depth.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..config.workers.max(1) {
+            tasks.spawn(work(receiver.clone()));
+        }
+        Self { sender }
+    }
+
+    /// Queues `job`, waiting while the queue is full.
+    pub(crate) async fn submit<F>(&self, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if self.sender.send(Box::pin(job)).await.is_err() {
+            error!("accept workers stopped, dropping connection");
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn work(receiver: Arc<Mutex<mpsc::Receiver<Job>>>) {
+    let mut jobs = FuturesUnordered::new();
+    loop {
+        let next = async { receiver.lock().await.recv().await };
+        tokio::select! {
+            job = next => match job {
+                Some(job) => jobs.push(job),
+                None => break,
+            },
+            Some(()) = jobs.next(), if !jobs.is_empty() => {}
+        }
+    }
+    while jobs.next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    #[tokio::test]
+    async fn one_worker_drives_several_connections() {
+        let metrics = Arc::new(Metrics::default());
+        let mut tasks = Tasks::new(metrics.clone());
+        let config = WorkerConfig {
+            workers: 1,
+            queue_depth: 1,
+        };
+        let pool = WorkerPool::new(config, &mut tasks);
+        assert_eq!(metrics.snapshot().connection_tasks, 1);
+
+        // Every job waits until all of them started.
+        let started = Arc::new(Semaphore::new(0));
+        let (done, mut finished) = mpsc::unbounded_channel();
+        for i in 0..3 {
+            let (started, done) = (started.clone(), done.clone());
+            pool.submit(async move {
+                started.add_permits(1);
+                let _ = started.acquire_many(3).await.unwrap();
+                done.send(i).unwrap();
+            })
+            .await;
+        }
+        drop(pool);
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let id =
+                tokio::time::timeout(Duration::from_secs(5), finished.recv());
+            ids.push(id.await.unwrap().unwrap());
+        }
+        ids.sort();
+        assert_eq!(ids, [0, 1, 2]);
+    }
+}
@@ -0,0 +1,268 @@
+use hyper::{Request, Response, StatusCode};
+use hyper_mtls_server::testing::fixtures::{
+    FixedClock, FixtureDir, CA_CERT, CRL, SERVER_KEY,
+};
+use hyper_mtls_server::testing::{connect_duplex, DuplexConnection};
+use hyper_mtls_server::{
+    AuthorizationLayer, ConnInfo, HandshakeError, HostValidationLayer,
+    MtlServer, PeerIdentity, PeerIdentityLayer, Requirement, RevocationCheck,
+    RevocationQuery, RevocationStatus,
+};
+use rustls::pki_types::UnixTime;
+use rustls::{ClientConfig, RootCertStore};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::{service_fn, Layer, Service, ServiceExt};
+use x509_parser::prelude::FromDer;
+use x509_parser::revocation_list::CertificateRevocationList;
+
+fn server(fixtures: &FixtureDir) -> MtlServer {
+    fixtures
+        .server()
+        .with_time_provider(Arc::new(FixedClock::fixture()))
+}
+
+async fn connect(
+    server: &MtlServer,
+    fixtures: &FixtureDir,
+    client: &str,
+) -> Result<DuplexConnection, HandshakeError> {
+    let acceptor = server.mtls_acceptor().unwrap();
+    let config = fixtures.client_config(client).unwrap();
+    connect_duplex(&acceptor, config, "localhost").await
+}
+
+/// The serial numbers the fixture CRL revokes, as lowercase hex.
+fn crl_serials() -> Vec<String> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(CRL.as_bytes()).unwrap();
+    let (_, crl) = CertificateRevocationList::from_der(&pem.contents).unwrap();
+    crl.iter_revoked_certificates()
+        .map(|x| {
+            x.raw_serial()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect()
+        })
+        .collect()
+}
+
+fn crl_check() -> RevocationCheck {
+    let revoked = Arc::new(crl_serials());
+    RevocationCheck::new(move |query: RevocationQuery| {
+        let revoked = revoked.clone();
+        async move {
+            let status = match revoked.iter().any(|x| *x == *query.serial) {
+                true => RevocationStatus::Revoked,
+                false => RevocationStatus::Good,
+            };
+            Ok::<_, Infallible>(status)
+        }
+    })
+}
+
+#[tokio::test]
+async fn accepts_a_trusted_client() {
+    let fixtures = FixtureDir::new().unwrap();
+    let conn = connect(&server(&fixtures), &fixtures, "alice")
+        .await
+        .unwrap();
+
+    let identity = conn.conn_info.client_identity().unwrap();
+    assert_eq!(identity.spiffe_id(), Some("spiffe://example.org/alice"));
+    assert_eq!(identity.common_name(), Some("alice"));
+    assert_eq!(conn.conn_info.server_name(), Some("localhost"));
+}
+
+#[tokio::test]
+async fn rejects_a_client_without_certificate() {
+    let fixtures = FixtureDir::new().unwrap();
+    let acceptor = server(&fixtures).mtls_acceptor().unwrap();
+    let mut roots = RootCertStore::empty();
+    let mut pem = CA_CERT.as_bytes();
+    let ca = rustls_pemfile::certs(&mut pem).map(Result::unwrap);
+    roots.add_parsable_certificates(ca);
+    let config = ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::aws_lc_rs::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    let err = connect_duplex(&acceptor, Arc::new(config), "localhost")
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(err, HandshakeError::NoClientCert), "{:?}", err);
+}
+
+#[tokio::test]
+async fn rejects_an_expired_client_certificate() {
+    let fixtures = FixtureDir::new().unwrap();
+    // 2200-01-01, after every fixture expired.
+    let later = UnixTime::since_unix_epoch(Duration::from_secs(7_258_118_400));
+    let server = fixtures
+        .server()
+        .with_time_provider(Arc::new(FixedClock::at(later)));
+
+    let err = connect(&server, &fixtures, "alice").await.err().unwrap();
+    assert!(matches!(err, HandshakeError::Expired), "{:?}", err);
+}
+
+#[tokio::test]
+async fn rejects_the_client_the_crl_revokes() {
+    assert_eq!(crl_serials(), ["05"]);
+    let fixtures = FixtureDir::new().unwrap();
+    let server = server(&fixtures).with_revocation_check(crl_check());
+
+    let err = connect(&server, &fixtures, "bob").await.err().unwrap();
+    assert!(matches!(err, HandshakeError::Revoked), "{:?}", err);
+    assert_eq!(server.handle().metrics().connections_revoked, 1);
+
+    connect(&server, &fixtures, "alice").await.unwrap();
+}
+
+#[tokio::test]
+async fn reloads_the_fixture_files() {
+    let fixtures = FixtureDir::new().unwrap();
+    let server = server(&fixtures);
+    let acceptor = server.mtls_acceptor().unwrap();
+    let handle = server.handle();
+    let config = fixtures.client_config("alice").unwrap();
+
+    std::fs::write(&*fixtures.file("server.key"), "").unwrap();
+    assert!(handle.reload().is_err());
+    assert!(handle.last_reload().unwrap().error.is_some());
+    connect_duplex(&acceptor, config.clone(), "localhost")
+        .await
+        .unwrap();
+
+    let key = std::fs::read(fixtures.path().join("alice.key")).unwrap();
+    std::fs::write(&*fixtures.file("server.key"), key).unwrap();
+    assert!(
+        handle.reload().is_err(),
+        "the key must match the certificate"
+    );
+
+    std::fs::write(&*fixtures.file("server.key"), SERVER_KEY).unwrap();
+    handle.reload().unwrap();
+    assert_eq!(handle.last_reload().unwrap().error, None);
+    connect_duplex(&acceptor, config, "localhost")
+        .await
+        .unwrap();
+}
+
+/// A request as `serve_service` would hand it to the service, on the
+/// connection of `conn`.
+fn request(conn: &DuplexConnection, host: &str, path: &str) -> Request<()> {
+    let mut req = Request::get(path).header("host", host).body(()).unwrap();
+    req.extensions_mut().insert(conn.conn_info.clone());
+    req
+}
+
+async fn status<S>(service: S, req: Request<()>) -> StatusCode
+where
+    S: Service<Request<()>, Response = Response<String>, Error = Infallible>,
+{
+    service.oneshot(req).await.unwrap().status()
+}
+
+/// Answers every request with `200 OK`.
+fn ok() -> impl Service<
+    Request<()>,
+    Response = Response<String>,
+    Error = Infallible,
+    Future = impl Send,
+> + Clone {
+    service_fn(|_| async { Ok(Response::new(String::new())) })
+}
+
+#[tokio::test]
+async fn authorizes_by_client_identity() {
+    let fixtures = FixtureDir::new().unwrap();
+    let conn = connect(&server(&fixtures), &fixtures, "alice")
+        .await
+        .unwrap();
+    let layer = AuthorizationLayer::new()
+        .require("/api", Requirement::SpiffeTrustDomain("example.org".into()))
+        .require("/admin", Requirement::CommonName("bob".into()));
+
+    let req = request(&conn, "localhost", "/api/items");
+    assert_eq!(status(layer.layer(ok()), req).await, StatusCode::OK);
+    let req = request(&conn, "localhost", "/admin");
+    assert_eq!(status(layer.layer(ok()), req).await, StatusCode::FORBIDDEN);
+    let req = request(&conn, "localhost", "/public");
+    assert_eq!(status(layer.layer(ok()), req).await, StatusCode::OK);
+
+    let req = Request::get("/api").body(()).unwrap();
+    assert_eq!(
+        status(layer.layer(ok()), req).await,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn validates_the_host_against_sni() {
+    let fixtures = FixtureDir::new().unwrap();
+    let conn = connect(&server(&fixtures), &fixtures, "alice")
+        .await
+        .unwrap();
+    let layer = HostValidationLayer::new().with_allowed_hosts(["127.0.0.1"]);
+
+    let req = request(&conn, "localhost:8443", "/");
+    assert_eq!(status(layer.layer(ok()), req).await, StatusCode::OK);
+    let req = request(&conn, "127.0.0.1", "/");
+    assert_eq!(status(layer.layer(ok()), req).await, StatusCode::OK);
+    let req = request(&conn, "other.example.org", "/");
+    assert_eq!(
+        status(layer.layer(ok()), req).await,
+        StatusCode::MISDIRECTED_REQUEST
+    );
+
+    let mut req = request(&conn, "localhost", "/");
+    req.headers_mut().remove("host");
+    assert_eq!(
+        status(layer.layer(ok()), req).await,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+/// The identity `layer` adds to a request carrying `xfcc`.
+async fn peer_identity(
+    layer: PeerIdentityLayer,
+    conn: &DuplexConnection,
+    xfcc: &str,
+) -> Option<PeerIdentity> {
+    let mut req = request(conn, "localhost", "/");
+    req.headers_mut()
+        .insert("x-forwarded-client-cert", xfcc.parse().unwrap());
+    let inner = service_fn(|req: Request<()>| async move {
+        assert!(req.extensions().get::<ConnInfo>().is_some());
+        Ok::<_, Infallible>(req.extensions().get::<PeerIdentity>().cloned())
+    });
+    layer.layer(inner).oneshot(req).await.unwrap()
+}
+
+#[tokio::test]
+async fn takes_the_peer_identity_from_trusted_proxies_only() {
+    let fixtures = FixtureDir::new().unwrap();
+    let conn = connect(&server(&fixtures), &fixtures, "alice")
+        .await
+        .unwrap();
+    let xfcc = "Hash=abcd;URI=spiffe://example.org/carol;Subject=\"CN=carol\"";
+
+    let direct = peer_identity(PeerIdentityLayer::new(), &conn, xfcc)
+        .await
+        .unwrap();
+    assert_eq!(direct.spiffe_id(), Some("spiffe://example.org/alice"));
+    assert_eq!(direct.forwarded_by(), None);
+
+    let layer = PeerIdentityLayer::new()
+        .with_trusted_proxies(["127.0.0.1".parse().unwrap()]);
+    let forwarded = peer_identity(layer, &conn, xfcc).await.unwrap();
+    assert_eq!(forwarded.spiffe_id(), Some("spiffe://example.org/carol"));
+    assert_eq!(forwarded.subject(), Some("CN=carol"));
+    assert_eq!(forwarded.fingerprint(), Some("abcd"));
+    assert_eq!(forwarded.forwarded_by(), Some(conn.conn_info.remote_addr()));
+}